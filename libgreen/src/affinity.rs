@@ -0,0 +1,77 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Pinning of scheduler threads to CPU cores
+//!
+//! Designs which shard work across schedulers (one per core) benefit from each
+//! scheduler, and the event loop it drives, staying on the same core for its
+//! whole life, keeping caches warm and interrupts local.
+
+/// Pins the calling OS thread to the CPU core numbered `cpu`, returning
+/// whether this succeeded.
+///
+/// This is currently supported on Linux and Windows, and always fails on other
+/// platforms.
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: uint) -> bool {
+    use std::mem;
+    use libc;
+
+    // The layout of glibc's cpu_set_t: a bitmask of 1024 cpus
+    #[repr(C)]
+    struct cpu_set_t { bits: [u64, ..16] }
+
+    extern {
+        fn sched_setaffinity(pid: libc::pid_t, size: libc::size_t,
+                             set: *const cpu_set_t) -> libc::c_int;
+    }
+
+    if cpu >= 1024 { return false }
+    let mut set = cpu_set_t { bits: [0, ..16] };
+    set.bits[cpu / 64] |= 1 << (cpu % 64);
+    // A pid of 0 refers to the calling thread
+    unsafe {
+        sched_setaffinity(0, mem::size_of::<cpu_set_t>() as libc::size_t,
+                          &set) == 0
+    }
+}
+
+#[cfg(windows)]
+pub fn pin_current_thread(cpu: uint) -> bool {
+    use std::uint;
+    use libc;
+
+    extern "system" {
+        fn GetCurrentThread() -> libc::HANDLE;
+        fn SetThreadAffinityMask(thread: libc::HANDLE,
+                                 mask: libc::size_t) -> libc::size_t;
+    }
+
+    if cpu >= uint::BITS { return false }
+    unsafe {
+        SetThreadAffinityMask(GetCurrentThread(), 1 << cpu) != 0
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn pin_current_thread(_cpu: uint) -> bool { false }
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::pin_current_thread;
+
+    #[test]
+    fn pin_first_cpu() {
+        let (tx, rx) = channel();
+        spawn(proc() tx.send(pin_current_thread(0)));
+        assert!(rx.recv());
+        assert!(!pin_current_thread(1024));
+    }
+}
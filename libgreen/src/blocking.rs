@@ -0,0 +1,125 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Offloading of blocking operations onto native threads.
+//!
+//! A green task which makes a blocking call (into a C library, or a slow
+//! syscall) blocks its entire scheduler, and with it every other task and all
+//! I/O on that scheduler's event loop. This module maintains a process-wide
+//! pool of native threads which such calls can be shipped off to instead.
+
+use std::mem;
+use std::rt;
+use std::rt::task::TaskOpts;
+use std::sync::{Arc, Mutex, Once, ONCE_INIT};
+use std::task::{mod, Spawner};
+use native::task::NativeSpawner;
+
+type Job = proc(): Send;
+
+static mut POOL: *const Mutex<Sender<Job>> = 0 as *const _;
+static INIT: Once = ONCE_INIT;
+
+/// Runs `f` on a native thread, returning its result.
+///
+/// Only the calling task is blocked while `f` is running; when called from a
+/// green task the task is descheduled, leaving its scheduler free to run other
+/// tasks and to service I/O in the meantime.
+///
+/// The native threads are shared by the whole process and are created the
+/// first time this function is called. There are as many of them as the
+/// runtime's default number of scheduler threads, so at most that many
+/// blocking operations make progress at once.
+///
+/// # Failure
+///
+/// This function will fail if `f` fails.
+pub fn run_blocking<T: Send>(f: proc(): Send -> T) -> T {
+    let (tx, rx) = channel();
+    let job: Job = proc() tx.send(f());
+    unsafe {
+        INIT.doit(init);
+        (*POOL).lock().send(job);
+    }
+    match rx.recv_opt() {
+        Ok(t) => t,
+        Err(()) => panic!("blocking operation failed"),
+    }
+}
+
+fn init() {
+    let (tx, rx) = channel();
+    let jobs = Arc::new(Mutex::new(rx));
+    for _ in range(0, rt::default_sched_threads()) {
+        spawn_worker(jobs.clone());
+    }
+    unsafe {
+        POOL = mem::transmute(box Mutex::new(tx));
+    }
+}
+
+fn spawn_worker(jobs: Arc<Mutex<Receiver<Job>>>) {
+    // If a job fails it takes its worker down with it, so the sentinel
+    // replaces the worker in order to keep the pool at full strength.
+    struct Sentinel { jobs: Arc<Mutex<Receiver<Job>>> }
+    impl Drop for Sentinel {
+        fn drop(&mut self) {
+            if task::failing() {
+                spawn_worker(self.jobs.clone());
+            }
+        }
+    }
+
+    let mut opts = TaskOpts::new();
+    opts.name = Some("<blocking worker>".into_maybe_owned());
+    NativeSpawner.spawn(opts, proc() {
+        let sentinel = Sentinel { jobs: jobs };
+        loop {
+            let job = match sentinel.jobs.lock().recv_opt() {
+                Ok(job) => job,
+                Err(()) => break,
+            };
+            job();
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use {SchedPool, PoolConfig, GreenTaskBuilder};
+    use std::task::TaskBuilder;
+    use super::run_blocking;
+
+    #[test]
+    fn smoke() {
+        let mut pool = SchedPool::new(PoolConfig::new());
+        let res = TaskBuilder::new().green(&mut pool).try(proc() {
+            run_blocking(proc() 1i + 2)
+        });
+        assert_eq!(res.ok().unwrap(), 3);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn failure_propagates() {
+        let mut pool = SchedPool::new(PoolConfig::new());
+        let res = TaskBuilder::new().green(&mut pool).try(proc() {
+            run_blocking::<()>(proc() panic!())
+        });
+        assert!(res.is_err());
+
+        // The pool is still usable after one of its jobs failed
+        let res = TaskBuilder::new().green(&mut pool).try(proc() {
+            run_blocking(proc() "ok")
+        });
+        assert_eq!(res.ok().unwrap(), "ok");
+        pool.shutdown();
+    }
+}
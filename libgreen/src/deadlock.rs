@@ -0,0 +1,142 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Detection of pools in which every task is blocked forever.
+//!
+//! A pool of schedulers is considered deadlocked when every scheduler in the
+//! pool has run out of work, none of them has any active I/O which could wake
+//! a task back up, and yet there are still tasks alive in the pool. In this
+//! situation all remaining tasks must be blocked on each other (for example on
+//! channels), and the process would otherwise hang silently.
+//!
+//! Note that tasks blocked on events from outside the pool (such as a native
+//! thread sending on a channel) look exactly like deadlocked tasks, which is
+//! why detection must be explicitly enabled.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUint, SeqCst};
+use time;
+
+/// Amount of time to wait for in-flight messages to wake up a scheduler before
+/// declaring the pool deadlocked.
+static GRACE_NS: u64 = 100 * 1000 * 1000;
+
+/// Told about pools in which every task is blocked forever.
+pub trait DeadlockHook {
+    /// Called with the number of tasks alive in the pool, along with the ids
+    /// and names of those tasks which were spawned while detection was
+    /// enabled.
+    ///
+    /// This is called from a scheduler thread of the deadlocked pool, outside
+    /// of any task, so it must not block on the tasks of the pool.
+    fn deadlocked(&self, active: uint, tasks: &[(uint, Option<String>)]);
+}
+
+pub struct Detector {
+    enabled: AtomicBool,
+    hook: Mutex<Option<Arc<Box<DeadlockHook + Send + Sync>>>>,
+    /// Number of schedulers running with this detector
+    scheds: AtomicUint,
+    /// Number of those schedulers which are out of work with no active I/O
+    idle: AtomicUint,
+    /// Bumped every time a scheduler changes between idle and awake
+    epoch: AtomicUint,
+    /// The epoch at which every scheduler was last seen idle, when that was
+    /// first noticed, and whether it has been reported already
+    suspect: Mutex<Option<(uint, u64, bool)>>,
+    /// Tasks spawned while detection was enabled, along with their names
+    tasks: Mutex<Vec<(uint, Option<String>)>>,
+}
+
+impl Detector {
+    pub fn new() -> Detector {
+        Detector {
+            enabled: AtomicBool::new(false),
+            hook: Mutex::new(None),
+            scheds: AtomicUint::new(0),
+            idle: AtomicUint::new(0),
+            epoch: AtomicUint::new(0),
+            suspect: Mutex::new(None),
+            tasks: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn set_hook(&self, hook: Option<Box<DeadlockHook + Send + Sync>>) {
+        let mut slot = self.hook.lock();
+        self.enabled.store(hook.is_some(), SeqCst);
+        if hook.is_none() {
+            self.tasks.lock().clear();
+        }
+        *slot = hook.map(|hook| Arc::new(hook));
+    }
+
+    pub fn sched_started(&self) { self.scheds.fetch_add(1, SeqCst); }
+    pub fn sched_exited(&self) { self.scheds.fetch_sub(1, SeqCst); }
+
+    pub fn task_started(&self, id: uint, name: Option<String>) {
+        if self.enabled.load(SeqCst) {
+            self.tasks.lock().push((id, name));
+        }
+    }
+
+    pub fn task_exited(&self, id: uint) {
+        if self.enabled.load(SeqCst) {
+            self.tasks.lock().retain(|&(task, _)| task != id);
+        }
+    }
+
+    /// Called when a scheduler which previously went idle has something to do
+    /// again.
+    pub fn awake(&self) {
+        self.epoch.fetch_add(1, SeqCst);
+        self.idle.fetch_sub(1, SeqCst);
+    }
+
+    /// Called when a scheduler runs out of work without any active I/O.
+    pub fn idle(&self) {
+        self.epoch.fetch_add(1, SeqCst);
+        self.idle.fetch_add(1, SeqCst);
+    }
+
+    /// Called by an idle scheduler with the number of tasks currently alive in
+    /// the pool, to check whether the pool is deadlocked.
+    ///
+    /// A pool is only reported once every scheduler has stayed idle for a grace
+    /// period, as a message waking up another scheduler may still be in
+    /// flight. Returns whether the caller should keep checking until then.
+    pub fn check(&self, active: uint) -> bool {
+        if !self.enabled.load(SeqCst) || active == 0 ||
+           self.idle.load(SeqCst) < self.scheds.load(SeqCst) {
+            return false
+        }
+
+        let epoch = self.epoch.load(SeqCst);
+        let now = time::precise_time_ns();
+        {
+            let mut suspect = self.suspect.lock();
+            match *suspect {
+                Some((e, _, true)) if e == epoch => return false,
+                Some((e, since, false)) if e == epoch => {
+                    if now - since < GRACE_NS { return true }
+                    *suspect = Some((epoch, since, true));
+                }
+                _ => { *suspect = Some((epoch, now, false)); return true }
+            }
+        }
+
+        let hook = match *self.hook.lock() {
+            Some(ref hook) => hook.clone(),
+            None => return false,
+        };
+        let tasks = self.tasks.lock().clone();
+        hook.deadlocked(active, tasks.as_slice());
+        false
+    }
+}
@@ -100,7 +100,8 @@
 //! * Native synchronization primitives (e.g. pthread mutexes) will also not
 //!   work for green threads. The reason for this is because native primitives
 //!   often operate on a _os thread_ granularity whereas green threads are
-//!   operating on a more granular unit of work.
+//!   operating on a more granular unit of work. The `sync` module provides
+//!   primitives which block green tasks instead.
 //!
 //! * A green threading runtime is not fork-safe. If the process forks(), it
 //!   cannot expect to make reasonable progress by continuing to use green
@@ -144,7 +145,23 @@
 //! }
 //! ```
 //!
-//! The above code can also be shortened with a macro from libgreen.
+//! The pool of schedulers can be configured further with a `Builder`:
+//!
+//! ```rust,ignore
+//! extern crate green;
+//! extern crate rustuv;
+//!
+//! #[start]
+//! fn start(argc: int, argv: *const *const u8) -> int {
+//!     rustuv::builder().threads(2).stack_size(64 * 1024).start(argc, argv, main)
+//! }
+//!
+//! fn main() {
+//!     // this code is running in a pool of two schedulers
+//! }
+//! ```
+//!
+//! The default configuration can also be started with a macro from libgreen.
 //!
 //! ```rust,ignore
 //! #![feature(phase)]
@@ -218,6 +235,7 @@
 extern crate libc;
 extern crate alloc;
 extern crate native;
+extern crate time;
 
 use std::mem::replace;
 use std::os;
@@ -230,21 +248,36 @@ use std::sync::deque;
 use std::task::{TaskBuilder, Spawner};
 
 use sched::{Shutdown, Scheduler, SchedHandle, TaskFromFriend, PinnedTask, NewNeighbor};
+use sched::{LostNeighbor, RetireNeighbor, SchedCounters, SchedStats};
+use deadlock::Detector;
 use sleeper_list::SleeperList;
 use stack::StackPool;
+use observer::{ObserverSlot, TaskObserver};
+use supervisor::{Hooks, PanicHook, RestartPolicy, Service};
 use task::GreenTask;
 
+pub use blocking::run_blocking;
+pub use deadlock::DeadlockHook;
+
 mod macros;
 mod simple;
 mod message_queue;
+mod deadlock;
+mod blocking;
 
+pub mod affinity;
 pub mod basic;
 pub mod context;
 pub mod coroutine;
+pub mod observer;
 pub mod sched;
+pub mod sigmask;
 pub mod sleeper_list;
 pub mod stack;
+pub mod supervisor;
+pub mod sync;
 pub mod task;
+pub mod testing;
 
 /// A helper macro for booting a program with libgreen
 ///
@@ -268,7 +301,7 @@ macro_rules! green_start( ($f:ident) => (
 
         #[start]
         fn start(argc: int, argv: *const *const u8) -> int {
-            green::start(argc, argv, rustuv::event_loop, super::$f)
+            rustuv::builder().start(argc, argv, super::$f)
         }
     }
 ) )
@@ -294,15 +327,7 @@ macro_rules! green_start( ($f:ident) => (
 pub fn start(argc: int, argv: *const *const u8,
              event_loop_factory: fn() -> Box<EventLoop + Send>,
              main: proc():Send) -> int {
-    rt::init(argc, argv);
-    let mut main = Some(main);
-    let mut ret = None;
-    simple::task().run(|| {
-        ret = Some(run(event_loop_factory, main.take().unwrap()));
-    }).destroy();
-    // unsafe is ok b/c we're sure that the runtime is gone
-    unsafe { rt::cleanup() }
-    ret.unwrap()
+    Builder::new().event_loop_factory(event_loop_factory).start(argc, argv, main)
 }
 
 /// Execute the main function in a pool of M:N schedulers.
@@ -315,27 +340,153 @@ pub fn start(argc: int, argv: *const *const u8,
 /// have returned.
 pub fn run(event_loop_factory: fn() -> Box<EventLoop + Send>,
            main: proc():Send) -> int {
-    // Create a scheduler pool and spawn the main task into this pool. We will
-    // get notified over a channel when the main task exits.
-    let mut cfg = PoolConfig::new();
-    cfg.event_loop_factory = event_loop_factory;
-    let mut pool = SchedPool::new(cfg);
-    let (tx, rx) = channel();
-    let mut opts = TaskOpts::new();
-    opts.on_exit = Some(proc(r) tx.send(r));
-    opts.name = Some("<main>".into_maybe_owned());
-    pool.spawn(opts, main);
-
-    // Wait for the main task to return, and set the process error code
-    // appropriately.
-    if rx.recv().is_err() {
-        os::set_exit_status(rt::DEFAULT_ERROR_CODE);
-    }
-
-    // Now that we're sure all tasks are dead, shut down the pool of schedulers,
-    // waiting for them all to return.
-    pool.shutdown();
-    os::get_exit_status()
+    Builder::new().event_loop_factory(event_loop_factory).run(main)
+}
+
+/// A builder for configuring and booting a program with libgreen.
+///
+/// This collects the configuration of the pool of schedulers along with that
+/// of the main task, so a `#[start]` function only needs a single call:
+///
+/// ```rust,ignore
+/// extern crate green;
+/// extern crate rustuv;
+///
+/// #[start]
+/// fn start(argc: int, argv: *const *const u8) -> int {
+///     rustuv::builder().threads(4).start(argc, argv, main)
+/// }
+///
+/// fn main() {
+///     // running in a pool of four schedulers powered by libuv
+/// }
+/// ```
+///
+/// Note that libgreen cannot depend on rustuv, so the event loop of a builder
+/// created with `Builder::new` is the basic event loop without I/O support.
+/// The `rustuv::builder` function returns a builder using rustuv's event loop.
+pub struct Builder {
+    config: PoolConfig,
+    options: PoolOptions,
+    stack_size: Option<uint>,
+}
+
+impl Builder {
+    /// Creates a new builder with the default configuration, as determined by
+    /// the environment variables of this process.
+    pub fn new() -> Builder {
+        Builder {
+            config: PoolConfig::new(),
+            options: PoolOptions::new(),
+            stack_size: None,
+        }
+    }
+
+    /// Sets the number of schedulers (OS threads) to spawn.
+    pub fn threads(mut self, threads: uint) -> Builder {
+        self.config.threads = threads;
+        self
+    }
+
+    /// Sets the size of the stack of the main task.
+    pub fn stack_size(mut self, size: uint) -> Builder {
+        self.stack_size = Some(size);
+        self
+    }
+
+    /// Sets the factory used to create the event loop of each scheduler.
+    pub fn event_loop_factory(mut self,
+                              factory: fn() -> Box<EventLoop + Send>)
+                              -> Builder {
+        self.config.event_loop_factory = factory;
+        self
+    }
+
+    /// Pins the schedulers (and with them their event loops) to the given CPU
+    /// cores.
+    ///
+    /// The nth scheduler spawned into the pool is pinned to the core at index
+    /// `n % len` of `cpus`, which must not be empty. Schedulers are free to
+    /// run on any core if this is not specified. See
+    /// `affinity::pin_current_thread` for the platforms on which this is
+    /// supported.
+    pub fn cpu_affinity(mut self, cpus: Vec<uint>) -> Builder {
+        assert!(cpus.len() > 0, "cpu affinity must list at least one core");
+        self.options.cpu_affinity = Some(cpus);
+        self
+    }
+
+    /// Blocks the given signals on every scheduler thread of the pool as it
+    /// starts, so they are never delivered to (and never interrupt system
+    /// calls made by) the schedulers.
+    ///
+    /// The signals are still received by libuv signal handles, as long as
+    /// some other thread of the process, such as the one which created the
+    /// pool, doesn't block them. See `sigmask::block_current_thread` for the
+    /// platforms on which this is supported.
+    pub fn block_signals(mut self, signals: Vec<libc::c_int>) -> Builder {
+        self.options.blocked_signals = signals;
+        self
+    }
+
+    /// Sets how many rounds a scheduler which runs out of work spends looking
+    /// for more (in its message queue and by stealing from the other
+    /// schedulers) before going to sleep.
+    ///
+    /// Spinning burns CPU, but it saves a full wakeup when work turns up
+    /// shortly after, as it does when tasks on different schedulers exchange
+    /// messages. Schedulers go to sleep right away if this is 0, the default.
+    pub fn spin_rounds(mut self, rounds: uint) -> Builder {
+        self.options.spin_rounds = rounds;
+        self
+    }
+
+    /// Creates a pool of schedulers with this configuration, without spawning
+    /// any task into it.
+    pub fn pool(self) -> SchedPool {
+        SchedPool::new_with(self.config, self.options)
+    }
+
+    /// Sets up the runtime given the compiler-supplied arguments and runs
+    /// `main` in a new pool of schedulers, as with the `start` function.
+    pub fn start(self, argc: int, argv: *const *const u8,
+                 main: proc():Send) -> int {
+        rt::init(argc, argv);
+        let mut main = Some(main);
+        let mut ret = None;
+        simple::task().run(|| {
+            ret = Some(self.run(main.take().unwrap()));
+        }).destroy();
+        // unsafe is ok b/c we're sure that the runtime is gone
+        unsafe { rt::cleanup() }
+        ret.unwrap()
+    }
+
+    /// Runs `main` in a new pool of schedulers, as with the `run` function.
+    pub fn run(self, main: proc():Send) -> int {
+        let Builder { config, options, stack_size } = self;
+
+        // Create a scheduler pool and spawn the main task into this pool. We
+        // will get notified over a channel when the main task exits.
+        let mut pool = SchedPool::new_with(config, options);
+        let (tx, rx) = channel();
+        let mut opts = TaskOpts::new();
+        opts.on_exit = Some(proc(r) tx.send(r));
+        opts.name = Some("<main>".into_maybe_owned());
+        opts.stack_size = stack_size;
+        pool.spawn(opts, main);
+
+        // Wait for the main task to return, and set the process error code
+        // appropriately.
+        if rx.recv().is_err() {
+            os::set_exit_status(rt::DEFAULT_ERROR_CODE);
+        }
+
+        // Now that we're sure all tasks are dead, shut down the pool of
+        // schedulers, waiting for them all to return.
+        pool.shutdown();
+        os::get_exit_status()
+    }
 }
 
 /// Configuration of how an M:N pool of schedulers is spawned.
@@ -358,18 +509,40 @@ impl PoolConfig {
     }
 }
 
+// Configuration of a pool which can only be set through a `Builder`, so that
+// adding to it doesn't break code constructing a `PoolConfig` literal.
+struct PoolOptions {
+    cpu_affinity: Option<Vec<uint>>,
+    blocked_signals: Vec<libc::c_int>,
+    spin_rounds: uint,
+}
+
+impl PoolOptions {
+    fn new() -> PoolOptions {
+        PoolOptions {
+            cpu_affinity: None,
+            blocked_signals: Vec::new(),
+            spin_rounds: 0,
+        }
+    }
+}
+
 /// A structure representing a handle to a pool of schedulers. This handle is
 /// used to keep the pool alive and also reap the status from the pool.
 pub struct SchedPool {
     id: uint,
     threads: Vec<Thread<()>>,
     handles: Vec<SchedHandle>,
+    counters: Vec<SchedCounters>,
     stealers: Vec<deque::Stealer<Box<task::GreenTask>>>,
     next_friend: uint,
     stack_pool: StackPool,
     deque_pool: deque::BufferPool<Box<task::GreenTask>>,
     sleepers: SleeperList,
     factory: fn() -> Box<EventLoop + Send>,
+    cpus: Option<Vec<uint>>,
+    signals: Vec<libc::c_int>,
+    spin_rounds: uint,
     task_state: TaskState,
     tasks_done: Receiver<()>,
 }
@@ -381,6 +554,9 @@ pub struct SchedPool {
 pub struct TaskState {
     cnt: Arc<AtomicUint>,
     done: Sender<()>,
+    deadlock: Arc<Detector>,
+    hooks: Arc<Hooks>,
+    observer: Arc<ObserverSlot>,
 }
 
 impl SchedPool {
@@ -389,19 +565,27 @@ impl SchedPool {
     /// This will configure the pool according to the `config` parameter, and
     /// initially run `main` inside the pool of schedulers.
     pub fn new(config: PoolConfig) -> SchedPool {
+        SchedPool::new_with(config, PoolOptions::new())
+    }
+
+    fn new_with(config: PoolConfig, options: PoolOptions) -> SchedPool {
         static mut POOL_ID: AtomicUint = INIT_ATOMIC_UINT;
 
-        let PoolConfig {
-            threads: nscheds,
-            event_loop_factory: factory
-        } = config;
+        let PoolConfig { threads: nscheds, event_loop_factory: factory } = config;
+        let PoolOptions {
+            cpu_affinity: cpus,
+            blocked_signals: signals,
+            spin_rounds: spin_rounds,
+        } = options;
         assert!(nscheds > 0);
+        assert!(cpus.as_ref().map_or(true, |cpus| cpus.len() > 0));
 
         // The pool of schedulers that will be returned from this function
         let (p, state) = TaskState::new();
         let mut pool = SchedPool {
             threads: vec![],
             handles: vec![],
+            counters: vec![],
             stealers: vec![],
             id: unsafe { POOL_ID.fetch_add(1, SeqCst) },
             sleepers: SleeperList::new(),
@@ -409,6 +593,9 @@ impl SchedPool {
             deque_pool: deque::BufferPool::new(),
             next_friend: 0,
             factory: factory,
+            cpus: cpus,
+            signals: signals,
+            spin_rounds: spin_rounds,
             task_state: state,
             tasks_done: p,
         };
@@ -437,8 +624,16 @@ impl SchedPool {
                                             pool.stealers.clone(),
                                             pool.sleepers.clone(),
                                             pool.task_state.clone());
+            sched.set_spin_rounds(pool.spin_rounds);
             pool.handles.push(sched.make_handle());
-            pool.threads.push(Thread::start(proc() { sched.bootstrap(); }));
+            pool.counters.push(sched.counters());
+            let cpu = pool.next_cpu();
+            let signals = pool.signals.clone();
+            pool.threads.push(Thread::start(proc() {
+                pin(cpu);
+                block_signals(signals.as_slice());
+                sched.bootstrap();
+            }));
         }
 
         return pool;
@@ -464,6 +659,9 @@ impl SchedPool {
         let task = self.task(opts, f);
 
         // Figure out someone to send this task to
+        if self.next_friend >= self.handles.len() {
+            self.next_friend = 0;
+        }
         let idx = self.next_friend;
         self.next_friend += 1;
         if self.next_friend >= self.handles.len() {
@@ -499,13 +697,124 @@ impl SchedPool {
                                         self.stealers.clone(),
                                         self.sleepers.clone(),
                                         self.task_state.clone());
+        sched.set_spin_rounds(self.spin_rounds);
         let ret = sched.make_handle();
         self.handles.push(sched.make_handle());
-        self.threads.push(Thread::start(proc() { sched.bootstrap() }));
+        self.counters.push(sched.counters());
+        let cpu = self.next_cpu();
+        let signals = self.signals.clone();
+        self.threads.push(Thread::start(proc() {
+            pin(cpu);
+            block_signals(signals.as_slice());
+            sched.bootstrap()
+        }));
 
         return ret;
     }
 
+    /// Removes a scheduler from this M:N pool, returning whether a scheduler was
+    /// removed. The last scheduler of a pool is never removed.
+    ///
+    /// The most recently added scheduler stops participating in work stealing
+    /// and hands all of its unpinned tasks to another scheduler in the pool.
+    /// Tasks pinned to the removed scheduler keep running on it, and its
+    /// thread exits once those tasks have exited and all handles to it have
+    /// been dropped.
+    pub fn retire_sched(&mut self) -> bool {
+        if self.handles.len() <= 1 { return false }
+
+        let idx = self.handles.len() - 1;
+        let victim = self.handles.pop().unwrap();
+        self.counters.pop();
+        self.stealers.remove(idx);
+
+        // Tell everyone to stop stealing from the retiring scheduler, then
+        // have one of them take over its work.
+        for handle in self.handles.iter_mut() {
+            handle.send(LostNeighbor(idx));
+        }
+        self.handles[0].send(RetireNeighbor(victim));
+        return true;
+    }
+
+    /// Returns a snapshot of the activity of each scheduler currently
+    /// participating in this pool, in the order they were spawned.
+    pub fn stats(&self) -> Vec<SchedStats> {
+        self.counters.iter().map(|c| c.snapshot()).collect()
+    }
+
+    /// Returns the number of schedulers currently participating in this pool.
+    pub fn num_scheds(&self) -> uint { self.handles.len() }
+
+    /// Grows or shrinks this pool to contain `n` schedulers.
+    ///
+    /// This allows a pool to add threads under load and to shed them again
+    /// once it's idle. See `spawn_sched` and `retire_sched` for more details.
+    pub fn resize(&mut self, n: uint) {
+        assert!(n > 0);
+        while self.handles.len() < n {
+            drop(self.spawn_sched());
+        }
+        while self.handles.len() > n {
+            self.retire_sched();
+        }
+    }
+
+    /// Enables deadlock detection for this pool, reporting to `hook`, or
+    /// disables it with `None`.
+    ///
+    /// When enabled, the hook is told about the blocked tasks if every
+    /// scheduler in the pool runs out of work, with no active I/O, while tasks
+    /// are still alive. Otherwise such a pool hangs silently.
+    ///
+    /// Detection assumes that tasks are only ever woken up by other tasks of
+    /// the pool or by I/O. Tasks waiting on native threads, or on callbacks
+    /// from timers, would be reported as deadlocked, which is why detection
+    /// is off by default. Only tasks spawned while detection is enabled are
+    /// listed by name in the report.
+    pub fn detect_deadlocks(&mut self,
+                            hook: Option<Box<DeadlockHook + Send + Sync>>) {
+        self.task_state.deadlock.set_hook(hook);
+    }
+
+    /// Sets the hook told about every task of this pool which panics, or
+    /// removes it with `None`.
+    ///
+    /// Only tasks started after the hook is set are reported to it.
+    pub fn set_panic_hook(&mut self, hook: Option<Box<PanicHook + Send + Sync>>) {
+        self.task_state.hooks.set(hook);
+    }
+
+    /// Sets the observer told about tasks of this pool starting, blocking,
+    /// yielding, resuming and exiting, or removes it with `None`.
+    ///
+    /// Only tasks started after the observer is set are reported to it.
+    pub fn set_task_observer(&mut self,
+                             observer: Option<Box<TaskObserver + Send + Sync>>) {
+        self.task_state.observer.set(observer);
+    }
+
+    /// Spawns a task into this pool which runs `service`, and runs it again
+    /// each time it panics for as long as `policy` allows.
+    ///
+    /// The service is run in a child of the spawned task, which is given the
+    /// name and stack size of `opts`. The spawned task exits once the service
+    /// returns without panicking or isn't restarted anymore.
+    pub fn spawn_supervised(&mut self, opts: TaskOpts,
+                            service: Box<Service + Send + Sync>,
+                            policy: Box<RestartPolicy + Send>) {
+        let name = opts.name.clone();
+        let stack_size = opts.stack_size;
+        self.spawn(opts, proc() {
+            supervisor::supervise(name, stack_size, service, policy)
+        });
+    }
+
+    // Returns the core the next scheduler to be spawned should be pinned to.
+    fn next_cpu(&self) -> Option<uint> {
+        self.cpus.as_ref().map(|cpus| cpus[self.threads.len() % cpus.len()])
+    }
+
     /// Consumes the pool of schedulers, waiting for all tasks to exit and all
     /// schedulers to shut down.
     ///
@@ -537,12 +846,32 @@ impl SchedPool {
     }
 }
 
+fn pin(cpu: Option<uint>) {
+    match cpu {
+        Some(cpu) => {
+            if !affinity::pin_current_thread(cpu) {
+                rtdebug!("failed to pin scheduler to cpu {}", cpu);
+            }
+        }
+        None => {}
+    }
+}
+
+fn block_signals(signals: &[libc::c_int]) {
+    if !signals.is_empty() && !sigmask::block_current_thread(signals) {
+        rtdebug!("failed to block signals {} on scheduler", signals);
+    }
+}
+
 impl TaskState {
     pub fn new() -> (Receiver<()>, TaskState) {
         let (tx, rx) = channel();
         (rx, TaskState {
             cnt: Arc::new(AtomicUint::new(0)),
             done: tx,
+            deadlock: Arc::new(Detector::new()),
+            hooks: Arc::new(Hooks::new()),
+            observer: Arc::new(ObserverSlot::new()),
         })
     }
 
@@ -631,7 +960,9 @@ pub trait Callback {
 #[cfg(test)]
 mod test {
     use std::task::TaskBuilder;
-    use super::{SchedPool, PoolConfig, GreenTaskBuilder};
+    use std::sync::Mutex;
+    use super::{SchedPool, PoolConfig, GreenTaskBuilder, DeadlockHook, Builder};
+    use basic;
 
     #[test]
     fn test_green_builder() {
@@ -642,4 +973,276 @@ mod test {
         assert_eq!(res.ok().unwrap(), "Success!".to_string());
         pool.shutdown();
     }
+
+    #[test]
+    fn test_resize() {
+        let mut pool = SchedPool::new(PoolConfig {
+            threads: 1,
+            event_loop_factory: basic::event_loop,
+        });
+        pool.resize(4);
+        assert_eq!(pool.num_scheds(), 4);
+
+        let (tx, rx) = channel();
+        for _ in range(0u, 20) {
+            let tx = tx.clone();
+            TaskBuilder::new().green(&mut pool).spawn(proc() tx.send(()));
+        }
+        pool.resize(1);
+        assert_eq!(pool.num_scheds(), 1);
+        assert!(!pool.retire_sched());
+        for _ in range(0u, 20) {
+            let tx = tx.clone();
+            TaskBuilder::new().green(&mut pool).spawn(proc() tx.send(()));
+        }
+        for _ in range(0u, 40) { rx.recv(); }
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut pool = SchedPool::new(PoolConfig {
+            threads: 2,
+            event_loop_factory: basic::event_loop,
+        });
+        let (tx, rx) = channel();
+        for _ in range(0u, 10) {
+            let tx = tx.clone();
+            TaskBuilder::new().green(&mut pool).spawn(proc() tx.send(()));
+        }
+        for _ in range(0u, 10) { rx.recv(); }
+
+        let stats = pool.stats();
+        assert_eq!(stats.len(), 2);
+        let switches = stats.iter().fold(0, |a, s| a + s.context_switches);
+        assert!(switches >= 10);
+        assert!(stats.iter().all(|s| s.steals <= s.steal_attempts));
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_builder() {
+        use super::Builder;
+
+        let mut pool = Builder::new().threads(2)
+                                     .event_loop_factory(basic::event_loop)
+                                     .pool();
+        assert_eq!(pool.num_scheds(), 2);
+        pool.shutdown();
+
+        let (tx, rx) = channel();
+        let ret = Builder::new().threads(1).stack_size(128 * 1024).run(proc() {
+            tx.send(::task::stack_size());
+        });
+        assert_eq!(ret, 0);
+        assert!(rx.recv() >= 120 * 1024);
+    }
+
+    #[test]
+    fn test_spin_rounds() {
+        let mut pool = Builder::new().threads(2).spin_rounds(1000).pool();
+
+        // Two tasks playing ping-pong keep finding work right after running
+        // out of it.
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        TaskBuilder::new().green(&mut pool).spawn(proc() {
+            for i in range(0u, 100) { tx1.send(i); assert_eq!(rx2.recv(), i); }
+        });
+        let res = TaskBuilder::new().green(&mut pool).try(proc() {
+            for _ in range(0u, 100) { tx2.send(rx1.recv()); }
+        });
+        assert!(res.is_ok());
+        let stats = pool.stats();
+        assert!(stats.iter().all(|s| s.spin_hits <= s.context_switches));
+        assert!(stats.iter().fold(0, |n, s| n + s.spin_hits) > 0);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_cpu_affinity() {
+        let mut pool = Builder::new().threads(2).cpu_affinity(vec![0]).pool();
+        let res = TaskBuilder::new().green(&mut pool).try(proc() 1i);
+        assert_eq!(res.ok().unwrap(), 1);
+        pool.shutdown();
+    }
+
+    #[test] #[should_fail]
+    fn test_empty_cpu_affinity() {
+        Builder::new().cpu_affinity(Vec::new());
+    }
+
+    #[test] #[cfg(unix)]
+    fn test_blocked_signals() {
+        use libc;
+        use sigmask;
+
+        let mut pool = Builder::new().threads(2)
+                                     .block_signals(vec![libc::SIGINT])
+                                     .pool();
+        let res = TaskBuilder::new().green(&mut pool).try(proc() {
+            (sigmask::blocked(libc::SIGINT), sigmask::blocked(libc::SIGTERM))
+        });
+        assert_eq!(res.ok().unwrap(), (true, false));
+        pool.shutdown();
+    }
+
+    struct DeadlockReport(Mutex<Sender<(uint, Vec<Option<String>>)>>);
+    impl DeadlockHook for DeadlockReport {
+        fn deadlocked(&self, active: uint, tasks: &[(uint, Option<String>)]) {
+            let DeadlockReport(ref tx) = *self;
+            let names = tasks.iter().map(|&(_, ref name)| name.clone());
+            tx.lock().send((active, names.collect()));
+        }
+    }
+
+    fn deadlock_pool() -> (SchedPool, Receiver<(uint, Vec<Option<String>>)>) {
+        let mut pool = SchedPool::new(PoolConfig {
+            threads: 2,
+            event_loop_factory: basic::event_loop,
+        });
+        let (tx, rx) = channel();
+        pool.detect_deadlocks(Some(box DeadlockReport(Mutex::new(tx))
+                                   as Box<DeadlockHook + Send + Sync>));
+        (pool, rx)
+    }
+
+    #[test]
+    fn test_deadlock_detection_quiet() {
+        let (mut pool, rx) = deadlock_pool();
+
+        // Tasks which block on one another for a while are not deadlocked.
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        TaskBuilder::new().green(&mut pool).spawn(proc() {
+            for i in range(0u, 100) { tx1.send(i); assert_eq!(rx2.recv(), i); }
+        });
+        TaskBuilder::new().green(&mut pool).spawn(proc() {
+            for _ in range(0u, 100) { tx2.send(rx1.recv()); }
+        });
+        pool.shutdown();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_deadlock_detection() {
+        let (mut pool, rx) = deadlock_pool();
+
+        // Nothing in the pool can wake up this task, as only this (native)
+        // thread holds the other end of its channel.
+        let (tx, rx2) = channel();
+        TaskBuilder::new().named("stuck").green(&mut pool).spawn(proc() {
+            rx2.recv();
+        });
+        let (active, names) = rx.recv();
+        assert_eq!(active, 1);
+        assert_eq!(names, vec![Some("stuck".to_string())]);
+
+        tx.send(());
+        pool.shutdown();
+    }
+
+    #[test]
+    fn test_supervised() {
+        use std::rt::task::TaskOpts;
+        use std::str::IntoMaybeOwned;
+        use supervisor::{Failure, PanicHook, Service, MaxRestarts};
+
+        struct Record(Mutex<Sender<(uint, Option<String>)>>);
+        impl PanicHook for Record {
+            fn panicked(&self, failure: &Failure) {
+                let Record(ref tx) = *self;
+                let name = failure.name.map(|name| name.to_string());
+                tx.lock().send((failure.id, name));
+            }
+        }
+        struct Crash(Mutex<Sender<()>>);
+        impl Service for Crash {
+            fn run(&self) {
+                let Crash(ref tx) = *self;
+                tx.lock().send(());
+                panic!()
+            }
+        }
+
+        let mut pool = SchedPool::new(PoolConfig {
+            threads: 2,
+            event_loop_factory: basic::event_loop,
+        });
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        let hook: Box<PanicHook + Send + Sync> = box Record(Mutex::new(tx1));
+        pool.set_panic_hook(Some(hook));
+
+        let mut opts = TaskOpts::new();
+        opts.name = Some("crash".into_maybe_owned());
+        pool.spawn_supervised(opts, box Crash(Mutex::new(tx2)),
+                              box MaxRestarts(2));
+        pool.shutdown();
+
+        // The service ran three times, and panicked each time.
+        assert_eq!(rx2.iter().count(), 3);
+        let failures: Vec<(uint, Option<String>)> = rx1.iter().collect();
+        assert_eq!(failures.len(), 3);
+        assert!(failures.iter().all(|&(_, ref name)| {
+            name.as_ref().map(|s| s.as_slice()) == Some("crash")
+        }));
+    }
+
+    #[test]
+    fn test_task_observer() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUint, SeqCst};
+        use std::task::deschedule;
+        use observer::TaskObserver;
+
+        struct Count(Arc<[AtomicUint, ..6]>);
+        impl TaskObserver for Count {
+            fn spawned(&self, _id: uint, _name: Option<&str>) {
+                let Count(ref c) = *self; c[0].fetch_add(1, SeqCst);
+            }
+            fn exited(&self, _id: uint) {
+                let Count(ref c) = *self; c[1].fetch_add(1, SeqCst);
+            }
+            fn blocked(&self, _id: uint) {
+                let Count(ref c) = *self; c[2].fetch_add(1, SeqCst);
+            }
+            fn unblocked(&self, _id: uint) {
+                let Count(ref c) = *self; c[3].fetch_add(1, SeqCst);
+            }
+            fn descheduled(&self, _id: uint) {
+                let Count(ref c) = *self; c[4].fetch_add(1, SeqCst);
+            }
+            fn resumed(&self, _id: uint) {
+                let Count(ref c) = *self; c[5].fetch_add(1, SeqCst);
+            }
+        }
+
+        let mut pool = SchedPool::new(PoolConfig {
+            threads: 2,
+            event_loop_factory: basic::event_loop,
+        });
+        let counts = Arc::new([AtomicUint::new(0), AtomicUint::new(0),
+                               AtomicUint::new(0), AtomicUint::new(0),
+                               AtomicUint::new(0), AtomicUint::new(0)]);
+        let observer: Box<TaskObserver + Send + Sync> = box Count(counts.clone());
+        pool.set_task_observer(Some(observer));
+
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        TaskBuilder::new().green(&mut pool).spawn(proc() {
+            tx1.send(());
+            rx2.recv();
+            deschedule();
+        });
+        rx1.recv();
+        tx2.send(());
+        pool.shutdown();
+
+        assert_eq!(counts[0].load(SeqCst), 1);
+        assert_eq!(counts[1].load(SeqCst), 1);
+        assert_eq!(counts[2].load(SeqCst), counts[3].load(SeqCst));
+        assert!(counts[4].load(SeqCst) >= 1);
+        assert_eq!(counts[4].load(SeqCst), counts[5].load(SeqCst));
+    }
 }
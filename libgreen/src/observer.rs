@@ -0,0 +1,74 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Instrumentation of the lifetime of green tasks.
+//!
+//! A green task shares its OS thread with every other task of its scheduler,
+//! so profilers and tracing tools measuring time per thread can't tell which
+//! task the time was spent in. A `TaskObserver` set on a pool is told whenever
+//! one of its tasks starts or stops running, which is enough to attribute
+//! wall-clock time to individual tasks.
+//!
+//! Every callback is invoked on the scheduler thread running the task at that
+//! point, right before the task starts running or after it stopped, so the
+//! time spent in between belongs to the task. Tasks are identified by the same
+//! id as in panic reports. The observer is picked up when a task is started,
+//! so a task keeps reporting to the observer which was set at that time.
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, SeqCst};
+
+/// Callbacks invoked over the lifetime of each task of a pool.
+pub trait TaskObserver {
+    /// The task is about to run for the first time.
+    fn spawned(&self, _id: uint, _name: Option<&str>) {}
+
+    /// The task has finished running, whether or not it panicked.
+    fn exited(&self, _id: uint) {}
+
+    /// The task is about to block, for example waiting on a channel or I/O.
+    fn blocked(&self, _id: uint) {}
+
+    /// The task is running again after having blocked.
+    fn unblocked(&self, _id: uint) {}
+
+    /// The task is yielding to other tasks of its scheduler, without blocking.
+    fn descheduled(&self, _id: uint) {}
+
+    /// The task is running again after having yielded.
+    fn resumed(&self, _id: uint) {}
+}
+
+/// The task observer of a pool, shared with all of its schedulers.
+pub struct ObserverSlot {
+    observed: AtomicBool,
+    observer: Mutex<Option<Arc<Box<TaskObserver + Send + Sync>>>>,
+}
+
+impl ObserverSlot {
+    pub fn new() -> ObserverSlot {
+        ObserverSlot {
+            observed: AtomicBool::new(false),
+            observer: Mutex::new(None),
+        }
+    }
+
+    pub fn set(&self, observer: Option<Box<TaskObserver + Send + Sync>>) {
+        let mut slot = self.observer.lock();
+        self.observed.store(observer.is_some(), SeqCst);
+        *slot = observer.map(|observer| Arc::new(observer));
+    }
+
+    /// Returns the observer which tasks started now report to.
+    pub fn get(&self) -> Option<Arc<Box<TaskObserver + Send + Sync>>> {
+        if !self.observed.load(SeqCst) { return None }
+        self.observer.lock().clone()
+    }
+}
@@ -9,7 +9,8 @@
 // except according to those terms.
 
 pub use self::SchedMessage::{Wake, Shutdown, NewNeighbor, PinnedTask};
-pub use self::SchedMessage::{TaskFromFriend, RunOnce};
+pub use self::SchedMessage::{TaskFromFriend, RunOnce, LostNeighbor};
+pub use self::SchedMessage::{RetireNeighbor, Retire};
 use self::EffortLevel::{GiveItYourBest, DontTryTooHard};
 
 use std::mem;
@@ -17,6 +18,8 @@ use std::rt::local::Local;
 use std::rt::mutex::NativeMutex;
 use std::rt::task::BlockedTask;
 use std::rt::task::Task;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUint, Relaxed, SeqCst};
 use std::sync::deque;
 use std::raw;
 
@@ -94,6 +97,20 @@ pub struct Scheduler {
     /// A flag to tell the scheduler loop it needs to do some stealing
     /// in order to introduce randomness as part of a yield
     steal_for_yield: bool,
+    /// Instrumentation of this scheduler's activity
+    counters: SchedCounters,
+    /// Whether this scheduler has told the deadlock detector that it is out
+    /// of work with no active I/O
+    deadlock_idle: bool,
+    /// How many rounds of looking for work this scheduler spins for before
+    /// going to sleep, see `Builder::spin_rounds`
+    spin_rounds: uint,
+    /// A task which was just woken up by an I/O callback, run before anything
+    /// in the work queue as its data is likely still in cache
+    lifo_slot: Option<Box<GreenTask>>,
+    /// How many tasks in a row were taken out of `lifo_slot`, used to keep
+    /// tasks waking each other up from starving the work queue
+    lifo_runs: uint,
 
     // n.b. currently destructors of an object are run in top-to-bottom in order
     //      of field declaration. Due to its nature, the pausable idle callback
@@ -115,6 +132,7 @@ enum EffortLevel {
 }
 
 static MAX_YIELD_CHECKS: uint = 20000;
+static MAX_LIFO_RUNS: uint = 3;
 
 fn reset_yield_check(rng: &mut XorShiftRng) -> uint {
     let r: uint = Rand::rand(rng);
@@ -169,6 +187,11 @@ impl Scheduler {
             yield_check_count: 0,
             steal_for_yield: false,
             task_state: state,
+            counters: SchedCounters::new(),
+            deadlock_idle: false,
+            spin_rounds: 0,
+            lifo_slot: None,
+            lifo_runs: 0,
         };
 
         sched.yield_check_count = reset_yield_check(&mut sched.rng);
@@ -203,11 +226,16 @@ impl Scheduler {
         // to the event loop, and this will only exit once the event loop no
         // longer has any references (handles or I/O objects).
         rtdebug!("starting scheduler {}", self.sched_id());
+        self.task_state.deadlock.sched_started();
         let mut sched_task = self.run(sched_task);
 
         // Close the idle callback.
         let mut sched = sched_task.sched.take().unwrap();
         sched.idle_callback.take();
+        if sched.deadlock_idle {
+            sched.task_state.deadlock.awake();
+        }
+        sched.task_state.deadlock.sched_exited();
         // Make one go through the loop to run the close callback.
         let mut stask = sched.run(sched_task);
 
@@ -320,10 +348,22 @@ impl Scheduler {
             keep_going = did_work && !sched.event_loop.has_active_io();
         }
 
+        // Work often turns up right after we ran out of it, for example when a
+        // task on another scheduler answers one of ours. Before paying for a
+        // trip through the sleeper list (and for the wakeup to get off of it),
+        // spin for a little while looking for some.
+        if !did_work && !sched.sleepy && !sched.no_sleep {
+            let (a, b, c) = sched.spin(stask);
+            sched = a;
+            stask = b;
+            did_work = c;
+        }
+
         // If we ever did some work, then we shouldn't put our scheduler
         // entirely to sleep just yet. Leave the idle callback active and fall
         // back to epoll() to see what's going on.
         if did_work {
+            sched.update_deadlock_state(false);
             return stask.put_with_sched(sched);
         }
 
@@ -333,6 +373,7 @@ impl Scheduler {
         if !sched.sleepy && !sched.no_sleep {
             rtdebug!("scheduler has no work to do, going to sleep");
             sched.sleepy = true;
+            sched.counters.bump(|c| &c.sleeps);
             let handle = sched.make_handle();
             sched.sleeper_list.push(handle);
             // Since we are sleeping, deactivate the idle callback.
@@ -344,11 +385,37 @@ impl Scheduler {
             sched.idle_callback.as_mut().unwrap().pause();
         }
 
+        // With no work and no I/O, nothing but another scheduler can wake us
+        // up, so let the deadlock detector know.
+        let idle = !sched.event_loop.has_active_io();
+        sched.update_deadlock_state(idle);
+
         // Finished a cycle without using the Scheduler. Place it back
         // in TLS.
         stask.put_with_sched(sched);
     }
 
+    // Keeps the deadlock detector up to date with whether this scheduler is
+    // idle, with no work and no I/O which could bring it some. While every
+    // scheduler of the pool is idle the idle callback is kept active, so we
+    // come back here to check again until the detector has made up its mind.
+    fn update_deadlock_state(&mut self, idle: bool) {
+        if idle != self.deadlock_idle {
+            self.deadlock_idle = idle;
+            if idle {
+                self.task_state.deadlock.idle();
+            } else {
+                self.task_state.deadlock.awake();
+            }
+        }
+        if idle {
+            let active = self.task_state.cnt.load(SeqCst);
+            if self.task_state.deadlock.check(active) {
+                self.idle_callback.as_mut().unwrap().resume();
+            }
+        }
+    }
+
     // This function returns None if the scheduler is "used", or it
     // returns the still-available scheduler. At this point all
     // message-handling will count as a turn of work, and as a result
@@ -403,38 +470,121 @@ impl Scheduler {
             }
             Some(Wake) => {
                 self.sleepy = false;
+                self.counters.bump(|c| &c.wakeups);
                 (self, stask, true)
             }
             Some(Shutdown) => {
                 rtdebug!("shutting down");
-                if self.sleepy {
-                    // There may be an outstanding handle on the
-                    // sleeper list.  Pop them all to make sure that's
-                    // not the case.
-                    loop {
-                        match self.sleeper_list.pop() {
-                            Some(handle) => {
-                                let mut handle = handle;
-                                handle.send(Wake);
-                            }
-                            None => break
-                        }
-                    }
-                }
-                // No more sleeping. After there are no outstanding
-                // event loop references we will shut down.
-                self.no_sleep = true;
-                self.sleepy = false;
+                self.stop_sleeping();
                 (self, stask, true)
             }
             Some(NewNeighbor(neighbor)) => {
                 self.work_queues.push(neighbor);
                 (self, stask, false)
             }
+            Some(LostNeighbor(idx)) => {
+                self.work_queues.remove(idx);
+                (self, stask, false)
+            }
+            Some(RetireNeighbor(victim)) => {
+                // We become the friend of the retiring scheduler, inheriting
+                // all of its unpinned work.
+                let mut victim = victim;
+                victim.send(Retire(self.make_handle()));
+                (self, stask, true)
+            }
+            Some(Retire(friend)) => {
+                rtdebug!("retiring from the pool");
+                self.retire(friend);
+                (self, stask, true)
+            }
             None => (self, stask, false)
         }
     }
 
+    // Looks for work for up to `spin_rounds` rounds, stopping as soon as some
+    // has been done.
+    fn spin(mut self: Box<Scheduler>, mut stask: Box<GreenTask>)
+            -> (Box<Scheduler>, Box<GreenTask>, bool) {
+        for _ in range(0, self.spin_rounds) {
+            let (sched, task, did_work) = match self.do_work(stask) {
+                (sched, task, false) => {
+                    sched.interpret_message_queue(task, GiveItYourBest)
+                }
+                ret => ret,
+            };
+            if did_work {
+                sched.counters.bump(|c| &c.spin_hits);
+                return (sched, task, true)
+            }
+            self = sched;
+            stask = task;
+        }
+        (self, stask, false)
+    }
+
+    // Stop this scheduler from going to sleep ever again, so that it shuts
+    // down once there are no outstanding event loop references.
+    fn stop_sleeping(&mut self) {
+        if self.sleepy {
+            // There may be an outstanding handle on the
+            // sleeper list.  Pop them all to make sure that's
+            // not the case.
+            loop {
+                match self.sleeper_list.pop() {
+                    Some(handle) => {
+                        let mut handle = handle;
+                        handle.send(Wake);
+                    }
+                    None => break
+                }
+            }
+        }
+        // No more sleeping. After there are no outstanding
+        // event loop references we will shut down.
+        self.no_sleep = true;
+        self.sleepy = false;
+    }
+
+    // Remove this scheduler from its pool. No more work is stolen by this
+    // scheduler, and all unpinned work (present and future) is handed to
+    // `friend`. Tasks pinned to this scheduler continue to run here, and the
+    // scheduler exits once they're all gone.
+    fn retire(&mut self, friend: SchedHandle) {
+        self.friend_handle = Some(friend);
+        self.run_anything = false;
+        self.work_queues = vec![];
+        match self.lifo_slot.take() {
+            Some(task) => self.push_work(task),
+            None => {}
+        }
+
+        let mut pinned = vec![];
+        loop {
+            match self.work_queue.pop() {
+                Some(task) => {
+                    let mut task = dequeued(task);
+                    match task.take_unwrap_home() {
+                        AnySched => {
+                            task.give_home(AnySched);
+                            self.send_to_friend(task);
+                        }
+                        HomeSched(home) => {
+                            task.give_home(HomeSched(home));
+                            pinned.push(task);
+                        }
+                    }
+                }
+                None => break,
+            }
+        }
+        for task in pinned.into_iter() {
+            self.push_work(task);
+        }
+
+        self.stop_sleeping();
+    }
+
     fn do_work(mut self: Box<Scheduler>, stask: Box<GreenTask>)
                -> (Box<Scheduler>, Box<GreenTask>, bool) {
         rtdebug!("scheduler calling do work");
@@ -464,11 +614,25 @@ impl Scheduler {
     // there, trying to steal from the remote work queues.
     fn find_work(&mut self) -> Option<Box<GreenTask>> {
         rtdebug!("scheduler looking for work");
+        if self.lifo_slot.is_some() {
+            if self.lifo_runs < MAX_LIFO_RUNS {
+                rtdebug!("found a task in the lifo slot");
+                self.lifo_runs += 1;
+                return self.lifo_slot.take();
+            }
+            // Give the work queue a turn, the slot is still there next time
+            self.lifo_runs = 0;
+            match self.work_queue.pop() {
+                Some(task) => return Some(dequeued(task)),
+                None => return self.lifo_slot.take(),
+            }
+        }
+        self.lifo_runs = 0;
         if !self.steal_for_yield {
             match self.work_queue.pop() {
                 Some(task) => {
                     rtdebug!("found a task locally");
-                    return Some(task)
+                    return Some(dequeued(task))
                 }
                 None => {
                     rtdebug!("scheduler trying to steal");
@@ -503,12 +667,15 @@ impl Scheduler {
     fn try_steals(&mut self) -> Option<Box<GreenTask>> {
         let work_queues = &mut self.work_queues;
         let len = work_queues.len();
+        if len == 0 { return None }
+        self.counters.bump(|c| &c.steal_attempts);
         let start_index = self.rng.gen_range(0, len);
         for index in range(0, len).map(|i| (i + start_index) % len) {
             match work_queues[index].steal() {
                 deque::Data(task) => {
                     rtdebug!("found task by stealing");
-                    return Some(task)
+                    self.counters.bump(|c| &c.steals);
+                    return Some(dequeued(task))
                 }
                 _ => ()
             }
@@ -585,7 +752,7 @@ impl Scheduler {
 
         // We push the task onto our local queue clone.
         assert!(!task.is_sched());
-        self.work_queue.push(task);
+        self.push_work(task);
         match self.idle_callback {
             Some(ref mut idle) => idle.resume(),
             None => {} // allow enqueuing before the scheduler starts
@@ -603,6 +770,35 @@ impl Scheduler {
         };
     }
 
+    /// Schedules a task which was woken up by an I/O callback.
+    ///
+    /// The task goes into the LIFO slot, to be run next, and any task already
+    /// there is moved to the work queue. At most `MAX_LIFO_RUNS` tasks in a
+    /// row are run out of the slot before the work queue gets a turn.
+    pub fn enqueue_woken(&mut self, task: Box<GreenTask>) {
+        assert!(!task.is_sched());
+        match mem::replace(&mut self.lifo_slot, Some(task)) {
+            Some(prev) => self.enqueue_task(prev),
+            None => {
+                match self.idle_callback {
+                    Some(ref mut idle) => idle.resume(),
+                    None => {}
+                }
+            }
+        }
+    }
+
+    // Push a task onto our local run queue. Its length is only kept track of
+    // once someone has looked at our counters, which spares every push an
+    // atomic reference count increment.
+    fn push_work(&mut self, mut task: Box<GreenTask>) {
+        if self.counters.watched() {
+            self.counters.bump(|c| &c.run_queue_len);
+            task.queued_on = Some(self.counters.clone());
+        }
+        self.work_queue.push(task);
+    }
+
     // * Core Context Switching Functions
 
     // The primary function for changing contexts. In the current
@@ -619,6 +815,7 @@ impl Scheduler {
                                f: |&mut Scheduler, Box<GreenTask>|)
                                -> Box<GreenTask> {
         let f_opaque = ClosureConverter::from_fn(f);
+        self.counters.bump(|c| &c.context_switches);
 
         let current_task_dupe = &mut *current_task as *mut GreenTask;
 
@@ -807,6 +1004,7 @@ impl Scheduler {
         let _cur = self.change_task_context(cur, stask, |sched, mut dead_task| {
             let coroutine = dead_task.coroutine.take().unwrap();
             coroutine.recycle(&mut sched.stack_pool);
+            sched.task_state.deadlock.task_exited(dead_task.as_uint());
             sched.task_state.decrement();
         });
         panic!("should never return!");
@@ -841,10 +1039,20 @@ impl Scheduler {
             self.yield_check_count = reset_yield_check(&mut self.rng);
             // Tell the scheduler to start stealing on the next iteration
             self.steal_for_yield = true;
+            let id = cur.as_uint();
+            let observer = cur.observer.clone();
+            match observer {
+                Some(ref observer) => observer.descheduled(id),
+                None => {}
+            }
             let stask = self.sched_task.take().unwrap();
             let cur = self.change_task_context(cur, stask, |sched, task| {
                 sched.enqueue_task(task);
             });
+            match observer {
+                Some(ref observer) => observer.resumed(id),
+                None => {}
+            }
             cur.put()
         }
     }
@@ -880,6 +1088,16 @@ impl Scheduler {
 
     pub fn sched_id(&self) -> uint { self as *const Scheduler as uint }
 
+    /// Returns a handle to the counters of this scheduler, which can be used
+    /// to take snapshots of its activity from any thread.
+    pub fn counters(&self) -> SchedCounters { self.counters.clone() }
+
+    /// Sets how many rounds of looking for work this scheduler spins for
+    /// before going to sleep.
+    pub fn set_spin_rounds(&mut self, rounds: uint) {
+        self.spin_rounds = rounds;
+    }
+
     pub fn run_cleanup_job(&mut self) {
         let cleanup_job = self.cleanup_job.take().unwrap();
         cleanup_job.run(self)
@@ -898,6 +1116,103 @@ impl Scheduler {
 
 // Supporting types
 
+/// A snapshot of the activity of a scheduler.
+///
+/// All values are counts since the scheduler was created, except for
+/// `run_queue_len` which is the length of the run queue at the time the
+/// snapshot was taken.
+#[deriving(Clone, PartialEq, Show)]
+pub struct SchedStats {
+    /// Number of times this scheduler tried to steal work from the run queues
+    /// of the schedulers in its pool.
+    pub steal_attempts: uint,
+    /// Number of those attempts which came back with a task.
+    pub steals: uint,
+    /// Number of tasks waiting in this scheduler's run queue. Only tasks
+    /// queued after the first snapshot of the scheduler was taken are
+    /// counted.
+    pub run_queue_len: uint,
+    /// Number of context switches performed, including switches to and from
+    /// the scheduler's own task.
+    pub context_switches: uint,
+    /// Number of times this scheduler went to sleep for lack of work.
+    pub sleeps: uint,
+    /// Number of times this scheduler found work while spinning before going
+    /// to sleep.
+    pub spin_hits: uint,
+    /// Number of wakeups received from other schedulers.
+    pub wakeups: uint,
+}
+
+/// A shareable handle to the live counters of a scheduler.
+#[deriving(Clone)]
+pub struct SchedCounters {
+    inner: Arc<Counters>,
+}
+
+struct Counters {
+    steal_attempts: AtomicUint,
+    steals: AtomicUint,
+    run_queue_len: AtomicUint,
+    context_switches: AtomicUint,
+    sleeps: AtomicUint,
+    spin_hits: AtomicUint,
+    wakeups: AtomicUint,
+    // Whether a snapshot has ever been taken
+    watched: AtomicBool,
+}
+
+impl SchedCounters {
+    fn new() -> SchedCounters {
+        SchedCounters {
+            inner: Arc::new(Counters {
+                steal_attempts: AtomicUint::new(0),
+                steals: AtomicUint::new(0),
+                run_queue_len: AtomicUint::new(0),
+                context_switches: AtomicUint::new(0),
+                sleeps: AtomicUint::new(0),
+                spin_hits: AtomicUint::new(0),
+                wakeups: AtomicUint::new(0),
+                watched: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    // Counters are only ever written by their own scheduler (aside from the
+    // run queue length), and they don't order any other memory, so relaxed
+    // operations suffice.
+    fn bump(&self, f: |&Counters| -> &AtomicUint) {
+        f(&*self.inner).fetch_add(1, Relaxed);
+    }
+
+    fn watched(&self) -> bool { self.inner.watched.load(Relaxed) }
+
+    /// Takes a snapshot of the current values of these counters.
+    pub fn snapshot(&self) -> SchedStats {
+        let c = &*self.inner;
+        c.watched.store(true, Relaxed);
+        SchedStats {
+            steal_attempts: c.steal_attempts.load(Relaxed),
+            steals: c.steals.load(Relaxed),
+            run_queue_len: c.run_queue_len.load(Relaxed),
+            context_switches: c.context_switches.load(Relaxed),
+            sleeps: c.sleeps.load(Relaxed),
+            spin_hits: c.spin_hits.load(Relaxed),
+            wakeups: c.wakeups.load(Relaxed),
+        }
+    }
+}
+
+// Called whenever a task is taken off of a run queue, by either the owner of
+// the queue or a thief, to keep the queue's length up to date.
+fn dequeued(mut task: Box<GreenTask>) -> Box<GreenTask> {
+    match task.queued_on.take() {
+        Some(counters) => { counters.inner.run_queue_len.fetch_sub(1, Relaxed); }
+        None => {}
+    }
+    task
+}
+
 type SchedulingFn = fn(Box<Scheduler>, Box<GreenTask>, Box<GreenTask>)
                        -> (Box<Scheduler>, Box<GreenTask>);
 
@@ -905,6 +1220,12 @@ pub enum SchedMessage {
     Wake,
     Shutdown,
     NewNeighbor(deque::Stealer<Box<GreenTask>>),
+    /// The neighbor at the given index of the work queues has left the pool.
+    LostNeighbor(uint),
+    /// Retire the given scheduler from the pool, becoming its friend.
+    RetireNeighbor(SchedHandle),
+    /// Leave the pool, sending all unpinned work to the given friend.
+    Retire(SchedHandle),
     PinnedTask(Box<GreenTask>),
     TaskFromFriend(Box<GreenTask>),
     RunOnce(Box<GreenTask>),
@@ -1034,7 +1355,7 @@ mod test {
     use {TaskState, PoolConfig, SchedPool};
     use basic;
     use sched::{TaskFromFriend, PinnedTask};
-    use task::{spawn, GreenTask, HomeSched, AnySched};
+    use task::{spawn, spawn_on, GreenTask, HomeSched, AnySched};
 
     fn pool() -> SchedPool {
         SchedPool::new(PoolConfig {
@@ -1136,6 +1457,24 @@ mod test {
         pool.shutdown();
     }
 
+    #[test]
+    fn test_spawn_on() {
+        let mut pool = pool();
+
+        let (tx, rx) = channel();
+        let handle = pool.spawn_sched();
+        let id = handle.sched_id;
+        pool.spawn(TaskOpts::new(), proc() {
+            let mut handle = handle;
+            spawn_on(&mut handle, proc() {
+                tx.send(sched_id());
+            });
+        });
+        assert_eq!(rx.recv(), id);
+
+        pool.shutdown();
+    }
+
     // An advanced test that checks all four possible states that a
     // (task,sched) can be in regarding homes.
 
@@ -1268,6 +1607,66 @@ mod test {
         }).join();
     }
 
+    #[test]
+    fn lifo_slot() {
+        use sleeper_list::SleeperList;
+        use super::{Shutdown, Scheduler, SchedHandle, MAX_LIFO_RUNS};
+        use std::rt::thread::Thread;
+        use std::sync::deque::BufferPool;
+
+        // Puts a new task in the LIFO slot of the current scheduler, as an I/O
+        // callback waking it up would.
+        fn wake(f: proc():Send) {
+            let mut task = GreenTask::convert(Local::take());
+            {
+                let sched = task.sched.as_mut().unwrap();
+                let next = GreenTask::new(&mut sched.stack_pool, None, f);
+                sched.enqueue_woken(next);
+            }
+            task.put();
+        }
+
+        // Every task of the chain wakes up the next one
+        fn chain(n: uint, tx: Sender<uint>, handle: SchedHandle) {
+            tx.send(n);
+            if n == MAX_LIFO_RUNS * 2 {
+                let mut handle = handle;
+                handle.send(Shutdown);
+            } else {
+                wake(proc() chain(n + 1, tx, handle));
+            }
+        }
+
+        let (tx, rx) = channel();
+        Thread::start(proc() {
+            let pool = BufferPool::new();
+            let (worker, stealer) = pool.deque();
+            let (_p, state) = TaskState::new();
+            let mut sched = box Scheduler::new(1, basic::event_loop(), worker,
+                                               vec![stealer],
+                                               SleeperList::new(), state);
+            let handle = sched.make_handle();
+
+            let tx2 = tx.clone();
+            let queued = GreenTask::new(&mut sched.stack_pool, None, proc() {
+                tx2.send(0);
+            });
+            sched.enqueue_task(queued);
+            let first = GreenTask::new(&mut sched.stack_pool, None, proc() {
+                chain(1, tx, handle);
+            });
+            sched.enqueue_woken(first);
+            sched.bootstrap();
+        }).join();
+
+        // The queued task gets its turn after `MAX_LIFO_RUNS` woken tasks
+        let order = rx.iter().collect::<Vec<uint>>();
+        let mut expected = range(1, MAX_LIFO_RUNS + 1).collect::<Vec<uint>>();
+        expected.push(0);
+        expected.extend(range(MAX_LIFO_RUNS + 1, MAX_LIFO_RUNS * 2 + 1));
+        assert_eq!(order, expected);
+    }
+
     #[test]
     fn wakeup_across_scheds() {
         let (tx1, rx1) = channel();
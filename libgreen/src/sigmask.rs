@@ -0,0 +1,103 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Blocking signals on scheduler threads
+//!
+//! A signal sent to the process is handled by an arbitrary thread which does
+//! not block it. If that thread is a scheduler in the middle of a system call,
+//! the call is interrupted and fails with `EINTR`, which event loops and tasks
+//! rarely expect. Blocking signals on every scheduler thread makes sure they
+//! are handled by another thread of the process (such as the one which started
+//! the pool), from where event loops such as libuv's still notice them.
+
+use libc;
+
+/// Blocks delivery of `signals` to the calling OS thread, returning whether
+/// this succeeded.
+///
+/// This is supported on unix platforms, and always fails on windows where
+/// there are no signal masks.
+#[cfg(unix)]
+pub fn block_current_thread(signals: &[libc::c_int]) -> bool {
+    use std::ptr;
+
+    unsafe {
+        let mut set = imp::sigset_t::new();
+        if imp::sigemptyset(&mut set) != 0 { return false }
+        for &signal in signals.iter() {
+            if imp::sigaddset(&mut set, signal) != 0 { return false }
+        }
+        imp::pthread_sigmask(imp::SIG_BLOCK, &set, ptr::null_mut()) == 0
+    }
+}
+
+#[cfg(windows)]
+pub fn block_current_thread(_signals: &[libc::c_int]) -> bool { false }
+
+#[cfg(unix)]
+mod imp {
+    use libc;
+
+    // Large enough for the sigset_t of every supported platform, whose layout
+    // is left to sigemptyset and sigaddset.
+    #[repr(C)]
+    pub struct sigset_t { bits: [u64, ..16] }
+
+    impl sigset_t {
+        pub fn new() -> sigset_t { sigset_t { bits: [0, ..16] } }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static SIG_BLOCK: libc::c_int = 0;
+    #[cfg(any(target_os = "macos", target_os = "ios",
+              target_os = "freebsd", target_os = "dragonfly"))]
+    pub static SIG_BLOCK: libc::c_int = 1;
+
+    extern {
+        pub fn sigemptyset(set: *mut sigset_t) -> libc::c_int;
+        pub fn sigaddset(set: *mut sigset_t,
+                         signum: libc::c_int) -> libc::c_int;
+        pub fn sigismember(set: *const sigset_t,
+                           signum: libc::c_int) -> libc::c_int;
+        pub fn pthread_sigmask(how: libc::c_int, set: *const sigset_t,
+                               oldset: *mut sigset_t) -> libc::c_int;
+    }
+}
+
+// Returns whether `signal` is blocked on the calling OS thread, for tests.
+#[cfg(all(test, unix))]
+pub fn blocked(signal: libc::c_int) -> bool {
+    use std::ptr;
+
+    unsafe {
+        let mut set = imp::sigset_t::new();
+        assert_eq!(imp::pthread_sigmask(imp::SIG_BLOCK, ptr::null(),
+                                        &mut set), 0);
+        imp::sigismember(&set, signal) == 1
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use libc;
+
+    use super::{block_current_thread, blocked};
+
+    #[test]
+    fn block_sigint() {
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let before = blocked(libc::SIGINT);
+            tx.send((before, block_current_thread(&[libc::SIGINT]),
+                     blocked(libc::SIGINT)));
+        });
+        assert_eq!(rx.recv(), (false, true, true));
+    }
+}
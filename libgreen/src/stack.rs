@@ -89,6 +89,54 @@ impl Stack {
             buf.data().offset(buf.len() as int) as *const uint
         }).unwrap_or(ptr::null())
     }
+
+    /// Returns the number of usable bytes in this stack, not counting the
+    /// guard page.
+    pub fn size(&self) -> uint {
+        match self.buf {
+            Some(ref buf) => buf.len() - page_size(),
+            None => 0,
+        }
+    }
+
+    /// Returns the number of bytes of this stack which have been touched,
+    /// measured from the high end of the stack and rounded up to a page.
+    ///
+    /// Stack pages are only committed by the OS once they're first used, so
+    /// this is a cheap way of finding the deepest point this stack has ever
+    /// reached. Note that stacks are cached and reused, so this is the high
+    /// water mark of all tasks which have run on this stack.
+    #[cfg(unix)]
+    pub fn high_water_mark(&self) -> uint {
+        extern {
+            fn mincore(addr: *mut libc::c_void, len: libc::size_t,
+                       vec: *mut u8) -> libc::c_int;
+        }
+
+        let buf = match self.buf {
+            Some(ref buf) => buf,
+            None => return 0,
+        };
+        let pages = buf.len() / page_size();
+        let mut resident = Vec::from_elem(pages, 0u8);
+        let ret = unsafe {
+            mincore(buf.data() as *mut libc::c_void, buf.len() as libc::size_t,
+                    resident.as_mut_ptr())
+        };
+        if ret != 0 { return self.size() }
+
+        // Stacks grow downwards, so everything between the lowest resident
+        // page and the end of the stack counts as used.
+        match resident.iter().position(|r| *r & 1 != 0) {
+            Some(idx) => (pages - idx) * page_size(),
+            None => 0,
+        }
+    }
+
+    /// Windows offers no cheap way to query the residency of the stack, so the
+    /// whole stack is conservatively reported as used.
+    #[cfg(windows)]
+    pub fn high_water_mark(&self) -> uint { self.size() }
 }
 
 #[cfg(unix)]
@@ -185,4 +233,21 @@ mod tests {
         let s = p.take_stack(10);
         assert_eq!(s.min_size, 10);
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn high_water_mark() {
+        use std::os::page_size;
+        use super::Stack;
+
+        let s = Stack::new(16 * page_size());
+        assert_eq!(s.size(), 15 * page_size());
+        assert_eq!(s.high_water_mark(), 0);
+        unsafe {
+            let top = s.end() as *mut u8;
+            *top.offset(-1) = 1;
+            *top.offset(-(page_size() as int) - 1) = 1;
+        }
+        assert_eq!(s.high_water_mark(), 2 * page_size());
+    }
 }
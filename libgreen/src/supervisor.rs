@@ -0,0 +1,145 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reporting of and recovery from task panics.
+//!
+//! A task which panics normally just disappears, leaving behind a message on
+//! stderr at most. A pool of schedulers can instead be given a `PanicHook`
+//! which is told about every task of the pool which panics, for example to log
+//! or count failures. Long-running tasks can also be spawned with
+//! `SchedPool::spawn_supervised`, in which case a `RestartPolicy` decides
+//! whether they are started again after panicking.
+
+use std::any::Any;
+use std::str::SendStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, SeqCst};
+use std::rt::task::Task;
+use std::task::TaskBuilder;
+
+/// A task of a pool which panicked.
+pub struct Failure<'a> {
+    /// Identifier of the task, unique among the tasks currently alive
+    pub id: uint,
+    /// Name of the task, if it was given one
+    pub name: Option<&'a str>,
+    /// Value the task panicked with
+    pub payload: &'a (Any + Send),
+}
+
+/// A hook invoked whenever a task of a pool panics.
+///
+/// The hook runs on the scheduler thread of the failed task while the task is
+/// exiting, so it should not block for long.
+pub trait PanicHook {
+    fn panicked(&self, failure: &Failure);
+}
+
+/// Decides whether a supervised task which panicked is started again.
+pub trait RestartPolicy {
+    /// Called with the value the task panicked with and the number of times
+    /// it has already been restarted.
+    fn restart(&self, payload: &(Any + Send), restarts: uint) -> bool;
+}
+
+/// The body of a supervised task, which is run again every time the task is
+/// restarted.
+pub trait Service {
+    fn run(&self);
+}
+
+/// A restart policy which restarts a task at most the given number of times.
+pub struct MaxRestarts(pub uint);
+
+impl RestartPolicy for MaxRestarts {
+    fn restart(&self, _payload: &(Any + Send), restarts: uint) -> bool {
+        let MaxRestarts(max) = *self;
+        restarts < max
+    }
+}
+
+/// The panic hook of a pool, shared with all of its schedulers.
+pub struct Hooks {
+    installed: AtomicBool,
+    hook: Mutex<Option<Arc<Box<PanicHook + Send + Sync>>>>,
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks {
+            installed: AtomicBool::new(false),
+            hook: Mutex::new(None),
+        }
+    }
+
+    pub fn set(&self, hook: Option<Box<PanicHook + Send + Sync>>) {
+        let mut slot = self.hook.lock();
+        self.installed.store(hook.is_some(), SeqCst);
+        *slot = hook.map(|hook| Arc::new(hook));
+    }
+
+    /// Arranges for the current hook, if any, to be told about `task`
+    /// panicking, ahead of the task's own exit callback.
+    pub fn watch(&self, task: &mut Task, id: uint) {
+        if !self.installed.load(SeqCst) { return }
+        let hook = match *self.hook.lock() {
+            Some(ref hook) => hook.clone(),
+            None => return,
+        };
+        let name = task.name.as_ref().map(|name| name.as_slice().to_string());
+        let on_exit = task.death.on_exit.take();
+        task.death.on_exit = Some(proc(result) {
+            match result {
+                Ok(()) => {}
+                Err(ref payload) => {
+                    hook.panicked(&Failure {
+                        id: id,
+                        name: name.as_ref().map(|name| name.as_slice()),
+                        payload: &**payload,
+                    });
+                }
+            }
+            match on_exit {
+                Some(f) => f(result),
+                None => {}
+            }
+        });
+    }
+}
+
+/// Runs `service` in a child task until it exits without panicking or
+/// `policy` decides against restarting it.
+///
+/// This is the body of the task spawned by `SchedPool::spawn_supervised`,
+/// and the children are spawned into the same pool.
+pub fn supervise(name: Option<SendStr>, stack_size: Option<uint>,
+                 service: Box<Service + Send + Sync>,
+                 policy: Box<RestartPolicy + Send>) {
+    let service = Arc::new(service);
+    let mut restarts = 0;
+    loop {
+        let mut builder = TaskBuilder::new();
+        match name {
+            Some(ref name) => builder = builder.named(name.clone()),
+            None => {}
+        }
+        match stack_size {
+            Some(size) => builder = builder.stack_size(size),
+            None => {}
+        }
+        let service = service.clone();
+        let payload = match builder.try(proc() service.run()) {
+            Ok(()) => return,
+            Err(payload) => payload,
+        };
+        if !policy.restart(&*payload, restarts) { return }
+        restarts += 1;
+    }
+}
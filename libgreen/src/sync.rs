@@ -0,0 +1,285 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Synchronization primitives which block tasks instead of threads.
+//!
+//! The primitives of `std::sync` block the OS thread of a task waiting on
+//! them, which for a green task means its whole scheduler: every other task on
+//! that scheduler stalls until the wait is over, and if the task holding the
+//! lock lives on the same scheduler it never gets to release it. The
+//! primitives of this module deschedule the waiting task instead, leaving its
+//! scheduler free to run other tasks in the meantime.
+//!
+//! They can also be used from native tasks, which block as usual.
+
+use std::cell::UnsafeCell;
+use std::collections::RingBuf;
+use std::mem;
+use std::rt::local::Local;
+use std::rt::task::{Task, BlockedTask};
+use std::sync::Mutex as NativeMutex;
+
+/// A mutual exclusion primitive protecting the data of type `T`.
+///
+/// Tasks waiting for the lock are woken up in the order in which they started
+/// waiting, and the lock is handed over to them directly.
+pub struct Mutex<T> {
+    lock: Lock,
+    data: UnsafeCell<T>,
+}
+
+/// An RAII guard giving access to the data of a locked `Mutex`, which is
+/// unlocked when the guard goes out of scope.
+pub struct MutexGuard<'a, T: 'a> {
+    lock: &'a Mutex<T>,
+}
+
+/// A condition variable, allowing tasks to wait for an event while holding a
+/// `Mutex`.
+pub struct Condvar {
+    waiters: NativeMutex<RingBuf<BlockedTask>>,
+}
+
+struct Lock {
+    state: NativeMutex<LockState>,
+}
+
+struct LockState {
+    locked: bool,
+    waiters: RingBuf<BlockedTask>,
+}
+
+impl<T: Send> Mutex<T> {
+    /// Creates a new unlocked mutex protecting `t`.
+    pub fn new(t: T) -> Mutex<T> {
+        Mutex {
+            lock: Lock {
+                state: NativeMutex::new(LockState {
+                    locked: false,
+                    waiters: RingBuf::new(),
+                }),
+            },
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Acquires the mutex, blocking the current task until it is available.
+    pub fn lock<'a>(&'a self) -> MutexGuard<'a, T> {
+        self.lock.lock();
+        MutexGuard { lock: self }
+    }
+
+    /// Acquires the mutex if it is available, without blocking.
+    pub fn try_lock<'a>(&'a self) -> Option<MutexGuard<'a, T>> {
+        let mut state = self.lock.state.lock();
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            Some(MutexGuard { lock: self })
+        }
+    }
+}
+
+impl Lock {
+    fn lock(&self) {
+        {
+            let mut state = self.state.lock();
+            if !state.locked {
+                state.locked = true;
+                return
+            }
+        }
+
+        // The lock may be released before the task is descheduled, in which
+        // case it doesn't block after all.
+        let task: Box<Task> = Local::take();
+        task.deschedule(1, |task| {
+            let mut state = self.state.lock();
+            if state.locked {
+                state.waiters.push_back(task);
+                Ok(())
+            } else {
+                state.locked = true;
+                Err(task)
+            }
+        });
+    }
+
+    // The lock is handed over to the next waiter, if any, so that it stays
+    // locked.
+    fn unlock(&self) {
+        let next = {
+            let mut state = self.state.lock();
+            let next = state.waiters.pop_front();
+            if next.is_none() {
+                state.locked = false;
+            }
+            next
+        };
+        match next {
+            Some(task) => task.reawaken(),
+            None => {}
+        }
+    }
+}
+
+impl<'a, T: Send> Deref<T> for MutexGuard<'a, T> {
+    fn deref<'b>(&'b self) -> &'b T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T: Send> DerefMut<T> for MutexGuard<'a, T> {
+    fn deref_mut<'b>(&'b mut self) -> &'b mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+#[unsafe_destructor]
+impl<'a, T: Send> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.lock.unlock();
+    }
+}
+
+impl Condvar {
+    /// Creates a new condition variable.
+    pub fn new() -> Condvar {
+        Condvar { waiters: NativeMutex::new(RingBuf::new()) }
+    }
+
+    /// Atomically unlocks the mutex of `guard` and blocks the current task
+    /// until this condition variable is notified, after which the mutex is
+    /// locked again.
+    ///
+    /// As with any condition variable, the task may also wake up without
+    /// having been notified, so the condition waited on must be checked again.
+    pub fn wait<T: Send>(&self, guard: &MutexGuard<T>) {
+        let task: Box<Task> = Local::take();
+        task.deschedule(1, |task| {
+            // Only unlock once queued, so a notification sent right after the
+            // mutex is unlocked isn't missed.
+            self.waiters.lock().push_back(task);
+            guard.lock.lock.unlock();
+            Ok(())
+        });
+        guard.lock.lock.lock();
+    }
+
+    /// Wakes up one task waiting on this condition variable, returning whether
+    /// there was one.
+    pub fn notify_one(&self) -> bool {
+        let task = self.waiters.lock().pop_front();
+        match task {
+            Some(task) => { task.reawaken(); true }
+            None => false,
+        }
+    }
+
+    /// Wakes up all tasks waiting on this condition variable, returning how
+    /// many there were.
+    pub fn notify_all(&self) -> uint {
+        let tasks = mem::replace(&mut *self.waiters.lock(), RingBuf::new());
+        let n = tasks.len();
+        for task in tasks.into_iter() {
+            task.reawaken();
+        }
+        n
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::task;
+    use testing::run;
+    use super::{Mutex, Condvar};
+
+    // A single scheduler runs all of these tasks, so blocking its thread while
+    // waiting on another task would hang.
+
+    #[test]
+    fn contended() {
+        run(proc() {
+            let m = Arc::new(Mutex::new(0u));
+            let (tx, rx) = channel();
+            for _ in range(0u, 10) {
+                let (m, tx) = (m.clone(), tx.clone());
+                task::spawn(proc() {
+                    for _ in range(0u, 10) {
+                        let mut n = m.lock();
+                        let before = *n;
+                        task::deschedule();
+                        *n = before + 1;
+                    }
+                    tx.send(());
+                });
+            }
+            for _ in range(0u, 10) { rx.recv() }
+            assert_eq!(*m.lock(), 100);
+        });
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = Mutex::new(());
+        let g = m.lock();
+        assert!(m.try_lock().is_none());
+        drop(g);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn condvar() {
+        run(proc() {
+            let pair = Arc::new((Mutex::new(false), Condvar::new()));
+            let pair2 = pair.clone();
+            task::spawn(proc() {
+                let &(ref m, ref c) = &*pair2;
+                *m.lock() = true;
+                c.notify_one();
+            });
+
+            let &(ref m, ref c) = &*pair;
+            let ready = m.lock();
+            while !*ready {
+                c.wait(&ready);
+            }
+        });
+    }
+
+    #[test]
+    fn notify_all() {
+        run(proc() {
+            let pair = Arc::new((Mutex::new(0u), Condvar::new()));
+            let (tx, rx) = channel();
+            for _ in range(0u, 5) {
+                let (pair, tx) = (pair.clone(), tx.clone());
+                task::spawn(proc() {
+                    let &(ref m, ref c) = &*pair;
+                    let mut n = m.lock();
+                    *n += 1;
+                    tx.send(());
+                    while *n != 0 {
+                        c.wait(&n);
+                    }
+                    tx.send(());
+                });
+            }
+            for _ in range(0u, 5) { rx.recv() }
+
+            let &(ref m, ref c) = &*pair;
+            *m.lock() = 0;
+            assert_eq!(c.notify_all(), 5);
+            for _ in range(0u, 5) { rx.recv() }
+        });
+    }
+}
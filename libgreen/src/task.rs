@@ -27,11 +27,14 @@ use std::rt::mutex::NativeMutex;
 use std::rt::stack;
 use std::rt::task::{Task, BlockedTask, TaskOpts};
 use std::rt;
+use std::sync::Arc;
+use std::task;
 
 use context::Context;
 use coroutine::Coroutine;
-use sched::{Scheduler, SchedHandle, RunOnce};
-use stack::StackPool;
+use observer::TaskObserver;
+use sched::{Scheduler, SchedHandle, SchedCounters, RunOnce, PinnedTask};
+use stack::{Stack, StackPool};
 
 pub use self::TaskType::{TypeGreen, TypeSched};
 pub use self::Home::{AnySched, HomeSched};
@@ -69,6 +72,14 @@ pub struct GreenTask {
 
     // See the comments in the scheduler about why this is necessary
     pub nasty_deschedule_lock: NativeMutex,
+
+    /// Counters of the scheduler whose run queue this task is currently
+    /// sitting in, if any.
+    pub queued_on: Option<SchedCounters>,
+
+    /// Observer of the pool this task was started in, if it had one at the
+    /// time.
+    pub observer: Option<Arc<Box<TaskObserver + Send + Sync>>>,
 }
 
 pub enum TaskType {
@@ -92,6 +103,132 @@ pub fn spawn(f: proc(): Send) {
 
 /// See documentation for `spawn`.
 pub fn spawn_opts(opts: TaskOpts, f: proc(): Send) {
+    let (mut me, sibling) = take_and_configure(opts, f);
+
+    // We bootstrap the new task immediately by switching to it. Upon
+    // returning, our task is back in TLS and we're good to return.
+    let sched = me.sched.take().unwrap();
+    sched.run_task(me, sibling)
+}
+
+/// Spawn a new green task pinned to the scheduler behind `handle`, assuming
+/// the current task is a green task.
+///
+/// The new task is sent directly to the specified scheduler and will only ever
+/// run there. This avoids having to migrate a task onto the event loop of the
+/// I/O handles it owns, and allows work to be sharded among schedulers.
+///
+/// # Failure
+///
+/// This function will fail if the current task is not already a green task.
+pub fn spawn_on(handle: &mut SchedHandle, f: proc(): Send) {
+    spawn_opts_on(handle, TaskOpts::new(), f)
+}
+
+/// See documentation for `spawn_on`.
+pub fn spawn_opts_on(handle: &mut SchedHandle, opts: TaskOpts,
+                     f: proc(): Send) {
+    let (me, sibling) = take_and_configure(opts, f);
+    me.put();
+    handle.send(PinnedTask(sibling));
+}
+
+/// A group of green tasks whose failure is linked to the task which spawned
+/// them.
+///
+/// Children are spawned as siblings of the current green task and report how
+/// they exited back to the group. Joining or dropping the group waits for all
+/// of its children to exit. If any of them panicked, `join` returns the value
+/// the first one panicked with, and dropping the group panics the current task
+/// in turn. A request handler which fans work out to sub-tasks is therefore
+/// torn down along with them instead of carrying on with a partial result.
+pub struct Linked {
+    tx: Sender<Result<(), Box<Any + Send>>>,
+    rx: Receiver<Result<(), Box<Any + Send>>>,
+    live: uint,
+    failure: Option<Box<Any + Send>>,
+}
+
+impl Linked {
+    /// Creates an empty group of linked tasks.
+    pub fn new() -> Linked {
+        let (tx, rx) = channel();
+        Linked { tx: tx, rx: rx, live: 0, failure: None }
+    }
+
+    /// Spawns a new green task into this group.
+    ///
+    /// # Failure
+    ///
+    /// This function will fail if the current task is not a green task.
+    pub fn spawn(&mut self, f: proc(): Send) {
+        self.spawn_opts(TaskOpts::new(), f)
+    }
+
+    /// See documentation for `spawn`.
+    ///
+    /// The exit of the child is reported through its `on_exit` callback, so
+    /// `opts` may not have one already.
+    pub fn spawn_opts(&mut self, mut opts: TaskOpts, f: proc(): Send) {
+        assert!(opts.on_exit.is_none(),
+                "linked tasks can't have their own exit callback");
+        let tx = self.tx.clone();
+        opts.on_exit = Some(proc(result) { let _ = tx.send_opt(result); });
+        self.live += 1;
+        spawn_opts(opts, f);
+    }
+
+    /// Returns whether a child of this group has panicked, without waiting for
+    /// the others to exit.
+    pub fn failed(&mut self) -> bool {
+        loop {
+            match self.rx.try_recv() {
+                Ok(result) => self.exited(result),
+                Err(..) => break,
+            }
+        }
+        self.failure.is_some()
+    }
+
+    /// Waits for every child of this group to exit, returning the value the
+    /// first one to panic panicked with, if any.
+    pub fn join(mut self) -> Result<(), Box<Any + Send>> {
+        self.wait();
+        match self.failure.take() {
+            Some(payload) => Err(payload),
+            None => Ok(()),
+        }
+    }
+
+    fn wait(&mut self) {
+        while self.live > 0 {
+            let result = self.rx.recv();
+            self.exited(result);
+        }
+    }
+
+    fn exited(&mut self, result: Result<(), Box<Any + Send>>) {
+        self.live -= 1;
+        if self.failure.is_none() {
+            self.failure = result.err();
+        }
+    }
+}
+
+impl Drop for Linked {
+    fn drop(&mut self) {
+        self.wait();
+        if self.failure.is_some() && !task::failing() {
+            panic!("a linked task panicked")
+        }
+    }
+}
+
+// Takes the current green task out of TLS and creates a new task from its
+// scheduler's stack pool, configured according to `opts`. The current task is
+// returned alongside the new one and must be placed back into TLS.
+fn take_and_configure(opts: TaskOpts,
+                      f: proc(): Send) -> (Box<GreenTask>, Box<GreenTask>) {
     let mut task: Box<Task> = Local::take();
     let task = match task.maybe_take_runtime::<GreenTask>() {
         Some(mut green) => {
@@ -115,19 +252,54 @@ pub fn spawn_opts(opts: TaskOpts, f: proc(): Send) {
     }
     let mut bomb = Bomb { inner: Some(task) };
 
-    // Spawns a task into the current scheduler. We allocate the new task's
-    // stack from the scheduler's stack pool, and then configure it
-    // accordingly to `opts`. Afterwards we bootstrap it immediately by
-    // switching to it.
-    //
-    // Upon returning, our task is back in TLS and we're good to return.
+    // We allocate the new task's stack from the scheduler's stack pool, and
+    // then configure it accordingly to `opts`.
     let sibling = {
         let sched = bomb.inner.as_mut().unwrap().sched.as_mut().unwrap();
         GreenTask::configure(&mut sched.stack_pool, opts, f)
     };
-    let mut me = bomb.inner.take().unwrap();
-    let sched = me.sched.take().unwrap();
-    sched.run_task(me, sibling)
+    (bomb.inner.take().unwrap(), sibling)
+}
+
+/// Returns the number of usable bytes of the current green task's stack.
+///
+/// This is the size requested through the `stack_size` option when spawning
+/// the task, or the runtime's default minimum stack size otherwise.
+///
+/// # Failure
+///
+/// This function will fail if the current task is not a green task.
+pub fn stack_size() -> uint {
+    with_stack(|stack| stack.size())
+}
+
+/// Returns the deepest point, in bytes, that the current green task's stack
+/// has reached so far.
+///
+/// Comparing this against `stack_size` shows how much of its stack a task
+/// actually needs, which allows a smaller `stack_size` to be requested when
+/// spawning large numbers of similar tasks. The value is page granular, and
+/// because stacks are recycled it may include usage by earlier tasks which ran
+/// on the same stack.
+///
+/// # Failure
+///
+/// This function will fail if the current task is not a green task.
+pub fn stack_high_water_mark() -> uint {
+    with_stack(|stack| stack.high_water_mark())
+}
+
+fn with_stack<T>(f: |&Stack| -> T) -> T {
+    let mut task = Local::borrow(None::<Task>);
+    match task.maybe_take_runtime::<GreenTask>() {
+        Some(green) => {
+            let ret = f(&green.coroutine.as_ref().unwrap()
+                              .current_stack_segment);
+            task.put_runtime(green);
+            ret
+        }
+        None => panic!("not a green task"),
+    }
 }
 
 /// Trampoline code for all new green tasks which are running around. This
@@ -150,12 +322,27 @@ extern fn bootstrap_green_task(task: uint, code: *mut (), env: *mut ()) -> ! {
     let mut task: Box<GreenTask> = unsafe { mem::transmute(task) };
 
     // First code after swap to this new context. Run our cleanup job
-    task.pool_id = {
+    let id = task.as_uint();
+    let name = task.task.as_ref().unwrap().name.as_ref().map(|name| {
+        name.as_slice().to_string()
+    });
+    let (pool_id, hooks, observer) = {
         let sched = task.sched.as_mut().unwrap();
         sched.run_cleanup_job();
         sched.task_state.increment();
-        sched.pool_id
+        sched.task_state.deadlock.task_started(id, name.clone());
+        (sched.pool_id, sched.task_state.hooks.clone(),
+         sched.task_state.observer.get())
     };
+    task.pool_id = pool_id;
+    hooks.watch(&mut **task.task.as_mut().unwrap(), id);
+    task.observer = observer;
+    match task.observer {
+        Some(ref observer) => {
+            observer.spawned(id, name.as_ref().map(|name| name.as_slice()))
+        }
+        None => {}
+    }
 
     // Convert our green task to a libstd task and then execute the code
     // requested. This is the "try/catch" block for this green task and
@@ -171,7 +358,12 @@ extern fn bootstrap_green_task(task: uint, code: *mut (), env: *mut ()) -> ! {
     // this we could add a `terminate` function to the `Runtime` trait
     // in libstd, but that seems less appropriate since the conversion
     // method exists.
-    GreenTask::convert(task).terminate();
+    let task = GreenTask::convert(task);
+    match task.observer {
+        Some(ref observer) => observer.exited(id),
+        None => {}
+    }
+    task.terminate();
 }
 
 impl GreenTask {
@@ -217,6 +409,8 @@ impl GreenTask {
             handle: None,
             nasty_deschedule_lock: unsafe { NativeMutex::new() },
             task: Some(box Task::new()),
+            queued_on: None,
+            observer: None,
         }
     }
 
@@ -391,6 +585,12 @@ impl Runtime for GreenTask {
                   f: |BlockedTask| -> Result<(), BlockedTask>) {
         self.put_task(cur_task);
         let mut sched = self.sched.take().unwrap();
+        let id = self.as_uint();
+        let observer = self.observer.clone();
+        match observer {
+            Some(ref observer) => observer.blocked(id),
+            None => {}
+        }
 
         // In order for this task to be reawoken in all possible contexts, we
         // may need a handle back in to the current scheduler. When we're woken
@@ -434,6 +634,12 @@ impl Runtime for GreenTask {
                 }
             });
         }
+
+        // Once we get here the task has been woken up and is running again.
+        match observer {
+            Some(ref observer) => observer.unblocked(id),
+            None => {}
+        }
     }
 
     fn reawaken(mut self: Box<GreenTask>, to_wake: Box<Task>) {
@@ -461,23 +667,30 @@ impl Runtime for GreenTask {
         // dynamically check to see which of these cases is the current
         // situation and then dispatch accordingly.
         //
-        // In case 1, we just use the local scheduler to resume ourselves
-        // immediately (if a rescheduling is possible).
+        // In case 1, we use the local scheduler to resume ourselves. I/O
+        // callbacks run on the scheduler task, which goes on to run the rest
+        // of the callbacks of this turn of the event loop, so a task woken by
+        // one is put in the scheduler's LIFO slot to be run next, ahead of the
+        // run queue and while its data is still in cache. A task woken by
+        // another task is resumed immediately (if a rescheduling is possible).
         //
         // In case 2 and 3, we need to remotely reawaken ourself in order to be
         // transplanted back to the correct scheduler pool.
         match running_task.maybe_take_runtime::<GreenTask>() {
             Some(mut running_green_task) => {
                 running_green_task.put_task(running_task);
-                let sched = running_green_task.sched.take().unwrap();
+                let mut sched = running_green_task.sched.take().unwrap();
 
-                if sched.pool_id == self.pool_id {
-                    sched.run_task(running_green_task, self);
-                } else {
+                if sched.pool_id != self.pool_id {
                     self.reawaken_remotely();
 
                     // put that thing back where it came from!
                     running_green_task.put_with_sched(sched);
+                } else if running_green_task.is_sched() {
+                    sched.enqueue_woken(self);
+                    running_green_task.put_with_sched(sched);
+                } else {
+                    sched.run_task(running_green_task, self);
                 }
             }
             None => {
@@ -566,6 +779,24 @@ mod tests {
         assert!(rx.recv().is_ok());
     }
 
+    #[test]
+    fn stack_usage() {
+        use std::os::page_size;
+        use super::{stack_size, stack_high_water_mark};
+
+        let mut opts = TaskOpts::new();
+        opts.stack_size = Some(32 * page_size());
+        let (tx, rx) = channel();
+        spawn_opts(opts, proc() {
+            let size = stack_size();
+            let used = stack_high_water_mark();
+            assert!(size >= 31 * page_size());
+            assert!(used > 0 && used <= size);
+            tx.send(());
+        });
+        rx.recv();
+    }
+
     #[test]
     fn smoke_opts_fail() {
         let mut opts = TaskOpts::new();
@@ -575,6 +806,35 @@ mod tests {
         assert!(rx.recv().is_err());
     }
 
+    #[test]
+    fn linked_join() {
+        use super::Linked;
+
+        let (tx, rx) = channel();
+        spawn_opts(TaskOpts::new(), proc() {
+            let mut linked = Linked::new();
+            linked.spawn(proc() {});
+            linked.spawn(proc() { panic!(7u) });
+            let payload = linked.join().err().unwrap();
+            tx.send(*payload.downcast_ref::<uint>().unwrap());
+        });
+        assert_eq!(rx.recv(), 7);
+    }
+
+    #[test]
+    fn linked_drop_fails_parent() {
+        use super::Linked;
+
+        let mut opts = TaskOpts::new();
+        let (tx, rx) = channel();
+        opts.on_exit = Some(proc(r) tx.send(r));
+        spawn_opts(opts, proc() {
+            let mut linked = Linked::new();
+            linked.spawn(proc() { panic!() });
+        });
+        assert!(rx.recv().is_err());
+    }
+
     #[test]
     fn yield_test() {
         let (tx, rx) = channel();
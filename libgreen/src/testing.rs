@@ -0,0 +1,190 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for testing code which must run inside of a green task.
+//!
+//! Tests are run by the native test harness, so code requiring a green task
+//! (for example code doing I/O through an event loop) has to spin up a
+//! scheduler first. The functions in this module run a procedure in a green
+//! task on a fresh scheduler powered by the calling thread, failing if the
+//! procedure fails:
+//!
+//! ```rust,ignore
+//! extern crate green;
+//!
+//! #[test]
+//! fn my_test() {
+//!     green::testing::run(proc() {
+//!         // running in a green task
+//!     });
+//! }
+//! ```
+//!
+//! A test waiting on an event which never arrives would hang the whole test
+//! binary, so `run_on_timeout` fails after a given time instead.
+
+use std::io::stdio;
+use std::io::timer::Timer;
+use std::rt::local::Local;
+use std::rt::task::Task;
+use std::rt::thread::Thread;
+use std::sync::deque::BufferPool;
+use std::time::Duration;
+
+use {basic, EventLoop, TaskState};
+use sched::{Scheduler, SchedHandle, Shutdown};
+use sleeper_list::SleeperList;
+use task::GreenTask;
+
+/// Runs `f` in a green task on a scheduler driven by the basic event loop,
+/// returning once the scheduler has exited.
+///
+/// # Failure
+///
+/// This function fails if `f` fails.
+pub fn run(f: proc(): Send) {
+    run_on(basic::event_loop(), f)
+}
+
+/// Runs `f` in a green task on a scheduler driven by `event_loop`, returning
+/// once the scheduler has exited.
+///
+/// # Failure
+///
+/// This function fails if `f` fails.
+pub fn run_on(event_loop: Box<EventLoop + Send>, f: proc(): Send) {
+    let (sched, exited, done) = scheduler(event_loop, f);
+
+    // Steal away the actual native task and then run the scheduler
+    {
+        let _native_task = Local::borrow(None::<Task>);
+        sched.bootstrap();
+    }
+
+    // Ensure the scheduler exited with all tasks having completed.
+    exited.recv();
+
+    // This will fail if the task did not exit cleanly.
+    if done.recv_opt().is_err() {
+        panic!("green task failed");
+    }
+}
+
+/// Runs `f` as `run_on` does, but fails if `f` hasn't completed after
+/// `timeout`.
+///
+/// The scheduler runs on a thread of its own, so a test waiting on an event
+/// which never arrives fails instead of hanging. A green task can't be
+/// interrupted though, so when the timeout expires the scheduler is asked to
+/// shut down and its thread is left behind until its tasks complete.
+///
+/// # Failure
+///
+/// This function fails if `f` fails or times out.
+pub fn run_on_timeout(event_loop: Box<EventLoop + Send>, timeout: Duration,
+                      f: proc(): Send) {
+    let (sched, exited, done) = scheduler(event_loop, f);
+    let mut handle = sched.make_handle();
+    Thread::spawn(proc() sched.bootstrap());
+
+    let mut timer = Timer::new().unwrap();
+    let expired = timer.oneshot(timeout);
+    select! {
+        result = done.recv_opt() => {
+            exited.recv();
+            if result.is_err() {
+                panic!("green task failed");
+            }
+        },
+        () = expired.recv() => {
+            handle.send(Shutdown);
+            panic!("green task timed out after {}ms",
+                   timeout.num_milliseconds());
+        }
+    }
+}
+
+// Creates a scheduler running `f` in a green task, which shuts down once `f`
+// has completed. Returns the scheduler along with receivers for the scheduler
+// exiting and `f` completing, the latter of which is disconnected if `f`
+// fails.
+fn scheduler(event_loop: Box<EventLoop + Send>, f: proc(): Send)
+             -> (Box<Scheduler>, Receiver<()>, Receiver<()>) {
+    struct SchedulerExiter { handle: SchedHandle }
+    impl Drop for SchedulerExiter {
+        fn drop(&mut self) { self.handle.send(Shutdown) }
+    }
+
+    let stdout = stdio::set_stdout(box stdio::stdout());
+    let stderr = stdio::set_stderr(box stdio::stderr());
+
+    // Create a scheduler to run locally
+    let pool = BufferPool::new();
+    let (worker, stealer) = pool.deque();
+    let (rx, state) = TaskState::new();
+    let mut sched = box Scheduler::new(100,
+                                       event_loop,
+                                       worker,
+                                       vec![stealer],
+                                       SleeperList::new(),
+                                       state);
+
+    // Schedule the shutdown message to the scheduler, but only send it after
+    // the scheduler has exited.
+    let exit = SchedulerExiter { handle: sched.make_handle() };
+
+    // Enqueue a fresh green task for the given procedure
+    let (tx1, rx1) = channel();
+    let task = GreenTask::new(&mut sched.stack_pool, None, proc() {
+        stdout.map(stdio::set_stdout);
+        stderr.map(stdio::set_stderr);
+        f();
+        tx1.send(());
+        drop(exit);
+    });
+    sched.enqueue_task(task);
+    (sched, rx, rx1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::task;
+    use std::time::Duration;
+    use super::{run, run_on_timeout};
+
+    #[test]
+    fn smoke() {
+        let (tx, rx) = channel();
+        run(proc() tx.send(::task::stack_size()));
+        assert!(rx.recv() > 0);
+    }
+
+    #[test]
+    fn failure() {
+        assert!(task::try(proc() run(proc() panic!())).is_err());
+    }
+
+    #[test]
+    fn timeout() {
+        let (tx, rx) = channel::<()>();
+        assert!(task::try(proc() {
+            run_on_timeout(::basic::event_loop(), Duration::milliseconds(10),
+                           proc() rx.recv())
+        }).is_err());
+
+        // Let the leftover scheduler shut down
+        drop(tx);
+    }
+
+    #[test]
+    fn no_timeout() {
+        run_on_timeout(::basic::event_loop(), Duration::seconds(10), proc() {});
+    }
+}
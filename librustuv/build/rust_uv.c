@@ -10,6 +10,7 @@
 
 #include <stdlib.h>
 #include <assert.h>
+#include <errno.h>
 
 #ifdef __WIN32__
 // For alloca
@@ -19,6 +20,8 @@
 #ifndef __WIN32__
 // for signal
 #include <signal.h>
+// for gettimeofday
+#include <sys/time.h>
 #endif
 
 #include "uv.h"
@@ -51,6 +54,20 @@ rust_uv_get_stream_handle_from_write_req(uv_write_t* write_req) {
     return write_req->handle;
 }
 
+#ifndef __WIN32__
+// The buffer which a pending write with a single buffer continues from. libuv
+// advances its base as the buffer is partially written.
+uv_buf_t*
+rust_uv_get_buf_from_write_req(uv_write_t* write_req) {
+    return &write_req->bufs[write_req->write_index];
+}
+#endif
+
+size_t
+rust_uv_get_write_queue_size(uv_stream_t* stream) {
+    return stream->write_queue_size;
+}
+
 uv_loop_t*
 rust_uv_get_loop_for_uv_handle(uv_handle_t* handle) {
     return handle->loop;
@@ -72,6 +89,11 @@ rust_uv_get_data_for_uv_handle(uv_handle_t* handle) {
     return handle->data;
 }
 
+uv_handle_type
+rust_uv_handle_type(uv_handle_t* handle) {
+    return handle->type;
+}
+
 void
 rust_uv_set_data_for_uv_handle(uv_handle_t* handle, void* data) {
     handle->data = data;
@@ -87,6 +109,14 @@ rust_uv_set_data_for_req(uv_req_t* req, void* data) {
     req->data = data;
 }
 
+// The version of the bundled libuv in the format of uv_version(), without the
+// patch number. This is taken from its uv-version.h by the Makefile, as with
+// the system-libuv feature uv.h is that of the system libuv instead.
+unsigned int
+rust_uv_bundled_version() {
+  return (RUST_UV_BUNDLED_MAJOR << 16) | (RUST_UV_BUNDLED_MINOR << 8);
+}
+
 uintptr_t
 rust_uv_handle_type_max() {
   return UV_HANDLE_TYPE_MAX;
@@ -167,3 +197,30 @@ int
 rust_uv_guess_handle(int fd) {
   return uv_guess_handle(fd);
 }
+
+// The bundled libuv predates uv_gettimeofday, so this mirrors its
+// implementation.
+int
+rust_uv_gettimeofday(int64_t* sec, int32_t* usec) {
+#ifdef __WIN32__
+  // Number of 100ns intervals between 1601-01-01 and 1970-01-01
+  const uint64_t epoch = 116444736000000000ULL;
+  FILETIME ft;
+  uint64_t time;
+
+  GetSystemTimeAsFileTime(&ft);
+  time = ((uint64_t) ft.dwHighDateTime << 32) | ft.dwLowDateTime;
+  time -= epoch;
+  *sec = (int64_t) (time / 10000000);
+  *usec = (int32_t) ((time % 10000000) / 10);
+  return 0;
+#else
+  struct timeval tv;
+
+  if (gettimeofday(&tv, NULL) != 0)
+    return -errno;
+  *sec = (int64_t) tv.tv_sec;
+  *usec = (int32_t) tv.tv_usec;
+  return 0;
+#endif
+}
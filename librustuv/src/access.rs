@@ -32,17 +32,31 @@ pub struct Guard<'a, T: 'a> {
 }
 
 struct Inner<T> {
-    queue: Vec<(BlockedTask, uint)>,
+    queue: WaitList,
     held: bool,
     closed: bool,
     data: T,
 }
 
+// A task blocked waiting for access to be granted. Waiters live on the stack of
+// the blocked task and are linked into an intrusive FIFO list, so waiting for
+// access never allocates. Access is always handed to the head of the list.
+struct Waiter {
+    task: Option<BlockedTask>,
+    token: uint,
+    next: *mut Waiter,
+}
+
+struct WaitList {
+    head: *mut Waiter,
+    tail: *mut Waiter,
+}
+
 impl<T: Send> Access<T> {
     pub fn new(data: T) -> Access<T> {
         Access {
             inner: Arc::new(UnsafeCell::new(Inner {
-                queue: vec![],
+                queue: WaitList::new(),
                 held: false,
                 closed: false,
                 data: data,
@@ -58,9 +72,15 @@ impl<T: Send> Access<T> {
         let inner = unsafe { &mut *self.inner.get() };
 
         if inner.held {
+            let mut waiter = Waiter {
+                task: None,
+                token: token,
+                next: 0 as *mut _,
+            };
             let t: Box<Task> = Local::take();
             t.deschedule(1, |task| {
-                inner.queue.push((task, token));
+                waiter.task = Some(task);
+                unsafe { inner.queue.push(&mut waiter); }
                 Ok(())
             });
             assert!(inner.held);
@@ -93,11 +113,7 @@ impl<T: Send> Access<T> {
     // is only safe to invoke while on the home event loop, and there is no
     // guarantee that this i being invoked on the home event loop.
     pub unsafe fn dequeue(&mut self, token: uint) -> Option<BlockedTask> {
-        let inner = &mut *self.inner.get();
-        match inner.queue.iter().position(|&(_, t)| t == token) {
-            Some(i) => Some(inner.queue.remove(i).unwrap().val0()),
-            None => None,
-        }
+        (*self.inner.get()).queue.remove(token)
     }
 
     /// Test whether this access is closed, using a homing missile to prove
@@ -145,7 +161,7 @@ impl<'a, T: Send> Drop for Guard<'a, T> {
             mem::transmute(self.access.inner.get())
         };
 
-        match inner.queue.remove(0) {
+        match unsafe { inner.queue.pop() } {
             // Here we have found a task that was waiting for access, and we
             // current have the "access lock" we need to relinquish access to
             // this sleeping task.
@@ -155,7 +171,7 @@ impl<'a, T: Send> Drop for Guard<'a, T> {
             // scheduled on this scheduler. Because we might be woken up on some
             // other scheduler, we drop our homing missile before we reawaken
             // the task.
-            Some((task, _)) => {
+            Some(task) => {
                 drop(self.missile.take());
                 task.reawaken();
             }
@@ -168,6 +184,60 @@ impl<'a, T: Send> Drop for Guard<'a, T> {
 impl<T> Drop for Inner<T> {
     fn drop(&mut self) {
         assert!(!self.held);
-        assert_eq!(self.queue.len(), 0);
+        assert!(self.queue.is_empty());
+    }
+}
+
+// All of these functions are unsafe because the waiters are raw pointers into
+// the stacks of blocked tasks. They are only valid while the owning task is
+// blocked, which is guaranteed as long as a waiter is popped or removed before
+// its task is reawakened.
+impl WaitList {
+    fn new() -> WaitList {
+        WaitList { head: 0 as *mut _, tail: 0 as *mut _ }
+    }
+
+    fn is_empty(&self) -> bool { self.head.is_null() }
+
+    unsafe fn push(&mut self, waiter: *mut Waiter) {
+        (*waiter).next = 0 as *mut _;
+        if self.tail.is_null() {
+            self.head = waiter;
+        } else {
+            (*self.tail).next = waiter;
+        }
+        self.tail = waiter;
+    }
+
+    unsafe fn pop(&mut self) -> Option<BlockedTask> {
+        if self.head.is_null() { return None }
+        let waiter = self.head;
+        self.head = (*waiter).next;
+        if self.head.is_null() {
+            self.tail = 0 as *mut _;
+        }
+        (*waiter).task.take()
+    }
+
+    unsafe fn remove(&mut self, token: uint) -> Option<BlockedTask> {
+        let mut prev: *mut Waiter = 0 as *mut _;
+        let mut cur = self.head;
+        while !cur.is_null() {
+            if (*cur).token == token {
+                let next = (*cur).next;
+                if prev.is_null() {
+                    self.head = next;
+                } else {
+                    (*prev).next = next;
+                }
+                if self.tail == cur {
+                    self.tail = prev;
+                }
+                return (*cur).task.take()
+            }
+            prev = cur;
+            cur = (*cur).next;
+        }
+        None
     }
 }
@@ -8,13 +8,16 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::io::net::ip::IpAddr;
+use std::c_str::CString;
+use std::io::net::addrinfo::{Hint, SocketType, Protocol};
+use std::io::net::ip::{IpAddr, SocketAddr};
 use std::mem;
 use std::rt::task::BlockedTask;
 use libc::c_int;
 use libc;
 
 use {uvll, raw, UvResult, UvError, EventLoop};
+use promise::{mod, Completer};
 use raw::Request;
 
 struct Data {
@@ -31,6 +34,19 @@ struct GetAddrInfo {
     handle: raw::GetAddrInfo,
 }
 
+/// One result of a `lookup`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct AddrInfoEntry {
+    pub address: SocketAddr,
+    /// Address family of this entry, one of the `AF_*` constants
+    pub family: uint,
+    pub socktype: Option<SocketType>,
+    pub protocol: Option<Protocol>,
+    /// Canonical name of the host, if `AI_CANONNAME` was passed in the hints.
+    /// The name is usually only reported on the first entry.
+    pub canonical_name: Option<String>,
+}
+
 /// Synchronous DNS resolution
 ///
 /// See [`std::io::net::get_host_addresses`][1]
@@ -44,6 +60,30 @@ pub fn get_host_addresses(host: &str) -> UvResult<Vec<IpAddr>> {
 /// Same as `get_host_addresses`, but specifies what event loop to run on.
 pub fn get_host_addresses_on(eloop: &mut EventLoop,
                              host: &str) -> UvResult<Vec<IpAddr>> {
+    let entries = try!(lookup_on(eloop, Some(host), None, None));
+    Ok(entries.into_iter().map(|e| e.address.ip).collect())
+}
+
+/// Full DNS resolution, returning every entry `getaddrinfo` reports.
+///
+/// Unlike `get_host_addresses`, the socket type, protocol and canonical name
+/// of each entry are kept, so callers can pick the entries appropriate for the
+/// protocol they speak. See [`std::io::net::addrinfo::lookup`][1] for the
+/// meaning of the arguments.
+///
+/// [1]: http://doc.rust-lang.org/std/io/net/addrinfo/fn.lookup.html
+pub fn lookup(host: Option<&str>, service: Option<&str>,
+              hints: Option<Hint>) -> UvResult<Vec<AddrInfoEntry>> {
+    let mut eloop = try!(EventLoop::borrow());
+    lookup_on(&mut *eloop, host, service, hints)
+}
+
+/// Same as `lookup`, but specifies what event loop to run on.
+pub fn lookup_on(eloop: &mut EventLoop, host: Option<&str>,
+                 service: Option<&str>,
+                 hints: Option<Hint>) -> UvResult<Vec<AddrInfoEntry>> {
+    let hints = hints.map(raw_hints);
+
     let mut req = unsafe { GetAddrInfo { handle: Request::alloc() } };
     let mut data = Data {
         blocker: None,
@@ -52,7 +92,8 @@ pub fn get_host_addresses_on(eloop: &mut EventLoop,
     };
     req.handle.set_data(&mut data as *mut _ as *mut _);
     unsafe {
-        try!(req.handle.send(&eloop.uv_loop(), Some(host), None, callback));
+        try!(req.handle.send(&eloop.uv_loop(), host, service, hints.as_ref(),
+                             callback));
         ::block(eloop.uv_loop(), |task| {
             data.blocker = Some(task);
         });
@@ -61,24 +102,90 @@ pub fn get_host_addresses_on(eloop: &mut EventLoop,
     if data.status < 0 { return Err(UvError(data.status)) }
 
     let addrinfo = data.addrinfo.unwrap();
+    Ok(unsafe { entries(addrinfo.handle) })
+}
+
+/// Starts resolving `host` without blocking the calling task, returning a
+/// receiver for the addresses found.
+///
+/// This allows a task to start many lookups at once and handle the results as
+/// they arrive. An error starting the lookup is sent on the receiver as well.
+pub fn get_host_addresses_async(host: &str)
+                                -> Receiver<UvResult<Vec<IpAddr>>> {
+    match EventLoop::borrow() {
+        Ok(mut eloop) => get_host_addresses_async_on(&mut *eloop, host),
+        Err(e) => {
+            let (tx, rx) = channel();
+            tx.send(Err(e));
+            rx
+        }
+    }
+}
+
+/// Same as `get_host_addresses_async`, but specifies what event loop to run
+/// on.
+pub fn get_host_addresses_async_on(eloop: &mut EventLoop, host: &str)
+                                   -> Receiver<UvResult<Vec<IpAddr>>> {
+    let uv_loop = eloop.uv_loop();
+    let (ret, completer) = promise::new(eloop.make_handle(), uv_loop);
     unsafe {
-        let mut addr = addrinfo.handle;
-
-        let mut addrs = Vec::new();
-        loop {
-            let rustaddr = raw::sockaddr_to_addr(mem::transmute((*addr).ai_addr),
-                                                 (*addr).ai_addrlen as uint);
-
-            addrs.push(rustaddr.ip);
-            if (*addr).ai_next.is_not_null() {
-                addr = (*addr).ai_next as *const _;
-            } else {
-                break;
-            }
+        let mut req: raw::GetAddrInfo = Request::alloc();
+        match req.send(&uv_loop, Some(host), None, None, async_callback) {
+            Ok(()) => req.set_data(mem::transmute(box completer)),
+            Err(e) => { req.free(); completer.complete(Err(e)) }
         }
+    }
+    ret.into_receiver()
+}
+
+fn raw_hints(hint: Hint) -> libc::addrinfo {
+    let mut raw: libc::addrinfo = unsafe { mem::zeroed() };
+    raw.ai_family = hint.family as c_int;
+    raw.ai_socktype = hint.socktype.map_or(0, |t| match t {
+        SocketType::Stream => libc::SOCK_STREAM,
+        SocketType::Datagram => libc::SOCK_DGRAM,
+        SocketType::Raw => libc::SOCK_RAW,
+    });
+    raw.ai_protocol = hint.protocol.map_or(0, |p| match p {
+        Protocol::TCP => libc::IPPROTO_TCP,
+        Protocol::UDP => libc::IPPROTO_UDP,
+    });
+    raw.ai_flags = hint.flags as c_int;
+    raw
+}
 
-        Ok(addrs)
+unsafe fn entries(mut addr: *const libc::addrinfo) -> Vec<AddrInfoEntry> {
+    let mut entries = Vec::new();
+    while addr.is_not_null() {
+        let address = raw::sockaddr_to_addr(mem::transmute((*addr).ai_addr),
+                                            (*addr).ai_addrlen as uint);
+        let socktype = match (*addr).ai_socktype {
+            t if t == libc::SOCK_STREAM => Some(SocketType::Stream),
+            t if t == libc::SOCK_DGRAM => Some(SocketType::Datagram),
+            t if t == libc::SOCK_RAW => Some(SocketType::Raw),
+            _ => None,
+        };
+        let protocol = match (*addr).ai_protocol {
+            p if p == libc::IPPROTO_TCP => Some(Protocol::TCP),
+            p if p == libc::IPPROTO_UDP => Some(Protocol::UDP),
+            _ => None,
+        };
+        let canonical_name = if (*addr).ai_canonname.is_null() {
+            None
+        } else {
+            let name = CString::new((*addr).ai_canonname as *const _, false);
+            name.as_str().map(|s| s.to_string())
+        };
+        entries.push(AddrInfoEntry {
+            address: address,
+            family: (*addr).ai_family as uint,
+            socktype: socktype,
+            protocol: protocol,
+            canonical_name: canonical_name,
+        });
+        addr = (*addr).ai_next as *const _;
     }
+    entries
 }
 
 extern fn callback(req: *mut uvll::uv_getaddrinfo_t,
@@ -94,6 +201,28 @@ extern fn callback(req: *mut uvll::uv_getaddrinfo_t,
     ::wakeup(&mut data.blocker);
 }
 
+extern fn async_callback(req: *mut uvll::uv_getaddrinfo_t,
+                         status: libc::c_int,
+                         res: *const libc::addrinfo) {
+    assert!(status != uvll::ECANCELED);
+
+    unsafe {
+        let mut req: raw::GetAddrInfo = Request::from_raw(req);
+        let completer: Box<Completer<Vec<IpAddr>>> =
+            mem::transmute(req.get_data());
+        req.free();
+        let addrinfo = AddrInfo { handle: res };
+        let result = if status < 0 {
+            Err(UvError(status))
+        } else {
+            let entries = entries(addrinfo.handle);
+            Ok(entries.into_iter().map(|e| e.address.ip).collect())
+        };
+        drop(addrinfo);
+        (*completer).complete(result);
+    }
+}
+
 impl Drop for AddrInfo {
     fn drop(&mut self) {
         unsafe { uvll::uv_freeaddrinfo(self.handle as *mut _) }
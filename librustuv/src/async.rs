@@ -89,7 +89,8 @@ extern fn async_cb(handle: *mut uvll::uv_async_t) {
 
         let should_exit = *data.exit_flag.lock();
 
-        data.callback.call();
+        ::watchdog::time(handle.uv_loop(), "async callback",
+                         || data.callback.call());
 
         if should_exit {
             handle.close(Some(close_cb));
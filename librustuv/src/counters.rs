@@ -0,0 +1,98 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Byte and operation counters for TCP, pipe and UDP handles.
+//!
+//! Counting is off by default and is enabled per event loop with
+//! `EventLoop::set_io_counters`. Handles created while it's enabled count
+//! their own I/O as well as adding it to the totals of their event loop. The
+//! handles of an event loop without counters pay a single branch per
+//! operation.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, SeqCst};
+
+/// A snapshot of the I/O performed by a handle or an event loop.
+#[deriving(Clone, PartialEq, Eq, Show, Default)]
+pub struct IoCounters {
+    /// Number of bytes read, or received in datagrams.
+    pub bytes_read: u64,
+    /// Number of bytes written, or sent in datagrams.
+    pub bytes_written: u64,
+    /// Number of completed reads, or received datagrams.
+    pub reads: u64,
+    /// Number of writes, or sent datagrams.
+    pub writes: u64,
+}
+
+pub struct Counters {
+    bytes_read: AtomicUint,
+    bytes_written: AtomicUint,
+    reads: AtomicUint,
+    writes: AtomicUint,
+    // The totals of the event loop, if these are the counters of a handle
+    parent: Option<Arc<Counters>>,
+}
+
+impl Counters {
+    /// Creates the totals of an event loop.
+    pub fn new() -> Arc<Counters> {
+        Counters::with_parent(None)
+    }
+
+    /// Creates the counters of a new handle on the event loop whose totals
+    /// are `parent`.
+    pub fn child(parent: &Arc<Counters>) -> Arc<Counters> {
+        Counters::with_parent(Some(parent.clone()))
+    }
+
+    /// Creates the counters of a new handle on the same event loop as the
+    /// handle these counters belong to.
+    pub fn sibling(&self) -> Arc<Counters> {
+        Counters::with_parent(self.parent.clone())
+    }
+
+    fn with_parent(parent: Option<Arc<Counters>>) -> Arc<Counters> {
+        Arc::new(Counters {
+            bytes_read: AtomicUint::new(0),
+            bytes_written: AtomicUint::new(0),
+            reads: AtomicUint::new(0),
+            writes: AtomicUint::new(0),
+            parent: parent,
+        })
+    }
+
+    pub fn read(&self, bytes: uint) {
+        self.reads.fetch_add(1, SeqCst);
+        self.bytes_read.fetch_add(bytes, SeqCst);
+        match self.parent {
+            Some(ref p) => p.read(bytes),
+            None => {}
+        }
+    }
+
+    pub fn wrote(&self, bytes: uint) {
+        self.writes.fetch_add(1, SeqCst);
+        self.bytes_written.fetch_add(bytes, SeqCst);
+        match self.parent {
+            Some(ref p) => p.wrote(bytes),
+            None => {}
+        }
+    }
+
+    pub fn snapshot(&self) -> IoCounters {
+        IoCounters {
+            bytes_read: self.bytes_read.load(SeqCst) as u64,
+            bytes_written: self.bytes_written.load(SeqCst) as u64,
+            reads: self.reads.load(SeqCst) as u64,
+            writes: self.writes.load(SeqCst) as u64,
+        }
+    }
+}
@@ -8,21 +8,151 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::fmt;
+use std::io::IoResult;
 use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, SeqCst};
 use std::kinds::marker;
 use std::cell::Cell;
+use std::rt::local::Local;
+use std::rt::task::Task;
+use std::time::Duration;
 use green;
+use libc;
 
 use {uvll, UvResult, Idle, Async, UvError};
 use raw::{mod, Loop, Handle};
-use queue::QueuePool;
-use homing::HomeHandle;
+use queue::{Queue, QueuePool, QueueStats};
+use counters::{Counters, IoCounters};
+use metrics::{IdleTime, LoopStats};
+use homing::{HomeHandle, HomingRecord, Registry};
+use trace::{Tracer, Filter, Logger};
+use watchdog::{Watchdog, Hook};
+use wheel::TimerWheel;
 
 scoped_tls!(static LOCAL_LOOP: Cell<(*mut EventLoop, bool)>)
 
 pub struct EventLoop {
     uv_loop: Loop,
     pool: Option<Box<QueuePool>>,
+    diagnostics: Option<Registry>,
+    counters: Option<Arc<Counters>>,
+    tracer: Option<Tracer>,
+    // Tracing configuration set by the logger while it was running, which is
+    // applied once it returns
+    next_tracer: Option<Option<Tracer>>,
+    blocked: Box<Blocked>,
+    // Pointed to by the data of `uv_loop`, see `loop_data`
+    data: Box<LoopData>,
+    watchdog: Option<Watchdog>,
+    // Watchdog set or removed by the hook of the watchdog while it was
+    // reporting, which is installed once the hook returns
+    next_watchdog: Option<Option<Watchdog>>,
+    idle_time: Option<IdleTime>,
+    // Timer reused by `rustuv::sleep`, created the first time it's needed
+    sleep_timer: Option<raw::Timer>,
+    wheel: Box<TimerWheel>,
+    reqs: FreeRequests,
+    // Whether a libgreen scheduler runs on this loop, shared with the handles
+    // of the loop as they can't stop it then
+    scheduled: Arc<AtomicBool>,
+}
+
+/// State of an event loop which its callbacks reach through the data of the
+/// libuv loop, which is cheaper than looking up the local event loop.
+pub struct LoopData {
+    /// The number of tasks blocked on the loop.
+    pub blocked: uint,
+    /// Whether the loop has a watchdog.
+    pub watched: bool,
+}
+
+/// The I/O operations which tasks are currently blocked on, recorded while
+/// blocked diagnostics are enabled.
+struct Blocked {
+    enabled: bool,
+    // Slots of the recorded operations, indexed by `BlockedGuard::slot`
+    ops: Vec<Option<BlockedEntry>>,
+    // Indices of the empty slots in `ops`
+    free: Vec<uint>,
+}
+
+struct BlockedEntry {
+    handle: uint,
+    kind: &'static str,
+    op: &'static str,
+    // Borrowed from the blocked task, which outlives the entry as the entry is
+    // removed before the task resumes.
+    task: Option<&'static str>,
+    start: u64,
+}
+
+/// An I/O operation on which a task is currently blocked, as reported by
+/// `EventLoop::blocked_ops`.
+#[deriving(Clone)]
+pub struct BlockedOp {
+    /// Address of the libuv handle the operation was issued on.
+    pub handle: uint,
+    /// The kind of handle, such as "tcp", "pipe" or "udp".
+    pub kind: &'static str,
+    /// The libuv operation being waited on, such as "uv_read".
+    pub op: &'static str,
+    /// The name of the blocked task, if it has one.
+    pub task: Option<String>,
+    /// How long the task has been blocked for.
+    pub elapsed: Duration,
+}
+
+/// Requests which completed on an event loop, kept around to be reused by later
+/// operations so that steady state I/O doesn't allocate.
+pub struct FreeRequests {
+    write: Vec<raw::Write>,
+    connect: Vec<raw::Connect>,
+    shutdown: Vec<raw::Shutdown>,
+    udp_send: Vec<raw::UdpSend>,
+}
+
+/// The number of requests of each type an event loop keeps around for reuse.
+static MAX_FREE_REQUESTS: uint = 128;
+
+/// A request type which is reused through `FreeRequests`.
+pub trait Recycle {
+    fn free_list(reqs: &mut FreeRequests) -> &mut Vec<Self>;
+    unsafe fn alloc() -> Self;
+    unsafe fn free(&mut self);
+}
+
+macro_rules! recycle( ($t:ty, $field:ident) => (
+    impl Recycle for $t {
+        fn free_list(reqs: &mut FreeRequests) -> &mut Vec<$t> {
+            &mut reqs.$field
+        }
+        unsafe fn alloc() -> $t { raw::Request::alloc() }
+        unsafe fn free(&mut self) { raw::Request::free(self) }
+    }
+) )
+
+recycle!(raw::Write, write)
+recycle!(raw::Connect, connect)
+recycle!(raw::Shutdown, shutdown)
+recycle!(raw::UdpSend, udp_send)
+
+/// Keeps an operation listed as blocked until dropped.
+pub struct BlockedGuard {
+    eloop: *mut EventLoop,
+    slot: Option<uint>,
+}
+
+/// A handle to an event loop which can be sent to other threads, created with
+/// `EventLoop::handle`.
+///
+/// Like the handles of the I/O objects created on an event loop, this keeps
+/// the loop from exiting on its own for as long as it is alive.
+#[deriving(Clone)]
+pub struct EventLoopHandle {
+    queue: Queue,
+    scheduled: Arc<AtomicBool>,
 }
 
 pub struct BorrowedEventLoop {
@@ -32,23 +162,68 @@ pub struct BorrowedEventLoop {
 }
 
 impl EventLoop {
+    /// Creates a new event loop.
+    ///
+    /// `ENOSYS` is returned if the libuv linked in isn't of the version
+    /// returned by `bundled_uv_version`, which can only happen with the
+    /// `system-libuv` feature.
     pub fn new() -> UvResult<EventLoop> {
+        try!(::check_uv_version());
         let mut uv_loop = try!(unsafe { Loop::new() });
-        uv_loop.set_data(0 as *mut _);
+        let mut data = box LoopData { blocked: 0, watched: false };
+        uv_loop.set_data(&mut *data as *mut LoopData as *mut _);
         let pool = try!(QueuePool::new(&uv_loop));
+        let wheel = try!(TimerWheel::new(&uv_loop));
+
         Ok(EventLoop {
             pool: Some(pool),
             uv_loop: uv_loop,
+            diagnostics: None,
+            counters: None,
+            tracer: Tracer::from_env(),
+            next_tracer: None,
+            blocked: box Blocked {
+                enabled: false,
+                ops: Vec::new(),
+                free: Vec::new(),
+            },
+            data: data,
+            watchdog: None,
+            next_watchdog: None,
+            idle_time: None,
+            sleep_timer: None,
+            wheel: wheel,
+            reqs: FreeRequests {
+                write: Vec::new(),
+                connect: Vec::new(),
+                shutdown: Vec::new(),
+                udp_send: Vec::new(),
+            },
+            scheduled: Arc::new(AtomicBool::new(false)),
         })
     }
 
     /// Borrow a reference to the local event loop.
     ///
-    /// If there is no local event loop, or the local event loop is already
-    /// borrowed, then an error is returned.
+    /// If there is no local event loop then `UNKNOWN` is returned, and if the
+    /// local event loop is already borrowed then `EBUSY` is returned. The
+    /// latter usually means that a rustuv function was called while a borrow
+    /// was held, in which case the `_on` variant of the function should be
+    /// called with the borrowed loop instead.
     pub fn borrow() -> UvResult<BorrowedEventLoop> {
+        match try!(EventLoop::try_borrow()) {
+            Some(eloop) => Ok(eloop),
+            None => Err(UvError(uvll::EBUSY)),
+        }
+    }
+
+    /// Borrow a reference to the local event loop, returning `None` if it is
+    /// already borrowed.
+    ///
+    /// An error is returned if there is no local event loop.
+    pub fn try_borrow() -> UvResult<Option<BorrowedEventLoop>> {
         let local = LOCAL_LOOP.with(|local| {
-            local.and_then(|c| {
+            local.map(|c| {
                 match c.get() {
                     (_, true) => None,
                     (p, false) => { c.set((p, true)); Some(p) }
@@ -56,15 +231,22 @@ impl EventLoop {
             })
         });
         match local {
-            Some(eloop) => Ok(BorrowedEventLoop {
+            Some(eloop) => Ok(eloop.map(|eloop| BorrowedEventLoop {
                 local: eloop,
                 marker1: marker::NoSend,
                 marker2: marker::NoSync,
-            }),
+            })),
             None => Err(UvError(uvll::UNKNOWN))
         }
     }
 
+    /// Runs `f` with the local event loop borrowed, failing as `borrow` does
+    /// if it cannot be borrowed.
+    pub fn with_loop<T>(f: |&mut EventLoop| -> T) -> UvResult<T> {
+        let mut eloop = try!(EventLoop::borrow());
+        Ok(f(&mut *eloop))
+    }
+
     /// Borrow an unsafe pointer to the local event loop
     pub unsafe fn borrow_raw() -> UvResult<*mut EventLoop> {
         let local = LOCAL_LOOP.with(|local| local.map(|c| c.get().val0()));
@@ -89,10 +271,454 @@ impl EventLoop {
         // It's understood by the homing code that the "local id" is just the
         // pointer of the local I/O factory cast to a uint.
         let id: uint = self as *mut _ as uint;
-        HomeHandle::new(id, &mut **self.pool.as_mut().unwrap())
+        HomeHandle::new(id, &mut **self.pool.as_mut().unwrap(),
+                        self.diagnostics.as_ref(), self.counters.as_ref())
+    }
+
+    /// Create a handle through which this event loop can be stopped from other
+    /// threads.
+    pub fn handle(&mut self) -> EventLoopHandle {
+        EventLoopHandle {
+            queue: self.pool.as_mut().unwrap().queue(),
+            scheduled: self.scheduled.clone(),
+        }
+    }
+
+    /// Stops this event loop, making `run` return once the current turn of
+    /// the loop is done instead of waiting for all handles to be closed.
+    ///
+    /// Handles which are still open are left alone, and running the loop again
+    /// picks up where it left off. If the loop isn't running, the next call to
+    /// `run` returns after a single turn.
+    ///
+    /// The loop of a libgreen scheduler can't be stopped, as the scheduler
+    /// would exit with its tasks still in it. `EINVAL` is returned for such
+    /// a loop, which should be shut down through its pool instead.
+    pub fn stop(&mut self) -> UvResult<()> {
+        if self.scheduled.load(SeqCst) { return Err(UvError(uvll::EINVAL)) }
+        self.uv_loop.stop();
+        Ok(())
+    }
+
+    /// Enable or disable homing diagnostics for this event loop.
+    ///
+    /// When enabled, every handle subsequently created on this event loop
+    /// counts how many times it forced a task to migrate onto this loop. These
+    /// counts can be retrieved with `homing_report`. Disabling diagnostics
+    /// discards all counters collected so far.
+    pub fn set_homing_diagnostics(&mut self, enabled: bool) {
+        if !enabled {
+            self.diagnostics = None;
+        } else if self.diagnostics.is_none() {
+            self.diagnostics = Some(Registry::new());
+        }
+    }
+
+    /// Enable or disable recording the operations tasks are blocked on.
+    ///
+    /// When enabled, every read, write, shutdown and accept on a TCP or pipe
+    /// handle and every UDP send and receive started on this event loop is
+    /// recorded for as long as its task is blocked, to be listed by
+    /// `blocked_ops`. This is disabled by default.
+    pub fn set_blocked_diagnostics(&mut self, enabled: bool) {
+        self.blocked.enabled = enabled;
+    }
+
+    /// Enable or disable I/O counters for this event loop.
+    ///
+    /// When enabled, every TCP, pipe and UDP handle subsequently created on
+    /// this event loop counts the bytes and operations it reads and writes,
+    /// which can be retrieved with its `io_counters` method. The totals of all
+    /// of these handles, including those which have since been closed, are
+    /// returned by `io_counters`. Disabling counters resets the totals.
+    pub fn set_io_counters(&mut self, enabled: bool) {
+        if !enabled {
+            self.counters = None;
+        } else if self.counters.is_none() {
+            self.counters = Some(Counters::new());
+        }
+    }
+
+    /// Returns the I/O totals of this event loop, if I/O counters are
+    /// enabled.
+    pub fn io_counters(&self) -> Option<IoCounters> {
+        self.counters.as_ref().map(|c| c.snapshot())
+    }
+
+    /// Returns the counters of the queue through which other event loops send
+    /// tasks to this one.
+    pub fn queue_stats(&self) -> QueueStats {
+        self.pool.as_ref().unwrap().stats()
+    }
+
+    /// Enable or disable idle time metrics for this event loop.
+    ///
+    /// When enabled, the event loop measures how long it spends waiting for
+    /// events, which is reported by `stats`. This mirrors configuring a libuv
+    /// loop with `UV_METRICS_IDLE_TIME`. Disabling the metrics resets the
+    /// idle time.
+    pub fn set_idle_time_metrics(&mut self, enabled: bool) -> UvResult<()> {
+        if !enabled {
+            self.idle_time = None;
+        } else if self.idle_time.is_none() {
+            self.idle_time = Some(try!(IdleTime::new(&self.uv_loop)));
+        }
+        Ok(())
+    }
+
+    /// Returns the statistics of this event loop.
+    pub fn stats(&self) -> LoopStats {
+        LoopStats {
+            idle_time: self.idle_time.as_ref().map(|t| t.get()),
+            queue: self.queue_stats(),
+        }
+    }
+
+    /// Deliver the trace events of this event loop which pass `filter` to
+    /// `logger`, replacing any previous configuration.
+    ///
+    /// See the `trace` module for the events which are emitted. This overrides
+    /// the `RUSTUV_TRACE` environment variable.
+    ///
+    /// If this is called by the logger of this event loop, the new
+    /// configuration takes effect once the logger returns.
+    pub fn set_tracing(&mut self, filter: Filter, logger: Box<Logger + Send>) {
+        self.replace_tracer(Some(Tracer::new(filter, logger)));
+    }
+
+    /// Stop tracing this event loop.
+    ///
+    /// If this is called by the logger of this event loop, tracing stops once
+    /// the logger returns.
+    pub fn disable_tracing(&mut self) {
+        self.replace_tracer(None);
+    }
+
+    // The running logger is owned by the current tracer, so the tracer can't
+    // be replaced until the logger returns, see `finish_logging`.
+    fn replace_tracer(&mut self, tracer: Option<Tracer>) {
+        if self.tracer.as_ref().map_or(false, |t| t.is_logging()) {
+            self.next_tracer = Some(tracer);
+        } else {
+            self.tracer = tracer;
+        }
+    }
+
+    /// Report every callback and turn of this event loop taking longer than
+    /// `threshold` to `hook`, or to stderr if no hook is given, replacing any
+    /// previous watchdog.
+    ///
+    /// See the `watchdog` module for what is measured. If this is called by
+    /// the hook of the current watchdog, the new watchdog is installed once
+    /// the hook returns.
+    pub fn set_watchdog(&mut self, threshold: Duration,
+                        hook: Option<Box<Hook + Send>>) -> UvResult<()> {
+        let watchdog = try!(Watchdog::new(&self.uv_loop, threshold, hook));
+        self.replace_watchdog(Some(watchdog));
+        Ok(())
+    }
+
+    /// Remove the watchdog of this event loop.
+    ///
+    /// If this is called by the hook of the watchdog, it is removed once the
+    /// hook returns.
+    pub fn disable_watchdog(&mut self) {
+        self.replace_watchdog(None);
+    }
+
+    // The running hook is owned by the current watchdog, so the watchdog
+    // can't be replaced until the hook returns, see `finish_reporting`.
+    fn replace_watchdog(&mut self, watchdog: Option<Watchdog>) {
+        if self.watchdog.as_ref().map_or(false, |w| w.is_reporting()) {
+            self.next_watchdog = Some(watchdog);
+        } else {
+            self.data.watched = watchdog.is_some();
+            self.watchdog = watchdog;
+        }
+    }
+
+    /// Returns the number of tasks which are currently blocked on this event
+    /// loop, for any kind of operation.
+    pub fn blocked_tasks(&self) -> uint {
+        self.data.blocked
+    }
+
+    /// Writes a list of every libuv handle of this event loop to `w`, one per
+    /// line.
+    ///
+    /// Each line shows whether the handle is referenced (R) and active (A),
+    /// followed by its type and address. Handles which libuv uses internally
+    /// aren't listed. This is meant for finding out what keeps a stuck event
+    /// loop from exiting.
+    pub fn dump_handles(&self, w: &mut Writer) -> IoResult<()> {
+        dump(self.uv_loop, false, w)
+    }
+
+    /// Same as `dump_handles`, but only lists the active handles.
+    pub fn dump_active_handles(&self, w: &mut Writer) -> IoResult<()> {
+        dump(self.uv_loop, true, w)
+    }
+
+    /// Returns every I/O operation on a TCP, pipe or UDP handle which a task
+    /// of this event loop is currently blocked on.
+    ///
+    /// Only operations started while blocked diagnostics were enabled with
+    /// `set_blocked_diagnostics` are listed.
+    ///
+    /// Operations are sorted so those which have been blocked the longest come
+    /// first. This is meant for diagnosing servers which have stopped making
+    /// progress.
+    pub fn blocked_ops(&self) -> Vec<BlockedOp> {
+        let now = self.uv_loop.now();
+        let mut ret: Vec<BlockedOp> = self.blocked.ops.iter().filter_map(|e| {
+            e.as_ref().map(|e| BlockedOp {
+                handle: e.handle,
+                kind: e.kind,
+                op: e.op,
+                task: e.task.map(|name| name.to_string()),
+                elapsed: Duration::milliseconds((now - e.start) as i64),
+            })
+        }).collect();
+        ret.sort_by(|a, b| b.elapsed.cmp(&a.elapsed));
+        ret
+    }
+
+    /// Returns the homing statistics of all live handles created on this event
+    /// loop while diagnostics were enabled.
+    ///
+    /// Handles are sorted so those causing the most migrations come first.
+    pub fn homing_report(&self) -> Vec<HomingRecord> {
+        match self.diagnostics {
+            Some(ref r) => r.report(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Takes the timer of `eloop` used by `rustuv::sleep`, or creates a fresh one
+/// if it's already in use by another sleeping task.
+pub fn take_sleep_timer(eloop: &mut EventLoop) -> UvResult<raw::Timer> {
+    match eloop.sleep_timer.take() {
+        Some(timer) => Ok(timer),
+        None => unsafe { raw::Timer::new(&eloop.uv_loop) },
+    }
+}
+
+/// Gives back a timer taken with `take_sleep_timer`.
+pub fn return_sleep_timer(eloop: &mut EventLoop, mut timer: raw::Timer) {
+    if eloop.sleep_timer.is_none() {
+        eloop.sleep_timer = Some(timer);
+    } else {
+        unsafe { timer.close_and_free() }
     }
 }
 
+/// Allocates a request, reusing one freed on the local event loop if possible.
+///
+/// The returned request is uninitialized, just as with `Request::alloc`.
+pub unsafe fn alloc_req<R: Recycle>() -> R {
+    match EventLoop::borrow_raw() {
+        Ok(eloop) => {
+            let list: &mut Vec<R> = Recycle::free_list(&mut (*eloop).reqs);
+            match list.pop() {
+                Some(req) => return req,
+                None => {}
+            }
+        }
+        Err(..) => {}
+    }
+    Recycle::alloc()
+}
+
+/// Frees a request which libuv is done with, keeping it on the local event loop
+/// to be reused unless enough requests of its type are already kept there.
+pub unsafe fn free_req<R: Recycle>(mut req: R) {
+    match EventLoop::borrow_raw() {
+        Ok(eloop) => {
+            let list: &mut Vec<R> = Recycle::free_list(&mut (*eloop).reqs);
+            if list.len() < MAX_FREE_REQUESTS {
+                list.push(req);
+                return
+            }
+        }
+        Err(..) => {}
+    }
+    req.free()
+}
+
+/// Lists `op` on `handle` as blocked on the local event loop until the
+/// returned guard is dropped, if blocked diagnostics are enabled. The calling
+/// task must be on the home event loop of `handle`, and must not run again
+/// before the guard is dropped.
+pub fn block_on<T, H>(handle: &H, op: &'static str) -> BlockedGuard
+                      where T: raw::Allocated, H: Handle<T> {
+    let eloop = unsafe { EventLoop::borrow_raw().unwrap() };
+    let blocked = unsafe { &mut *(*eloop).blocked };
+    if !blocked.enabled {
+        return BlockedGuard { eloop: eloop, slot: None }
+    }
+
+    let kind = match handle.handle_type() {
+        uvll::UV_TCP => "tcp",
+        uvll::UV_NAMED_PIPE => "pipe",
+        uvll::UV_UDP => "udp",
+        uvll::UV_TTY => "tty",
+        _ => "handle",
+    };
+    let task = {
+        let task = Local::borrow(None::<Task>);
+        task.name.as_ref().map(|n| unsafe { mem::transmute(n.as_slice()) })
+    };
+    let entry = BlockedEntry {
+        handle: handle.raw() as uint,
+        kind: kind,
+        op: op,
+        task: task,
+        start: unsafe { (*eloop).uv_loop.now() },
+    };
+    let slot = match blocked.free.pop() {
+        Some(i) => { *blocked.ops.get_mut(i) = Some(entry); i }
+        None => { blocked.ops.push(Some(entry)); blocked.ops.len() - 1 }
+    };
+    BlockedGuard { eloop: eloop, slot: Some(slot) }
+}
+
+impl Drop for BlockedGuard {
+    fn drop(&mut self) {
+        match self.slot {
+            Some(i) => {
+                let blocked = unsafe { &mut *(*self.eloop).blocked };
+                *blocked.ops.get_mut(i) = None;
+                blocked.free.push(i);
+            }
+            None => {}
+        }
+    }
+}
+
+impl fmt::Show for BlockedOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.task {
+            Some(ref name) => try!(write!(f, "task `{}`", name)),
+            None => try!(write!(f, "<unnamed task>")),
+        }
+        write!(f, " blocked in {} on {} handle {:#x} for {}ms", self.op,
+               self.kind, self.handle, self.elapsed.num_milliseconds())
+    }
+}
+
+// The handles are all collected before anything is written, as `w` could
+// itself be doing I/O on this loop.
+fn dump(uv_loop: Loop, only_active: bool, w: &mut Writer) -> IoResult<()> {
+    extern fn walk_cb(handle: *mut uvll::uv_handle_t, arg: *mut libc::c_void) {
+        let handles: &mut Vec<*mut uvll::uv_handle_t> = unsafe {
+            mem::transmute(arg)
+        };
+        handles.push(handle);
+    }
+
+    let mut handles = Vec::new();
+    unsafe {
+        uvll::uv_walk(uv_loop.raw(), walk_cb,
+                      &mut handles as *mut _ as *mut libc::c_void);
+    }
+    for &handle in handles.iter() {
+        let (referenced, active, ty) = unsafe {
+            (uvll::uv_has_ref(handle as *const _) != 0,
+             uvll::uv_is_active(handle as *const _) != 0,
+             uvll::rust_uv_handle_type(handle))
+        };
+        if only_active && !active { continue }
+        try!(writeln!(w, "[{}{}] {} {:p}", if referenced {'R'} else {'-'},
+                      if active {'A'} else {'-'}, handle_type_name(ty),
+                      handle));
+    }
+    Ok(())
+}
+
+// The names libuv gives each type of handle.
+fn handle_type_name(ty: uvll::uv_handle_type) -> &'static str {
+    match ty {
+        uvll::UV_ASYNC => "async",
+        uvll::UV_CHECK => "check",
+        uvll::UV_FS_EVENT => "fs_event",
+        uvll::UV_FS_POLL => "fs_poll",
+        uvll::UV_HANDLE => "handle",
+        uvll::UV_IDLE => "idle",
+        uvll::UV_NAMED_PIPE => "named_pipe",
+        uvll::UV_POLL => "poll",
+        uvll::UV_PREPARE => "prepare",
+        uvll::UV_PROCESS => "process",
+        uvll::UV_STREAM => "stream",
+        uvll::UV_TCP => "tcp",
+        uvll::UV_TIMER => "timer",
+        uvll::UV_TTY => "tty",
+        uvll::UV_UDP => "udp",
+        uvll::UV_SIGNAL => "signal",
+        uvll::UV_UNKNOWN_HANDLE | uvll::UV_FILE |
+        uvll::UV_HANDLE_TYPE_MAX => "unknown",
+    }
+}
+
+/// Returns the tracer of the local event loop, if tracing is enabled.
+pub fn local_tracer() -> Option<&'static mut Tracer> {
+    match unsafe { EventLoop::borrow_raw() } {
+        Ok(eloop) => unsafe { (*eloop).tracer.as_mut() },
+        Err(..) => None,
+    }
+}
+
+/// Applies the tracing configuration set by the logger of the local event loop
+/// while it was running, if any.
+pub fn finish_logging() {
+    match unsafe { EventLoop::borrow_raw() } {
+        Ok(eloop) => unsafe {
+            match (*eloop).next_tracer.take() {
+                Some(tracer) => (*eloop).tracer = tracer,
+                None => {}
+            }
+        },
+        Err(..) => {}
+    }
+}
+
+/// Returns the timer wheel of `eloop`, which every timeout on it shares.
+pub fn wheel(eloop: &mut EventLoop) -> &mut TimerWheel {
+    &mut *eloop.wheel
+}
+
+/// Returns the timer wheel of the local event loop.
+pub fn local_wheel() -> &'static mut TimerWheel {
+    unsafe { wheel(&mut *EventLoop::borrow_raw().unwrap()) }
+}
+
+/// Returns the watchdog of the local event loop, if any.
+pub fn local_watchdog() -> Option<&'static mut Watchdog> {
+    match unsafe { EventLoop::borrow_raw() } {
+        Ok(eloop) => unsafe { (*eloop).watchdog.as_mut() },
+        Err(..) => None,
+    }
+}
+
+/// Installs the watchdog set or removed by the hook of the local event loop's
+/// watchdog while it was reporting, if any.
+pub fn finish_reporting() {
+    match unsafe { EventLoop::borrow_raw() } {
+        Ok(eloop) => unsafe {
+            match (*eloop).next_watchdog.take() {
+                Some(watchdog) => (*eloop).replace_watchdog(watchdog),
+                None => {}
+            }
+        },
+        Err(..) => {}
+    }
+}
+
+/// Returns the data of `uv_loop`, which must be the loop of an `EventLoop`.
+pub unsafe fn loop_data<'a>(mut uv_loop: Loop) -> &'a mut LoopData {
+    &mut *(uv_loop.get_data() as *mut LoopData)
+}
+
 impl green::EventLoop for EventLoop {
     fn run(&mut self) {
         let tls = Cell::new((self as *mut _, false));
@@ -114,14 +740,19 @@ impl green::EventLoop for EventLoop {
             unsafe {
                 let mut idle: raw::Idle = Handle::from_raw(handle);
                 let f: Box<proc()> = mem::transmute(idle.get_data());
+                let uv_loop = idle.uv_loop();
                 idle.close_and_free();
-                (*f)();
+                let mut f = Some(*f);
+                ::watchdog::time(uv_loop, "callback", || f.take().unwrap()());
             }
         }
     }
 
+    // Only a scheduler runs its tasks from a pausable idle callback, so this
+    // is where a loop finds out that it's owned by one.
     fn pausable_idle_callback(&mut self, cb: Box<green::Callback + Send>)
                               -> Box<green::PausableIdleCallback + Send> {
+        self.scheduled.store(true, SeqCst);
         box Idle::new_on(self, cb).unwrap()
             as Box<green::PausableIdleCallback + Send>
     }
@@ -132,7 +763,7 @@ impl green::EventLoop for EventLoop {
     }
 
     fn has_active_io(&self) -> bool {
-        unsafe { self.uv_loop().get_data() as uint > 0 }
+        self.data.blocked > 0
     }
 }
 
@@ -151,9 +782,25 @@ impl Drop for EventLoop {
         // shuts down cleanly.
         let mut handle = self.pool.as_ref().unwrap().handle();
         drop(self.pool.take());
+        self.watchdog = None;
+        self.next_watchdog = None;
+        self.idle_time = None;
+        unsafe {
+            self.wheel.close();
+            match self.sleep_timer.take() {
+                Some(mut timer) => timer.close_and_free(),
+                None => {}
+            }
+        }
         self.run();
 
+        // Closing callbacks may have freed requests, so the kept ones are only
+        // freed for good once the loop has been pumped.
         unsafe {
+            free_all(&mut self.reqs.write);
+            free_all(&mut self.reqs.connect);
+            free_all(&mut self.reqs.shutdown);
+            free_all(&mut self.reqs.udp_send);
             self.uv_loop.close().unwrap();
             handle.free();
             self.uv_loop.free();
@@ -161,6 +808,26 @@ impl Drop for EventLoop {
     }
 }
 
+unsafe fn free_all<R: Recycle>(reqs: &mut Vec<R>) {
+    for req in reqs.iter_mut() {
+        req.free();
+    }
+    reqs.clear();
+}
+
+impl EventLoopHandle {
+    /// Stops the event loop as with `EventLoop::stop`, which includes
+    /// returning `EINVAL` for the loop of a libgreen scheduler.
+    ///
+    /// The loop is woken up through its queue of remote messages, so it stops
+    /// shortly after this returns rather than immediately.
+    pub fn stop(&self) -> UvResult<()> {
+        if self.scheduled.load(SeqCst) { return Err(UvError(uvll::EINVAL)) }
+        self.queue.stop();
+        Ok(())
+    }
+}
+
 impl Deref<EventLoop> for BorrowedEventLoop {
     fn deref<'a>(&'a self) -> &'a EventLoop { unsafe { &*self.local } }
 }
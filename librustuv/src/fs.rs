@@ -11,10 +11,15 @@
 use std::c_str::{mod, CString};
 use std::io;
 use std::mem;
+use std::os;
+use std::ptr;
+use std::rand;
 use std::rt::task::BlockedTask;
 use libc;
+#[cfg(unix)] use std::os::unix::AsRawFd;
 
 use {uvll, raw, UvResult, EventLoop, UvError};
+use promise::{mod, Promise, Completer};
 use raw::Request;
 
 pub struct File {
@@ -27,50 +32,185 @@ struct Fs {
     fired: bool,
 }
 
-impl File {
-    pub fn open(path: &Path) -> UvResult<File> {
-        File::open_mode(path, io::Open, io::Read)
+/// Options and flags which can be used to configure how a file is opened.
+///
+/// A fresh set of options has every flag turned off, so at least one of
+/// `read`, `write` or `append` must be enabled before opening a file.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let file = OpenOptions::new().write(true).create(true).open(&path);
+/// ```
+#[deriving(Clone)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+    mode: io::FilePermission,
+}
+
+/// A file timestamp with nanosecond precision.
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show)]
+pub struct FileTime {
+    /// Seconds since the Unix epoch.
+    pub sec: i64,
+    /// Nanoseconds past `sec`, always less than one billion.
+    pub nsec: u32,
+}
+
+/// All timestamps of a file with full precision.
+///
+/// `io::FileStat` only has millisecond precision, which is not enough to tell
+/// whether two files were modified at exactly the same time.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct FileTimes {
+    pub accessed: FileTime,
+    pub modified: FileTime,
+    /// The last time the metadata of the file changed.
+    pub changed: FileTime,
+    /// The time the file was created, or the Unix epoch on platforms which
+    /// do not track it.
+    pub created: FileTime,
+}
+
+impl FileTime {
+    /// Creates a timestamp from milliseconds since the Unix epoch, the unit
+    /// used by `io::FileStat`.
+    pub fn from_msec(msec: u64) -> FileTime {
+        FileTime {
+            sec: (msec / 1000) as i64,
+            nsec: ((msec % 1000) * 1000000) as u32,
+        }
     }
 
-    pub fn create(path: &Path) -> UvResult<File> {
-        File::open_mode(path, io::Truncate, io::Write)
+    /// Returns this timestamp truncated to milliseconds since the Unix epoch.
+    pub fn to_msec(&self) -> u64 {
+        (self.sec as u64) * 1000 + (self.nsec as u64) / 1000000
+    }
+
+    fn from_uv(t: uvll::uv_timespec_t) -> FileTime {
+        FileTime { sec: t.tv_sec as i64, nsec: t.tv_nsec as u32 }
+    }
+}
+
+impl FileTimes {
+    fn from_uv(stat: uvll::uv_stat_t) -> FileTimes {
+        FileTimes {
+            accessed: FileTime::from_uv(stat.st_atim),
+            modified: FileTime::from_uv(stat.st_mtim),
+            changed: FileTime::from_uv(stat.st_ctim),
+            created: FileTime::from_uv(stat.st_birthtim),
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Creates a blank set of options. Files created with these options are
+    /// readable and writable by their owner.
+    pub fn new() -> OpenOptions {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            truncate: false,
+            create: false,
+            create_new: false,
+            mode: io::USER_READ | io::USER_WRITE,
+        }
     }
 
-    pub fn open_mode(path: &Path,
-                     mode: io::FileMode,
-                     access: io::FileAccess) -> UvResult<File> {
+    /// Opens the file for reading.
+    pub fn read(&mut self, read: bool) -> &mut OpenOptions {
+        self.read = read;
+        self
+    }
+
+    /// Opens the file for writing.
+    pub fn write(&mut self, write: bool) -> &mut OpenOptions {
+        self.write = write;
+        self
+    }
+
+    /// Opens the file for writing, with every write going to the end of the
+    /// file.
+    pub fn append(&mut self, append: bool) -> &mut OpenOptions {
+        self.append = append;
+        self
+    }
+
+    /// Truncates the file to zero length if it already exists.
+    pub fn truncate(&mut self, truncate: bool) -> &mut OpenOptions {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Creates the file if it doesn't already exist.
+    pub fn create(&mut self, create: bool) -> &mut OpenOptions {
+        self.create = create;
+        self
+    }
+
+    /// Creates the file, failing with `EEXIST` if it already exists.
+    pub fn create_new(&mut self, create_new: bool) -> &mut OpenOptions {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Sets the permissions a newly created file will have.
+    pub fn mode(&mut self, mode: io::FilePermission) -> &mut OpenOptions {
+        self.mode = mode;
+        self
+    }
+
+    /// Opens the file at `path` with the configured options.
+    pub fn open(&self, path: &Path) -> UvResult<File> {
         let mut eloop = try!(EventLoop::borrow());
-        File::open_mode_on(&mut *eloop, path, mode, access)
+        self.open_on(&mut *eloop, path)
     }
 
-    pub fn open_mode_on(eloop: &mut EventLoop,
-                        path: &Path,
-                        mode: io::FileMode,
-                        access: io::FileAccess) -> UvResult<File> {
-        let flags = match mode {
-            io::Open => 0,
-            io::Append => libc::O_APPEND,
-            io::Truncate => libc::O_TRUNC,
-        };
-        // Opening with a write permission must silently create the file.
-        let (flags, mode) = match access {
-            io::Read => (flags | libc::O_RDONLY, 0),
-            io::Write => (flags | libc::O_WRONLY | libc::O_CREAT,
-                          libc::S_IRUSR | libc::S_IWUSR),
-            io::ReadWrite => (flags | libc::O_RDWR | libc::O_CREAT,
-                              libc::S_IRUSR | libc::S_IWUSR),
+    pub fn open_on(&self, eloop: &mut EventLoop,
+                   path: &Path) -> UvResult<File> {
+        let writable = self.write || self.append;
+        let mut flags = match (self.read, writable) {
+            (true, false) => libc::O_RDONLY,
+            (false, true) => libc::O_WRONLY,
+            (true, true) => libc::O_RDWR,
+            (false, false) => return Err(UvError(uvll::EINVAL)),
         };
+        if self.append { flags |= libc::O_APPEND }
+        if self.truncate { flags |= libc::O_TRUNC }
+        if self.create_new {
+            flags |= libc::O_CREAT | libc::O_EXCL;
+        } else if self.create {
+            flags |= libc::O_CREAT;
+        }
 
         execute(|req, cb| unsafe {
-            req.open(eloop.uv_loop(), path.to_c_str(), flags,
-                     mode as libc::c_int, cb)
+            req.open(eloop.uv_loop(), uv_path(path), flags,
+                     self.mode.bits() as libc::c_int, cb)
         }).map(|req| {
-            File {
-                path: path.clone(),
-                fd: req.handle.result().unwrap() as libc::c_int,
-            }
+            let fd = req.handle.result().unwrap() as libc::c_int;
+            uvtrace!(Debug, Fs, "opened {} as fd {}", path.display(), fd);
+            File { path: path.clone(), fd: fd }
         })
     }
+}
+
+impl File {
+    /// Opens a file at `path` in read-only mode.
+    pub fn open(path: &Path) -> UvResult<File> {
+        OpenOptions::new().read(true).open(path)
+    }
+
+    /// Opens a file at `path` in write-only mode, creating it if it doesn't
+    /// exist and truncating it if it does.
+    pub fn create(path: &Path) -> UvResult<File> {
+        OpenOptions::new().write(true).create(true).truncate(true).open(path)
+    }
 
     /// Create a new `File` object for the specified descriptor.
     ///
@@ -81,6 +221,44 @@ impl File {
         File { path: path.clone(), fd: fd }
     }
 
+    /// Converts a file of the standard library into a `File`.
+    ///
+    /// The file descriptor is duplicated and `file` is closed. Fails with
+    /// EINVAL if the descriptor isn't a file.
+    #[cfg(unix)]
+    pub fn from_std(file: io::File) -> UvResult<File> {
+        let fd = try!(::dup_std(file.as_raw_fd(), ::HandleType::File));
+        Ok(unsafe { File::wrap(fd, file.path()) })
+    }
+
+    /// Releases ownership of the file descriptor of this file, for use outside
+    /// of libuv.
+    ///
+    /// The descriptor is no longer closed when this file goes out of scope,
+    /// instead it's up to the caller (or the process it's handed to) to close
+    /// it.
+    pub fn into_fd(self) -> libc::c_int {
+        let fd = self.fd;
+        unsafe {
+            drop(ptr::read(&self.path));
+            mem::forget(self);
+        }
+        fd
+    }
+
+    /// Creates a new `File` for the same open file, backed by a duplicate of
+    /// its descriptor.
+    ///
+    /// The new file is closed independently of this one. The two share the
+    /// current position though, so tasks using both at once should use
+    /// `read_at` and `write_at` with explicit offsets.
+    pub fn try_clone(&self) -> UvResult<File> {
+        match unsafe { libc::dup(self.fd) } {
+            -1 => Err(dup_error()),
+            fd => Ok(File { fd: fd, path: self.path.clone() }),
+        }
+    }
+
     pub fn path(&self) -> &Path { &self.path }
 
     pub fn fsync(&self) -> UvResult<()> {
@@ -111,6 +289,17 @@ impl File {
         }).map(|req| req.handle.io_stat())
     }
 
+    /// Same as `stat`, but also returns the timestamps of this file with full
+    /// precision.
+    pub fn stat_times(&self) -> UvResult<(io::FileStat, FileTimes)> {
+        let eloop = try!(EventLoop::borrow());
+        execute(|req, cb| unsafe {
+            req.fstat(eloop.uv_loop(), self.fd, cb)
+        }).map(|req| {
+            (req.handle.io_stat(), FileTimes::from_uv(req.handle.uv_stat()))
+        })
+    }
+
     /// Read some bytes at `pos`.
     ///
     /// If `pos` is -1, then the data will be read from the current position in
@@ -143,6 +332,56 @@ impl File {
         Ok(())
     }
 
+    /// Read data into each of `bufs` in turn, starting at position `pos`.
+    ///
+    /// This is done with a single request, and a single `preadv` where the
+    /// platform has one, instead of one request per buffer. As with `read_at`,
+    /// EOF is returned at the end of the file and a `pos` of -1 reads from the
+    /// current position.
+    pub fn read_at_vectored(&mut self, bufs: &mut [&mut [u8]],
+                            pos: i64) -> UvResult<uint> {
+        let eloop = try!(EventLoop::borrow());
+        execute(|req, cb| unsafe {
+            req.read_vectored(eloop.uv_loop(), self.fd, bufs, pos, cb)
+        }).and_then(|req| {
+            match req.handle.result().unwrap() as uint {
+                0 => Err(UvError(uvll::EOF)),
+                n => Ok(n),
+            }
+        })
+    }
+
+    /// Write the contents of each of `bufs` in turn, starting at position
+    /// `pos`.
+    ///
+    /// This is the vectored counterpart of `write_at`, writing all of the
+    /// buffers with a single request unless the write comes up short.
+    pub fn write_at_vectored(&mut self, bufs: &[&[u8]],
+                             pos: i64) -> UvResult<()> {
+        let eloop = try!(EventLoop::borrow());
+        let mut bufs: Vec<&[u8]> = bufs.iter().map(|b| *b)
+                                       .filter(|b| b.len() > 0).collect();
+        let mut amt = 0;
+        while bufs.len() > 0 {
+            let pos = if pos == -1 {pos} else {pos + amt as i64};
+            let mut n = try!(execute(|req, cb| unsafe {
+                req.write_vectored(eloop.uv_loop(), self.fd, bufs.as_slice(),
+                                   pos, cb)
+            }).map(|req| req.handle.result().unwrap() as uint));
+            amt += n;
+
+            // Skip over what was written, resuming partway into a buffer if
+            // the write came up short.
+            while n > 0 && n >= bufs[0].len() {
+                n -= bufs.remove(0).unwrap().len();
+            }
+            if n > 0 {
+                bufs[0] = bufs[0].slice_from(n);
+            }
+        }
+        Ok(())
+    }
+
     fn seek_common(&self, pos: i64, whence: libc::c_int) -> io::IoResult<u64> {
         match unsafe { libc::lseek(self.fd, pos as libc::off_t, whence) } {
             -1 => Err(io::IoError::last_error()),
@@ -160,13 +399,13 @@ impl File {
 
 impl Reader for File {
     fn read(&mut self, into: &mut [u8]) -> io::IoResult<uint> {
-        self.read_at(into, -1).map_err(|e| e.to_io_error())
+        Ok(try!(self.read_at(into, -1)))
     }
 }
 
 impl Writer for File {
     fn write(&mut self, buf: &[u8]) -> io::IoResult<()> {
-        self.write_at(buf, -1).map_err(|e| e.to_io_error())
+        Ok(try!(self.write_at(buf, -1)))
     }
 }
 
@@ -187,12 +426,27 @@ impl Seek for File {
 impl Drop for File {
     fn drop(&mut self) {
         let eloop = EventLoop::borrow().unwrap();
+        uvtrace!(Debug, Fs, "closing {} (fd {})", self.path.display(), self.fd);
         let _ = execute_nop(|req, cb| unsafe {
             req.close(eloop.uv_loop(), self.fd, cb)
         });
     }
 }
 
+#[cfg(unix)]
+fn dup_error() -> UvError { UvError(-(os::errno() as libc::c_int)) }
+
+// libuv hands out CRT descriptors on windows, so files are duplicated with
+// `_dup`, which only fails if the descriptor is invalid or none are left.
+#[cfg(windows)]
+fn dup_error() -> UvError {
+    if os::errno() as libc::c_int == libc::EMFILE {
+        UvError(uvll::EMFILE)
+    } else {
+        UvError(uvll::EBADF)
+    }
+}
+
 macro_rules! f(
     (
         pub fn $name_on:ident($eloop:ident: &mut EventLoop,
@@ -216,15 +470,65 @@ f!(pub fn change_file_times_on(eloop: &mut EventLoop,
     let atime = atime as libc::c_double / 1000.0;
     let mtime = mtime as libc::c_double / 1000.0;
     execute_nop(|req, cb| unsafe {
-        req.utime(eloop.uv_loop(), path.to_c_str(), atime, mtime, cb)
+        req.utime(eloop.uv_loop(), uv_path(path), atime, mtime, cb)
     })
 } as change_file_times)
 
+/// Same as `change_file_times`, but with timestamps of full precision.
+///
+/// libuv passes timestamps around as floating point seconds, which cannot
+/// represent nanoseconds exactly, so where possible this bypasses libuv and
+/// calls `utimensat` on a blocking thread instead.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub fn change_file_times_precise(path: &Path, atime: FileTime,
+                                 mtime: FileTime) -> UvResult<()> {
+    use green;
+
+    extern {
+        fn utimensat(dirfd: libc::c_int, path: *const libc::c_char,
+                     times: *const libc::timespec,
+                     flags: libc::c_int) -> libc::c_int;
+    }
+    static AT_FDCWD: libc::c_int = -100;
+
+    let path = uv_path(path);
+    let times = [
+        libc::timespec { tv_sec: atime.sec as libc::time_t,
+                         tv_nsec: atime.nsec as libc::c_long },
+        libc::timespec { tv_sec: mtime.sec as libc::time_t,
+                         tv_nsec: mtime.nsec as libc::c_long },
+    ];
+    green::run_blocking(proc() unsafe {
+        match utimensat(AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) {
+            0 => Ok(()),
+            _ => Err(UvError(-(os::errno() as libc::c_int))),
+        }
+    })
+}
+
+/// Same as `change_file_times`, but with timestamps of full precision.
+///
+/// On this platform the timestamps are passed through libuv as floating point
+/// seconds, so precision is limited to around a microsecond.
+#[cfg(not(any(target_os = "linux", target_os = "android",
+              target_os = "freebsd")))]
+pub fn change_file_times_precise(path: &Path, atime: FileTime,
+                                 mtime: FileTime) -> UvResult<()> {
+    fn secs(t: FileTime) -> libc::c_double {
+        t.sec as libc::c_double + t.nsec as libc::c_double / 1e9
+    }
+    let eloop = try!(EventLoop::borrow());
+    execute_nop(|req, cb| unsafe {
+        req.utime(eloop.uv_loop(), uv_path(path), secs(atime), secs(mtime),
+                  cb)
+    })
+}
+
 f!(pub fn chmod_on(eloop: &mut EventLoop,
                    path: &Path,
                    mode: io::FilePermission) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.chmod(eloop.uv_loop(), path.to_c_str(), mode.bits() as libc::c_int, cb)
+        req.chmod(eloop.uv_loop(), uv_path(path), mode.bits() as libc::c_int, cb)
     })
 } as chmod)
 
@@ -233,7 +537,7 @@ f!(pub fn chown_on(eloop: &mut EventLoop,
                    uid: int,
                    gid: int) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.chown(eloop.uv_loop(), path.to_c_str(),
+        req.chown(eloop.uv_loop(), uv_path(path),
                   uid as uvll::uv_uid_t, gid as uvll::uv_gid_t, cb)
     })
 } as chown)
@@ -242,7 +546,7 @@ f!(pub fn symlink_on(eloop: &mut EventLoop,
                      src: &Path,
                      dst: &Path) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.symlink(eloop.uv_loop(), src.to_c_str(), dst.to_c_str(), 0, cb)
+        req.symlink(eloop.uv_loop(), uv_path(src), uv_path(dst), 0, cb)
     })
 } as symlink)
 
@@ -250,7 +554,7 @@ f!(pub fn link_on(eloop: &mut EventLoop,
                   src: &Path,
                   dst: &Path) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.link(eloop.uv_loop(), src.to_c_str(), dst.to_c_str(), cb)
+        req.link(eloop.uv_loop(), uv_path(src), uv_path(dst), cb)
     })
 } as link)
 
@@ -258,7 +562,7 @@ f!(pub fn mkdir_on(eloop: &mut EventLoop,
                    dir: &Path,
                    perm: io::FilePermission) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.mkdir(eloop.uv_loop(), dir.to_c_str(),
+        req.mkdir(eloop.uv_loop(), uv_path(dir),
                   perm.bits() as libc::c_int, cb)
     })
 } as mkdir)
@@ -266,30 +570,75 @@ f!(pub fn mkdir_on(eloop: &mut EventLoop,
 f!(pub fn rmdir_on(eloop: &mut EventLoop,
                    dir: &Path) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.rmdir(eloop.uv_loop(), dir.to_c_str(), cb)
+        req.rmdir(eloop.uv_loop(), uv_path(dir), cb)
     })
 } as rmdir)
 
 f!(pub fn lstat_on(eloop: &mut EventLoop, path: &Path) -> UvResult<io::FileStat> {
     execute(|req, cb| unsafe {
-        req.lstat(eloop.uv_loop(), path.to_c_str(), cb)
+        req.lstat(eloop.uv_loop(), uv_path(path), cb)
     }).map(|req| req.handle.io_stat())
 } as lstat)
 
+f!(pub fn lstat_times_on(eloop: &mut EventLoop, path: &Path)
+                         -> UvResult<(io::FileStat, FileTimes)> {
+    execute(|req, cb| unsafe {
+        req.lstat(eloop.uv_loop(), uv_path(path), cb)
+    }).map(|req| {
+        (req.handle.io_stat(), FileTimes::from_uv(req.handle.uv_stat()))
+    })
+} as lstat_times)
+
 f!(pub fn stat_on(eloop: &mut EventLoop, path: &Path) -> UvResult<io::FileStat> {
     execute(|req, cb| unsafe {
-        req.stat(eloop.uv_loop(), path.to_c_str(), cb)
+        req.stat(eloop.uv_loop(), uv_path(path), cb)
     }).map(|req| req.handle.io_stat())
 } as stat)
 
+f!(pub fn stat_times_on(eloop: &mut EventLoop, path: &Path)
+                        -> UvResult<(io::FileStat, FileTimes)> {
+    execute(|req, cb| unsafe {
+        req.stat(eloop.uv_loop(), uv_path(path), cb)
+    }).map(|req| {
+        (req.handle.io_stat(), FileTimes::from_uv(req.handle.uv_stat()))
+    })
+} as stat_times)
+
+f!(pub fn stat_async_on(eloop: &mut EventLoop, path: &Path)
+                        -> UvResult<Promise<io::FileStat>> {
+    unsafe {
+        let uv_loop = eloop.uv_loop();
+        let mut req: raw::Fs = Request::alloc();
+        let (ret, completer) = promise::new(eloop.make_handle(), uv_loop);
+        match req.stat(uv_loop, uv_path(path), stat_async_cb) {
+            Ok(()) => {}
+            Err(e) => { req.free(); return Err(e) }
+        }
+        req.set_data(mem::transmute(box completer));
+        Ok(ret)
+    }
+} as stat_async)
+
+extern fn stat_async_cb(req: *mut uvll::uv_fs_t) {
+    unsafe {
+        let raw: raw::Fs = Request::from_raw(req);
+        let completer: Box<Completer<io::FileStat>> =
+            mem::transmute(raw.get_data());
+        let req = Fs { handle: raw, fired: true };
+        let result = req.handle.result().map(|_| req.handle.io_stat());
+        drop(req);
+        (*completer).complete(result);
+    }
+}
+
 f!(pub fn readlink_on(eloop: &mut EventLoop, path: &Path) -> UvResult<Path> {
     execute(|req, cb| unsafe {
-        req.readlink(eloop.uv_loop(), path.to_c_str(), cb)
+        req.readlink(eloop.uv_loop(), uv_path(path), cb)
     }).map(|req| {
         let result = unsafe {
             CString::new(req.handle.get_ptr() as *const libc::c_char, false)
         };
-        Path::new(result.as_bytes_no_nul())
+        from_uv_path(result.as_bytes_no_nul())
     })
 } as readlink)
 
@@ -297,32 +646,52 @@ f!(pub fn rename_on(eloop: &mut EventLoop,
                     src: &Path,
                     dst: &Path) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.rename(eloop.uv_loop(), src.to_c_str(), dst.to_c_str(), cb)
+        req.rename(eloop.uv_loop(), uv_path(src), uv_path(dst), cb)
     })
 } as rename)
 
 f!(pub fn unlink_on(eloop: &mut EventLoop,
                     path: &Path) -> UvResult<()> {
     execute_nop(|req, cb| unsafe {
-        req.unlink(eloop.uv_loop(), path.to_c_str(), cb)
+        req.unlink(eloop.uv_loop(), uv_path(path), cb)
     })
 } as unlink)
 
 f!(pub fn readdir_on(eloop: &mut EventLoop,
                      path: &Path) -> UvResult<Vec<Path>> {
     execute(|req, cb| unsafe {
-        req.readdir(eloop.uv_loop(), path.to_c_str(), 0, cb)
+        req.readdir(eloop.uv_loop(), uv_path(path), 0, cb)
     }).map(|req| unsafe {
         let mut paths = vec!();
         let size = req.handle.result().unwrap() as uint;
         let cstr = req.handle.get_ptr() as *const libc::c_char;
         let _ = c_str::from_c_multistring(cstr, Some(size), |rel| {
-            paths.push(path.join(rel.as_bytes_no_nul()));
+            paths.push(path.join(from_uv_path(rel.as_bytes_no_nul())));
         });
         paths
     })
 } as readdir)
 
+// libuv takes and returns UTF-8 paths on all platforms, converting them to and
+// from UTF-16 itself on windows. Paths are passed through untouched elsewhere,
+// as they needn't be unicode at all.
+#[cfg(unix)]
+fn uv_path(path: &Path) -> CString { path.to_c_str() }
+
+#[cfg(windows)]
+fn uv_path(path: &Path) -> CString {
+    // a windows path is always valid unicode
+    path.as_str().unwrap().to_c_str()
+}
+
+#[cfg(unix)]
+fn from_uv_path(path: &[u8]) -> Path { Path::new(path) }
+
+#[cfg(windows)]
+fn from_uv_path(path: &[u8]) -> Path {
+    Path::new(String::from_utf8_lossy(path).as_slice())
+}
+
 fn execute(f: |&mut raw::Fs, uvll::uv_fs_cb| -> UvResult<()>) -> UvResult<Fs> {
     unsafe {
         let mut raw = Fs { handle: Request::alloc(), fired: false };
@@ -330,10 +699,14 @@ fn execute(f: |&mut raw::Fs, uvll::uv_fs_cb| -> UvResult<()>) -> UvResult<Fs> {
         raw.fired = true;
         let mut slot = None;
         raw.handle.set_data(&mut slot as *mut _ as *mut _);
+        uvtrace!(Trace, Fs, "fs request {:p} started", raw.handle.raw());
         ::block(raw.handle.uv_loop(), |task| {
             slot = Some(task);
         });
-        return match raw.handle.result() {
+        let ret = raw.handle.result();
+        uvtrace!(Trace, Fs, "fs request {:p} finished: {}", raw.handle.raw(),
+                 ret);
+        return match ret {
             Ok(_) => Ok(raw),
             Err(e) => Err(e),
         }
@@ -462,3 +835,157 @@ pub fn copy(from: &Path, to: &Path) -> UvResult<()> {
 
     chmod(to, try!(stat(from)).perm)
 }
+
+/// Number of random names tried before giving up on creating a temporary file
+/// or directory.
+static NUM_RETRIES: uint = 1 << 16;
+
+fn temp_name(dir: &Path, prefix: &str) -> Path {
+    dir.join(format!("{}{:08x}", prefix, rand::random::<u32>()))
+}
+
+/// A directory which is recursively deleted when it goes out of scope.
+pub struct TempDir {
+    path: Option<Path>,
+}
+
+impl TempDir {
+    /// Creates a new uniquely named directory inside of `dir` whose name will
+    /// start with `prefix`. The directory is readable, writable and
+    /// searchable only by the owner.
+    pub fn new_in(dir: &Path, prefix: &str) -> UvResult<TempDir> {
+        let dir = if dir.is_relative() {
+            os::make_absolute(dir)
+        } else {
+            dir.clone()
+        };
+        for _ in range(0, NUM_RETRIES) {
+            let path = temp_name(&dir, prefix);
+            match mkdir(&path, io::USER_RWX) {
+                Ok(()) => return Ok(TempDir { path: Some(path) }),
+                Err(ref e) if e.code() == uvll::EEXIST => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(UvError(uvll::EEXIST))
+    }
+
+    /// Same as `new_in`, but creates the directory inside of `os::tmpdir()`.
+    pub fn new(prefix: &str) -> UvResult<TempDir> {
+        TempDir::new_in(&os::tmpdir(), prefix)
+    }
+
+    /// Returns the path of this temporary directory.
+    pub fn path(&self) -> &Path { self.path.as_ref().unwrap() }
+
+    /// Consumes this `TempDir` without deleting the directory, returning its
+    /// path.
+    pub fn into_inner(mut self) -> Path { self.path.take().unwrap() }
+
+    /// Deletes the directory and its contents now, returning any error which
+    /// occurs.
+    pub fn close(mut self) -> UvResult<()> { self.cleanup() }
+
+    fn cleanup(&mut self) -> UvResult<()> {
+        match self.path.take() {
+            Some(ref p) => rmdir_recursive(p),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = self.cleanup();
+    }
+}
+
+/// A file which is deleted when it goes out of scope.
+///
+/// The file is opened for both reading and writing, and it can be accessed
+/// through the `Reader`, `Writer` and `Seek` implementations or through the
+/// underlying `File` object.
+pub struct NamedTempFile {
+    file: Option<File>,
+}
+
+impl NamedTempFile {
+    /// Creates a new uniquely named file inside of `dir` whose name will start
+    /// with `prefix`. The file is readable and writable only by the owner.
+    pub fn new_in(dir: &Path, prefix: &str) -> UvResult<NamedTempFile> {
+        let mut eloop = try!(EventLoop::borrow());
+        let dir = if dir.is_relative() {
+            os::make_absolute(dir)
+        } else {
+            dir.clone()
+        };
+        let mut opts = OpenOptions::new();
+        opts.read(true).write(true).create_new(true);
+        for _ in range(0, NUM_RETRIES) {
+            let path = temp_name(&dir, prefix);
+            match opts.open_on(&mut *eloop, &path) {
+                Ok(file) => return Ok(NamedTempFile { file: Some(file) }),
+                Err(ref e) if e.code() == uvll::EEXIST => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Err(UvError(uvll::EEXIST))
+    }
+
+    /// Same as `new_in`, but creates the file inside of `os::tmpdir()`.
+    pub fn new(prefix: &str) -> UvResult<NamedTempFile> {
+        NamedTempFile::new_in(&os::tmpdir(), prefix)
+    }
+
+    /// Returns the path of this temporary file.
+    pub fn path(&self) -> &Path { self.file().path() }
+
+    /// Returns the underlying file object.
+    pub fn file(&self) -> &File { self.file.as_ref().unwrap() }
+
+    /// Returns the underlying file object mutably.
+    pub fn file_mut(&mut self) -> &mut File { self.file.as_mut().unwrap() }
+
+    /// Consumes this `NamedTempFile` without deleting the file, returning the
+    /// open file object.
+    pub fn into_inner(mut self) -> File { self.file.take().unwrap() }
+
+    /// Closes and deletes the file now, returning any error which occurs.
+    pub fn close(mut self) -> UvResult<()> { self.cleanup() }
+
+    fn cleanup(&mut self) -> UvResult<()> {
+        match self.file.take() {
+            Some(file) => {
+                let path = file.path().clone();
+                drop(file);
+                unlink(&path)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Reader for NamedTempFile {
+    fn read(&mut self, into: &mut [u8]) -> io::IoResult<uint> {
+        self.file_mut().read(into)
+    }
+}
+
+impl Writer for NamedTempFile {
+    fn write(&mut self, buf: &[u8]) -> io::IoResult<()> {
+        self.file_mut().write(buf)
+    }
+}
+
+impl Seek for NamedTempFile {
+    fn tell(&self) -> io::IoResult<u64> { self.file().tell() }
+    fn seek(&mut self, pos: i64, whence: io::SeekStyle) -> io::IoResult<()> {
+        self.file_mut().seek(pos, whence)
+    }
+}
+
+impl Drop for NamedTempFile {
+    fn drop(&mut self) {
+        let _ = self.cleanup();
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::c_str::CString;
+use std::cmp;
+use std::io;
+use std::mem;
+use std::rt::task::BlockedTask;
+use std::time::Duration;
+use libc;
+
+use {raw, uvll, fs, EventLoop, UvResult, UvError};
+use raw::Handle;
+use homing::{HomingIO, HomeHandle};
+
+/// Whether libuv is able to watch an entire directory tree with one handle on
+/// this platform. Elsewhere each subdirectory is watched individually.
+#[cfg(any(target_os = "macos", target_os = "ios", windows))]
+static NATIVE_RECURSIVE: bool = true;
+#[cfg(not(any(target_os = "macos", target_os = "ios", windows)))]
+static NATIVE_RECURSIVE: bool = false;
+
+/// An event delivered by a `FsWatcher`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct FsEvent {
+    /// The path which changed.
+    pub path: Path,
+    /// Whether the path was created, deleted or renamed.
+    pub rename: bool,
+    /// Whether the contents or metadata of the path changed.
+    pub change: bool,
+}
+
+/// A watcher for changes to a file or a directory.
+///
+/// Events are queued up on the event loop as they occur and are returned in
+/// batches by `recv`.
+pub struct FsWatcher {
+    handles: Vec<(Path, raw::FsEvent)>,
+    home: HomeHandle,
+    data: Box<Data>,
+    // Whether subdirectories are watched by hand on this platform
+    emulate: bool,
+}
+
+struct Data {
+    /// Events coalesced while a debounce window is open
+    pending: Vec<FsEvent>,
+    /// Events ready to be returned from `recv`
+    ready: Vec<FsEvent>,
+    /// First error reported by any of the handles
+    error: Option<UvError>,
+    /// Task blocked in `recv`
+    to_wake: Option<BlockedTask>,
+    /// Debounce window, in milliseconds
+    debounce: Option<u64>,
+    timer: raw::Timer,
+}
+
+struct Watch {
+    /// Directory against which reported file names are resolved
+    dir: Path,
+    data: *mut Data,
+}
+
+impl FsWatcher {
+    /// Starts watching `path` for changes.
+    ///
+    /// If `recursive` is true and `path` is a directory, changes anywhere in
+    /// the directory tree are reported. Platforms which cannot watch trees
+    /// natively have each subdirectory watched separately, including those
+    /// created after the watcher was started.
+    pub fn new(path: &Path, recursive: bool) -> UvResult<FsWatcher> {
+        FsWatcher::new_on(&mut *try!(EventLoop::borrow()), path, recursive)
+    }
+
+    pub fn new_on(eloop: &mut EventLoop, path: &Path,
+                  recursive: bool) -> UvResult<FsWatcher> {
+        let stat = try!(fs::stat_on(eloop, path));
+        let is_dir = stat.kind == io::TypeDirectory;
+        let data = box Data {
+            pending: Vec::new(),
+            ready: Vec::new(),
+            error: None,
+            to_wake: None,
+            debounce: None,
+            timer: unsafe { try!(raw::Timer::new(&eloop.uv_loop())) },
+        };
+        let mut ret = FsWatcher {
+            handles: Vec::new(),
+            home: eloop.make_handle(),
+            data: data,
+            emulate: recursive && is_dir && !NATIVE_RECURSIVE,
+        };
+        let data = &mut *ret.data as *mut Data;
+        ret.data.timer.set_data(data as *mut _);
+
+        if ret.emulate {
+            try!(ret.watch_tree(eloop, path));
+        } else if is_dir {
+            let flags = if recursive {uvll::FS_EVENT_RECURSIVE} else {0};
+            try!(ret.watch(eloop, path, path, flags));
+        } else {
+            try!(ret.watch(eloop, path, &path.dir_path(), 0));
+        }
+        Ok(ret)
+    }
+
+    /// Coalesces bursts of events.
+    ///
+    /// When set, events are held back until no new event has arrived for
+    /// `dur`, and multiple events for the same path are merged into one.
+    /// Passing `None` delivers events as soon as they arrive.
+    pub fn set_debounce(&mut self, dur: Option<Duration>) {
+        let _m = self.fire_homing_missile();
+        let data = &mut *self.data;
+        data.debounce = dur.map(|d| cmp::max(d.num_milliseconds(), 0) as u64);
+        if data.debounce.is_none() {
+            data.timer.stop().unwrap();
+            flush(data);
+        }
+    }
+
+    /// Blocks until events are available, returning all of them.
+    pub fn recv(&mut self) -> UvResult<Vec<FsEvent>> {
+        let _m = self.fire_homing_missile();
+        loop {
+            let data = &mut *self.data;
+            match data.error.take() {
+                Some(e) => return Err(e),
+                None => {}
+            }
+            if data.ready.len() > 0 { break }
+            assert!(data.to_wake.is_none());
+            let uv_loop = data.timer.uv_loop();
+            ::block(uv_loop, |task| {
+                data.to_wake = Some(task);
+            });
+        }
+        let events = mem::replace(&mut self.data.ready, Vec::new());
+        if self.emulate {
+            let mut eloop = try!(EventLoop::borrow());
+            for event in events.iter().filter(|e| e.rename) {
+                try!(self.update_tree(&mut *eloop, &event.path));
+            }
+        }
+        Ok(events)
+    }
+
+    /// Gain access to the underlying raw handles, paired with the path which
+    /// each is watching.
+    ///
+    /// This function is unsafe as there is no guarantee that any safe
+    /// modifications to the handles are actually safe to perform given the
+    /// assumptions of this object.
+    pub unsafe fn raw(&self) -> &[(Path, raw::FsEvent)] {
+        self.handles.as_slice()
+    }
+
+    fn watch(&mut self, eloop: &mut EventLoop, path: &Path, dir: &Path,
+             flags: libc::c_uint) -> UvResult<()> {
+        unsafe {
+            let mut handle = try!(raw::FsEvent::new(&eloop.uv_loop()));
+            let watch = box Watch {
+                dir: dir.clone(),
+                data: &mut *self.data as *mut Data,
+            };
+            handle.set_data(mem::transmute(watch));
+            match handle.start(path.to_c_str(), flags, fs_event_cb) {
+                Ok(()) => {
+                    self.handles.push((path.clone(), handle));
+                    Ok(())
+                }
+                Err(e) => { close(handle); Err(e) }
+            }
+        }
+    }
+
+    // Watches `dir` and every directory beneath it.
+    fn watch_tree(&mut self, eloop: &mut EventLoop,
+                  dir: &Path) -> UvResult<()> {
+        let mut stack = vec![dir.clone()];
+        loop {
+            let dir = match stack.pop() { Some(d) => d, None => break };
+            try!(self.watch(eloop, &dir, &dir, 0));
+            for child in try!(fs::readdir_on(eloop, &dir)).into_iter() {
+                match fs::lstat_on(eloop, &child) {
+                    Ok(ref s) if s.kind == io::TypeDirectory => {
+                        stack.push(child)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Starts or stops watching `path` after it was created, deleted or renamed
+    // when subdirectories are watched by hand.
+    fn update_tree(&mut self, eloop: &mut EventLoop,
+                   path: &Path) -> UvResult<()> {
+        let watched = self.handles.iter().any(|&(ref p, _)| p == path);
+        match fs::lstat_on(eloop, path) {
+            Ok(ref s) if s.kind == io::TypeDirectory && !watched => {
+                match self.watch_tree(eloop, path) {
+                    Err(ref e) if e.code() == uvll::ENOENT => Ok(()),
+                    other => other,
+                }
+            }
+            Err(ref e) if e.code() == uvll::ENOENT && watched => {
+                let (gone, kept) = mem::replace(&mut self.handles, Vec::new())
+                    .partition(|&(ref p, _)| path.is_ancestor_of(p));
+                self.handles = kept;
+                for (_, handle) in gone.into_iter() {
+                    unsafe { close(handle) }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+unsafe fn close(mut handle: raw::FsEvent) {
+    handle.close_and_free();
+    let _watch: Box<Watch> = mem::transmute(handle.get_data());
+}
+
+// Merges `event` into the events already queued for the same path.
+fn coalesce(events: &mut Vec<FsEvent>, event: FsEvent) {
+    match events.iter_mut().find(|e| e.path == event.path) {
+        Some(prev) => {
+            prev.rename |= event.rename;
+            prev.change |= event.change;
+            return
+        }
+        None => {}
+    }
+    events.push(event);
+}
+
+fn flush(data: &mut Data) {
+    let pending = mem::replace(&mut data.pending, Vec::new());
+    data.ready.extend(pending.into_iter());
+    if data.ready.len() > 0 && data.to_wake.is_some() {
+        ::wakeup(&mut data.to_wake);
+    }
+}
+
+extern fn fs_event_cb(handle: *mut uvll::uv_fs_event_t,
+                      filename: *const libc::c_char,
+                      events: libc::c_int,
+                      status: libc::c_int) {
+    unsafe {
+        let raw: raw::FsEvent = Handle::from_raw(handle);
+        let watch: &mut Watch = mem::transmute(raw.get_data());
+        let data: &mut Data = &mut *watch.data;
+        if status < 0 {
+            if data.error.is_none() {
+                data.error = Some(UvError(status));
+            }
+            if data.to_wake.is_some() {
+                ::wakeup(&mut data.to_wake);
+            }
+            return
+        }
+
+        let path = if filename.is_null() {
+            watch.dir.clone()
+        } else {
+            let name = CString::new(filename, false);
+            watch.dir.join(name.as_bytes_no_nul())
+        };
+        let event = FsEvent {
+            path: path,
+            rename: events & uvll::UV_RENAME != 0,
+            change: events & uvll::UV_CHANGE != 0,
+        };
+        match data.debounce {
+            Some(ms) => {
+                coalesce(&mut data.pending, event);
+                data.timer.start(ms, 0, timer_cb).unwrap();
+            }
+            None => {
+                data.ready.push(event);
+                flush(data);
+            }
+        }
+    }
+}
+
+extern fn timer_cb(timer: *mut uvll::uv_timer_t) {
+    unsafe {
+        let raw: raw::Timer = Handle::from_raw(timer);
+        let data: &mut Data = mem::transmute(raw.get_data());
+        flush(data);
+    }
+}
+
+impl HomingIO for FsWatcher {
+    fn home(&self) -> &HomeHandle { &self.home }
+}
+
+impl Drop for FsWatcher {
+    fn drop(&mut self) {
+        let _m = self.fire_homing_missile();
+        assert!(self.data.to_wake.is_none());
+        for (_, handle) in mem::replace(&mut self.handles,
+                                        Vec::new()).into_iter() {
+            unsafe { close(handle) }
+        }
+        unsafe { self.data.timer.close_and_free() }
+    }
+}
@@ -30,13 +30,23 @@
 //!
 //! This enqueueing is done with a concurrent queue from libstd, and the
 //! signalling is achieved with an async handle.
+//!
+//! Homing is cheap when a task stays on one event loop, but a handle which is
+//! used from tasks spread across a pool of schedulers can force a task to
+//! bounce between loops on every operation. To track these down, an event loop
+//! can optionally count the migrations caused by each of its handles (see
+//! `EventLoop::set_homing_diagnostics`).
 
 #![allow(dead_code)]
 
+use std::cmp;
 use std::rt::local::Local;
 use std::rt::task::{Task, BlockedTask};
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUint, SeqCst};
 
 use {ForbidUnwind, EventLoop};
+use counters::{Counters, IoCounters};
 use queue::{Queue, QueuePool};
 
 /// A handle to a remote libuv event loop. This handle will keep the event loop
@@ -45,15 +55,76 @@ use queue::{Queue, QueuePool};
 ///
 /// Handles are clone-able in order to derive new handles from existing handles
 /// (very useful for when accepting a socket from a server).
-#[deriving(Clone)]
 pub struct HomeHandle {
     queue: Queue,
     id: uint,
+    stats: Option<Arc<Stats>>,
+    counters: Option<Arc<Counters>>,
+}
+
+/// A summary of the homing operations performed on behalf of one handle.
+#[deriving(Clone, Show)]
+pub struct HomingRecord {
+    /// Identifier of the handle, unique within its event loop and assigned in
+    /// order of creation.
+    pub id: uint,
+    /// Number of times a task had to be moved onto the handle's event loop.
+    pub migrations: uint,
+}
+
+/// The set of homing counters for the handles of one event loop. This only
+/// exists when homing diagnostics have been enabled.
+#[deriving(Clone)]
+pub struct Registry {
+    inner: Arc<Mutex<RegistryInner>>,
+}
+
+struct RegistryInner {
+    next_id: uint,
+    stats: Vec<Weak<Stats>>,
+    // Length of `stats` at which the entries of dead handles are pruned
+    prune_at: uint,
+}
+
+static MIN_PRUNE: uint = 64;
+
+struct Stats {
+    id: uint,
+    migrations: AtomicUint,
+    registry: Registry,
 }
 
 impl HomeHandle {
-    pub fn new(id: uint, pool: &mut QueuePool) -> HomeHandle {
-        HomeHandle { queue: pool.queue(), id: id }
+    pub fn new(id: uint, pool: &mut QueuePool,
+               diagnostics: Option<&Registry>,
+               counters: Option<&Arc<Counters>>) -> HomeHandle {
+        HomeHandle {
+            queue: pool.queue(),
+            id: id,
+            stats: diagnostics.map(|r| r.register()),
+            counters: counters.map(|c| Counters::child(c)),
+        }
+    }
+
+    /// Returns whether this handle's home is the event loop of the current
+    /// task, meaning that its I/O doesn't need to migrate the task.
+    pub fn is_local(&self) -> bool { local_id() == self.id }
+
+    /// Returns the homing statistics for this handle, if diagnostics were
+    /// enabled on its event loop when it was created.
+    pub fn record(&self) -> Option<HomingRecord> {
+        self.stats.as_ref().map(|s| s.record())
+    }
+
+    /// Returns the I/O counters of this handle, if I/O counters were enabled
+    /// on its event loop when it was created.
+    pub fn io_counters(&self) -> Option<IoCounters> {
+        self.counters.as_ref().map(|c| c.snapshot())
+    }
+
+    /// Returns the counters to record the I/O of this handle in, if any.
+    pub fn counters(&self) -> Option<Arc<Counters>> {
+        self.counters.clone()
     }
 
     fn send(&self, task: BlockedTask) {
@@ -64,9 +135,6 @@ impl HomeHandle {
     /// that this function does *not* pin the task to the I/O scheduler, but
     /// rather it simply moves it to running on the I/O scheduler.
     fn go_home(&self) -> uint {
-        let _f = ForbidUnwind::new("going home");
-
-        let cur_loop_id = local_id();
         let destination = self.id;
 
         // Try at all costs to avoid the homing operation because it is quite
@@ -74,21 +142,100 @@ impl HomeHandle {
         // event loop. If we're already on the home event loop, then we're good
         // to go (remember we have no preemption, so we're guaranteed to stay on
         // this event loop as long as we avoid the scheduler).
-        if cur_loop_id != destination {
-            let cur_task: Box<Task> = Local::take();
-            cur_task.deschedule(1, |task| {
-                self.send(task);
-                Ok(())
-            });
-
-            // Once we wake up, assert that we're in the right location
-            assert_eq!(local_id(), destination);
+        //
+        // This check happens before anything touches the local task (even the
+        // unwinding guard below borrows it) so loop-affine workloads pay for
+        // nothing more than TLS reads here.
+        if local_id() == destination { return destination }
+
+        let _f = ForbidUnwind::new("going home");
+        uvtrace!(Info, Homing, "migrating task to event loop {:x}",
+                 destination);
+        match self.stats {
+            Some(ref stats) => { stats.migrations.fetch_add(1, SeqCst); }
+            None => {}
         }
+        let cur_task: Box<Task> = Local::take();
+        cur_task.deschedule(1, |task| {
+            self.send(task);
+            Ok(())
+        });
+
+        // Once we wake up, assert that we're in the right location
+        assert_eq!(local_id(), destination);
+        uvtrace!(Info, Homing, "task arrived on event loop {:x}", destination);
 
         return destination;
     }
 }
 
+impl Clone for HomeHandle {
+    fn clone(&self) -> HomeHandle {
+        // Derived handles are separate handles from the point of view of
+        // diagnostics, so they get a counter of their own.
+        HomeHandle {
+            queue: self.queue.clone(),
+            id: self.id,
+            stats: self.stats.as_ref().map(|s| s.registry.register()),
+            counters: self.counters.as_ref().map(|c| c.sibling()),
+        }
+    }
+}
+
+impl HomingIO for HomeHandle {
+    fn home(&self) -> &HomeHandle { self }
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry {
+            inner: Arc::new(Mutex::new(RegistryInner {
+                next_id: 0,
+                stats: Vec::new(),
+                prune_at: MIN_PRUNE,
+            })),
+        }
+    }
+
+    fn register(&self) -> Arc<Stats> {
+        let mut inner = self.inner.lock();
+        let stats = Arc::new(Stats {
+            id: inner.next_id,
+            migrations: AtomicUint::new(0),
+            registry: self.clone(),
+        });
+        inner.next_id += 1;
+
+        // Handles come and go without the registry hearing about it, so dead
+        // entries are pruned whenever the number of entries has doubled since
+        // the last time, which keeps registration amortized constant time.
+        if inner.stats.len() >= inner.prune_at {
+            inner.stats.retain(|s| s.upgrade().is_some());
+            inner.prune_at = cmp::max(inner.stats.len() * 2, MIN_PRUNE);
+        }
+        inner.stats.push(stats.downgrade());
+        stats
+    }
+
+    /// Generates a report of all live handles, sorted with the handles which
+    /// have caused the most migrations first.
+    pub fn report(&self) -> Vec<HomingRecord> {
+        let mut inner = self.inner.lock();
+        inner.stats.retain(|s| s.upgrade().is_some());
+        let mut ret: Vec<HomingRecord> = inner.stats.iter().filter_map(|s| {
+            s.upgrade().map(|s| s.record())
+        }).collect();
+        ret.sort_by(|a, b| b.migrations.cmp(&a.migrations));
+        ret
+    }
+}
+
+impl Stats {
+    fn record(&self) -> HomingRecord {
+        HomingRecord { id: self.id, migrations: self.migrations.load(SeqCst) }
+    }
+}
+
 pub fn local_id() -> uint {
     unsafe { EventLoop::borrow_raw().unwrap() as uint }
 }
@@ -122,14 +269,30 @@ impl HomingMissile {
 
 impl Drop for HomingMissile {
     fn drop(&mut self) {
-        let _f = ForbidUnwind::new("leaving home");
-
         // It would truly be a sad day if we had moved off the home I/O
-        // scheduler while we were doing I/O.
+        // scheduler while we were doing I/O. Note that no unwinding guard is
+        // created here as the check never deschedules, and creating one would
+        // borrow the local task on every single I/O operation.
         self.check("task moved away from the home scheduler");
     }
 }
 
+#[cfg(test)]
+mod registry_test {
+    use super::{Registry, MIN_PRUNE};
+
+    #[test]
+    fn prunes_dead_handles() {
+        let registry = Registry::new();
+        let live: Vec<_> = range(0u, 10).map(|_| registry.register()).collect();
+        for _ in range(0u, 1000) {
+            drop(registry.register());
+        }
+        assert!(registry.inner.lock().stats.len() <= 2 * MIN_PRUNE);
+        assert_eq!(registry.report().len(), live.len());
+    }
+}
+
 // #[cfg(test)]
 // mod test {
 //     use green::{SchedPool, PoolConfig, GreenTaskBuilder};
@@ -33,6 +33,12 @@ impl Idle {
         }
     }
 
+    /// Sets whether this callback keeps the event loop alive while it's
+    /// resumed, which it does by default. See `Timer::keep_alive`.
+    pub fn keep_alive(&mut self, keep: bool) {
+        if keep { self.handle.uv_ref() } else { self.handle.uv_unref() }
+    }
+
     /// Gain access to the underlying raw idle handle.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -50,7 +56,8 @@ extern fn idle_cb(handle: *mut uvll::uv_idle_t) {
     unsafe {
         let raw: raw::Idle = Handle::from_raw(handle);
         let data: &mut Data = mem::transmute(raw.get_data());
-        data.callback.call();
+        ::watchdog::time(raw.uv_loop(), "idle callback",
+                         || data.callback.call());
     }
 }
 
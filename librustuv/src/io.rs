@@ -0,0 +1,124 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Utilities for working with streams generically.
+
+use std::time::Duration;
+use green;
+
+use {uvll, UvResult, Tcp, Pipe, Tty};
+
+/// Size of the buffer used to move data in each direction of a proxy.
+static BUF_SIZE: uint = 64 * 1024;
+
+/// Operations common to all duplex libuv streams: TCP sockets, pipes and
+/// TTYs.
+///
+/// This allows protocol code to be written once and used over any kind of
+/// stream. Each method behaves like the inherent method of the same name on
+/// the stream types.
+pub trait UvStream {
+    /// Reads some bytes into `buf`, returning how many were read. EOF is
+    /// reported as an error with the `uvll::EOF` code.
+    fn uv_read(&mut self, buf: &mut [u8]) -> UvResult<uint>;
+
+    /// Writes all of `buf` to the stream.
+    fn uv_write(&mut self, buf: &[u8]) -> UvResult<()>;
+
+    /// Sets a timeout for reads on this stream, or clears it with `None`.
+    fn set_read_timeout(&mut self, dur: Option<Duration>);
+
+    /// Closes the reading half of this stream, causing all pending and future
+    /// reads to return EOF.
+    fn close_read(&mut self) -> UvResult<()>;
+
+    /// Shuts down the writing half of this stream once all pending writes have
+    /// completed.
+    fn close_write(&mut self) -> UvResult<()>;
+}
+
+/// Splices two streams together, copying all bytes read from one into the
+/// other until both directions have been closed.
+///
+/// Data from `a` to `b` is pumped in a new green task on the current
+/// scheduler while the calling task pumps data from `b` to `a`. When EOF is
+/// reached on one stream the write half of the other stream is shut down, so
+/// half-closed connections are propagated through the proxy.
+///
+/// This function returns once both directions are done. If either direction
+/// hits an error, the other direction is torn down as well and the first error
+/// is returned.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut acceptor = TcpListener::bind(addr).unwrap().listen().unwrap();
+/// for client in acceptor.incoming() {
+///     green::task::spawn(proc() {
+///         let server = Tcp::connect(upstream).unwrap();
+///         let _ = rustuv::io::proxy(client.unwrap(), server);
+///     });
+/// }
+/// ```
+pub fn proxy<A, B>(a: A, b: B) -> UvResult<()>
+                   where A: UvStream + Clone + Send,
+                         B: UvStream + Clone + Send {
+    let (tx, rx) = channel();
+    let (a2, b2) = (a.clone(), b.clone());
+    green::task::spawn(proc() {
+        tx.send(pump(a2, b2));
+    });
+    let ret = pump(b, a);
+    let other = rx.recv();
+    ret.and(other)
+}
+
+fn pump<R: UvStream, W: UvStream>(mut from: R, mut to: W) -> UvResult<()> {
+    let ret = copy(&mut from, &mut to);
+
+    // The other direction of the proxy is reading from `to`, so closing its
+    // read half wakes that task up with an EOF and lets it finish as well.
+    if ret.is_err() {
+        let _ = to.close_read();
+    }
+    ret
+}
+
+fn copy<R: UvStream, W: UvStream>(from: &mut R, to: &mut W) -> UvResult<()> {
+    let mut buf = Vec::from_elem(BUF_SIZE, 0u8);
+    loop {
+        let n = match from.uv_read(buf.as_mut_slice()) {
+            Ok(n) => n,
+            Err(ref e) if e.code() == uvll::EOF => return to.close_write(),
+            Err(e) => return Err(e),
+        };
+        try!(to.uv_write(buf.slice_to(n)));
+    }
+}
+
+macro_rules! uv_stream( ($t:ty) => (
+    impl UvStream for $t {
+        fn uv_read(&mut self, buf: &mut [u8]) -> UvResult<uint> {
+            self.uv_read(buf)
+        }
+        fn uv_write(&mut self, buf: &[u8]) -> UvResult<()> {
+            self.uv_write(buf)
+        }
+        fn set_read_timeout(&mut self, dur: Option<Duration>) {
+            self.set_read_timeout(dur)
+        }
+        fn close_read(&mut self) -> UvResult<()> { self.close_read() }
+        fn close_write(&mut self) -> UvResult<()> { self.close_write() }
+    }
+) )
+
+uv_stream!(Tcp)
+uv_stream!(Pipe)
+uv_stream!(Tty)
@@ -45,29 +45,43 @@ extern crate green;
 extern crate libc;
 #[phase(plugin, link)] extern crate tls;
 
+use std::error::FromError;
 use std::fmt;
-use std::io;
+use std::io::IoError;
 use std::rt::local::Local;
 use std::rt::task::{BlockedTask, Task};
 use std::str;
 use std::task;
 use libc::c_int;
 
-pub use addrinfo::get_host_addresses;
+pub use addrinfo::{get_host_addresses, get_host_addresses_async, lookup};
+pub use addrinfo::AddrInfoEntry;
 pub use async::Async;
-pub use event_loop::EventLoop;
+pub use counters::IoCounters;
+pub use event_loop::{EventLoop, EventLoopHandle, BlockedOp};
 pub use fs::File;
+pub use fs_event::{FsWatcher, FsEvent};
 pub use idle::Idle;
+pub use io::UvStream;
+pub use metrics::LoopStats;
 pub use pipe::{Pipe, PipeListener, PipeAcceptor};
+pub use process::{Command, Process, Output, Stdio};
+pub use promise::Promise;
+pub use queue::QueueStats;
+#[cfg(unix)] pub use selector::Selector;
+pub use semaphore::{Semaphore, SemaphoreGuard};
 pub use signal::Signal;
-pub use tcp::{Tcp, TcpListener, TcpAcceptor};
-pub use timer::Timer;
+pub use tcp::{Tcp, TcpListener, TcpAcceptor, CloseMode};
+pub use timeout::with_timeout;
+pub use timer::{Timer, sleep};
 pub use tty::Tty;
 pub use udp::Udp;
 
 mod macros;
 
 mod access;
+mod counters;
+mod metrics;
 mod timeout;
 pub mod homing;
 mod queue;
@@ -80,40 +94,166 @@ mod event_loop;
 mod addrinfo;
 mod async;
 pub mod fs;
+mod fs_event;
 mod idle;
+pub mod io;
+pub mod net;
 mod pipe;
-// mod process;
+mod process;
+pub mod promise;
+#[cfg(unix)] pub mod selector;
+mod semaphore;
+pub mod shutdown;
 mod signal;
 mod stream;
 mod tcp;
 mod timer;
+pub mod trace;
+pub mod util;
+pub mod watchdog;
+mod wheel;
 mod tty;
 mod udp;
 
-// /// Creates a new event loop which is powered by libuv
-// ///
-// /// This function is used in tandem with libgreen's `PoolConfig` type as a value
-// /// for the `event_loop_factory` field. Using this function as the event loop
-// /// factory will power programs with libuv and enable green threading.
-// ///
-// /// # Example
-// ///
-// /// ```
-// /// extern crate rustuv;
-// /// extern crate green;
-// ///
-// /// #[start]
-// /// fn start(argc: int, argv: *const *const u8) -> int {
-// ///     green::start(argc, argv, rustuv::event_loop, main)
-// /// }
-// ///
-// /// fn main() {
-// ///     // this code is running inside of a green task powered by libuv
-// /// }
-// /// ```
-// pub fn event_loop() -> Box<green::EventLoop + Send> {
-//     box uvio::UvEventLoop::new() as Box<green::EventLoop + Send>
-// }
+pub mod testing;
+
+/// Creates a new event loop which is powered by libuv
+///
+/// This function is used in tandem with libgreen's `PoolConfig` type as a value
+/// for the `event_loop_factory` field. Using this function as the event loop
+/// factory will power programs with libuv and enable green threading.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// extern crate rustuv;
+/// extern crate green;
+///
+/// #[start]
+/// fn start(argc: int, argv: *const *const u8) -> int {
+///     green::start(argc, argv, rustuv::event_loop, main)
+/// }
+///
+/// fn main() {
+///     // this code is running inside of a green task powered by libuv
+/// }
+/// ```
+pub fn event_loop() -> Box<green::EventLoop + Send> {
+    box EventLoop::new().unwrap() as Box<green::EventLoop + Send>
+}
+
+/// Returns a libgreen `Builder` whose schedulers are powered by libuv.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// extern crate rustuv;
+///
+/// #[start]
+/// fn start(argc: int, argv: *const *const u8) -> int {
+///     rustuv::builder().start(argc, argv, main)
+/// }
+///
+/// fn main() {
+///     // this code is running inside of a green task powered by libuv
+/// }
+/// ```
+pub fn builder() -> green::Builder {
+    green::Builder::new().event_loop_factory(event_loop)
+}
+
+/// Returns the current wall-clock time as seconds and microseconds since the
+/// Unix epoch.
+///
+/// Unlike the event loop's cached notion of "now", this queries the system
+/// clock on every call and is not monotonic. It does not require an event
+/// loop, so it may be called from any task.
+pub fn wall_clock() -> (i64, u32) {
+    let mut sec = 0;
+    let mut usec = 0;
+    match unsafe { uvll::rust_uv_gettimeofday(&mut sec, &mut usec) } {
+        0 => (sec, usec as u32),
+        n => panic!("failed to read the wall clock: {}", UvError(n)),
+    }
+}
+
+/// The kind of object behind a file descriptor, as detected by
+/// `guess_handle`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum HandleType {
+    /// A TCP socket, which can be opened with `Tcp::open`.
+    Tcp,
+    /// A UDP socket, which can be opened with `Udp::open`.
+    Udp,
+    /// A named pipe or unix domain socket, which can be opened with
+    /// `Pipe::open`.
+    Pipe,
+    /// A terminal, which can be opened with `Tty::new`.
+    Tty,
+    /// A regular file, which can be wrapped with `fs::File::wrap`.
+    File,
+    /// Anything else, or an invalid descriptor.
+    Unknown,
+}
+
+/// Detects what kind of object `fd` refers to.
+///
+/// This is useful to decide how to wrap descriptors inherited from a parent
+/// process, such as the standard streams.
+pub fn guess_handle(fd: c_int) -> HandleType {
+    match raw::Tty::guess_handle(fd) {
+        uvll::UV_TCP => HandleType::Tcp,
+        uvll::UV_UDP => HandleType::Udp,
+        uvll::UV_NAMED_PIPE => HandleType::Pipe,
+        uvll::UV_TTY => HandleType::Tty,
+        uvll::UV_FILE => HandleType::File,
+        _ => HandleType::Unknown,
+    }
+}
+
+/// Returns whether `fd` refers to a terminal.
+///
+/// This is a cheap way to decide whether to print colors or progress bars,
+/// without creating a `Tty` for the descriptor.
+pub fn isatty(fd: c_int) -> bool {
+    guess_handle(fd) == HandleType::Tty
+}
+
+/// Returns the version of the libuv linked in, as its major, minor and patch
+/// numbers.
+///
+/// This is usually the copy of libuv bundled with this crate, unless the
+/// `system-libuv` feature is enabled.
+pub fn uv_version() -> (uint, uint, uint) {
+    let v = unsafe { uvll::uv_version() } as uint;
+    ((v >> 16) & 0xff, (v >> 8) & 0xff, v & 0xff)
+}
+
+/// Returns the major and minor version of the libuv bundled with this crate,
+/// which the bindings in `uvll` are written against.
+///
+/// With the `system-libuv` feature, the libuv of the system must be of this
+/// same version. The bindings still use `uv_fs_readdir`, which became
+/// `uv_fs_scandir` in libuv 1.0, so no released 1.x version of libuv works;
+/// the system libuv has to be built from the same snapshot as the bundled one.
+pub fn bundled_uv_version() -> (uint, uint) {
+    let v = unsafe { uvll::rust_uv_bundled_version() } as uint;
+    ((v >> 16) & 0xff, (v >> 8) & 0xff)
+}
+
+// A libuv of another version than the bundled one may have a different ABI,
+// which would only show up as memory corruption later on.
+fn check_uv_version() -> UvResult<()> {
+    let (major, minor, patch) = uv_version();
+    let (bundled_major, bundled_minor) = bundled_uv_version();
+    if (major, minor) != (bundled_major, bundled_minor) {
+        uvtrace!(Error, Loop, "rustuv requires libuv {}.{}.x, but libuv \
+                               {}.{}.{} is linked in", bundled_major,
+                 bundled_minor, major, minor, patch);
+        return Err(UvError(uvll::ENOSYS))
+    }
+    Ok(())
+}
 
 struct ForbidUnwind {
     msg: &'static str,
@@ -135,23 +275,83 @@ impl Drop for ForbidUnwind {
     }
 }
 
-fn block(mut uv_loop: raw::Loop, f: |BlockedTask|) {
+fn block(uv_loop: raw::Loop, f: |BlockedTask|) {
     let _f = ForbidUnwind::new("wait_until_woken_after");
     let task: Box<Task> = Local::take();
-    let cnt = uv_loop.get_data() as uint;
-    uv_loop.set_data((cnt + 1) as *mut _);
+    let data = unsafe { event_loop::loop_data(uv_loop) };
+    let cnt = data.blocked;
+    data.blocked = cnt + 1;
+    uvtrace!(Trace, Loop, "blocking task, {} active", cnt + 1);
     task.deschedule(1, |task| {
         f(task);
         Ok(())
     });
-    uv_loop.set_data(cnt as *mut _);
+    data.blocked = cnt;
+    uvtrace!(Trace, Loop, "task resumed, {} active", cnt);
 }
 
 fn wakeup(slot: &mut Option<BlockedTask>) {
     assert!(slot.is_some());
+    uvtrace!(Trace, Loop, "waking task");
     slot.take().unwrap().reawaken();
 }
 
+// Duplicates the file descriptor backing `handle` so it can be reopened on
+// another event loop after the original handle has been closed.
+#[cfg(unix)]
+fn dup_fileno<T, H>(handle: &H) -> UvResult<c_int>
+                    where T: raw::Allocated, H: raw::Handle<T> {
+    let fd = try!(handle.fileno());
+    match unsafe { libc::dup(fd) } {
+        -1 => Err(UvError(-(std::os::errno() as c_int))),
+        n => Ok(n),
+    }
+}
+
+// Accepts a connection pending on the listening `handle` without going through
+// libuv, returning `None` if there is none. libuv only accepts connections
+// while polling, and on kqueue platforms a connection made while an acceptor
+// isn't blocked may not be picked up before the acceptor gives up on it. The
+// returned socket is non-blocking and not inherited by child processes.
+#[cfg(unix)]
+fn accept_pending<T, H>(handle: &H) -> UvResult<Option<c_int>>
+                        where T: raw::Allocated, H: raw::Handle<T> {
+    use std::ptr;
+    use uvll::fcntl::{fcntl, F_SETFD, F_SETFL, FD_CLOEXEC, O_NONBLOCK};
+
+    let listener = try!(handle.fileno());
+    unsafe {
+        let fd = libc::accept(listener, ptr::null_mut(), ptr::null_mut());
+        if fd == -1 {
+            return match std::os::errno() as c_int {
+                libc::EAGAIN | libc::ECONNABORTED | libc::EINTR => Ok(None),
+                n => Err(UvError(-n)),
+            }
+        }
+        if fcntl(fd, F_SETFL, O_NONBLOCK) == -1 ||
+           fcntl(fd, F_SETFD, FD_CLOEXEC) == -1 {
+            let err = UvError(-(std::os::errno() as c_int));
+            libc::close(fd);
+            return Err(err)
+        }
+        Ok(Some(fd))
+    }
+}
+
+// Duplicates `fd`, which belongs to a standard library object, so the new
+// descriptor can be handed to libuv. Fails with EINVAL unless the descriptor
+// is of type `ty`.
+#[cfg(unix)]
+fn dup_std(fd: c_int, ty: HandleType) -> UvResult<c_int> {
+    if guess_handle(fd) != ty {
+        return Err(UvError(uvll::EINVAL))
+    }
+    match unsafe { libc::dup(fd) } {
+        -1 => Err(UvError(-(std::os::errno() as c_int))),
+        n => Ok(n),
+    }
+}
+
 pub type UvResult<T> = Result<T, UvError>;
 
 #[deriving(Eq, PartialEq, Clone)]
@@ -184,17 +384,17 @@ impl UvError {
 
     /// Convert this libuv-based error to a std IoError instance
     #[cfg(unix)]
-    pub fn to_io_error(&self) -> io::IoError {
+    pub fn to_io_error(&self) -> IoError {
         let code = if self.code() == uvll::EOF {
             libc::EOF as uint
         } else {
             -self.code() as uint
         };
-        io::IoError::from_errno(code, true)
+        self.stash(IoError::from_errno(code, true))
     }
 
     #[cfg(windows)]
-    pub fn to_io_error(&self) -> io::IoError {
+    pub fn to_io_error(&self) -> IoError {
         let code = match self.code() {
             uvll::EOF => libc::EOF,
             uvll::EACCES => libc::ERROR_ACCESS_DENIED,
@@ -209,23 +409,130 @@ impl UvError {
             uvll::EADDRINUSE => libc::WSAEADDRINUSE,
             uvll::EPERM => libc::ERROR_ACCESS_DENIED,
             err => {
-                uvdebug!("uverr.code {}", err as int);
+                uvtrace!(Debug, Loop, "unmapped uv error code {}", err as int);
                 // FIXME: Need to map remaining uv error types
                 -1
             }
         };
-        io::IoError::from_errno(code, true)
+        self.stash(IoError::from_errno(code, true))
+    }
+
+    /// Recovers the libuv error which an `IoError` was created from.
+    ///
+    /// Errors converted with `to_io_error` remember their original code, so
+    /// code written against the std `Reader` and `Writer` traits can still
+    /// tell apart, for example, a timeout (`ECANCELED`) from a reset
+    /// connection. Any other error is mapped to the libuv code closest to its
+    /// kind, or `UNKNOWN`.
+    pub fn from_io_error(err: &IoError) -> UvError {
+        use std::io::{EndOfFile, FileNotFound, PathDoesntExist, TimedOut,
+                      PermissionDenied, PathAlreadyExists, ConnectionRefused,
+                      ConnectionReset, ConnectionAborted, NotConnected,
+                      BrokenPipe, ResourceUnavailable, InvalidInput};
+
+        match stashed_code(err.desc) {
+            Some(code) => return UvError(code),
+            None => {}
+        }
+        UvError(match err.kind {
+            EndOfFile => uvll::EOF,
+            FileNotFound | PathDoesntExist => uvll::ENOENT,
+            PathAlreadyExists => uvll::EEXIST,
+            PermissionDenied => uvll::EACCES,
+            ConnectionRefused => uvll::ECONNREFUSED,
+            ConnectionReset => uvll::ECONNRESET,
+            ConnectionAborted => uvll::ECONNABORTED,
+            NotConnected => uvll::ENOTCONN,
+            BrokenPipe => uvll::EPIPE,
+            ResourceUnavailable => uvll::EAGAIN,
+            InvalidInput => uvll::EINVAL,
+            TimedOut => uvll::ECANCELED,
+            _ => uvll::UNKNOWN,
+        })
+    }
+
+    // The description of the error is replaced with libuv's own, so the code
+    // can be found again by `from_io_error`.
+    fn stash(&self, mut err: IoError) -> IoError {
+        err.desc = self.desc();
+        err
     }
 }
 
+// libuv's descriptions are static strings, a distinct one for each error code,
+// so the description of an error converted by `to_io_error` identifies its
+// code. Every libuv error code lies between UV_EOF (-4095) and zero.
+fn stashed_code(desc: &'static str) -> Option<c_int> {
+    let desc = desc.as_ptr() as uint;
+    let unknown = unsafe { uvll::uv_strerror(0) as uint };
+    if desc == unknown { return None }
+    range(-4095 as c_int, 0).find(|&code| {
+        unsafe { uvll::uv_strerror(code) as uint == desc }
+    })
+}
+
+/// Allows `try!` to be used on a `UvResult` in functions returning an
+/// `IoResult`.
+impl FromError<UvError> for IoError {
+    fn from_error(err: UvError) -> IoError { err.to_io_error() }
+}
+
+/// Allows `try!` to be used on an `IoResult` in functions returning a
+/// `UvResult`. See `UvError::from_io_error` for how the error is converted.
+impl FromError<IoError> for UvError {
+    fn from_error(err: IoError) -> UvError { UvError::from_io_error(&err) }
+}
+
 impl fmt::Show for UvError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}: {}", self.name(), self.desc())
     }
 }
 
+#[test]
+fn wall_clock_smoke_test() {
+    let (sec, usec) = wall_clock();
+    // 2014-01-01T00:00:00Z
+    assert!(sec > 1388534400);
+    assert!(usec < 1000000);
+}
+
 #[test]
 fn error_smoke_test() {
     let err: UvError = UvError(uvll::EOF);
     assert_eq!(err.to_string(), "EOF: end of file".to_string());
 }
+
+#[test]
+fn from_error() {
+    fn uv() -> UvResult<()> { Err(UvError(uvll::EOF)) }
+    fn io_result() -> std::io::IoResult<()> { try!(uv()); Ok(()) }
+    fn uv_again() -> UvResult<()> { try!(io_result()); Ok(()) }
+
+    assert_eq!(io_result().err().unwrap().kind, std::io::EndOfFile);
+    assert_eq!(uv_again().err().unwrap().code(), uvll::EOF);
+}
+
+#[test]
+fn io_error_round_trip() {
+    for &code in [uvll::ECANCELED, uvll::ECONNRESET, uvll::EOF,
+                  uvll::EADDRINUSE].iter() {
+        let err = UvError(code).to_io_error();
+        assert_eq!(UvError::from_io_error(&err).code(), code);
+    }
+
+    let err = std::io::standard_error(std::io::TimedOut);
+    assert_eq!(UvError::from_io_error(&err).code(), uvll::ECANCELED);
+}
+
+#[test]
+fn uv_version_smoke_test() {
+    let (major, minor, _) = uv_version();
+    assert_eq!((major, minor), bundled_uv_version());
+}
+
+#[test]
+fn guess_handle_smoke_test() {
+    assert_eq!(guess_handle(-1), HandleType::Unknown);
+    assert!(!isatty(-1));
+}
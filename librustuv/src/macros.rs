@@ -19,11 +19,17 @@ macro_rules! uverrln (
     } )
 )
 
-// Some basic logging. Enabled by passing `--cfg uvdebug` to the libstd build.
-macro_rules! uvdebug (
-    ($($arg:tt)*) => ( {
-        if cfg!(uvdebug) {
-            uverrln!($($arg)*)
+// Emits a trace event on the local event loop, see the `trace` module. The
+// message is only formatted if the event would be delivered.
+macro_rules! uvtrace (
+    ($lvl:ident, $cat:ident, $($arg:tt)*) => ( {
+        let lvl = ::trace::Level::$lvl;
+        let cat = ::trace::Category::$cat;
+        if ::trace::enabled(lvl, cat) {
+            let f = |args: &::std::fmt::Arguments| {
+                ::trace::emit(lvl, cat, args)
+            };
+            format_args!(f, $($arg)*)
         }
     })
 )
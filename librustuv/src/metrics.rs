@@ -0,0 +1,128 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Utilization metrics of an event loop.
+//!
+//! The bundled libuv predates `uv_metrics_idle_time`, so this mirrors its
+//! implementation: the time an event loop spends idle is the time between
+//! preparing to poll for I/O and the poll returning, which is measured with a
+//! prepare and a check handle.
+
+use std::mem;
+use std::time::Duration;
+use libc;
+
+use {raw, uvll, UvResult};
+use raw::{Loop, Handle};
+use queue::QueueStats;
+
+/// Statistics of an event loop, as returned by `EventLoop::stats`.
+#[deriving(Clone, Show)]
+pub struct LoopStats {
+    /// Total time the event loop has spent waiting for events since idle time
+    /// metrics were enabled, or `None` if they are disabled.
+    ///
+    /// Comparing this to the time elapsed over the same period gives the
+    /// utilization of the event loop.
+    pub idle_time: Option<Duration>,
+    /// Counters of the queue through which other event loops send tasks to
+    /// this one.
+    pub queue: QueueStats,
+}
+
+/// A prepare and a check handle, whose callbacks run right before and right
+/// after the event loop polls for I/O, and which don't keep the loop alive.
+pub struct PollHooks {
+    prepare: raw::Prepare,
+    check: raw::Check,
+}
+
+/// Measures the idle time of an event loop.
+pub struct IdleTime {
+    _hooks: PollHooks,
+    state: Box<State>,
+}
+
+struct State {
+    // Nanoseconds spent polling in previous turns of the loop
+    total: u64,
+    // When the loop started polling, if it currently is
+    poll_start: Option<u64>,
+}
+
+impl PollHooks {
+    /// Starts invoking `prepare_cb` and `check_cb` on every turn of
+    /// `uv_loop`, with `data` as the data of their handles.
+    ///
+    /// This is unsafe as `data` must stay valid until the hooks are dropped.
+    pub unsafe fn new(uv_loop: &Loop, data: *mut libc::c_void,
+                      prepare_cb: uvll::uv_prepare_cb,
+                      check_cb: uvll::uv_check_cb) -> UvResult<PollHooks> {
+        let mut prepare = try!(raw::Prepare::new(uv_loop));
+        let check = match raw::Check::new(uv_loop) {
+            Ok(check) => check,
+            Err(e) => { prepare.close_and_free(); return Err(e) }
+        };
+        let mut ret = PollHooks { prepare: prepare, check: check };
+        ret.prepare.set_data(data);
+        ret.check.set_data(data);
+        ret.prepare.uv_unref();
+        ret.check.uv_unref();
+        try!(ret.prepare.start(prepare_cb));
+        try!(ret.check.start(check_cb));
+        Ok(ret)
+    }
+}
+
+impl Drop for PollHooks {
+    fn drop(&mut self) {
+        unsafe {
+            self.prepare.close_and_free();
+            self.check.close_and_free();
+        }
+    }
+}
+
+impl IdleTime {
+    pub fn new(uv_loop: &Loop) -> UvResult<IdleTime> {
+        let mut state = box State { total: 0, poll_start: None };
+        let data = &mut *state as *mut State as *mut _;
+        let hooks = try!(unsafe {
+            PollHooks::new(uv_loop, data, prepare_cb, check_cb)
+        });
+        Ok(IdleTime { _hooks: hooks, state: state })
+    }
+
+    /// Returns the time spent polling so far.
+    pub fn get(&self) -> Duration {
+        Duration::microseconds((self.state.total / 1000) as i64)
+    }
+}
+
+// The loop is about to poll for I/O
+extern fn prepare_cb(handle: *mut uvll::uv_prepare_t) {
+    unsafe {
+        let prepare: raw::Prepare = Handle::from_raw(handle);
+        let state: &mut State = mem::transmute(prepare.get_data());
+        state.poll_start = Some(uvll::uv_hrtime());
+    }
+}
+
+// The loop has polled for I/O
+extern fn check_cb(handle: *mut uvll::uv_check_t) {
+    unsafe {
+        let check: raw::Check = Handle::from_raw(handle);
+        let state: &mut State = mem::transmute(check.get_data());
+        match state.poll_start.take() {
+            Some(start) => state.total += uvll::uv_hrtime() - start,
+            None => {}
+        }
+    }
+}
@@ -0,0 +1,130 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers shared by all kinds of streams.
+
+use std::cmp;
+use std::mem;
+use std::rt::task::BlockedTask;
+use std::time::Duration;
+use libc;
+
+use raw::{Handle, Request};
+use {raw, uvll, UvResult, UvError};
+use event_loop::{block_on, alloc_req, free_req};
+
+/// Shuts down the writing half of `handle`, blocking the current task until
+/// all pending writes have been flushed, or for at most `timeout`.
+///
+/// This is how `close_write` is implemented for the streams of this crate, and
+/// it can be used the same way by wrappers around other kinds of streams. The
+/// current task must be running on the event loop of `handle`, for example by
+/// holding a homing missile. Fails with ECANCELED if the timeout expires, in
+/// which case the shutdown is still pending and the only way to cancel it is to
+/// close the handle.
+pub fn shutdown<T, U>(mut handle: U, timeout: Option<Duration>) -> UvResult<()>
+                      where T: raw::Allocated, U: raw::Stream<T> {
+    // If the shutdown times out the request is still pending, so the context
+    // lives on the heap and is handed over to the shutdown callback, which
+    // frees it along with the request once libuv is done with them.
+    struct Ctx {
+        slot: Option<BlockedTask>,
+        status: Option<libc::c_int>,
+        abandoned: bool,
+    }
+    unsafe {
+        // A shutdown request can't be taken back once it's sent, so the timer
+        // is set up first and any error is returned before sending it.
+        let mut timer = match timeout {
+            Some(..) => Some(try!(raw::Timer::new(&handle.uv_loop()))),
+            None => None,
+        };
+        let mut req: raw::Shutdown = alloc_req();
+        let cx: *mut Ctx = mem::transmute(box Ctx {
+            slot: None,
+            status: None,
+            abandoned: false,
+        });
+        req.set_data(cx as *mut _);
+        let started = match (timer.as_mut(), timeout) {
+            (Some(timer), Some(dur)) => {
+                let ms = cmp::max(dur.num_milliseconds(), 0) as u64;
+                timer.set_data(cx as *mut _);
+                timer.start(ms, 0, timer_cb)
+            }
+            _ => Ok(()),
+        };
+        let sent = match started {
+            Ok(()) => req.send(&mut handle, shutdown_cb),
+            Err(e) => Err(e),
+        };
+        match sent {
+            Ok(()) => {}
+            Err(e) => {
+                match timer {
+                    Some(ref mut t) => t.close_and_free(),
+                    None => {}
+                }
+                free_req(req);
+                let _cx: Box<Ctx> = mem::transmute(cx);
+                return Err(e)
+            }
+        }
+
+        {
+            let _b = block_on(&handle, "uv_shutdown");
+            ::block(handle.uv_loop(), |task| {
+                (*cx).slot = Some(task);
+            });
+        }
+        match timer {
+            Some(ref mut t) => t.close_and_free(),
+            None => {}
+        }
+
+        return match (*cx).status {
+            Some(status) => {
+                free_req(req);
+                let _cx: Box<Ctx> = mem::transmute(cx);
+                if status < 0 {Err(UvError(status))} else {Ok(())}
+            }
+            None => {
+                (*cx).abandoned = true;
+                Err(UvError(uvll::ECANCELED))
+            }
+        }
+    }
+
+    extern fn shutdown_cb(req: *mut uvll::uv_shutdown_t, status: libc::c_int) {
+        unsafe {
+            let req: raw::Shutdown = raw::Request::from_raw(req);
+            let cx: &mut Ctx = mem::transmute(req.get_data());
+            if cx.abandoned {
+                free_req(req);
+                let _cx: Box<Ctx> = mem::transmute(cx);
+                return
+            }
+            cx.status = Some(status);
+            if cx.slot.is_some() {
+                ::wakeup(&mut cx.slot);
+            }
+        }
+    }
+
+    extern fn timer_cb(timer: *mut uvll::uv_timer_t) {
+        unsafe {
+            let timer: raw::Timer = Handle::from_raw(timer);
+            let cx: &mut Ctx = mem::transmute(timer.get_data());
+            if cx.slot.is_some() {
+                ::wakeup(&mut cx.slot);
+            }
+        }
+    }
+}
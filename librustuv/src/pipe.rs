@@ -9,32 +9,39 @@
 // except according to those terms.
 
 use std::c_str::CString;
+use std::cmp;
 use std::io;
 use std::mem;
 use std::rt::task::BlockedTask;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, SeqCst};
 use std::time::Duration;
+#[cfg(unix)] use green::Callback;
 use libc;
 
-use access::Access;
-use homing::{HomingIO, HomeHandle};
+use counters::IoCounters;
+use homing::{HomingIO, HomeHandle, HomingRecord};
 use raw::Handle;
-use stream::Stream;
+#[cfg(unix)] use selector::Selectable;
+use stream::{mod, Stream};
 use timeout::{Pusher, AcceptTimeout, ConnectCtx, AccessTimeout};
-use {raw, uvll, tcp, EventLoop, UvResult, UvError};
+use {raw, uvll, net, tcp, EventLoop, UvResult, UvError};
+use event_loop::block_on;
 
 pub struct Pipe {
     data: Arc<PipeData>,
     stream: Stream<raw::Pipe>,
 
-    // see comments in TcpWatcher for why these exist
-    write_access: Access<()>,
-    read_access: AccessTimeout<()>,
+    // see comments in Tcp for why this exists
+    access: AccessTimeout<uint>,
 }
 
 struct PipeData {
     handle: raw::Pipe,
     home: HomeHandle,
+    // Milliseconds plus one to wait for pending writes when the last clone is
+    // dropped, or zero to close right away. See `set_drain_on_drop`.
+    drain: AtomicUint,
 }
 
 pub struct PipeListener {
@@ -62,12 +69,12 @@ impl Pipe {
     unsafe fn new(uv_loop: &raw::Loop, home: HomeHandle) -> UvResult<Pipe> {
         let raw = try!(raw::Pipe::new(uv_loop, false));
         Ok(Pipe {
-            write_access: Access::new(()),
-            read_access: AccessTimeout::new(()),
-            stream: Stream::new(raw, true),
+            access: AccessTimeout::duplex(0, 0),
+            stream: Stream::new(raw, true, home.counters()),
             data: Arc::new(PipeData {
                 home: home,
                 handle: raw,
+                drain: AtomicUint::new(0),
             })
         })
     }
@@ -85,6 +92,40 @@ impl Pipe {
         Ok(pipe)
     }
 
+    /// Moves this pipe onto the event loop of the calling task.
+    ///
+    /// The underlying file descriptor is duplicated and reopened on the local
+    /// event loop, after which this handle is closed on its original loop.
+    /// Clones of this pipe which are still alive keep using the old handle.
+    #[cfg(unix)]
+    pub fn migrate(self) -> UvResult<Pipe> {
+        let fd = try!(self.dup_fd());
+        let mut eloop = try!(EventLoop::borrow());
+        Pipe::reopen(&mut *eloop, fd)
+    }
+
+    /// Same as `migrate`, but specifies what event loop the pipe is moved to.
+    #[cfg(unix)]
+    pub fn migrate_on(self, eloop: &mut EventLoop) -> UvResult<Pipe> {
+        let fd = try!(self.dup_fd());
+        Pipe::reopen(eloop, fd)
+    }
+
+    // Duplicates the descriptor of this pipe and then closes it.
+    #[cfg(unix)]
+    fn dup_fd(self) -> UvResult<libc::c_int> {
+        let _m = self.data.fire_homing_missile();
+        ::dup_fileno(&self.data.handle)
+    }
+
+    #[cfg(unix)]
+    fn reopen(eloop: &mut EventLoop, fd: libc::c_int) -> UvResult<Pipe> {
+        Pipe::open_on(eloop, fd).map_err(|e| {
+            unsafe { libc::close(fd); }
+            e
+        })
+    }
+
     pub fn connect<T: ToCStr>(name: &T) -> UvResult<Pipe> {
         Pipe::connect_on(&mut *try!(EventLoop::borrow()), name.to_c_str(), None)
     }
@@ -114,9 +155,21 @@ impl Pipe {
     /// assumptions of this object.
     pub unsafe fn raw(&self) -> raw::Pipe { self.data.handle }
 
+    /// Returns the homing statistics of this pipe, if homing diagnostics were
+    /// enabled on its event loop when it was created.
+    pub fn homing_record(&self) -> Option<HomingRecord> {
+        self.data.home.record()
+    }
+
+    /// Returns the I/O counters of this pipe, shared with its clones, if I/O
+    /// counters were enabled on its event loop when it was created.
+    pub fn io_counters(&self) -> Option<IoCounters> {
+        self.data.home.io_counters()
+    }
+
     pub fn uv_read(&mut self, buf: &mut [u8]) -> UvResult<uint> {
         let m = self.data.fire_homing_missile();
-        let guard = try!(self.read_access.grant(m));
+        let guard = try!(self.access.grant(m));
 
         // see comments in close_read about this check
         if guard.access.is_closed() {
@@ -126,10 +179,33 @@ impl Pipe {
         self.stream.read(buf)
     }
 
+    /// Reads all remaining data from this pipe until EOF.
+    ///
+    /// This is much faster than reading in a loop for bulk transfers as
+    /// reading stays active the whole time, with libuv choosing how much to
+    /// read at once and the returned buffer growing to accommodate it.
+    pub fn read_to_end(&mut self) -> UvResult<Vec<u8>> {
+        let m = self.data.fire_homing_missile();
+        let guard = try!(self.access.grant(m));
+
+        // see comments in close_read about this check
+        if guard.access.is_closed() {
+            return Ok(Vec::new())
+        }
+
+        self.stream.read_to_end()
+    }
+
     pub fn uv_write(&mut self, buf: &[u8]) -> UvResult<()> {
         let m = self.data.fire_homing_missile();
-        let _guard = self.write_access.grant(0, m);
-        self.stream.write(buf)
+        let mut guard = try!(self.access.grant_write(m));
+
+        // Advertise who's writing so `cancel_io` can find the writer from any
+        // clone of this pipe.
+        *guard.access = self.stream.writer();
+        let ret = self.stream.write(buf);
+        *guard.access = 0;
+        ret
     }
 
     pub fn close_read(&mut self) -> UvResult<()> {
@@ -152,23 +228,120 @@ impl Pipe {
         // in before we set the flag.
         let task = {
             let m = self.data.fire_homing_missile();
-            self.read_access.access().close(&m);
+            self.access.access().close(&m);
             Stream::cancel_read(self.stream.handle, uvll::EOF as libc::ssize_t)
         };
         let _ = task.map(|t| t.reawaken());
         Ok(())
     }
 
+    /// Cancels any I/O which is currently pending on this pipe.
+    ///
+    /// Any task currently blocked reading from or writing to this pipe
+    /// (through any clone) is woken up with an ECANCELED error. Tasks which are
+    /// still waiting for their turn to read or write are not affected, and
+    /// future operations proceed as usual.
+    ///
+    /// Note that libuv cannot cancel a write which is in progress, so a
+    /// cancelled write may still be written in the background.
+    ///
+    /// A connect is not covered, as the pipe doesn't exist until the connect
+    /// has completed. Use `connect_timeout` or `with_timeout` to bound it.
+    pub fn cancel_io(&mut self) -> UvResult<()> {
+        let (reader, writer) = {
+            let m = self.data.fire_homing_missile();
+            let reader = Stream::cancel_read(self.stream.handle,
+                                             uvll::ECANCELED as libc::ssize_t);
+            let writer = match *self.access.write_access().get_mut(&m) {
+                0 => None,
+                writer => stream::cancel_write(writer),
+            };
+            (reader, writer)
+        };
+        let _ = reader.map(|t| t.reawaken());
+        let _ = writer.map(|t| t.reawaken());
+        Ok(())
+    }
+
+    /// Returns whether this stream can still be read from, which is no longer
+    /// the case once the peer has closed its writing half.
+    pub fn is_readable(&self) -> bool {
+        use raw::Stream;
+
+        let _m = self.data.fire_homing_missile();
+        self.stream.handle.is_readable()
+    }
+
+    /// Returns whether this stream can still be written to, which is no longer
+    /// the case once it or its writing half has been closed.
+    pub fn is_writable(&self) -> bool {
+        use raw::Stream;
+
+        let _m = self.data.fire_homing_missile();
+        self.stream.handle.is_writable()
+    }
+
     pub fn close_write(&mut self) -> UvResult<()> {
         let _m = self.data.fire_homing_missile();
-        tcp::shutdown(self.stream.handle)
+        net::shutdown(self.stream.handle, None)
+    }
+
+    /// Same as `close_write`, but gives up once `dur` has elapsed.
+    ///
+    /// See `Tcp::close_write_timeout` for details.
+    pub fn close_write_timeout(&mut self, dur: Duration) -> UvResult<()> {
+        let m = self.data.fire_homing_missile();
+        match net::shutdown(self.stream.handle, Some(dur)) {
+            Err(e) if e.code() == uvll::ECANCELED => {
+                tcp::close_stream(self.stream.handle, &mut self.access, m);
+                Err(e)
+            }
+            other => other,
+        }
+    }
+
+    /// Blocks until every write queued on this stream has completed.
+    ///
+    /// Writes normally block until they complete, but writes which timed out
+    /// or were cancelled are still written in the background. This waits for
+    /// those as well, and can be bounded with `with_timeout`.
+    pub fn flush(&mut self) -> UvResult<()> {
+        let m = self.data.fire_homing_missile();
+        let mut guard = try!(self.access.grant_write(m));
+        *guard.access = self.stream.writer();
+        let ret = self.stream.flush();
+        *guard.access = 0;
+        ret
+    }
+
+    /// Closes this stream after all pending writes have been flushed, giving
+    /// up after `dur`.
+    ///
+    /// This is a shorthand for `close_write_timeout` followed by dropping
+    /// this handle.
+    pub fn graceful_close(mut self, dur: Duration) -> UvResult<()> {
+        self.close_write_timeout(dur)
+    }
+
+    /// Sets whether dropping the last clone of this stream waits for pending
+    /// writes to be flushed before closing it.
+    ///
+    /// By default the handle is closed immediately, discarding any writes
+    /// which are still queued. With a timeout set, the stream is instead shut
+    /// down first, blocking the dropping task for up to `dur`. This suits
+    /// servers which close each connection right after writing a response.
+    pub fn set_drain_on_drop(&mut self, dur: Option<Duration>) {
+        let ms = dur.map_or(0, |d| {
+            cmp::max(d.num_milliseconds(), 0) as uint + 1
+        });
+        self.data.drain.store(ms, SeqCst);
     }
 
     pub fn set_read_timeout(&mut self, dur: Option<Duration>) {
         let _m = self.data.fire_homing_missile();
         let uv_loop = self.stream.handle.uv_loop();
-        self.read_access.set_timeout(dur, uv_loop, cancel_read,
-                                     self.stream.handle.raw() as uint);
+        self.access.set_timeout(dur, uv_loop, cancel_read,
+                                self.stream.handle.raw() as uint);
 
         fn cancel_read(stream: uint) -> Option<BlockedTask> {
             let stream = stream as *mut uvll::uv_pipe_t;
@@ -176,6 +349,88 @@ impl Pipe {
             Stream::cancel_read(raw, uvll::ECANCELED as libc::ssize_t)
         }
     }
+
+    /// Returns the user id, group id and process id of the process on the
+    /// other end of this unix domain socket, as recorded by the kernel when
+    /// the connection was made.
+    ///
+    /// This allows a service listening on a unix socket to authenticate its
+    /// local clients. Fails with ENOSYS on platforms other than Linux, Android,
+    /// OS X and iOS.
+    #[cfg(unix)]
+    pub fn peer_credentials(&self)
+                            -> UvResult<(libc::uid_t, libc::gid_t, libc::pid_t)> {
+        let _m = self.data.fire_homing_missile();
+        peercred::get(&self.data.handle)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod peercred {
+    use libc;
+
+    use UvResult;
+    use raw::{Pipe, Handle};
+    use uvll::sockopt::SO_PEERCRED;
+
+    #[repr(C)]
+    struct ucred {
+        pid: libc::pid_t,
+        uid: libc::uid_t,
+        gid: libc::gid_t,
+    }
+
+    pub fn get(handle: &Pipe)
+               -> UvResult<(libc::uid_t, libc::gid_t, libc::pid_t)> {
+        let cred: ucred = try!(handle.get_sockopt(libc::SOL_SOCKET,
+                                                  SO_PEERCRED));
+        Ok((cred.uid, cred.gid, cred.pid))
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod peercred {
+    use libc;
+
+    use {uvll, UvResult, UvError};
+    use raw::{Pipe, Handle};
+    use uvll::sockopt::{SOL_LOCAL, LOCAL_PEERCRED, LOCAL_PEERPID};
+
+    static XUCRED_VERSION: libc::c_uint = 0;
+
+    #[repr(C)]
+    struct xucred {
+        cr_version: libc::c_uint,
+        cr_uid: libc::uid_t,
+        cr_ngroups: libc::c_short,
+        cr_groups: [libc::gid_t, ..16],
+    }
+
+    // The first group is the effective group id of the peer.
+    pub fn get(handle: &Pipe)
+               -> UvResult<(libc::uid_t, libc::gid_t, libc::pid_t)> {
+        let cred: xucred = try!(handle.get_sockopt(SOL_LOCAL, LOCAL_PEERCRED));
+        if cred.cr_version != XUCRED_VERSION || cred.cr_ngroups < 1 {
+            return Err(UvError(uvll::EINVAL))
+        }
+        let pid: libc::pid_t = try!(handle.get_sockopt(SOL_LOCAL,
+                                                       LOCAL_PEERPID));
+        Ok((cred.cr_uid, cred.cr_groups[0], pid))
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android",
+                        target_os = "macos", target_os = "ios"))))]
+mod peercred {
+    use libc;
+
+    use {uvll, UvResult, UvError};
+    use raw::Pipe;
+
+    pub fn get(_handle: &Pipe)
+               -> UvResult<(libc::uid_t, libc::gid_t, libc::pid_t)> {
+        Err(UvError(uvll::ENOSYS))
+    }
 }
 
 impl HomingIO for PipeData {
@@ -185,33 +440,72 @@ impl HomingIO for PipeData {
 impl Clone for Pipe {
     fn clone(&self) -> Pipe {
         Pipe {
-            read_access: self.read_access.clone(),
-            write_access: self.write_access.clone(),
-            stream: Stream::new(self.data.handle, false),
+            access: self.access.clone(),
+            stream: Stream::new(self.data.handle, false,
+                                self.data.home.counters()),
             data: self.data.clone(),
         }
     }
 }
 
+#[cfg(unix)]
+impl Selectable for Pipe {
+    fn is_local(&self) -> bool { self.data.home.is_local() }
+
+    fn start_watch(&mut self, cb: Box<Callback + Send>) -> UvResult<()> {
+        Stream::watch(self.data.handle, cb)
+    }
+
+    fn stop_watch(&mut self) { Stream::unwatch(self.data.handle) }
+}
+
 impl Reader for Pipe {
     fn read(&mut self, into: &mut [u8]) -> io::IoResult<uint> {
-        self.uv_read(into).map_err(|e| e.to_io_error())
+        Ok(try!(self.uv_read(into)))
+    }
+
+    fn read_to_end(&mut self) -> io::IoResult<Vec<u8>> {
+        Ok(try!(self.read_to_end()))
     }
 }
 
+impl Buffer for Pipe {
+    fn fill_buf<'a>(&'a mut self) -> io::IoResult<&'a [u8]> {
+        let m = self.data.fire_homing_missile();
+        let guard = try!(self.access.grant(m));
+        if guard.access.is_closed() {
+            return Err(UvError(uvll::EOF).to_io_error())
+        }
+        Ok(try!(self.stream.fill_buf()))
+    }
+
+    fn consume(&mut self, amt: uint) { self.stream.consume(amt) }
+}
+
 impl Writer for Pipe {
     fn write(&mut self, buf: &[u8]) -> io::IoResult<()> {
-        self.uv_write(buf).map_err(|e| e.to_io_error())
+        Ok(try!(self.uv_write(buf)))
+    }
+
+    fn flush(&mut self) -> io::IoResult<()> {
+        Ok(try!(self.flush()))
     }
 }
 
 impl Drop for PipeData {
     fn drop(&mut self) {
         let _m = self.fire_homing_missile();
-        unsafe { self.handle.close_and_free(); }
+        tcp::drain(self.handle, &self.drain);
+        unsafe { tcp::close_handle(self.handle) }
     }
 }
 
+// Creates a pipe which isn't connected to anything yet, for example to be
+// handed to a child process as one of its stdio streams.
+pub fn unbound(eloop: &mut EventLoop) -> UvResult<Pipe> {
+    unsafe { Pipe::new(&eloop.uv_loop(), eloop.make_handle()) }
+}
+
 // PipeListener implementation and traits
 
 impl PipeListener {
@@ -253,6 +547,14 @@ impl PipeListener {
         Ok(acceptor)
     }
 
+    /// Sets whether listening for connections keeps the event loop alive,
+    /// which it does by default. This setting carries over to the acceptor
+    /// returned by `listen`. See `Timer::keep_alive`.
+    pub fn keep_alive(&mut self, keep: bool) {
+        let _m = self.fire_homing_missile();
+        if keep { self.handle.uv_ref() } else { self.handle.uv_unref() }
+    }
+
     /// Gain access to the underlying raw pipe object.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -263,7 +565,7 @@ impl PipeListener {
 
 impl io::Listener<Pipe, PipeAcceptor> for PipeListener {
     fn listen(self) -> io::IoResult<PipeAcceptor> {
-        self.listen().map_err(|e| e.to_io_error())
+        Ok(try!(self.listen()))
     }
 }
 
@@ -306,10 +608,45 @@ impl Drop for PipeListener {
 // PipeAcceptor implementation and traits
 
 impl PipeAcceptor {
+    /// Waits for the next connection, or for the timeout of this acceptor to
+    /// expire.
+    ///
+    /// Once the timeout has expired, this returns connections which are
+    /// already pending and fails with `ECANCELED` if there are none.
     pub fn accept(&mut self) -> UvResult<Pipe> {
         let m = self.fire_homing_missile();
         let uv_loop = self.data.listener.handle.uv_loop();
-        self.access.accept(m, uv_loop)
+        let _b = block_on(&self.data.listener.handle, "uv_accept");
+        match self.access.accept(m, uv_loop) {
+            Err(ref e) if e.code() == uvll::ECANCELED => self.accept_pending(),
+            ret => ret,
+        }
+    }
+
+    // Picks up a connection which libuv hasn't accepted yet after a timeout,
+    // see `::accept_pending`.
+    #[cfg(unix)]
+    fn accept_pending(&mut self) -> UvResult<Pipe> {
+        let _m = self.fire_homing_missile();
+        let listener = &self.data.listener;
+        let fd = match try!(::accept_pending(&listener.handle)) {
+            Some(fd) => fd,
+            None => return Err(UvError(uvll::ECANCELED)),
+        };
+        let client = unsafe {
+            Pipe::new(&listener.handle.uv_loop(), listener.home.clone())
+        };
+        let ret = client.and_then(|client| {
+            let mut handle = client.data.handle;
+            handle.open(fd).map(|()| client)
+        });
+        if ret.is_err() { unsafe { libc::close(fd); } }
+        ret
+    }
+
+    #[cfg(windows)]
+    fn accept_pending(&mut self) -> UvResult<Pipe> {
+        Err(UvError(uvll::ECANCELED))
     }
 
     pub fn set_timeout(&mut self, dur: Option<Duration>) {
@@ -324,6 +661,15 @@ impl PipeAcceptor {
         Ok(())
     }
 
+    /// Sets whether accepting connections keeps the event loop alive, which
+    /// it does by default. This affects all clones of this acceptor. See
+    /// `Timer::keep_alive`.
+    pub fn keep_alive(&mut self, keep: bool) {
+        let _m = self.fire_homing_missile();
+        let handle = self.data.listener.handle;
+        if keep { handle.uv_ref() } else { handle.uv_unref() }
+    }
+
     /// Gain access to the underlying raw pipe object.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -334,7 +680,7 @@ impl PipeAcceptor {
 
 impl io::Acceptor<Pipe> for PipeAcceptor {
     fn accept(&mut self) -> io::IoResult<Pipe> {
-        self.accept().map_err(|e| e.to_io_error())
+        Ok(try!(self.accept()))
     }
 }
 
@@ -8,317 +8,689 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use libc::c_int;
-use libc;
-use std::ptr;
-use std::c_str::CString;
-use std::rt::rtio;
-use std::rt::rtio::IoResult;
+use std::c_str::{CString, ToCStr};
+use std::cmp;
+use std::io::process::{ProcessExit, ExitStatus, ExitSignal};
+use std::mem;
+use std::os;
 use std::rt::task::BlockedTask;
+use std::time::Duration;
+use libc;
+
+use green::Callback;
 
+use {raw, uvll, pipe, EventLoop, UvResult, UvError, File, Pipe, Tty};
+use raw::Handle;
 use homing::{HomingIO, HomeHandle};
-use pipe::PipeWatcher;
-use super::{UvHandle, UvError, uv_error_to_io_error,
-            wait_until_woken_after, wakeup, Loop};
-use timer::TimerWatcher;
-use uvio::UvIoFactory;
-use uvll;
 
+/// A builder for configuring and spawning child processes, modeled after
+/// `std::io::process::Command`.
+#[deriving(Clone)]
+pub struct Command {
+    program: CString,
+    args: Vec<CString>,
+    env: Option<Vec<(CString, CString)>>,
+    cwd: Option<CString>,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+    extra_io: Vec<Stdio>,
+    uid: Option<uint>,
+    gid: Option<uint>,
+    detach: bool,
+    kill_on_drop: Option<Duration>,
+}
+
+/// How one of the stdio streams of a child process is set up.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Stdio {
+    /// The child shares the stream of this process with the same number.
+    Inherit,
+    /// The stream is connected to the null device. Streams beyond the
+    /// standard three are closed in the child instead.
+    Null,
+    /// The stream is connected to a new pipe, which is handed out in the
+    /// `Process`.
+    ///
+    /// The child can only read from the pipe of stdin and only write to those
+    /// of stdout and stderr. There's no telling which way the child uses an
+    /// extra stream, so the pipes of those can be used in both directions.
+    Piped,
+    /// The child inherits this file descriptor of this process.
+    Fd(libc::c_int),
+}
+
+/// A child process spawned on an event loop.
 pub struct Process {
-    handle: *mut uvll::uv_process_t,
+    handle: raw::Process,
     home: HomeHandle,
+    pid: libc::pid_t,
+    kill_on_drop: Option<Duration>,
+
+    /// Handles to the stdio streams of the child, present if they were
+    /// configured with `Stdio::Piped`.
+    pub stdin: Option<Pipe>,
+    pub stdout: Option<Pipe>,
+    pub stderr: Option<Pipe>,
+
+    /// Handles to the extra streams of the child, in the same order as they
+    /// were configured with `Command::extra_io`.
+    pub extra_io: Vec<Option<Pipe>>,
+}
 
-    /// Task to wake up (may be null) for when the process exits
-    to_wake: Option<BlockedTask>,
+/// The exit status and output of a child process, see `Command::output`.
+pub struct Output {
+    pub status: ProcessExit,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
 
+struct Data {
     /// Collected from the exit_cb
-    exit_status: Option<rtio::ProcessExit>,
-
-    /// Lazily initialized timeout timer
-    timer: Option<Box<TimerWatcher>>,
-    timeout_state: TimeoutState,
+    exit_status: Option<ProcessExit>,
+    /// Task to wake up for when the process exits
+    to_wake: Option<BlockedTask>,
+    /// Callback to invoke on the event loop when the process exits
+    on_exit: Option<Box<Callback + Send>>,
+    /// Lazily initialized timer for `wait_timeout`, also used to wait for a
+    /// child killed on drop to exit
+    timer: Option<raw::Timer>,
+    /// The handle of a child which was being killed when its `Process` was
+    /// dropped, which is then freed along with this data once it exits
+    orphan: Option<raw::Process>,
 }
 
-enum TimeoutState {
-    NoTimeout,
-    TimeoutPending,
-    TimeoutElapsed,
-}
+// Signal numbers which libuv also understands on windows
+static SIGTERM: libc::c_int = 15;
+static SIGKILL: libc::c_int = 9;
 
-impl Process {
-    /// Spawn a new process inside the specified event loop.
+impl Command {
+    /// Creates a new command for spawning `program`.
     ///
-    /// Returns either the corresponding process object or an error which
-    /// occurred.
-    pub fn spawn(io_loop: &mut UvIoFactory, cfg: rtio::ProcessConfig)
-                -> Result<(Box<Process>, Vec<Option<PipeWatcher>>), UvError> {
-        let mut io = vec![cfg.stdin, cfg.stdout, cfg.stderr];
-        for slot in cfg.extra_io.iter() {
-            io.push(*slot);
+    /// By default the child inherits the environment and working directory of
+    /// this process, and all of its stdio streams are piped.
+    pub fn new<T: ToCStr>(program: T) -> Command {
+        Command {
+            program: program.to_c_str(),
+            args: Vec::new(),
+            env: None,
+            cwd: None,
+            stdin: Stdio::Piped,
+            stdout: Stdio::Piped,
+            stderr: Stdio::Piped,
+            extra_io: Vec::new(),
+            uid: None,
+            gid: None,
+            detach: false,
+            kill_on_drop: None,
         }
-        let mut stdio = Vec::<uvll::uv_stdio_container_t>::with_capacity(io.len());
-        let mut ret_io = Vec::with_capacity(io.len());
-        unsafe {
-            stdio.set_len(io.len());
-            for (slot, other) in stdio.mut_iter().zip(io.iter()) {
-                let io = set_stdio(slot as *mut uvll::uv_stdio_container_t, other,
-                                   io_loop);
-                ret_io.push(io);
+    }
+
+    /// Adds an argument to pass to the program.
+    pub fn arg<T: ToCStr>(&mut self, arg: T) -> &mut Command {
+        self.args.push(arg.to_c_str());
+        self
+    }
+
+    /// Adds multiple arguments to pass to the program.
+    pub fn args<T: ToCStr>(&mut self, args: &[T]) -> &mut Command {
+        self.args.extend(args.iter().map(|arg| arg.to_c_str()));
+        self
+    }
+
+    /// Sets an environment variable for the child, starting out with a copy of
+    /// the environment of this process if no variables were set yet.
+    pub fn env<T: ToCStr, U: ToCStr>(&mut self, key: T, val: U)
+                                     -> &mut Command {
+        let key = key.to_c_str();
+        let val = val.to_c_str();
+        {
+            let env = self.env_mut();
+            match env.iter().position(|&(ref k, _)| {
+                k.as_bytes_no_nul() == key.as_bytes_no_nul()
+            }) {
+                Some(i) => { env.as_mut_slice()[i] = (key, val); }
+                None => env.push((key, val)),
             }
         }
+        self
+    }
 
-        let ret = with_argv(cfg.program, cfg.args, |argv| {
-            with_env(cfg.env, |envp| {
-                let mut flags = 0;
-                if cfg.uid.is_some() {
-                    flags |= uvll::PROCESS_SETUID;
-                }
-                if cfg.gid.is_some() {
-                    flags |= uvll::PROCESS_SETGID;
-                }
-                if cfg.detach {
-                    flags |= uvll::PROCESS_DETACHED;
-                }
-                let mut options = uvll::uv_process_options_t {
-                    exit_cb: on_exit,
-                    file: unsafe { *argv },
-                    args: argv,
-                    env: envp,
-                    cwd: match cfg.cwd {
-                        Some(cwd) => cwd.as_ptr(),
-                        None => ptr::null(),
-                    },
-                    flags: flags as libc::c_uint,
-                    stdio_count: stdio.len() as libc::c_int,
-                    stdio: stdio.as_mut_ptr(),
-                    uid: cfg.uid.unwrap_or(0) as uvll::uv_uid_t,
-                    gid: cfg.gid.unwrap_or(0) as uvll::uv_gid_t,
-                };
-
-                let handle = UvHandle::alloc(None::<Process>, uvll::UV_PROCESS);
-                let process = box Process {
-                    handle: handle,
-                    home: io_loop.make_handle(),
-                    to_wake: None,
-                    exit_status: None,
-                    timer: None,
-                    timeout_state: NoTimeout,
-                };
-                match unsafe {
-                    uvll::uv_spawn(io_loop.uv_loop(), handle, &mut options)
-                } {
-                    0 => Ok(process.install()),
-                    err => Err(UvError(err)),
-                }
-            })
-        });
+    /// Clears the environment of the child, so only variables set afterwards
+    /// with `env` are passed along.
+    pub fn env_clear(&mut self) -> &mut Command {
+        self.env = Some(Vec::new());
+        self
+    }
 
-        match ret {
-            Ok(p) => Ok((p, ret_io)),
-            Err(e) => Err(e),
-        }
+    /// Sets the working directory of the child.
+    pub fn cwd<T: ToCStr>(&mut self, dir: T) -> &mut Command {
+        self.cwd = Some(dir.to_c_str());
+        self
     }
 
-    pub fn kill(pid: libc::pid_t, signum: int) -> Result<(), UvError> {
-        match unsafe {
-            uvll::uv_kill(pid as libc::c_int, signum as libc::c_int)
-        } {
-            0 => Ok(()),
-            n => Err(UvError(n))
-        }
+    /// Configures the stdin stream of the child.
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Command {
+        self.stdin = cfg;
+        self
     }
-}
 
-extern fn on_exit(handle: *mut uvll::uv_process_t,
-                  exit_status: i64,
-                  term_signal: libc::c_int) {
-    let p: &mut Process = unsafe { UvHandle::from_uv_handle(&handle) };
+    /// Configures the stdout stream of the child.
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut Command {
+        self.stdout = cfg;
+        self
+    }
 
-    assert!(p.exit_status.is_none());
-    p.exit_status = Some(match term_signal {
-        0 => rtio::ExitStatus(exit_status as int),
-        n => rtio::ExitSignal(n as int),
-    });
+    /// Configures the stderr stream of the child.
+    pub fn stderr(&mut self, cfg: Stdio) -> &mut Command {
+        self.stderr = cfg;
+        self
+    }
 
-    if p.to_wake.is_none() { return }
-    wakeup(&mut p.to_wake);
-}
+    /// Configures streams for the child beyond the standard three, which the
+    /// child sees as file descriptors 3 and onwards.
+    ///
+    /// Pipes created for these streams are readable and writable by both the
+    /// child and this process.
+    pub fn extra_io(&mut self, cfg: &[Stdio]) -> &mut Command {
+        self.extra_io = cfg.to_vec();
+        self
+    }
 
-unsafe fn set_stdio(dst: *mut uvll::uv_stdio_container_t,
-                    io: &rtio::StdioContainer,
-                    io_loop: &mut UvIoFactory) -> Option<PipeWatcher> {
-    match *io {
-        rtio::Ignored => {
-            uvll::set_stdio_container_flags(dst, uvll::STDIO_IGNORE);
-            None
-        }
-        rtio::InheritFd(fd) => {
-            uvll::set_stdio_container_flags(dst, uvll::STDIO_INHERIT_FD);
-            uvll::set_stdio_container_fd(dst, fd);
-            None
-        }
-        rtio::CreatePipe(readable, writable) => {
-            let mut flags = uvll::STDIO_CREATE_PIPE as libc::c_int;
-            if readable {
-                flags |= uvll::STDIO_READABLE_PIPE as libc::c_int;
-            }
-            if writable {
-                flags |= uvll::STDIO_WRITABLE_PIPE as libc::c_int;
-            }
-            let pipe = PipeWatcher::new(io_loop, false);
-            uvll::set_stdio_container_flags(dst, flags);
-            uvll::set_stdio_container_stream(dst, pipe.handle());
-            Some(pipe)
-        }
+    /// Sets the user id the child runs as.
+    ///
+    /// Setting the user id typically requires elevated privileges, and is not
+    /// supported on Windows, where spawning fails with ENOTSUP.
+    pub fn uid(&mut self, id: uint) -> &mut Command {
+        self.uid = Some(id);
+        self
+    }
+
+    /// Sets the group id the child runs as, with the same caveats as `uid`.
+    pub fn gid(&mut self, id: uint) -> &mut Command {
+        self.gid = Some(id);
+        self
+    }
+
+    /// Sets whether the child is detached from this process.
+    ///
+    /// On Unix a detached child is made the leader of a new session, and
+    /// therefore also of a new process group, so it is not affected by signals
+    /// sent to the process group of its parent and it has no controlling
+    /// terminal. On Windows it is created in a new process group without a
+    /// console.
+    ///
+    /// The child can still be waited on, but it keeps running if this process
+    /// exits first.
+    pub fn detached(&mut self, detach: bool) -> &mut Command {
+        self.detach = detach;
+        self
     }
-}
 
-/// Converts the program and arguments to the argv array expected by libuv.
-fn with_argv<T>(prog: &CString, args: &[CString],
-                cb: |*const *const libc::c_char| -> T) -> T {
-    let mut ptrs: Vec<*const libc::c_char> = Vec::with_capacity(args.len()+1);
+    /// Sets whether the child is killed when its `Process` is dropped before it
+    /// exited, which it isn't by default.
+    ///
+    /// With `Some(grace)` the child is sent SIGTERM, and then SIGKILL if it is
+    /// still running after `grace` has elapsed. This keeps children from being
+    /// orphaned when the task supervising them panics. Dropping the `Process`
+    /// doesn't wait for the child to exit.
+    pub fn kill_on_drop(&mut self, grace: Option<Duration>) -> &mut Command {
+        self.kill_on_drop = grace;
+        self
+    }
 
-    // Convert the CStrings into an array of pointers. Note: the
-    // lifetime of the various CStrings involved is guaranteed to be
-    // larger than the lifetime of our invocation of cb, but this is
-    // technically unsafe as the callback could leak these pointers
-    // out of our scope.
-    ptrs.push(prog.as_ptr());
-    ptrs.extend(args.iter().map(|tmp| tmp.as_ptr()));
+    /// Spawns the configured process on the local event loop.
+    pub fn spawn(&self) -> UvResult<Process> {
+        self.spawn_on(&mut *try!(EventLoop::borrow()))
+    }
 
-    // Add a terminating null pointer (required by libc).
-    ptrs.push(ptr::null());
+    /// Same as `spawn`, but specifies what event loop to spawn on.
+    pub fn spawn_on(&self, eloop: &mut EventLoop) -> UvResult<Process> {
+        let mut io = vec![self.stdin.clone(), self.stdout.clone(),
+                          self.stderr.clone()];
+        io.push_all(self.extra_io.as_slice());
+
+        let mut stdio = Vec::with_capacity(io.len());
+        let mut pipes = Vec::with_capacity(io.len());
+        for (slot, cfg) in io.iter().enumerate() {
+            let (container, pipe) = try!(stdio_container(eloop, slot, cfg));
+            stdio.push(container);
+            pipes.push(pipe);
+        }
 
-    cb(ptrs.as_ptr())
-}
+        let mut flags = 0;
+        if self.uid.is_some() {
+            flags |= uvll::PROCESS_SETUID;
+        }
+        if self.gid.is_some() {
+            flags |= uvll::PROCESS_SETGID;
+        }
+        if self.detach {
+            flags |= uvll::PROCESS_DETACHED;
+        }
 
-/// Converts the environment to the env array expected by libuv
-fn with_env<T>(env: Option<&[(&CString, &CString)]>,
-               cb: |*const *const libc::c_char| -> T) -> T {
-    // We can pass a char** for envp, which is a null-terminated array
-    // of "k=v\0" strings. Since we must create these strings locally,
-    // yet expose a raw pointer to them, we create a temporary vector
-    // to own the CStrings that outlives the call to cb.
-    match env {
-        Some(env) => {
-            let mut tmps = Vec::with_capacity(env.len());
-
-            for pair in env.iter() {
+        let mut args = Vec::with_capacity(self.args.len() + 1);
+        args.push(self.program.clone());
+        args.push_all(self.args.as_slice());
+        let env = self.env.as_ref().map(|env| {
+            env.iter().map(|&(ref k, ref v)| {
                 let mut kv = Vec::new();
-                kv.push_all(pair.ref0().as_bytes_no_nul());
+                kv.push_all(k.as_bytes_no_nul());
                 kv.push('=' as u8);
-                kv.push_all(pair.ref1().as_bytes()); // includes terminal \0
-                tmps.push(kv);
-            }
+                kv.push_all(v.as_bytes_no_nul());
+                kv.as_slice().to_c_str()
+            }).collect::<Vec<CString>>()
+        });
+
+        let mut options = raw::ProcessOptions::new(&self.program, exit_cb);
+        options.args = args.as_slice();
+        options.env = env.as_ref().map(|env| env.as_slice());
+        options.cwd = self.cwd.as_ref();
+        options.flags = flags;
+        options.stdio = stdio.as_slice();
+        options.uid = self.uid.unwrap_or(0) as uvll::uv_uid_t;
+        options.gid = self.gid.unwrap_or(0) as uvll::uv_gid_t;
+        let mut handle = unsafe {
+            try!(raw::Process::spawn_with(&eloop.uv_loop(), &options))
+        };
+
+        let data = box Data {
+            exit_status: None,
+            to_wake: None,
+            on_exit: None,
+            timer: None,
+            orphan: None,
+        };
+        unsafe { handle.set_data(mem::transmute(data)); }
+
+        let mut pipes = pipes.into_iter();
+        Ok(Process {
+            pid: handle.pid() as libc::pid_t,
+            kill_on_drop: self.kill_on_drop,
+            handle: handle,
+            home: eloop.make_handle(),
+            stdin: pipes.next().unwrap(),
+            stdout: pipes.next().unwrap(),
+            stderr: pipes.next().unwrap(),
+            extra_io: pipes.collect(),
+        })
+    }
 
-            // As with `with_argv`, this is unsafe, since cb could leak the pointers.
-            let mut ptrs: Vec<*const libc::c_char> =
-                tmps.iter()
-                    .map(|tmp| tmp.as_ptr() as *const libc::c_char)
-                    .collect();
-            ptrs.push(ptr::null());
+    /// Spawns the configured process with its stdout and stderr piped, and
+    /// waits for it to exit, collecting everything it wrote to them.
+    ///
+    /// Both pipes are drained at the same time, one of them by a separate
+    /// task, so a child which fills up one pipe while this task waits on the
+    /// other can't deadlock. A piped stdin is closed right away, so the child
+    /// sees EOF on it.
+    pub fn output(&self) -> UvResult<Output> {
+        let mut cmd = self.clone();
+        cmd.stdout(Stdio::Piped).stderr(Stdio::Piped);
+        let mut p = try!(cmd.spawn());
+        drop(p.stdin.take());
+
+        let mut stdout = p.stdout.take().unwrap();
+        let stderr = p.stderr.take().unwrap();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            let mut stderr = stderr;
+            tx.send(stderr.read_to_end());
+        });
+        let stdout = stdout.read_to_end();
+        let stderr = rx.recv();
+
+        Ok(Output {
+            status: try!(p.wait()),
+            stdout: try!(stdout),
+            stderr: try!(stderr),
+        })
+    }
 
-            cb(ptrs.as_ptr())
+    /// Spawns the configured process with its stdin, stdout and stderr all
+    /// connected to a new pseudo-terminal, returning the master side of the
+    /// terminal along with the process.
+    ///
+    /// Writing to the returned `Tty` is seen by the child as input typed on
+    /// its terminal, and reading from it yields whatever the child printed.
+    /// Once the child and all of its descendants have closed the terminal,
+    /// reads fail with an error instead of returning EOF.
+    ///
+    /// Note that libuv provides no way to make the pseudo-terminal the
+    /// controlling terminal of the child, so programs which open `/dev/tty`
+    /// directly will not see it.
+    ///
+    /// Pseudo-terminals are not currently supported on Windows.
+    pub fn spawn_pty(&self) -> UvResult<(Process, Tty)> {
+        self.spawn_pty_on(&mut *try!(EventLoop::borrow()))
+    }
+
+    /// Same as `spawn_pty`, but specifies what event loop to spawn on.
+    #[cfg(unix)]
+    pub fn spawn_pty_on(&self, eloop: &mut EventLoop)
+                        -> UvResult<(Process, Tty)> {
+        let (master, slave) = try!(pty::open());
+        let mut cmd = self.clone();
+        cmd.stdin(Stdio::Fd(slave))
+           .stdout(Stdio::Fd(slave))
+           .stderr(Stdio::Fd(slave));
+        let ret = cmd.spawn_on(eloop);
+
+        // The child has its own copies of the slave now
+        unsafe { libc::close(slave); }
+        let process = match ret {
+            Ok(p) => p,
+            Err(e) => { unsafe { libc::close(master); } return Err(e) }
+        };
+        match Tty::new_on(eloop, master, true) {
+            Ok(tty) => Ok((process, tty)),
+            Err(e) => { unsafe { libc::close(master); } Err(e) }
         }
-        _ => cb(ptr::null())
     }
-}
 
-impl HomingIO for Process {
-    fn home<'r>(&'r mut self) -> &'r mut HomeHandle { &mut self.home }
-}
+    #[cfg(windows)]
+    pub fn spawn_pty_on(&self, _eloop: &mut EventLoop)
+                        -> UvResult<(Process, Tty)> {
+        Err(UvError(uvll::ENOSYS))
+    }
 
-impl UvHandle<uvll::uv_process_t> for Process {
-    fn uv_handle(&self) -> *mut uvll::uv_process_t { self.handle }
+    fn env_mut(&mut self) -> &mut Vec<(CString, CString)> {
+        if self.env.is_none() {
+            self.env = Some(os::env_as_bytes().into_iter().map(|(k, v)| {
+                (k.as_slice().to_c_str(), v.as_slice().to_c_str())
+            }).collect());
+        }
+        self.env.as_mut().unwrap()
+    }
 }
 
-impl rtio::RtioProcess for Process {
-    fn id(&self) -> libc::pid_t {
-        unsafe { uvll::process_pid(self.handle) as libc::pid_t }
+impl Process {
+    /// Returns the process id of the child.
+    pub fn id(&self) -> libc::pid_t { self.pid }
+
+    /// Sends the signal `signum` to the child.
+    ///
+    /// On Windows only SIGTERM, SIGKILL and SIGINT are supported, all of which
+    /// terminate the process.
+    pub fn signal(&mut self, signum: int) -> UvResult<()> {
+        let _m = self.fire_homing_missile();
+        self.handle.kill_me(signum as libc::c_int)
     }
 
-    fn kill(&mut self, signal: int) -> IoResult<()> {
+    /// Sends the signal `signum` to the process with id `pid`.
+    pub fn kill(pid: libc::pid_t, signum: int) -> UvResult<()> {
+        raw::Process::kill(pid as libc::c_int, signum as libc::c_int)
+    }
+
+    /// Returns the exit status of the child if it has already exited, without
+    /// blocking.
+    pub fn try_wait(&mut self) -> Option<ProcessExit> {
         let _m = self.fire_homing_missile();
-        match unsafe {
-            uvll::uv_process_kill(self.handle, signal as libc::c_int)
-        } {
-            0 => Ok(()),
-            err => Err(uv_error_to_io_error(UvError(err)))
+        self.data().exit_status.clone()
+    }
+
+    /// Blocks the current task until the child exits, returning its exit
+    /// status.
+    ///
+    /// Note that this does not close the stdin handle of the child, so a child
+    /// which reads its input until EOF will never exit unless `stdin` is
+    /// dropped first.
+    pub fn wait(&mut self) -> UvResult<ProcessExit> {
+        self.wait_for(None)
+    }
+
+    /// Same as `wait`, but gives up after `dur` has elapsed, returning an
+    /// ECANCELED error if the child is still running at that point.
+    pub fn wait_timeout(&mut self, dur: Duration) -> UvResult<ProcessExit> {
+        self.wait_for(Some(dur))
+    }
+
+    /// Registers a callback to be invoked on the event loop once the child
+    /// exits, replacing any callback registered previously.
+    ///
+    /// This allows one task to supervise many children at once, for example
+    /// by having each callback send the id of its child on a shared channel
+    /// and then calling `try_wait` on the child which exited. If the child has
+    /// already exited, the callback is invoked immediately.
+    pub fn on_exit(&mut self, cb: Box<Callback + Send>) {
+        // As with signals, callbacks are run and destroyed outside of the
+        // homing missile.
+        let (exited, _prev) = {
+            let _m = self.fire_homing_missile();
+            let data = self.data();
+            match data.exit_status {
+                Some(..) => (Some(cb), None),
+                None => (None, mem::replace(&mut data.on_exit, Some(cb))),
+            }
+        };
+        match exited {
+            Some(mut cb) => cb.call(),
+            None => {}
         }
     }
 
-    fn wait(&mut self) -> IoResult<rtio::ProcessExit> {
-        // Make sure (on the home scheduler) that we have an exit status listed
+    fn wait_for(&mut self, dur: Option<Duration>) -> UvResult<ProcessExit> {
         let _m = self.fire_homing_missile();
-        match self.exit_status {
-            Some(status) => return Ok(status),
+        let uv_loop = self.handle.uv_loop();
+        let data = self.data();
+        match data.exit_status {
+            Some(ref status) => return Ok(status.clone()),
             None => {}
         }
+        assert!(data.to_wake.is_none());
+
+        let timer = match dur {
+            Some(..) if data.timer.is_none() => {
+                let mut timer = unsafe { try!(raw::Timer::new(&uv_loop)) };
+                timer.set_data(&mut *data as *mut Data as *mut _);
+                data.timer = Some(timer);
+                data.timer
+            }
+            Some(..) => data.timer,
+            None => None,
+        };
+        let ms = dur.map(|d| cmp::max(d.num_milliseconds(), 0) as u64);
 
-        // If there's no exit code previously listed, then the process's exit
-        // callback has yet to be invoked. We just need to deschedule ourselves
-        // and wait to be reawoken.
-        match self.timeout_state {
-            NoTimeout | TimeoutPending => {
-                wait_until_woken_after(&mut self.to_wake, &self.uv_loop(), || {});
+        ::block(uv_loop, |task| {
+            data.to_wake = Some(task);
+            match timer {
+                Some(mut t) => t.start(ms.unwrap(), 0, timer_cb).unwrap(),
+                None => {}
             }
-            TimeoutElapsed => {}
-        }
+        });
 
-        // If there's still no exit status listed, then we timed out, and we
-        // need to return.
-        match self.exit_status {
-            Some(status) => Ok(status),
-            None => Err(uv_error_to_io_error(UvError(uvll::ECANCELED)))
+        match timer {
+            Some(mut timer) => timer.stop().unwrap(),
+            None => {}
+        }
+        match data.exit_status {
+            Some(ref status) => Ok(status.clone()),
+            None => Err(UvError(uvll::ECANCELED)),
         }
     }
 
-    fn set_timeout(&mut self, timeout: Option<u64>) {
-        let _m = self.fire_homing_missile();
-        self.timeout_state = NoTimeout;
-        let ms = match timeout {
-            Some(ms) => ms,
+    /// Gain access to the underlying raw process handle.
+    ///
+    /// This function is unsafe as there is no guarantee that any safe
+    /// modifications to the process handle are actually safe to perform given
+    /// the assumptions of this object.
+    pub unsafe fn raw(&self) -> raw::Process { self.handle }
+
+    fn data(&mut self) -> &mut Data {
+        unsafe { mem::transmute(self.handle.get_data()) }
+    }
+
+    // Sends SIGTERM to the child, and arranges for it to be sent SIGKILL if
+    // it's still running after `grace`. Fails if the child can't be
+    // signalled, in which case it has exited already.
+    fn terminate(&mut self, grace: Duration) -> UvResult<()> {
+        try!(self.handle.kill_me(SIGTERM));
+        let uv_loop = self.handle.uv_loop();
+        let handle = self.handle;
+        let data = self.data();
+        let mut timer = match data.timer {
+            Some(timer) => timer,
             None => {
-                match self.timer {
-                    Some(ref mut timer) => timer.stop(),
-                    None => {}
-                }
-                return
+                let mut timer = unsafe { try!(raw::Timer::new(&uv_loop)) };
+                timer.set_data(&mut *data as *mut Data as *mut _);
+                data.timer = Some(timer);
+                timer
             }
         };
-        if self.timer.is_none() {
-            let loop_ = Loop::wrap(unsafe {
-                uvll::get_loop_for_uv_handle(self.uv_handle())
-            });
-            let mut timer = box TimerWatcher::new_home(&loop_, self.home().clone());
-            unsafe {
-                timer.set_data(self as *mut _);
+        let ms = cmp::max(grace.num_milliseconds(), 0) as u64;
+        try!(timer.start(ms, 0, kill_cb));
+        data.orphan = Some(handle);
+        Ok(())
+    }
+}
+
+extern fn exit_cb(handle: *mut uvll::uv_process_t,
+                  exit_status: i64,
+                  term_signal: libc::c_int) {
+    unsafe {
+        let raw: raw::Process = Handle::from_raw(handle);
+        let data: &mut Data = mem::transmute(raw.get_data());
+        assert!(data.exit_status.is_none());
+        data.exit_status = Some(match term_signal {
+            0 => ExitStatus(exit_status as int),
+            n => ExitSignal(n as int),
+        });
+        if data.to_wake.is_some() {
+            ::wakeup(&mut data.to_wake);
+        }
+        match data.on_exit.take() {
+            Some(mut cb) => {
+                ::watchdog::time(raw.uv_loop(), "exit callback", || cb.call())
+            }
+            None => {}
+        }
+
+        // Nobody is left to close the handle of a child which was killed on
+        // drop, so that's up to us now.
+        match data.orphan.take() {
+            Some(mut handle) => {
+                let mut timer = data.timer.unwrap();
+                timer.stop().unwrap();
+                timer.close_and_free();
+                handle.close_and_free();
+                let _data: Box<Data> = mem::transmute(data);
             }
-            self.timer = Some(timer);
+            None => {}
+        }
+    }
+}
+
+extern fn kill_cb(timer: *mut uvll::uv_timer_t) {
+    unsafe {
+        let raw: raw::Timer = Handle::from_raw(timer);
+        let data: &mut Data = mem::transmute(raw.get_data());
+        let _ = data.orphan.unwrap().kill_me(SIGKILL);
+    }
+}
+
+extern fn timer_cb(timer: *mut uvll::uv_timer_t) {
+    unsafe {
+        let raw: raw::Timer = Handle::from_raw(timer);
+        let data: &mut Data = mem::transmute(raw.get_data());
+        match data.to_wake.take() {
+            Some(task) => task.reawaken(),
+            None => {}
         }
+    }
+}
 
-        let timer = self.timer.get_mut_ref();
-        timer.stop();
-        timer.start(timer_cb, ms, 0);
-        self.timeout_state = TimeoutPending;
+impl Stdio {
+    /// Redirects the stream to `file`, which must stay open until the child
+    /// has been spawned.
+    pub fn file(file: &File) -> Stdio {
+        Stdio::Fd(unsafe { file.fd() })
+    }
+}
 
-        extern fn timer_cb(timer: *mut uvll::uv_timer_t) {
-            let p: &mut Process = unsafe {
-                &mut *(uvll::get_data_for_uv_handle(timer) as *mut Process)
+// Pipes are set up for the child to read stdin and write stdout and stderr,
+// while extra pipes go both ways, as documented on `Stdio::Piped`.
+fn stdio_container(eloop: &mut EventLoop, slot: uint, cfg: &Stdio)
+                   -> UvResult<(raw::Stdio, Option<Pipe>)> {
+    Ok(match *cfg {
+        Stdio::Inherit => (raw::Stdio::InheritFd(slot as libc::c_int), None),
+        Stdio::Null => (raw::Stdio::Ignore, None),
+        Stdio::Fd(fd) => (raw::Stdio::InheritFd(fd), None),
+        Stdio::Piped => {
+            let pipe = try!(pipe::unbound(eloop));
+            let stream = unsafe { pipe.raw().raw() as *mut uvll::uv_stream_t };
+            let (readable, writable) = match slot {
+                0 => (true, false),
+                1 | 2 => (false, true),
+                _ => (true, true),
             };
-            p.timeout_state = TimeoutElapsed;
-            match p.to_wake.take() {
-                Some(task) => { let _t = task.wake().map(|t| t.reawaken()); }
-                None => {}
+            (raw::Stdio::CreatePipe(stream, readable, writable), Some(pipe))
+        }
+    })
+}
+
+#[cfg(unix)]
+mod pty {
+    use std::os;
+    use libc::{c_int, c_char, c_void};
+
+    use {UvResult, UvError};
+    use uvll::fcntl::{fcntl, F_SETFD, FD_CLOEXEC};
+
+    #[cfg(any(target_os = "linux", target_os = "android",
+              target_os = "freebsd", target_os = "dragonfly"))]
+    #[link(name = "util")]
+    extern {}
+
+    extern {
+        fn openpty(master: *mut c_int, slave: *mut c_int, name: *mut c_char,
+                   termp: *const c_void, winp: *const c_void) -> c_int;
+    }
+
+    /// Opens a new pseudo-terminal, returning the master and slave file
+    /// descriptors, neither of which is inherited by child processes.
+    pub fn open() -> UvResult<(c_int, c_int)> {
+        let mut master = -1;
+        let mut slave = -1;
+        unsafe {
+            if openpty(&mut master, &mut slave, 0 as *mut _,
+                       0 as *const _, 0 as *const _) != 0 {
+                return Err(UvError(-(os::errno() as c_int)))
             }
+            fcntl(master, F_SETFD, FD_CLOEXEC);
+            fcntl(slave, F_SETFD, FD_CLOEXEC);
         }
+        Ok((master, slave))
     }
 }
 
+impl HomingIO for Process {
+    fn home(&self) -> &HomeHandle { &self.home }
+}
+
 impl Drop for Process {
     fn drop(&mut self) {
-        let _m = self.fire_homing_missile();
-        assert!(self.to_wake.is_none());
-        self.close();
+        // Closing the handle only stops watching the child, it keeps running,
+        // unless it is to be killed in which case the handle stays open until
+        // the child exits. The exit callback is destroyed outside of the
+        // homing missile.
+        let _data: Option<Box<Data>> = unsafe {
+            let _m = self.fire_homing_missile();
+            assert!(self.data().to_wake.is_none());
+            let kill = match self.kill_on_drop {
+                Some(grace) if self.data().exit_status.is_none() => {
+                    self.terminate(grace).is_ok()
+                }
+                _ => false,
+            };
+            if kill {
+                None
+            } else {
+                match self.data().timer {
+                    Some(mut timer) => timer.close_and_free(),
+                    None => {}
+                }
+                self.handle.close_and_free();
+                Some(mem::transmute(self.handle.get_data()))
+            }
+        };
     }
 }
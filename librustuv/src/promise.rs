@@ -0,0 +1,212 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Non-blocking variants of I/O operations.
+//!
+//! Functions such as `Tcp::read_async`, `fs::stat_async` and `Timer::after`
+//! start an operation and return a `Promise` for its result instead of blocking
+//! the calling task. This allows a single task to have several operations in
+//! flight at once, and to wait for all of them or for the first to complete,
+//! without spawning a task per operation.
+//!
+//! A promise must be waited on by the task which created it. Dropping a promise
+//! doesn't cancel its operation, whose result is then discarded.
+//!
+//! A promise can also be turned into a channel receiver, so that a task can
+//! wait on I/O, timers and channels at once with `select!`:
+//!
+//! ```rust,ignore
+//! let data = try!(stream.read_async(1024)).into_receiver();
+//! let timeout = try!(Timer::after(Duration::seconds(5))).into_receiver();
+//! select! {
+//!     msg = messages.recv() => { /* a message from another task */ },
+//!     data = data.recv() => { /* the stream was read from */ },
+//!     () = timeout.recv() => { /* nothing happened in time */ }
+//! }
+//! ```
+
+use std::mem;
+use std::rt::task::BlockedTask;
+
+use {raw, UvResult, UvError, uvll};
+use homing::{HomingIO, HomeHandle};
+
+/// The eventual result of an I/O operation.
+pub struct Promise<T> {
+    inner: *mut Inner<T>,
+    uv_loop: raw::Loop,
+    home: HomeHandle,
+}
+
+/// The half of a promise held by the operation, which must be completed from
+/// the home event loop of the promise.
+pub struct Completer<T> {
+    inner: *mut Inner<T>,
+}
+
+struct Inner<T> {
+    result: Option<UvResult<T>>,
+    done: bool,
+    // The task waiting on this promise, which may also be waiting on others
+    waiter: Option<BlockedTask>,
+    // Set once the promise has been dropped, after which the completer frees
+    // this structure
+    abandoned: bool,
+    // Where the result is sent once an abandoned promise completes
+    sender: Option<Sender<UvResult<T>>>,
+}
+
+/// Creates a promise homed to `home`, along with the completer for it.
+pub fn new<T: Send>(home: HomeHandle,
+                    uv_loop: raw::Loop) -> (Promise<T>, Completer<T>) {
+    let inner = box Inner {
+        result: None,
+        done: false,
+        waiter: None,
+        abandoned: false,
+        sender: None,
+    };
+    let inner: *mut Inner<T> = unsafe { mem::transmute(inner) };
+    (Promise { inner: inner, uv_loop: uv_loop, home: home },
+     Completer { inner: inner })
+}
+
+impl<T: Send> Promise<T> {
+    /// Returns whether the operation has completed, in which case `wait` won't
+    /// block.
+    pub fn is_ready(&self) -> bool {
+        let _m = self.fire_homing_missile();
+        unsafe { (*self.inner).done }
+    }
+
+    /// Blocks the current task until the operation has completed, returning
+    /// its result.
+    pub fn wait(self) -> UvResult<T> {
+        let _m = self.fire_homing_missile();
+        let inner = unsafe { &mut *self.inner };
+        if !inner.done {
+            ::block(self.uv_loop, |task| {
+                inner.waiter = Some(task);
+            });
+        }
+        inner.result.take().unwrap()
+    }
+
+    /// Blocks the current task until one of `promises` has completed,
+    /// returning its index.
+    ///
+    /// # Failure
+    ///
+    /// This function fails if `promises` is empty or if the promises weren't
+    /// all created on the same event loop.
+    pub fn wait_any(promises: &[Promise<T>]) -> uint {
+        assert!(promises.len() > 0, "waiting on no promises");
+        let uv_loop = promises[0].uv_loop;
+        assert!(promises.iter().all(|p| p.uv_loop.raw() == uv_loop.raw()),
+                "promises created on different event loops");
+
+        let _m = promises[0].fire_homing_missile();
+        let ready = || {
+            promises.iter().position(|p| unsafe { (*p.inner).done })
+        };
+        match ready() {
+            Some(i) => return i,
+            None => {}
+        }
+
+        // Whichever promise completes first wakes us up, the others can't.
+        ::block(uv_loop, |task| {
+            let tasks = task.make_selectable(promises.len());
+            for (p, task) in promises.iter().zip(tasks) {
+                unsafe { (*p.inner).waiter = Some(task) }
+            }
+        });
+        for p in promises.iter() {
+            unsafe { (*p.inner).waiter = None }
+        }
+        ready().unwrap()
+    }
+
+    /// Converts this promise into a receiver which the result is sent on once
+    /// the operation completes.
+    ///
+    /// Unlike `wait`, the receiver can be used from any task, and with
+    /// `select!` to also wait on channels and other promises.
+    pub fn into_receiver(self) -> Receiver<UvResult<T>> {
+        let (tx, rx) = channel();
+        let _m = self.fire_homing_missile();
+        let inner = unsafe { &mut *self.inner };
+        if inner.done {
+            tx.send(inner.result.take().unwrap());
+        } else {
+            inner.sender = Some(tx);
+        }
+        rx
+    }
+
+    /// Waits for all of `promises` to complete, returning their results in
+    /// order.
+    pub fn wait_all(promises: Vec<Promise<T>>) -> Vec<UvResult<T>> {
+        promises.into_iter().map(|p| p.wait()).collect()
+    }
+}
+
+impl<T: Send> HomingIO for Promise<T> {
+    fn home(&self) -> &HomeHandle { &self.home }
+}
+
+#[unsafe_destructor]
+impl<T: Send> Drop for Promise<T> {
+    fn drop(&mut self) {
+        let _m = self.fire_homing_missile();
+        unsafe {
+            if (*self.inner).done {
+                let _inner: Box<Inner<T>> = mem::transmute(self.inner);
+            } else {
+                (*self.inner).abandoned = true;
+            }
+        }
+    }
+}
+
+impl<T: Send> Completer<T> {
+    /// Completes the promise with `result`, waking up its waiter if any.
+    pub fn complete(mut self, result: UvResult<T>) {
+        let inner = mem::replace(&mut self.inner, 0 as *mut _);
+        unsafe {
+            if (*inner).abandoned {
+                let inner: Box<Inner<T>> = mem::transmute(inner);
+                match inner.sender {
+                    Some(ref tx) => { let _ = tx.send_opt(result); }
+                    None => {}
+                }
+                return
+            }
+            (*inner).result = Some(result);
+            (*inner).done = true;
+            match (*inner).waiter.take() {
+                Some(task) => task.reawaken(),
+                None => {}
+            }
+        }
+    }
+}
+
+// An operation which goes away without completing its promise, for example
+// because it couldn't be started, cancels it.
+#[unsafe_destructor]
+impl<T: Send> Drop for Completer<T> {
+    fn drop(&mut self) {
+        if self.inner.is_null() { return }
+        let me = Completer { inner: mem::replace(&mut self.inner,
+                                                 0 as *mut _) };
+        me.complete(Err(UvError(uvll::ECANCELED)));
+    }
+}
@@ -17,6 +17,16 @@
 //! The uv_async_t is stored next to the event loop, so in order to not keep the
 //! event loop alive we use uv_ref and uv_unref in order to control when the
 //! async handle is active or not.
+//!
+//! Wakeups are coalesced: only the first message pushed onto an idle queue
+//! signals the async handle, and the event loop then drains every message
+//! which has been pushed in the meantime in one go.
+//!
+//! Creating, cloning and dropping a `Queue` only touches an atomic count of the
+//! live queues, and the event loop only hears about it when the last one goes
+//! away (at which point it can unref the async handle). Pushing a task is
+//! lock-free, so many tasks on other threads can home onto one loop without
+//! contending with each other.
 
 #![allow(dead_code)]
 
@@ -24,28 +34,50 @@ use std::mem;
 use std::rt::mutex::NativeMutex;
 use std::rt::task::BlockedTask;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUint, SeqCst, Relaxed};
 use std::sync::mpsc_queue as mpsc;
 
 use raw::{Async, Handle, Loop};
 use {uvll, UvResult};
 
-enum Message {
-    Task(BlockedTask),
-    Increment,
-    Decrement,
-}
-
 struct State {
     handle: Async,
     lock: NativeMutex, // see comments in async_cb for why this is needed
-    queue: mpsc::Queue<Message>,
+    queue: mpsc::Queue<BlockedTask>,
+    // Whether the async handle has been signaled and the queue not yet drained
+    pending: AtomicBool,
+    // Whether the event loop has been asked to stop, see `Queue::stop`
+    stop: AtomicBool,
+    // The number of live `Queue`s, which keep the event loop alive
+    handles: AtomicUint,
+    enqueued: AtomicUint,
+    dequeued: AtomicUint,
+    wakeups: AtomicUint,
+    callbacks: AtomicUint,
+}
+
+/// Counters describing the traffic on the queue of tasks sent to an event loop
+/// by other event loops.
+#[deriving(Clone, PartialEq, Show)]
+pub struct QueueStats {
+    /// Number of tasks pushed onto the queue.
+    pub enqueued: uint,
+    /// Number of tasks pushed onto the queue which the event loop has yet to
+    /// pick up.
+    pub depth: uint,
+    /// Number of times the event loop was signaled to drain the queue.
+    pub wakeups: uint,
+    /// Number of times the event loop actually woke up to drain the queue.
+    /// This can be lower than `wakeups` as libuv coalesces signals as well.
+    pub callbacks: uint,
+    /// Number of live handles which can push onto the queue.
+    pub handles: uint,
 }
 
 /// This structure is intended to be stored next to the event loop, and it is
 /// used to create new `Queue` structures.
 pub struct QueuePool {
     state: Arc<State>,
-    refcnt: uint,
 }
 
 /// This type is used to send messages back to the original event loop.
@@ -58,48 +90,51 @@ extern fn async_cb(handle: *mut uvll::uv_async_t) {
     let pool: &mut QueuePool = unsafe { mem::transmute(async.get_data()) };
     let state: &State = &*pool.state;
 
+    state.callbacks.fetch_add(1, Relaxed);
+
+    // Any message pushed after this point needs to signal the handle again.
+    // Messages pushed before this point are picked up by the loop below.
+    state.pending.store(false, SeqCst);
+
     // Remember that there is no guarantee about how many times an async
     // callback is called with relation to the number of sends, so process the
     // entire queue in a loop.
     loop {
         match state.queue.pop() {
-            mpsc::Data(Message::Task(task)) => {
+            mpsc::Data(task) => {
+                state.dequeued.fetch_add(1, Relaxed);
                 let _ = task.wake().map(|t| t.reawaken());
             }
-            mpsc::Data(Message::Increment) => {
-                if pool.refcnt == 0 {
-                    async.uv_ref();
-                }
-                pool.refcnt += 1;
-            },
-            mpsc::Data(Message::Decrement) => {
-                pool.refcnt -= 1;
-                if pool.refcnt == 0 {
-                    async.uv_unref();
-                }
-            },
             mpsc::Empty | mpsc::Inconsistent => break
         };
     }
 
-    // If the refcount is now zero after processing the queue, then there is no
-    // longer a reference on the async handle and it is possible that this event
-    // loop can exit. What we're not guaranteed, however, is that a producer in
-    // the middle of dropping itself is yet done with the handle. It could be
-    // possible that we saw their Decrement message but they have yet to signal
+    if state.stop.swap(false, SeqCst) {
+        async.uv_loop().stop();
+    }
+
+    // If there are no handles left, then there is no longer a reason to keep
+    // the async handle referenced and it is possible that this event loop can
+    // exit. What we're not guaranteed, however, is that the producer which
+    // dropped the last handle is yet done with the async handle. It could be
+    // possible that we saw the count drop to zero but they have yet to signal
     // on the async handle. If we were to return immediately, the entire uv loop
     // could be destroyed meaning the call to uv_async_send would abort()
     //
     // In order to fix this, an OS mutex is used to wait for the other end to
-    // finish before we continue. The drop block on a handle will acquire a
-    // mutex and then drop it after both the push and send have been completed.
-    // If we acquire the mutex here, then we are guaranteed that there are no
-    // longer any senders which are holding on to their handles, so we can
-    // safely allow the event loop to exit.
-    if pool.refcnt == 0 {
+    // finish before we continue. Dropping the last handle acquires the mutex
+    // and only releases it after both the decrement and the send have been
+    // completed. If we acquire the mutex here, then we are guaranteed that
+    // there are no longer any senders which are holding on to their handles,
+    // so we can safely allow the event loop to exit.
+    //
+    // Handles are only created on this event loop (see `QueuePool::queue`),
+    // so the count can't go back up behind our back.
+    if state.handles.load(SeqCst) == 0 {
         unsafe {
             let _l = state.lock.lock();
         }
+        async.uv_unref();
     }
 }
 
@@ -110,9 +145,16 @@ impl QueuePool {
             handle: handle,
             lock: unsafe { NativeMutex::new() },
             queue: mpsc::Queue::new(),
+            pending: AtomicBool::new(false),
+            stop: AtomicBool::new(false),
+            handles: AtomicUint::new(0),
+            enqueued: AtomicUint::new(0),
+            dequeued: AtomicUint::new(0),
+            wakeups: AtomicUint::new(0),
+            callbacks: AtomicUint::new(0),
         });
 
-        let ret = box QueuePool { refcnt: 0, state: state };
+        let ret = box QueuePool { state: state };
 
         handle.uv_unref();
         handle.set_data(&*ret as *const _ as *mut _);
@@ -121,43 +163,84 @@ impl QueuePool {
     }
 
     pub fn queue(&mut self) -> Queue {
-        if self.refcnt == 0 {
-            self.state.handle.uv_ref();
-        }
-        self.refcnt += 1;
+        // uv_ref is idempotent, and if the count just dropped to zero the
+        // async callback which would unref the handle hasn't run yet.
+        self.state.handles.fetch_add(1, SeqCst);
+        self.state.handle.uv_ref();
         Queue { state: self.state.clone() }
     }
 
     pub fn handle(&self) -> Async { self.state.handle }
+
+    pub fn stats(&self) -> QueueStats {
+        let state = &*self.state;
+        let enqueued = state.enqueued.load(Relaxed);
+        let dequeued = state.dequeued.load(Relaxed);
+        QueueStats {
+            enqueued: enqueued,
+            // The two loads above are racy, so don't trust them to be ordered
+            depth: if enqueued > dequeued { enqueued - dequeued } else { 0 },
+            wakeups: state.wakeups.load(Relaxed),
+            callbacks: state.callbacks.load(Relaxed),
+            handles: state.handles.load(Relaxed),
+        }
+    }
+}
+
+impl State {
+    // Signal the event loop to drain the queue, unless it has already been
+    // signaled and hasn't gotten around to it yet. This must be called after
+    // the message has been pushed.
+    fn signal(&self) {
+        if !self.pending.swap(true, SeqCst) {
+            self.wakeups.fetch_add(1, Relaxed);
+            self.handle.send();
+        }
+    }
 }
 
 impl Queue {
     pub fn push(&self, task: BlockedTask) {
-        self.state.queue.push(Message::Task(task));
-        self.state.handle.send();
+        self.state.enqueued.fetch_add(1, Relaxed);
+        self.state.queue.push(task);
+        self.state.signal();
+    }
+
+    /// Stops the event loop as with `EventLoop::stop`, from any thread.
+    pub fn stop(&self) {
+        self.state.stop.store(true, SeqCst);
+        self.state.signal();
     }
 }
 
 impl Clone for Queue {
     fn clone(&self) -> Queue {
-        // Push a request to increment on the queue, but there's no need to
-        // signal the event loop to process it at this time. We're guaranteed
-        // that the count is at least one (because we have a queue right here),
-        // and if the queue is dropped later on it'll see the increment for the
-        // decrement anyway.
-        self.state.queue.push(Message::Increment);
+        // We're guaranteed that the count is at least one (because we have a
+        // queue right here), so the event loop has nothing to do about it.
+        self.state.handles.fetch_add(1, SeqCst);
         Queue { state: self.state.clone() }
     }
 }
 
 impl Drop for Queue {
     fn drop(&mut self) {
+        // Dropping any handle but the last one is of no concern to the event
+        // loop, so only the count is updated.
+        let state = &*self.state;
+        loop {
+            let n = state.handles.load(SeqCst);
+            if n == 1 { break }
+            if state.handles.compare_and_swap(n, n - 1, SeqCst) == n { return }
+        }
+
         // See the comments in the async_cb function for why there is a lock
-        // that is acquired only on a drop.
+        // that is acquired when dropping the last handle. Another handle may
+        // have been cloned in the meantime, in which case it's the last one.
         unsafe {
-            let _l = self.state.lock.lock();
-            self.state.queue.push(Message::Decrement);
-            self.state.handle.send();
+            let _l = state.lock.lock();
+            if state.handles.fetch_sub(1, SeqCst) == 1 {
+                state.signal();
+            }
         }
     }
 }
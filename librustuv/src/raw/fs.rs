@@ -11,7 +11,7 @@
 use std::c_str::CString;
 use std::io;
 use libc;
-use libc::c_int;
+use libc::{c_int, c_uint};
 
 use raw::{Request, Allocated, Loop};
 use {UvResult, UvError, uvll};
@@ -121,6 +121,25 @@ impl Fs {
         }
     }
 
+    /// Same as `read`, but reads into each of `bufs` in turn.
+    pub fn read_vectored(&mut self, uv_loop: Loop, file: c_int,
+                         bufs: &mut [&mut [u8]], offset: i64,
+                         cb: uvll::uv_fs_cb) -> UvResult<()> {
+        // libuv copies the buffer descriptions into the request
+        let bufs: Vec<uvll::uv_buf_t> = bufs.iter_mut().map(|buf| {
+            uvll::uv_buf_t {
+                base: buf.as_mut_ptr(),
+                len: buf.len() as uvll::uv_buf_len_t,
+            }
+        }).collect();
+        unsafe {
+            try!(call!(uvll::uv_fs_read(uv_loop.raw(), self.handle, file,
+                                        bufs.as_ptr(), bufs.len() as c_uint,
+                                        offset, cb)));
+            Ok(())
+        }
+    }
+
     pub fn unlink(&mut self, uv_loop: Loop, path: CString,
                   cb: uvll::uv_fs_cb) -> UvResult<()> {
         unsafe {
@@ -143,6 +162,24 @@ impl Fs {
         }
     }
 
+    /// Same as `write`, but writes each of `bufs` in turn.
+    pub fn write_vectored(&mut self, uv_loop: Loop, file: c_int,
+                          bufs: &[&[u8]], offset: i64,
+                          cb: uvll::uv_fs_cb) -> UvResult<()> {
+        let bufs: Vec<uvll::uv_buf_t> = bufs.iter().map(|buf| {
+            uvll::uv_buf_t {
+                base: buf.as_ptr() as *mut _,
+                len: buf.len() as uvll::uv_buf_len_t,
+            }
+        }).collect();
+        unsafe {
+            try!(call!(uvll::uv_fs_write(uv_loop.raw(), self.handle, file,
+                                         bufs.as_ptr(), bufs.len() as c_uint,
+                                         offset, cb)));
+            Ok(())
+        }
+    }
+
     pub fn mkdir(&mut self, uv_loop: Loop, path: CString, mode: c_int,
                  cb: uvll::uv_fs_cb) -> UvResult<()> {
         unsafe {
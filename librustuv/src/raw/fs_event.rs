@@ -0,0 +1,60 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::c_str::CString;
+use libc;
+
+use raw::{Loop, Handle, Allocated, Raw};
+use {uvll, UvResult};
+
+pub struct FsEvent {
+    handle: *mut uvll::uv_fs_event_t,
+}
+
+impl FsEvent {
+    /// Create a new uv_fs_event_t handle.
+    ///
+    /// This function is unsafe as a successful return value is not
+    /// automatically deallocated.
+    pub unsafe fn new(uv_loop: &Loop) -> UvResult<FsEvent> {
+        let raw = Raw::new();
+        try!(call!(uvll::uv_fs_event_init(uv_loop.raw(), raw.get())));
+        Ok(FsEvent { handle: raw.unwrap() })
+    }
+
+    pub fn start(&mut self, path: CString, flags: libc::c_uint,
+                 cb: uvll::uv_fs_event_cb) -> UvResult<()> {
+        unsafe {
+            try!(call!(uvll::uv_fs_event_start(self.handle, cb,
+                                               path.as_ptr(), flags)));
+            Ok(())
+        }
+    }
+
+    pub fn stop(&mut self) -> UvResult<()> {
+        unsafe {
+            try!(call!(uvll::uv_fs_event_stop(self.handle)));
+            Ok(())
+        }
+    }
+}
+
+impl Allocated for uvll::uv_fs_event_t {
+    fn size(_self: Option<uvll::uv_fs_event_t>) -> uint {
+        unsafe { uvll::uv_handle_size(uvll::UV_FS_EVENT) as uint }
+    }
+}
+
+impl Handle<uvll::uv_fs_event_t> for FsEvent {
+    fn raw(&self) -> *mut uvll::uv_fs_event_t { self.handle }
+    fn from_raw(t: *mut uvll::uv_fs_event_t) -> FsEvent {
+        FsEvent { handle: t }
+    }
+}
@@ -8,6 +8,8 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use libc;
+
 use uvll;
 
 use raw::{Request, Allocated, Loop};
@@ -22,18 +24,20 @@ impl GetAddrInfo {
                 uv_loop: &Loop,
                 node: Option<&str>,
                 service: Option<&str>,
+                hints: Option<&libc::addrinfo>,
                 cb: uvll::uv_getaddrinfo_cb) -> UvResult<()> {
         let node = node.map(|s| s.to_c_str());
         let service = service.map(|s| s.to_c_str());
         let node = node.as_ref().map(|c| c.as_ptr()).unwrap_or(0 as *const _);
         let service = service.as_ref().map(|c| c.as_ptr()).unwrap_or(0 as *const _);
+        let hints = hints.map(|h| h as *const _).unwrap_or(0 as *const _);
         unsafe {
             try!(call!(uvll::uv_getaddrinfo(uv_loop.raw(),
                                             self.handle,
                                             cb,
                                             node,
                                             service,
-                                            0 as *const _)));
+                                            hints)));
         }
         Ok(())
     }
@@ -43,6 +43,17 @@ impl Loop {
         Ok(())
     }
 
+    /// Makes the current or next call to `run` return once the current turn
+    /// of the loop is done.
+    pub fn stop(&mut self) {
+        unsafe { uvll::uv_stop(self.handle) }
+    }
+
+    /// Returns the cached current time of this event loop, in milliseconds.
+    pub fn now(&self) -> u64 {
+        unsafe { uvll::uv_now(self.handle as *const _) }
+    }
+
     pub fn get_data(&mut self) -> *mut libc::c_void {
         unsafe { uvll::rust_uv_get_data_for_uv_loop(self.handle) }
     }
@@ -11,19 +11,24 @@
 use std::io::net::ip;
 use std::mem;
 use std::num::Int;
+#[cfg(unix)] use std::os;
 use std::rt::heap;
 use libc;
 
 use {uvll, UvResult};
 
 pub use self::async::Async;
+pub use self::check::Check;
 pub use self::connect::Connect;
 pub use self::fs::Fs;
+pub use self::fs_event::FsEvent;
 pub use self::getaddrinfo::GetAddrInfo;
 pub use self::idle::Idle;
 pub use self::loop_::Loop;
 pub use self::pipe::Pipe;
-pub use self::process::Process;
+pub use self::poll::Poll;
+pub use self::prepare::Prepare;
+pub use self::process::{Process, ProcessOptions, Stdio};
 pub use self::shutdown::Shutdown;
 pub use self::signal::Signal;
 pub use self::tcp::Tcp;
@@ -41,12 +46,16 @@ macro_rules! call( ($e:expr) => (
 ) )
 
 mod async;
+mod check;
 mod connect;
 mod fs;
+mod fs_event;
 mod getaddrinfo;
 mod idle;
 mod loop_;
 mod pipe;
+mod poll;
+mod prepare;
 mod process;
 mod shutdown;
 mod signal;
@@ -81,6 +90,10 @@ pub trait Handle<T: Allocated> {
         unsafe { uvll::rust_uv_get_data_for_uv_handle(self.raw() as *mut _) }
     }
 
+    fn handle_type(&self) -> uvll::uv_handle_type {
+        unsafe { uvll::rust_uv_handle_type(self.raw() as *mut _) }
+    }
+
     fn set_data(&mut self, data: *mut libc::c_void) {
         unsafe {
             uvll::rust_uv_set_data_for_uv_handle(self.raw() as *mut _, data)
@@ -95,6 +108,11 @@ pub trait Handle<T: Allocated> {
         uvll::uv_close(self.raw() as *mut _, thunk)
     }
 
+    /// Returns whether `close` has been called on this handle.
+    fn is_closing(&self) -> bool {
+        unsafe { uvll::uv_is_closing(self.raw() as *const _) != 0 }
+    }
+
     /// Deallocate this handle.
     ///
     /// This is unsafe as there is no guarantee that no one else is using this
@@ -109,9 +127,53 @@ pub trait Handle<T: Allocated> {
         extern fn done<T: Allocated>(t: *mut uvll::uv_handle_t) {
             unsafe { drop(Raw::wrap(t as *mut T)) }
         }
+        uvtrace!(Debug, Loop, "closing handle {:p}", self.raw());
         self.close(Some(done::<T>))
     }
 
+    /// Returns the platform-dependent file descriptor backing this handle.
+    ///
+    /// Fails with EBADF if the handle has no file descriptor yet (or if it's
+    /// a kind of handle which never has one).
+    fn fileno(&self) -> UvResult<uvll::uv_os_fd_t> {
+        let mut fd = 0 as uvll::uv_os_fd_t;
+        unsafe {
+            try!(call!(uvll::uv_fileno(self.raw() as *const _, &mut fd)));
+        }
+        Ok(fd)
+    }
+
+    /// Sets a socket option on the underlying file descriptor, for options
+    /// which libuv has no function for.
+    #[cfg(unix)]
+    fn set_sockopt<V>(&mut self, level: libc::c_int, name: libc::c_int,
+                      val: V) -> UvResult<()> {
+        let fd = try!(self.fileno());
+        let len = mem::size_of::<V>() as libc::socklen_t;
+        unsafe {
+            let ret = libc::setsockopt(fd, level, name,
+                                       &val as *const V as *const _, len);
+            if ret != 0 { return Err(::UvError(-(os::errno() as libc::c_int))) }
+        }
+        Ok(())
+    }
+
+    /// Reads a socket option from the underlying file descriptor.
+    #[cfg(unix)]
+    fn get_sockopt<V>(&self, level: libc::c_int,
+                      name: libc::c_int) -> UvResult<V> {
+        let fd = try!(self.fileno());
+        unsafe {
+            let mut val: V = mem::zeroed();
+            let mut len = mem::size_of::<V>() as libc::socklen_t;
+            let ret = libc::getsockopt(fd, level, name,
+                                       &mut val as *mut V as *mut _,
+                                       &mut len);
+            if ret != 0 { return Err(::UvError(-(os::errno() as libc::c_int))) }
+            Ok(val)
+        }
+    }
+
     fn uv_ref(&self) { unsafe { uvll::uv_ref(self.raw() as *mut _) } }
     fn uv_unref(&self) { unsafe { uvll::uv_unref(self.raw() as *mut _) } }
 }
@@ -187,6 +249,36 @@ pub trait Stream<T: Allocated>: Handle<T> {
             Ok(())
         }
     }
+
+    /// Writes as much of `buf` as possible without blocking, returning the
+    /// number of bytes written.
+    ///
+    /// Fails with EAGAIN if nothing could be written immediately.
+    fn try_write(&mut self, buf: &[u8]) -> UvResult<uint> {
+        unsafe {
+            let buf = slice_to_uv_buf(buf);
+            let n = try!(call!(uvll::uv_try_write(self.raw() as *mut _,
+                                                  &buf, 1)));
+            Ok(n as uint)
+        }
+    }
+
+    /// Returns the number of bytes queued up for writing on this stream.
+    fn write_queue_size(&self) -> uint {
+        unsafe {
+            uvll::rust_uv_get_write_queue_size(self.raw() as *mut _) as uint
+        }
+    }
+
+    /// Returns whether this stream can still be read from.
+    fn is_readable(&self) -> bool {
+        unsafe { uvll::uv_is_readable(self.raw() as *const _) != 0 }
+    }
+
+    /// Returns whether this stream can still be written to.
+    fn is_writable(&self) -> bool {
+        unsafe { uvll::uv_is_writable(self.raw() as *const _) != 0 }
+    }
 }
 
 impl<T: Allocated> Raw<T> {
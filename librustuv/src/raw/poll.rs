@@ -0,0 +1,58 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use libc;
+
+use raw::{Loop, Handle, Allocated, Raw};
+use {uvll, UvResult};
+
+pub struct Poll {
+    handle: *mut uvll::uv_poll_t,
+}
+
+impl Poll {
+    /// Create a new uv_poll_t handle watching `socket`.
+    ///
+    /// This function is unsafe as a successful return value is not
+    /// automatically deallocated.
+    pub unsafe fn new(uv_loop: &Loop,
+                      socket: uvll::uv_os_socket_t) -> UvResult<Poll> {
+        let raw = Raw::new();
+        try!(call!(uvll::uv_poll_init_socket(uv_loop.raw(), raw.get(),
+                                             socket)));
+        Ok(Poll { handle: raw.unwrap() })
+    }
+
+    pub fn start(&mut self, events: libc::c_int,
+                 cb: uvll::uv_poll_cb) -> UvResult<()> {
+        unsafe {
+            try!(call!(uvll::uv_poll_start(self.handle, events, cb)));
+            Ok(())
+        }
+    }
+
+    pub fn stop(&mut self) -> UvResult<()> {
+        unsafe {
+            try!(call!(uvll::uv_poll_stop(self.handle)));
+            Ok(())
+        }
+    }
+}
+
+impl Allocated for uvll::uv_poll_t {
+    fn size(_self: Option<uvll::uv_poll_t>) -> uint {
+        unsafe { uvll::uv_handle_size(uvll::UV_POLL) as uint }
+    }
+}
+
+impl Handle<uvll::uv_poll_t> for Poll {
+    fn raw(&self) -> *mut uvll::uv_poll_t { self.handle }
+    fn from_raw(t: *mut uvll::uv_poll_t) -> Poll { Poll { handle: t } }
+}
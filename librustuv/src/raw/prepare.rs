@@ -0,0 +1,51 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use uvll;
+
+use raw::{Loop, Handle, Allocated, Raw};
+use UvResult;
+
+pub struct Prepare {
+    handle: *mut uvll::uv_prepare_t,
+}
+
+impl Prepare {
+    /// Create a new uv_prepare_t handle.
+    ///
+    /// This function is unsafe as a successful return value is not
+    /// automatically deallocated.
+    pub unsafe fn new(uv_loop: &Loop) -> UvResult<Prepare> {
+        let raw = Raw::new();
+        try!(call!(uvll::uv_prepare_init(uv_loop.raw(), raw.get())));
+        Ok(Prepare { handle: raw.unwrap() })
+    }
+
+    pub fn start(&mut self, f: uvll::uv_prepare_cb) -> UvResult<()> {
+        unsafe { try!(call!(uvll::uv_prepare_start(self.handle, f))); }
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> UvResult<()> {
+        unsafe { try!(call!(uvll::uv_prepare_stop(self.handle))); }
+        Ok(())
+    }
+}
+
+impl Allocated for uvll::uv_prepare_t {
+    fn size(_self: Option<uvll::uv_prepare_t>) -> uint {
+        unsafe { uvll::uv_handle_size(uvll::UV_PREPARE) as uint }
+    }
+}
+
+impl Handle<uvll::uv_prepare_t> for Prepare {
+    fn raw(&self) -> *mut uvll::uv_prepare_t { self.handle }
+    fn from_raw(t: *mut uvll::uv_prepare_t) -> Prepare { Prepare { handle: t } }
+}
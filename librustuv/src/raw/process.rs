@@ -8,6 +8,10 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::c_str::CString;
+use std::mem;
+use std::ptr;
+use std::slice;
 use libc;
 
 use raw::{Loop, Handle, Allocated, Raw};
@@ -17,7 +21,70 @@ pub struct Process {
     handle: *mut uvll::uv_process_t,
 }
 
+/// The configuration of one stdio stream of a child process, see
+/// `uv_stdio_container_t`.
+pub enum Stdio {
+    /// The descriptor is closed in the child.
+    Ignore,
+    /// The child inherits this file descriptor of the parent.
+    InheritFd(libc::c_int),
+    /// The child inherits the file descriptor of this stream.
+    InheritStream(*mut uvll::uv_stream_t),
+    /// The child is connected to this stream, which must be an unopened
+    /// `uv_pipe_t`. The flags are whether the child can read from and write to
+    /// the pipe, respectively.
+    CreatePipe(*mut uvll::uv_stream_t, bool, bool),
+}
+
+/// The options used to spawn a process, see `uv_process_options_t`.
+pub struct ProcessOptions<'a> {
+    /// Invoked on the event loop once the process exits.
+    pub exit_cb: uvll::uv_exit_cb,
+    /// The program to run, searched for in `PATH` if it contains no slashes.
+    pub file: &'a CString,
+    /// The full argument vector, conventionally starting with the program
+    /// name.
+    pub args: &'a [CString],
+    /// The environment of the child, as `KEY=VALUE` strings. The child
+    /// inherits the environment of this process if this is `None`.
+    pub env: Option<&'a [CString]>,
+    /// The working directory of the child, defaulting to that of this process.
+    pub cwd: Option<&'a CString>,
+    /// A combination of the `uvll::PROCESS_*` flags.
+    pub flags: libc::c_int,
+    /// The stdio streams of the child, starting with stdin.
+    pub stdio: &'a [Stdio],
+    /// The user and group to run as, if the `PROCESS_SETUID` and
+    /// `PROCESS_SETGID` flags are set.
+    pub uid: uvll::uv_uid_t,
+    pub gid: uvll::uv_gid_t,
+}
+
+impl<'a> ProcessOptions<'a> {
+    /// Creates options to run `file` without any arguments (so the argument
+    /// vector is just `[file]`), inheriting the environment and working
+    /// directory but none of the stdio streams of this process.
+    pub fn new(file: &'a CString, exit_cb: uvll::uv_exit_cb)
+               -> ProcessOptions<'a> {
+        ProcessOptions {
+            exit_cb: exit_cb,
+            file: file,
+            args: slice::ref_slice(file),
+            env: None,
+            cwd: None,
+            flags: 0,
+            stdio: &[],
+            uid: 0,
+            gid: 0,
+        }
+    }
+}
+
 impl Process {
+    /// Spawn a new process with the given uv_process_options_t.
+    ///
+    /// This function is unsafe as a successful return value is not
+    /// automatically deallocated, and `opts` is not validated.
     pub unsafe fn spawn(uv_loop: &Loop, opts: *mut uvll::uv_process_options_t)
                         -> UvResult<Process> {
         let raw = Raw::new();
@@ -25,6 +92,44 @@ impl Process {
         Ok(Process { handle: raw.unwrap() })
     }
 
+    /// Spawn a new process configured by `opts`.
+    ///
+    /// This function is unsafe as a successful return value is not
+    /// automatically deallocated.
+    pub unsafe fn spawn_with(uv_loop: &Loop, opts: &ProcessOptions)
+                             -> UvResult<Process> {
+        let mut args: Vec<*const libc::c_char> =
+            opts.args.iter().map(|a| a.as_ptr()).collect();
+        args.push(ptr::null());
+        let env: Option<Vec<*const libc::c_char>> = opts.env.map(|env| {
+            let mut env: Vec<_> = env.iter().map(|e| e.as_ptr()).collect();
+            env.push(ptr::null());
+            env
+        });
+        let mut stdio: Vec<uvll::uv_stdio_container_t> =
+            opts.stdio.iter().map(|s| s.to_container()).collect();
+
+        let mut options = uvll::uv_process_options_t {
+            exit_cb: opts.exit_cb,
+            file: opts.file.as_ptr(),
+            args: args.as_ptr(),
+            env: match env {
+                Some(ref env) => env.as_ptr(),
+                None => ptr::null(),
+            },
+            cwd: match opts.cwd {
+                Some(cwd) => cwd.as_ptr(),
+                None => ptr::null(),
+            },
+            flags: opts.flags as libc::c_uint,
+            stdio_count: stdio.len() as libc::c_int,
+            stdio: stdio.as_mut_ptr(),
+            uid: opts.uid,
+            gid: opts.gid,
+        };
+        Process::spawn(uv_loop, &mut options)
+    }
+
     pub fn pid(&self) -> libc::c_int {
         unsafe { uvll::rust_uv_process_pid(self.handle) }
     }
@@ -36,6 +141,7 @@ impl Process {
         }
     }
 
+    /// Sends `signum` to this process, see `uv_process_kill`.
     pub fn kill_me(&mut self, signum: libc::c_int) -> UvResult<()> {
         unsafe {
             try!(call!(uvll::uv_process_kill(self.handle, signum)));
@@ -44,6 +150,44 @@ impl Process {
     }
 }
 
+impl Stdio {
+    /// Converts this configuration to the container passed to libuv.
+    pub fn to_container(&self) -> uvll::uv_stdio_container_t {
+        let (flags, fd, stream) = match *self {
+            Stdio::Ignore => (uvll::STDIO_IGNORE, None, None),
+            Stdio::InheritFd(fd) => (uvll::STDIO_INHERIT_FD, Some(fd), None),
+            Stdio::InheritStream(stream) => {
+                (uvll::STDIO_INHERIT_STREAM, None, Some(stream))
+            }
+            Stdio::CreatePipe(stream, readable, writable) => {
+                let mut flags = uvll::STDIO_CREATE_PIPE;
+                if readable {
+                    flags |= uvll::STDIO_READABLE_PIPE;
+                }
+                if writable {
+                    flags |= uvll::STDIO_WRITABLE_PIPE;
+                }
+                (flags, None, Some(stream))
+            }
+        };
+        unsafe {
+            let mut ret: uvll::uv_stdio_container_t = mem::zeroed();
+            let dst = &mut ret as *mut _;
+            uvll::rust_set_stdio_container_flags(dst, flags);
+            match fd {
+                Some(fd) => uvll::rust_set_stdio_container_fd(dst, fd),
+                None => {}
+            }
+            match stream {
+                Some(stream) => uvll::rust_set_stdio_container_stream(dst,
+                                                                      stream),
+                None => {}
+            }
+            ret
+        }
+    }
+}
+
 impl Allocated for uvll::uv_process_t {
     fn size(_self: Option<uvll::uv_process_t>) -> uint {
         unsafe { uvll::uv_handle_size(uvll::UV_PROCESS) as uint }
@@ -30,6 +30,27 @@ impl Write {
             Ok(())
         }
     }
+
+    /// Moves the unwritten part of this pending write into a new buffer, which
+    /// the write then continues from.
+    ///
+    /// This allows the buffer originally passed to `send` to be freed before
+    /// the write completes. The returned vector must be kept alive until then.
+    #[cfg(unix)]
+    pub unsafe fn detach_buf(&mut self) -> Vec<u8> {
+        let buf = uvll::rust_uv_get_buf_from_write_req(self.handle);
+        let data = Vec::from_raw_buf((*buf).base as *const u8,
+                                     (*buf).len as uint);
+        (*buf).base = data.as_ptr() as *mut u8;
+        data
+    }
+
+    // The buffer of a write is handed straight to the kernel on windows, so
+    // it can't be swapped out while the write is pending.
+    #[cfg(windows)]
+    pub unsafe fn detach_buf(&mut self) -> Vec<u8> {
+        panic!("cannot detach the buffer of a pending write on windows")
+    }
 }
 
 impl Allocated for uvll::uv_write_t {
@@ -0,0 +1,280 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Waiting for any of several handles to become ready.
+//!
+//! A `Selector` lets a single task wait until any of a set of TCP, pipe or UDP
+//! handles is readable or writable, after which I/O on the ready handles
+//! completes without blocking for long. This allows serving many connections
+//! from one task, in the style of mio, instead of dedicating a task to each of
+//! them.
+//!
+//! Readability is detected by starting a read of no bytes on each handle, so
+//! that libuv itself reports when the handle is readable without consuming
+//! anything; the handle's file descriptor is never watched separately from
+//! the handle. Writability is reported right away, as writes are queued by
+//! libuv rather than failing on a full socket. Data which a stream has
+//! already buffered (see `Buffer`) or a datagram returned by `peek_from`
+//! doesn't make a handle readable. Selectors are only available on unix, as
+//! libuv on windows can't start a read of no bytes.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let mut selector = try!(Selector::new());
+//! try!(selector.register(&conn1, 1, Interest::Readable));
+//! try!(selector.register(&conn2, 2, Interest::Readable));
+//! for event in try!(selector.select(None)).iter() {
+//!     // read from the connection identified by `event.token`
+//! }
+//! ```
+
+use std::mem;
+use std::rt::task::BlockedTask;
+use std::time::Duration;
+use green::Callback;
+
+use {raw, uvll, EventLoop, UvResult, UvError};
+use homing::{HomingIO, HomeHandle};
+use raw::Handle;
+
+/// The readiness a handle is registered for.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Interest {
+    Readable,
+    Writable,
+    Both,
+}
+
+/// A handle which became ready, identified by the token it was registered
+/// with.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Event {
+    pub token: uint,
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// A handle which can be registered with a `Selector`.
+///
+/// All methods are called on the event loop of the selector.
+pub trait Selectable {
+    /// Returns whether the handle's home is the current event loop.
+    fn is_local(&self) -> bool;
+
+    /// Starts waiting for the handle to become readable, invoking `cb` once
+    /// it is without reading anything from the handle.
+    ///
+    /// Fails with EBUSY if a read of the handle is already pending.
+    fn start_watch(&mut self, cb: Box<Callback + Send>) -> UvResult<()>;
+
+    /// Stops waiting for the handle to become readable, if it still is.
+    fn stop_watch(&mut self);
+}
+
+/// Waits for any of a set of handles to become ready.
+pub struct Selector {
+    entries: Vec<Box<Entry>>,
+    state: Box<State>,
+    timer: raw::Timer,
+    home: HomeHandle,
+}
+
+struct Entry {
+    token: uint,
+    interest: Interest,
+    handle: Box<Selectable + Send>,
+    state: *mut State,
+}
+
+struct State {
+    events: Vec<Event>,
+    waiter: Option<BlockedTask>,
+}
+
+// Notifies the selector that the handle of an entry is readable.
+struct Notify {
+    entry: *mut Entry,
+}
+
+impl Selector {
+    /// Creates a selector on the local event loop.
+    pub fn new() -> UvResult<Selector> {
+        Selector::new_on(&mut *try!(EventLoop::borrow()))
+    }
+
+    /// Same as `new`, but specifies what event loop to be created on.
+    pub fn new_on(eloop: &mut EventLoop) -> UvResult<Selector> {
+        let mut ret = Selector {
+            entries: Vec::new(),
+            state: box State { events: Vec::new(), waiter: None },
+            timer: unsafe { try!(raw::Timer::new(&eloop.uv_loop())) },
+            home: eloop.make_handle(),
+        };
+        let data = &mut *ret.state as *mut State as *mut _;
+        ret.timer.set_data(data);
+        Ok(ret)
+    }
+
+    /// Starts watching `handle` for `interest`, reporting its events with
+    /// `token`. The selector keeps a clone of `handle`.
+    ///
+    /// Fails with EEXIST if `token` is already registered, and with EINVAL if
+    /// `handle` doesn't live on the event loop of the selector.
+    pub fn register<H>(&mut self, handle: &H, token: uint,
+                       interest: Interest) -> UvResult<()>
+                       where H: Selectable + Clone + Send {
+        let _m = self.fire_homing_missile();
+        if self.position(token).is_some() {
+            return Err(UvError(uvll::EEXIST))
+        }
+        if !handle.is_local() { return Err(UvError(uvll::EINVAL)) }
+        self.entries.push(box Entry {
+            token: token,
+            interest: interest,
+            handle: box handle.clone() as Box<Selectable + Send>,
+            state: &mut *self.state as *mut State,
+        });
+        Ok(())
+    }
+
+    /// Changes the interest of the handle registered with `token`.
+    ///
+    /// Fails with ENOENT if `token` isn't registered.
+    pub fn reregister(&mut self, token: uint,
+                      interest: Interest) -> UvResult<()> {
+        let _m = self.fire_homing_missile();
+        match self.position(token) {
+            Some(i) => { self.entries[mut][i].interest = interest; Ok(()) }
+            None => Err(UvError(uvll::ENOENT)),
+        }
+    }
+
+    /// Stops watching the handle registered with `token`.
+    ///
+    /// Fails with ENOENT if `token` isn't registered.
+    pub fn deregister(&mut self, token: uint) -> UvResult<()> {
+        let _m = self.fire_homing_missile();
+        match self.position(token) {
+            Some(i) => { self.entries.remove(i); Ok(()) }
+            None => Err(UvError(uvll::ENOENT)),
+        }
+    }
+
+    /// Blocks the current task until at least one registered handle is
+    /// ready, returning the events of every ready handle.
+    ///
+    /// If `timeout` elapses first, an empty list is returned. Fails with
+    /// EBUSY if a handle registered as readable is being read from.
+    pub fn select(&mut self, timeout: Option<Duration>) -> UvResult<Vec<Event>> {
+        let _m = self.fire_homing_missile();
+        assert!(self.state.events.is_empty());
+
+        // Watches only run while a task is selecting, as they would otherwise
+        // keep firing for handles which stay ready.
+        let mut ret = Ok(());
+        for entry in self.entries.iter_mut() {
+            if entry.interest != Interest::Readable {
+                push(&mut *self.state, entry.token, false, true);
+            }
+            if entry.interest != Interest::Writable {
+                let notify = box Notify { entry: &mut **entry as *mut Entry };
+                ret = entry.handle.start_watch(notify as Box<Callback + Send>);
+                if ret.is_err() { break }
+            }
+        }
+
+        // With writable handles the event loop is only given a turn, to pick
+        // up the handles which are readable as well.
+        let ms = if self.state.events.is_empty() {
+            timeout.map(|dur| {
+                let ms = dur.num_milliseconds();
+                if ms < 0 { 0 } else { ms as u64 }
+            })
+        } else {
+            Some(0)
+        };
+        match ms {
+            Some(ms) => ret = ret.and(self.timer.start(ms, 0, timer_cb)),
+            None => {}
+        }
+
+        if ret.is_ok() {
+            let state = &mut *self.state;
+            ::block(self.timer.uv_loop(), |task| {
+                state.waiter = Some(task);
+            });
+        }
+        for entry in self.entries.iter_mut() {
+            entry.handle.stop_watch();
+        }
+        self.timer.stop().unwrap();
+        self.state.waiter = None;
+
+        let events = mem::replace(&mut self.state.events, Vec::new());
+        ret.map(|()| events)
+    }
+
+    fn position(&self, token: uint) -> Option<uint> {
+        self.entries.iter().position(|e| e.token == token)
+    }
+}
+
+// Records an event, merging it with an earlier one of the same handle.
+fn push(state: &mut State, token: uint, readable: bool, writable: bool) {
+    match state.events.iter_mut().find(|e| e.token == token) {
+        Some(event) => {
+            event.readable |= readable;
+            event.writable |= writable;
+            return
+        }
+        None => {}
+    }
+    state.events.push(Event {
+        token: token,
+        readable: readable,
+        writable: writable,
+    });
+}
+
+impl Callback for Notify {
+    fn call(&mut self) {
+        unsafe {
+            let entry = &*self.entry;
+            let state = &mut *entry.state;
+            push(state, entry.token, true, false);
+            if state.waiter.is_some() {
+                ::wakeup(&mut state.waiter);
+            }
+        }
+    }
+}
+
+extern fn timer_cb(handle: *mut uvll::uv_timer_t) {
+    unsafe {
+        let timer: raw::Timer = Handle::from_raw(handle);
+        let state: &mut State = mem::transmute(timer.get_data());
+        if state.waiter.is_some() {
+            ::wakeup(&mut state.waiter);
+        }
+    }
+}
+
+impl HomingIO for Selector {
+    fn home(&self) -> &HomeHandle { &self.home }
+}
+
+impl Drop for Selector {
+    fn drop(&mut self) {
+        let _m = self.fire_homing_missile();
+        self.entries.clear();
+        unsafe { self.timer.close_and_free() }
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::mem;
+use std::time::Duration;
+use green::sync::{Mutex, Condvar};
+
+use {raw, uvll, EventLoop, UvResult, UvError};
+use homing::HomingIO;
+use raw::Handle;
+
+/// A counting semaphore which blocks green tasks.
+///
+/// This is typically used to cap how many tasks perform an operation at once,
+/// for example how many filesystem operations are queued on the threadpool or
+/// how many outbound connections are being made. Waiting for a permit only
+/// deschedules the waiting task, as the semaphore is built on the `Mutex` and
+/// `Condvar` of `green::sync`, and waiting tasks are woken up in the order in
+/// which they started waiting.
+///
+/// A semaphore can be shared among tasks on any event loop, for example by
+/// placing it in an `Arc`.
+pub struct Semaphore {
+    permits: Mutex<uint>,
+    cond: Condvar,
+}
+
+/// A permit acquired from a `Semaphore`, which is released when the guard
+/// goes out of scope.
+pub struct SemaphoreGuard<'a> {
+    sem: &'a Semaphore,
+}
+
+// A task waiting for a permit with a timeout
+struct Waiter<'a> {
+    sem: &'a Semaphore,
+    timed_out: bool,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` permits available.
+    pub fn new(permits: uint) -> Semaphore {
+        Semaphore { permits: Mutex::new(permits), cond: Condvar::new() }
+    }
+
+    /// Acquires a permit, blocking the current task until one is available.
+    pub fn acquire<'a>(&'a self) -> UvResult<SemaphoreGuard<'a>> {
+        let mut permits = self.permits.lock();
+        while *permits == 0 {
+            self.cond.wait(&permits);
+        }
+        *permits -= 1;
+        Ok(SemaphoreGuard { sem: self })
+    }
+
+    /// Acquires a permit, blocking the current task for at most `dur`.
+    ///
+    /// Fails with ETIMEDOUT if no permit became available in time.
+    pub fn acquire_timeout<'a>(&'a self,
+                               dur: Duration) -> UvResult<SemaphoreGuard<'a>> {
+        match self.try_acquire() {
+            Some(guard) => return Ok(guard),
+            None => {}
+        }
+
+        let (uv_loop, home) = {
+            let mut eloop = try!(EventLoop::borrow());
+            (eloop.uv_loop(), eloop.make_handle())
+        };
+        let _m = home.fire_homing_missile();
+        let mut waiter = box Waiter { sem: self, timed_out: false };
+        let mut timer = unsafe { try!(raw::Timer::new(&uv_loop)) };
+        timer.set_data(&mut *waiter as *mut Waiter as *mut _);
+        let ms = dur.num_milliseconds();
+        let ms = if ms < 0 { 0 } else { ms as u64 };
+        let ret = timer.start(ms, 0, timer_cb).and_then(|()| {
+            // A permit released after the timeout still goes to this task if
+            // it gets to check before giving up.
+            let mut permits = self.permits.lock();
+            loop {
+                if *permits > 0 {
+                    *permits -= 1;
+                    return Ok(SemaphoreGuard { sem: self })
+                }
+                if waiter.timed_out { return Err(UvError(uvll::ETIMEDOUT)) }
+                self.cond.wait(&permits);
+            }
+        });
+        unsafe { timer.close_and_free() }
+        ret
+    }
+
+    /// Acquires a permit if one is available, without blocking.
+    pub fn try_acquire<'a>(&'a self) -> Option<SemaphoreGuard<'a>> {
+        let mut permits = self.permits.lock();
+        if *permits == 0 { return None }
+        *permits -= 1;
+        Some(SemaphoreGuard { sem: self })
+    }
+
+    /// Returns the number of permits currently available.
+    pub fn available(&self) -> uint {
+        *self.permits.lock()
+    }
+
+    fn release(&self) {
+        *self.permits.lock() += 1;
+        self.cond.notify_one();
+    }
+}
+
+// Every waiter is woken up so that this one notices that it timed out. The
+// others go back to waiting, as for any spurious wakeup.
+extern fn timer_cb(handle: *mut uvll::uv_timer_t) {
+    unsafe {
+        let timer: raw::Timer = Handle::from_raw(handle);
+        let waiter: &mut Waiter = mem::transmute(timer.get_data());
+        waiter.timed_out = true;
+        waiter.sem.cond.notify_all();
+    }
+}
+
+#[unsafe_destructor]
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        self.sem.release();
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Graceful shutdown of the local event loop.
+//!
+//! A server shutting down first stops accepting new connections, then gives
+//! the requests in flight some time to complete, and finally gives up on the
+//! ones which didn't. A `Shutdown` runs this sequence: the listeners it was
+//! given are closed, the task waits until no other task of the event loop is
+//! blocked on it anymore or the grace period is over, and any connection
+//! still in use is then cancelled.
+//!
+//! # Example
+//!
+//! ```rust,ignore
+//! let mut shutdown = Shutdown::new();
+//! shutdown.listener(box acceptor.clone());
+//! for conn in conns.iter() {
+//!     shutdown.connection(box conn.clone());
+//! }
+//! try!(shutdown.run(Duration::seconds(30)));
+//! ```
+
+use std::cmp;
+use std::time::Duration;
+
+use {EventLoop, UvResult, Tcp, TcpAcceptor, Pipe, PipeAcceptor, Udp};
+use timer;
+
+/// Something a `Shutdown` stops: a listener to stop accepting on, or a
+/// connection to cancel the pending I/O of.
+pub trait Cancel {
+    fn cancel(&mut self) -> UvResult<()>;
+}
+
+/// The stages of a shutdown, as reported to its `Progress` hook.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Stage {
+    /// The listeners have been closed and the given number of tasks are still
+    /// blocked on the event loop. This is reported whenever the number changes.
+    Draining(uint),
+    /// The grace period is over with the given number of tasks still blocked,
+    /// and the connections are being cancelled.
+    Cancelling(uint),
+    /// The shutdown is complete.
+    Done,
+}
+
+/// A hook told about the progress of a shutdown.
+pub trait Progress {
+    fn progress(&mut self, stage: Stage);
+}
+
+/// Coordinates the graceful shutdown of the local event loop.
+pub struct Shutdown {
+    listeners: Vec<Box<Cancel + Send>>,
+    connections: Vec<Box<Cancel + Send>>,
+    progress: Option<Box<Progress + Send>>,
+    interval: Duration,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown {
+            listeners: Vec::new(),
+            connections: Vec::new(),
+            progress: None,
+            interval: Duration::milliseconds(10),
+        }
+    }
+
+    /// Adds a listener which is closed as soon as the shutdown starts.
+    pub fn listener(&mut self, listener: Box<Cancel + Send>) {
+        self.listeners.push(listener);
+    }
+
+    /// Adds a connection which is cancelled if tasks are still blocked once
+    /// the grace period is over.
+    pub fn connection(&mut self, conn: Box<Cancel + Send>) {
+        self.connections.push(conn);
+    }
+
+    /// Sets the hook which is told about the progress of the shutdown.
+    pub fn on_progress(&mut self, progress: Box<Progress + Send>) {
+        self.progress = Some(progress);
+    }
+
+    /// Sets how often the number of blocked tasks is checked while draining,
+    /// which is every 10ms by default.
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Runs the shutdown, waiting at most `grace` for blocked tasks to finish
+    /// before cancelling the connections.
+    ///
+    /// This must be called from a task of the event loop being shut down, and
+    /// blocks that task until the shutdown is complete. Errors cancelling the
+    /// connections are ignored, as they have usually been closed already.
+    pub fn run(mut self, grace: Duration) -> UvResult<()> {
+        for listener in self.listeners.iter_mut() {
+            try!(listener.cancel());
+        }
+
+        let grace = cmp::max(grace.num_milliseconds(), 0) as u64;
+        let deadline = try!(now()) + grace;
+        let mut last = None;
+        loop {
+            let blocked = try!(EventLoop::borrow()).blocked_tasks();
+            if blocked == 0 { break }
+            if last != Some(blocked) {
+                self.report(Stage::Draining(blocked));
+                last = Some(blocked);
+            }
+
+            let now = try!(now());
+            if now >= deadline {
+                self.report(Stage::Cancelling(blocked));
+                for conn in self.connections.iter_mut() {
+                    let _ = conn.cancel();
+                }
+                break
+            }
+            let left = Duration::milliseconds((deadline - now) as i64);
+            try!(timer::sleep(cmp::min(self.interval, left)));
+        }
+        self.report(Stage::Done);
+        Ok(())
+    }
+
+    fn report(&mut self, stage: Stage) {
+        match self.progress {
+            Some(ref mut progress) => progress.progress(stage),
+            None => {}
+        }
+    }
+}
+
+fn now() -> UvResult<u64> {
+    let eloop = try!(EventLoop::borrow());
+    Ok(unsafe { eloop.uv_loop() }.now())
+}
+
+impl Cancel for TcpAcceptor {
+    fn cancel(&mut self) -> UvResult<()> { self.close_accept() }
+}
+
+impl Cancel for PipeAcceptor {
+    fn cancel(&mut self) -> UvResult<()> { self.close_accept() }
+}
+
+impl Cancel for Tcp {
+    fn cancel(&mut self) -> UvResult<()> { self.cancel_io() }
+}
+
+impl Cancel for Pipe {
+    fn cancel(&mut self) -> UvResult<()> { self.cancel_io() }
+}
+
+impl Cancel for Udp {
+    fn cancel(&mut self) -> UvResult<()> { self.cancel_io() }
+}
@@ -9,13 +9,16 @@
 // except according to those terms.
 
 use std::mem;
+use std::rt::task::BlockedTask;
+use std::time::Duration;
 use libc;
 
 use green::Callback;
 
-use {raw, uvll, EventLoop, UvResult};
+use {event_loop, raw, uvll, wheel, EventLoop, UvResult, UvError};
 use raw::Handle;
 use homing::{HomingIO, HomeHandle};
+use wheel::TimerWheel;
 
 pub struct Signal {
     handle: raw::Signal,
@@ -26,6 +29,14 @@ struct Data {
     callback: Option<Box<Callback + Send>>,
 }
 
+// A task blocked in `Signal::wait`
+struct Waiter {
+    handle: raw::Signal,
+    task: Option<BlockedTask>,
+    timeout: Option<(*mut TimerWheel, wheel::Timeout)>,
+    timed_out: bool,
+}
+
 impl Signal {
     pub fn new() -> UvResult<Signal> {
         Signal::new_on(&mut *try!(EventLoop::borrow()))
@@ -43,15 +54,94 @@ impl Signal {
         }
     }
 
+    /// Returns the range of realtime signal numbers, `SIGRTMIN` to `SIGRTMAX`
+    /// inclusive, or `None` if the platform has no realtime signals.
+    ///
+    /// On Linux the range is determined at runtime, as the C library may
+    /// reserve some of the lowest realtime signals for itself.
+    pub fn realtime_range() -> Option<(libc::c_int, libc::c_int)> {
+        realtime_range()
+    }
+
+    /// Returns the number of the realtime signal `SIGRTMIN + n`.
+    ///
+    /// Fails with EINVAL if the platform has no realtime signals or if the
+    /// signal would be beyond `SIGRTMAX`.
+    pub fn realtime(n: uint) -> UvResult<libc::c_int> {
+        match realtime_range() {
+            Some((min, max)) if n <= (max - min) as uint => {
+                Ok(min + n as libc::c_int)
+            }
+            _ => Err(UvError(uvll::EINVAL)),
+        }
+    }
+
+    /// Blocks the current task until the process receives the signal
+    /// `signal`, or for at most `timeout`.
+    ///
+    /// The signal is only listened for during this call, which makes waiting
+    /// for a single delivery, such as a SIGTERM asking the process to shut
+    /// down, simpler than managing a `Signal` with a callback. Fails with
+    /// ETIMEDOUT if the signal wasn't received in time.
+    pub fn wait(signal: libc::c_int,
+                timeout: Option<Duration>) -> UvResult<()> {
+        if !valid(signal) { return Err(UvError(uvll::EINVAL)) }
+
+        let (uv_loop, home) = {
+            let mut eloop = try!(EventLoop::borrow());
+            (eloop.uv_loop(), eloop.make_handle())
+        };
+        let _m = home.fire_homing_missile();
+        let mut waiter = box Waiter {
+            handle: unsafe { try!(raw::Signal::new(&uv_loop)) },
+            task: None,
+            timeout: None,
+            timed_out: false,
+        };
+        let data = &mut *waiter as *mut Waiter;
+        waiter.handle.set_data(data as *mut _);
+        match waiter.handle.start(signal, wait_cb) {
+            Ok(()) => {}
+            Err(e) => {
+                unsafe { waiter.handle.close_and_free() }
+                return Err(e)
+            }
+        }
+        match timeout {
+            Some(dur) => {
+                let ms = dur.num_milliseconds();
+                let ms = if ms < 0 { 0 } else { ms as u64 };
+                let wheel = event_loop::local_wheel();
+                let deadline = wheel.now() + ms;
+                let timeout = wheel.insert(deadline, wait_expired, data as uint);
+                waiter.timeout = Some((wheel as *mut TimerWheel, timeout));
+            }
+            None => {}
+        }
+
+        ::block(uv_loop, |task| waiter.task = Some(task));
+        unsafe { waiter.handle.close_and_free() }
+        if waiter.timed_out {
+            Err(UvError(uvll::ETIMEDOUT))
+        } else {
+            Ok(())
+        }
+    }
+
     /// Attempts to start listening for the signal `signal`.
     ///
     /// When the process receives the specified signal, the callback `cb` will
     /// be invoked on the event loop. This function will cancel any previous
     /// signal being listened for.
     ///
+    /// Besides the standard signals, the realtime signals of `realtime_range`
+    /// can be listened for. Any other signal number fails with EINVAL.
+    ///
     /// For more information, see `uv_signal_start`.
     pub fn start(&mut self, signal: libc::c_int,
                  cb: Box<Callback + Send>) -> UvResult<()> {
+        if !valid(signal) { return Err(UvError(uvll::EINVAL)) }
+
         // Be sure to run user destructors outside the homing missile, not
         // inside.
         let _prev = {
@@ -78,6 +168,13 @@ impl Signal {
         Ok(())
     }
 
+    /// Sets whether listening for a signal keeps the event loop alive, which
+    /// it does by default. See `Timer::keep_alive`.
+    pub fn keep_alive(&mut self, keep: bool) {
+        let _m = self.fire_homing_missile();
+        if keep { self.handle.uv_ref() } else { self.handle.uv_unref() }
+    }
+
     /// Gain access to the underlying raw signal handle.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -86,12 +183,77 @@ impl Signal {
     pub unsafe fn raw(&self) -> raw::Signal { self.handle }
 }
 
+// Number of the last standard signal, past which only realtime signals are
+// valid.
+#[cfg(unix)]
+static MAX_STANDARD: libc::c_int = 31;
+
+#[cfg(unix)]
+fn valid(signal: libc::c_int) -> bool {
+    if signal > 0 && signal <= MAX_STANDARD { return true }
+    match realtime_range() {
+        Some((min, max)) => signal >= min && signal <= max,
+        None => false,
+    }
+}
+
+// libuv emulates a handful of signals on windows and rejects the rest itself.
+#[cfg(windows)]
+fn valid(_signal: libc::c_int) -> bool { true }
+
+#[cfg(target_os = "linux")]
+fn realtime_range() -> Option<(libc::c_int, libc::c_int)> {
+    extern {
+        fn __libc_current_sigrtmin() -> libc::c_int;
+        fn __libc_current_sigrtmax() -> libc::c_int;
+    }
+    unsafe { Some((__libc_current_sigrtmin(), __libc_current_sigrtmax())) }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+fn realtime_range() -> Option<(libc::c_int, libc::c_int)> { Some((65, 126)) }
+
+#[cfg(not(any(target_os = "linux", target_os = "freebsd",
+              target_os = "dragonfly")))]
+fn realtime_range() -> Option<(libc::c_int, libc::c_int)> { None }
+
 extern fn signal_cb(handle: *mut uvll::uv_signal_t, _signum: libc::c_int) {
     unsafe {
         let raw: raw::Signal = Handle::from_raw(handle);
         let data: &mut Data = mem::transmute(raw.get_data());
         assert!(data.callback.is_some());
-        data.callback.as_mut().unwrap().call();
+        let cb = data.callback.as_mut().unwrap();
+        ::watchdog::time(raw.uv_loop(), "signal callback", || cb.call());
+    }
+}
+
+// Only the first delivery or the timeout, whichever comes first, wakes the
+// task, as the other is cancelled right away.
+extern fn wait_cb(handle: *mut uvll::uv_signal_t, _signum: libc::c_int) {
+    unsafe {
+        let raw: raw::Signal = Handle::from_raw(handle);
+        let waiter: &mut Waiter = mem::transmute(raw.get_data());
+        match waiter.timeout.take() {
+            Some((wheel, timeout)) => (*wheel).cancel(timeout),
+            None => {}
+        }
+        waiter.finish();
+    }
+}
+
+fn wait_expired(waiter: uint) {
+    unsafe {
+        let waiter: &mut Waiter = mem::transmute(waiter);
+        waiter.timeout = None;
+        waiter.timed_out = true;
+        waiter.finish();
+    }
+}
+
+impl Waiter {
+    fn finish(&mut self) {
+        self.handle.stop().unwrap();
+        ::wakeup(&mut self.task);
     }
 }
 
@@ -9,11 +9,20 @@
 // except according to those terms.
 
 use libc::{c_int, size_t, ssize_t};
+use std::cmp;
 use std::mem;
 use std::rt::task::BlockedTask;
+use std::slice::bytes;
+use std::sync::Arc;
+use green::Callback;
 
 use raw::{mod, Handle, Request};
 use {uvll, UvResult, UvError};
+use counters::Counters;
+use event_loop::{block_on, alloc_req, free_req};
+use homing::HomeHandle;
+use promise::{mod, Promise, Completer};
+use timeout;
 
 // This is a helper structure which is intended to get embedded into other
 // structures. This structure will retain a handle to the underlying
@@ -22,26 +31,69 @@ use {uvll, UvResult, UvError};
 pub struct Stream<T> {
     pub handle: T,
 
-    // Cache the last used uv_write_t so we don't have to allocate a new one on
-    // every call to uv_write(). Ideally this would be a stack-allocated
-    // structure, but currently we don't have mappings for all the structures
-    // defined in libuv, so we're forced to malloc this.
-    last_write_req: Option<Write>,
-}
+    // The context of reads on the handle, which the data of the handle always
+    // points to. It's allocated by the first stream created for the handle and
+    // shared with all of its clones (only one of which reads at a time), and
+    // it's freed by `Stream::destroy` when the handle is closed.
+    read: *mut ReadContext,
+
+    // The task currently blocked in `write`. This is boxed so that a write
+    // timeout can find the blocked task by address (see `writer`) even if this
+    // structure is moved in the meantime.
+    blocked_writer: Box<Option<BlockedTask>>,
 
-struct Write {
-    handle: raw::Write,
+    // Data read by `fill_buf` which hasn't been consumed yet. This is only
+    // allocated once the stream is first used as a `Buffer`, and it is handed
+    // out by `read` before any more data is read from the stream.
+    read_buf: Vec<u8>,
+    read_pos: uint,
+    read_cap: uint,
+
+    // Where the I/O performed on this stream is counted, if anywhere
+    counters: Option<Arc<Counters>>,
 }
 
+static READ_BUF_SIZE: uint = 64 * 1024;
+
 struct ReadContext {
+    state: ReadState,
     buf: Option<uvll::uv_buf_t>,
     result: ssize_t,
     task: Option<BlockedTask>,
+
+    // When reading the whole stream, the data read so far. In this mode the
+    // callbacks keep reading into this vector until EOF or an error.
+    all: Option<Vec<u8>>,
+
+    // For reads started by `read_async`, which have no task blocked on them.
+    async: Option<AsyncRead>,
+
+    // Invoked once the stream is readable, for reads started by `watch`.
+    watch: Option<Box<Callback + Send>>,
+}
+
+#[deriving(PartialEq)]
+enum ReadState {
+    // No read is pending
+    Idle,
+    // A task is blocked in `read` or `read_to_end`
+    Blocked,
+    // A read started by `read_async` is pending
+    Async,
+    // A selector is waiting for the stream to become readable, see `watch`
+    Watch,
+}
+
+struct AsyncRead {
+    buf: Vec<u8>,
+    completer: Completer<Vec<u8>>,
+    counters: Option<Arc<Counters>>,
 }
 
 struct WriteContext {
-    result: c_int,
-    task: Option<BlockedTask>,
+    result: Option<c_int>,
+    writer: *mut Option<BlockedTask>,
+    data: Option<Vec<u8>>,
 }
 
 impl<T: raw::Allocated, U: raw::Stream<T>> Stream<U> {
@@ -54,102 +106,434 @@ impl<T: raw::Allocated, U: raw::Stream<T>> Stream<U> {
     // leaks.
     //
     // It should also be noted that the `data` field of the underlying uv handle
-    // will be manipulated on each of the methods called on this watcher.
-    // Wrappers should ensure to always reset the field to an appropriate value
-    // if they rely on the field to perform an action.
-    pub fn new(mut stream: U, init: bool) -> Stream<U> {
+    // is owned by this watcher. If `init` is true, this is the first stream
+    // created for the handle and the field is set to point to a new read
+    // context, otherwise the context of the existing stream is shared.
+    //
+    // The bytes and operations read and written are added to `counters`, if
+    // any.
+    pub fn new(mut stream: U, init: bool,
+               counters: Option<Arc<Counters>>) -> Stream<U> {
         if init {
-            stream.set_data(0 as *mut _);
+            let rcx = box ReadContext {
+                state: ReadState::Idle,
+                buf: None,
+                result: 0,
+                task: None,
+                all: None,
+                async: None,
+                watch: None,
+            };
+            stream.set_data(unsafe { mem::transmute(rcx) });
         }
+        uvtrace!(Debug, Stream, "created stream {:p}", stream.raw());
         Stream {
+            read: stream.get_data() as *mut ReadContext,
             handle: stream,
-            last_write_req: None,
+            blocked_writer: box None,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            read_cap: 0,
+            counters: counters,
         }
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> UvResult<uint> {
-        let mut rcx = ReadContext {
-            buf: Some(raw::slice_to_uv_buf(buf)),
-            result: 0,
-            task: None,
+        if self.read_pos < self.read_cap {
+            let amt = cmp::min(buf.len(), self.read_cap - self.read_pos);
+            bytes::copy_memory(buf, self.read_buf.slice(self.read_pos,
+                                                         self.read_pos + amt));
+            self.read_pos += amt;
+            return Ok(amt)
+        }
+        self.read_uv(buf)
+    }
+
+    // Implementation of `Buffer::fill_buf` for the stream, returning the
+    // buffered data, reading more from the stream if there is none.
+    pub fn fill_buf<'a>(&'a mut self) -> UvResult<&'a [u8]> {
+        if self.read_pos == self.read_cap {
+            if self.read_buf.len() == 0 {
+                self.read_buf = Vec::from_elem(READ_BUF_SIZE, 0u8);
+            }
+            self.read_pos = 0;
+            self.read_cap = 0;
+            let mut buf = mem::replace(&mut self.read_buf, Vec::new());
+            let ret = self.read_uv(buf.as_mut_slice());
+            self.read_buf = buf;
+            self.read_cap = try!(ret);
+        }
+        Ok(self.read_buf.slice(self.read_pos, self.read_cap))
+    }
+
+    pub fn consume(&mut self, amt: uint) {
+        self.read_pos = cmp::min(self.read_pos + amt, self.read_cap);
+    }
+
+    // The number of bytes read by `fill_buf` which haven't been consumed yet
+    pub fn buffered(&self) -> uint {
+        self.read_cap - self.read_pos
+    }
+
+    // Reads the rest of the stream into a vector. Unlike a loop around
+    // `read`, this leaves reading active for the whole duration and lets libuv
+    // decide how much to read at a time, growing the vector as necessary.
+    pub fn read_to_end(&mut self) -> UvResult<Vec<u8>> {
+        if self.async_pending() { return Err(UvError(uvll::EBUSY)) }
+        let _p = try!(timeout::pending(cancel_scoped_read::<T, U>,
+                                       self.handle.raw() as uint,
+                                       self.handle.uv_loop()));
+        let mut data = Vec::new();
+        data.push_all(self.read_buf.slice(self.read_pos, self.read_cap));
+        self.read_pos = self.read_cap;
+        let buffered = data.len();
+
+        let rcx = unsafe { &mut *self.read };
+        rcx.all = Some(data);
+        rcx.result = 0;
+        rcx.state = ReadState::Blocked;
+        let ret = match self.handle.read_start(alloc_cb::<T, U>,
+                                               read_cb::<T, U>) {
+            Ok(()) => {
+                let _b = block_on(&self.handle, "uv_read");
+                ::block(self.handle.uv_loop(), |task| {
+                    rcx.task = Some(task);
+                });
+                match rcx.result as c_int {
+                    uvll::EOF => {
+                        let all = rcx.all.take().unwrap();
+                        self.count_read(all.len() - buffered);
+                        Ok(all)
+                    }
+                    n => Err(UvError(n)),
+                }
+            }
+            Err(e) => Err(e),
         };
+        rcx.state = ReadState::Idle;
+        rcx.all = None;
+        return ret;
+    }
 
-        self.handle.set_data(&mut rcx as *mut _ as *mut _);
+    fn read_uv(&mut self, buf: &mut [u8]) -> UvResult<uint> {
+        if self.async_pending() { return Err(UvError(uvll::EBUSY)) }
+        let _p = try!(timeout::pending(cancel_scoped_read::<T, U>,
+                                       self.handle.raw() as uint,
+                                       self.handle.uv_loop()));
+        let rcx = unsafe { &mut *self.read };
+        rcx.buf = Some(raw::slice_to_uv_buf(buf));
+        rcx.result = 0;
+        rcx.state = ReadState::Blocked;
 
         // Send off the read request, but don't block until we're sure that the
         // read request is queued.
         let ret = match self.handle.read_start(alloc_cb::<T, U>,
                                                read_cb::<T, U>) {
             Ok(()) => {
+                let _b = block_on(&self.handle, "uv_read");
                 ::block(self.handle.uv_loop(), |task| {
                     rcx.task = Some(task);
                 });
                 match rcx.result {
                     n if n < 0 => Err(UvError(n as c_int)),
-                    n => Ok(n as uint),
+                    n => { self.count_read(n as uint); Ok(n as uint) }
                 }
             }
             Err(e) => Err(e),
         };
         // Make sure a read cancellation sees that there's no pending read
-        self.handle.set_data(0 as *mut _);
+        rcx.state = ReadState::Idle;
+        rcx.buf = None;
         return ret;
     }
 
+    // Starts reading up to `len` bytes without blocking, returning a promise
+    // for the data read. The promise completes with EOF at the end of the
+    // stream. No other read may be started on the stream until it completes.
+    pub fn read_async(&mut self, len: uint,
+                      home: &HomeHandle) -> UvResult<Promise<Vec<u8>>> {
+        if self.async_pending() { return Err(UvError(uvll::EBUSY)) }
+        let (ret, completer) = promise::new(home.clone(),
+                                            self.handle.uv_loop());
+
+        // Data which has already been buffered is handed out first
+        if self.read_pos < self.read_cap {
+            let amt = cmp::min(len, self.read_cap - self.read_pos);
+            let data = self.read_buf.slice(self.read_pos,
+                                           self.read_pos + amt).to_vec();
+            self.read_pos += amt;
+            completer.complete(Ok(data));
+            return Ok(ret)
+        }
+
+        let mut buf = Vec::from_elem(len, 0u8);
+        let rcx = unsafe { &mut *self.read };
+        rcx.buf = Some(raw::slice_to_uv_buf(buf.as_mut_slice()));
+        rcx.result = 0;
+        rcx.async = Some(AsyncRead {
+            buf: buf,
+            completer: completer,
+            counters: self.counters.clone(),
+        });
+        rcx.state = ReadState::Async;
+        match self.handle.read_start(alloc_cb::<T, U>, read_cb::<T, U>) {
+            Ok(()) => Ok(ret),
+            Err(e) => {
+                // Dropping the completer cancels the promise
+                rcx.state = ReadState::Idle;
+                rcx.buf = None;
+                rcx.async = None;
+                Err(e)
+            }
+        }
+    }
+
+    // Whether a read started by `read_async` is still pending. A selector
+    // watching the stream is notified instead, as the read is about to
+    // consume whatever it was waiting for.
+    fn async_pending(&mut self) -> bool {
+        let rcx = unsafe { &mut *self.read };
+        match rcx.state {
+            ReadState::Async => return true,
+            ReadState::Watch => {}
+            _ => return false,
+        }
+        self.handle.read_stop().unwrap();
+        rcx.state = ReadState::Idle;
+        complete_watch(rcx);
+        false
+    }
+
+    // Starts waiting for `handle` to become readable on behalf of a selector,
+    // invoking `cb` once it is. Fails with EBUSY if a read is already pending,
+    // and a read started before then invokes `cb` right away.
+    //
+    // libuv is handed an empty buffer, so it reports ENOBUFS as soon as the
+    // stream is readable without reading anything from it. Data which a
+    // stream has already buffered isn't taken into account.
+    pub fn watch(mut handle: U, cb: Box<Callback + Send>) -> UvResult<()> {
+        let rcx: &mut ReadContext = unsafe {
+            mem::transmute(handle.get_data())
+        };
+        if rcx.state != ReadState::Idle { return Err(UvError(uvll::EBUSY)) }
+        rcx.buf = Some(raw::slice_to_uv_buf(&[]));
+        rcx.watch = Some(cb);
+        rcx.state = ReadState::Watch;
+        match handle.read_start(alloc_cb::<T, U>, read_cb::<T, U>) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                rcx.state = ReadState::Idle;
+                rcx.buf = None;
+                rcx.watch = None;
+                Err(e)
+            }
+        }
+    }
+
+    // Stops waiting for `handle` to become readable, if `watch` is.
+    pub fn unwatch(mut handle: U) {
+        let rcx: &mut ReadContext = unsafe {
+            mem::transmute(handle.get_data())
+        };
+        if rcx.state == ReadState::Watch {
+            handle.read_stop().unwrap();
+            rcx.state = ReadState::Idle;
+            rcx.buf = None;
+            rcx.watch = None;
+        }
+    }
+
     pub fn cancel_read(mut handle: U, reason: ssize_t) -> Option<BlockedTask> {
         // When we invoke uv_read_stop, it cancels the read and alloc
         // callbacks. We need to manually wake up a pending task (if one was
         // present).
         handle.read_stop().unwrap();
-        let data = handle.get_data();
-        if data.is_null() { return None }
+        let rcx: &mut ReadContext = unsafe {
+            mem::transmute(handle.get_data())
+        };
+        let state = mem::replace(&mut rcx.state, ReadState::Idle);
+        rcx.result = reason;
+        match state {
+            ReadState::Idle => None,
+            ReadState::Blocked => rcx.task.take(),
+            ReadState::Async => { complete_async(rcx); None }
+            ReadState::Watch => { complete_watch(rcx); None }
+        }
+    }
+
+    // Cancels any pending read on `handle` and frees the read context shared
+    // by its streams. This must be called once the last stream of the handle
+    // is gone, before the handle is freed.
+    pub unsafe fn destroy(mut handle: U) {
+        Stream::cancel_read(handle, uvll::ECANCELED as ssize_t);
+        let _rcx: Box<ReadContext> = mem::transmute(handle.get_data());
+        handle.set_data(0 as *mut _);
+    }
 
-        unsafe {
-            handle.set_data(0 as *mut _);
-            let data: &mut ReadContext = &mut *(data as *mut ReadContext);
-            data.result = reason;
-            data.task.take()
+    pub fn write(&mut self, buf: &[u8]) -> UvResult<()> {
+        // First, attempt to write everything without blocking. This is the
+        // common case, and it needs neither a write request nor a context
+        // switch.
+        let amt = match self.handle.try_write(buf) {
+            Ok(n) => n,
+            Err(UvError(uvll::EAGAIN)) => 0,
+            Err(e) => return Err(e),
+        };
+        if amt == buf.len() {
+            self.count_write(amt);
+            return Ok(())
         }
+        uvtrace!(Trace, Stream, "write of {} bytes on {:p} queued, {} written",
+                 buf.len(), self.handle.raw(), amt);
+
+        let ret = self.send(buf.slice_from(amt));
+        self.count_write(if ret.is_ok() {buf.len()} else {amt});
+        ret
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> Result<(), UvError> {
-        // Prepare the write request, either using a cached one or allocating a
-        // new one
-        let mut req = match self.last_write_req.take() {
-            Some(req) => req,
-            None => unsafe { Write { handle: raw::Request::alloc() } },
+    /// Blocks until every write queued on this stream has completed.
+    pub fn flush(&mut self) -> UvResult<()> {
+        if self.handle.write_queue_size() == 0 { return Ok(()) }
+
+        // libuv completes writes in order, so an empty write completes only
+        // once everything queued before it has been written.
+        self.send(&[])
+    }
+
+    // Queues a write request for `buf` and blocks until it completes.
+    //
+    // libuv has no way to cancel an in-flight write, so when a write is
+    // cancelled (or times out) all we can do is detach the blocked task from
+    // the request. The write then completes asynchronously while the caller
+    // sees an error, and the caller's buffer isn't guaranteed to live that
+    // long. On unix the unwritten data is copied to the heap only once the
+    // write is abandoned, but on windows the buffer is handed straight to the
+    // kernel, so it's copied up front.
+    fn send(&mut self, buf: &[u8]) -> UvResult<()> {
+        let _p = try!(timeout::pending(cancel_write, self.writer(),
+                                       self.handle.uv_loop()));
+
+        let data = if cfg!(windows) {Some(buf.to_vec())} else {None};
+        let buf = match data {
+            Some(ref data) => data.as_slice(),
+            None => buf,
         };
-        req.handle.set_data(0 as *mut _);
-        try!(req.handle.send(&mut self.handle, buf, write_cb));
+
+        // Prepare the write request, reusing one freed on this event loop if
+        // there is one
+        let mut req: raw::Write = unsafe { alloc_req() };
+        req.set_data(0 as *mut _);
+        match req.send(&mut self.handle, buf, write_cb) {
+            Ok(()) => {}
+            Err(e) => unsafe { free_req(req); return Err(e) }
+        }
 
         let mut wcx = WriteContext {
-            result: 0,
-            task: None,
+            result: None,
+            writer: &mut *self.blocked_writer as *mut _,
+            data: None,
         };
-        req.handle.set_data(&mut wcx as *mut _ as *mut _);
-        ::block(self.handle.uv_loop(), |task| {
-            wcx.task = Some(task);
-        });
+        req.set_data(&mut wcx as *mut _ as *mut _);
+        let writer = &mut *self.blocked_writer;
+        {
+            let _b = block_on(&self.handle, "uv_write");
+            ::block(self.handle.uv_loop(), |task| {
+                *writer = Some(task);
+            });
+        }
+
+        match wcx.result {
+            Some(n) => {
+                unsafe { free_req(req) }
+                if n < 0 {Err(UvError(n))} else {Ok(())}
+            }
 
-        self.last_write_req = Some(req);
-        return match wcx.result {
-            0 => Ok(()),
-            n => Err(UvError(n)),
+            // If we were woken up without a result then we were cancelled,
+            // but the write request is still pending. Ownership of the request
+            // and the buffer is transferred to a heap-allocated context which
+            // the write callback will free once libuv is done with it.
+            None => unsafe {
+                let data = match data {
+                    Some(data) => data,
+                    None => req.detach_buf(),
+                };
+                let cx = box WriteContext {
+                    result: None,
+                    writer: 0 as *mut _,
+                    data: Some(data),
+                };
+                req.set_data(mem::transmute(cx));
+                Err(UvError(uvll::ECANCELED))
+            }
+        }
+    }
+
+    fn count_read(&self, bytes: uint) {
+        match self.counters {
+            Some(ref c) => c.read(bytes),
+            None => {}
         }
     }
+
+    fn count_write(&self, bytes: uint) {
+        match self.counters {
+            Some(ref c) => c.wrote(bytes),
+            None => {}
+        }
+    }
+
+    /// Returns a token identifying the slot for a task blocked in `write`.
+    ///
+    /// This token remains valid for as long as this stream is alive, and it is
+    /// intended to be passed to `cancel_write`.
+    pub fn writer(&mut self) -> uint {
+        &mut *self.blocked_writer as *mut _ as uint
+    }
+}
+
+// Cancels a read for an expired `with_timeout` scope.
+fn cancel_scoped_read<T, U>(handle: uint) -> Option<BlockedTask>
+                            where T: raw::Allocated, U: raw::Stream<T> {
+    let handle: U = unsafe { Handle::from_raw(handle as *mut T) };
+    Stream::cancel_read(handle, uvll::ECANCELED as ssize_t)
+}
+
+/// Cancels a pending write on a stream, returning the task which was blocked
+/// on the write (if any).
+///
+/// The `writer` argument is a token previously acquired through
+/// `Stream::writer`. The write itself will still complete in the background.
+pub fn cancel_write(writer: uint) -> Option<BlockedTask> {
+    unsafe { (*(writer as *mut Option<BlockedTask>)).take() }
 }
 
-// This allocation callback expects to be invoked once and only once. It will
-// unwrap the buffer in the ReadContext stored in the stream and return it. This
-// will fail if it is called more than once.
-extern fn alloc_cb<T, U>(stream: *mut uvll::uv_handle_t, _hint: size_t,
+// For a single read, this allocation callback expects to be invoked once and
+// only once. It will unwrap the buffer in the ReadContext stored in the stream
+// and return it. This will fail if it is called more than once.
+//
+// When reading the whole stream, the vector being read into is instead grown
+// by libuv's suggested size and its spare capacity is handed out.
+extern fn alloc_cb<T, U>(stream: *mut uvll::uv_handle_t, hint: size_t,
                          buf: *mut uvll::uv_buf_t)
                          where T: raw::Allocated, U: raw::Stream<T> {
-    uvdebug!("alloc_cb");
+    uvtrace!(Trace, Stream, "alloc_cb, hint {}", hint);
     unsafe {
         let raw: U = raw::Handle::from_raw(stream as *mut T);
         let rcx: &mut ReadContext = mem::transmute(raw.get_data());
-        *buf = rcx.buf.take().expect("stream alloc_cb called more than once");
+        match rcx.all {
+            Some(ref mut v) => {
+                v.reserve(hint as uint);
+                let len = v.len();
+                *buf = uvll::uv_buf_t {
+                    base: v.as_mut_ptr().offset(len as int),
+                    len: (v.capacity() - len) as uvll::uv_buf_len_t,
+                };
+            }
+            None => {
+                *buf = rcx.buf.take()
+                          .expect("stream alloc_cb called more than once");
+            }
+        }
     }
 }
 
@@ -166,29 +550,78 @@ extern fn read_cb<T, U>(stream: *mut uvll::uv_stream_t, nread: ssize_t,
         let mut raw: U = raw::Handle::from_raw(stream as *mut T);
         let rcx: &mut ReadContext = mem::transmute(raw.get_data());
 
+        // When reading the whole stream keep going until EOF or an error,
+        // accounting for the data which was read into the vector.
+        match rcx.all {
+            Some(ref mut v) if nread >= 0 => {
+                let len = v.len();
+                v.set_len(len + nread as uint);
+                return
+            }
+            _ => {}
+        }
+
         // Stop reading so that no read callbacks are
         // triggered before the user calls `read` again.
         raw.read_stop().unwrap();
         rcx.result = nread;
 
-        ::wakeup(&mut rcx.task);
+        match mem::replace(&mut rcx.state, ReadState::Idle) {
+            ReadState::Async => complete_async(rcx),
+            ReadState::Watch => complete_watch(rcx),
+            _ => ::wakeup(&mut rcx.task),
+        }
     }
 }
 
+// Completes the promise of a read started by `read_async` with the result
+// stored in the read context. The context must already be marked as idle.
+fn complete_async(rcx: &mut ReadContext) {
+    let result = rcx.result;
+    rcx.buf = None;
+    let AsyncRead { mut buf, completer, counters } = rcx.async.take().unwrap();
+    if result < 0 {
+        completer.complete(Err(UvError(result as c_int)));
+    } else {
+        buf.truncate(result as uint);
+        match counters {
+            Some(ref c) => c.read(buf.len()),
+            None => {}
+        }
+        completer.complete(Ok(buf));
+    }
+}
+
+// Notifies the selector waiting for a stream to become readable. An error is
+// reported as the stream being readable, so that the read performed in
+// response surfaces it. The context must already be marked as idle.
+fn complete_watch(rcx: &mut ReadContext) {
+    rcx.buf = None;
+    rcx.watch.take().unwrap().call();
+}
+
 // Unlike reading, the WriteContext is stored in the uv_write_t request. Like
 // reading, however, all this does is wake up the blocked task after squirreling
 // away the error code as a result.
+//
+// If the write was cancelled, however, then the context has been moved to the
+// heap and there's no task to wake up, so instead everything is freed here.
 extern fn write_cb(req: *mut uvll::uv_write_t, status: c_int) {
     unsafe {
         let raw: raw::Write = raw::Request::from_raw(req);
         let wcx: &mut WriteContext = mem::transmute(raw.get_data());
-        wcx.result = status;
-        ::wakeup(&mut wcx.task);
-    }
-}
+        if wcx.writer.is_null() {
+            let _wcx: Box<WriteContext> = mem::transmute(wcx);
+            free_req(raw);
+            return
+        }
 
-impl Drop for Write {
-    fn drop(&mut self) {
-        unsafe { self.handle.free(); }
+        // The writer may have been cancelled already, in which case the task
+        // is waiting to run and will pick up the result we store here.
+        wcx.result = Some(status);
+        let writer = &mut *wcx.writer;
+        if writer.is_some() {
+            ::wakeup(writer);
+        }
     }
 }
@@ -8,20 +8,32 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp;
 use std::io::net::ip;
 use std::io;
 use std::mem;
+#[cfg(unix)] use std::os;
 use std::rt::task::BlockedTask;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUint, SeqCst};
 use std::time::Duration;
 use libc;
-
-use access::Access;
-use homing::{HomingIO, HomeHandle};
-use raw::{Handle, Request};
-use stream::Stream;
+#[cfg(unix)] use green;
+#[cfg(unix)] use green::sched::SchedHandle;
+#[cfg(unix)] use green::Callback;
+#[cfg(unix)] use std::io::net::tcp::TcpStream;
+#[cfg(unix)] use std::os::unix::AsRawFd;
+
+use counters::IoCounters;
+use homing::{HomingIO, HomeHandle, HomingMissile, HomingRecord};
+use promise::Promise;
+#[cfg(unix)] use selector::Selectable;
+use raw::Handle;
+use stream::{mod, Stream};
 use timeout::{Pusher, AcceptTimeout, ConnectCtx, AccessTimeout};
 use {raw, uvll, EventLoop, UvResult, UvError};
+use event_loop::block_on;
+use net::shutdown;
 
 pub struct Tcp {
     data: Arc<TcpData>,
@@ -30,14 +42,35 @@ pub struct Tcp {
     // libuv can't support concurrent reads and concurrent writes of the same
     // stream object, so we use these access guards in order to arbitrate among
     // multiple concurrent reads and writes. Note that libuv *can* read and
-    // write simultaneously, it just can't read and read simultaneously.
-    write_access: Access<()>,
-    read_access: AccessTimeout<()>,
+    // write simultaneously, it just can't read and read simultaneously. Both
+    // halves share one timer for their timeouts.
+    access: AccessTimeout<uint>,
 }
 
 struct TcpData {
     handle: raw::Tcp,
     home: HomeHandle,
+    // Milliseconds plus one to wait for pending writes when the last clone is
+    // dropped, or zero to close right away. See `set_drain_on_drop`.
+    drain: AtomicUint,
+    // Whether to reset the connection if the writes couldn't be drained
+    reset_on_timeout: AtomicBool,
+}
+
+/// How a TCP stream is closed once its last clone is dropped, see
+/// `Tcp::set_close_mode`.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum CloseMode {
+    /// Close the handle right away. Writes still queued in libuv are
+    /// discarded, while data already handed to the kernel is still sent.
+    Normal,
+    /// Wait up to the given duration for queued writes to be flushed and the
+    /// stream to be shut down, resetting the connection if that doesn't
+    /// happen in time.
+    Linger(Duration),
+    /// Reset the connection, discarding all unsent data. This frees the
+    /// connection immediately instead of leaving it in TIME_WAIT.
+    Reset,
 }
 
 pub struct TcpListener {
@@ -65,12 +98,13 @@ impl Tcp {
     unsafe fn new(uv_loop: &raw::Loop, home: HomeHandle) -> UvResult<Tcp> {
         let raw = try!(raw::Tcp::new(uv_loop));
         Ok(Tcp {
-            write_access: Access::new(()),
-            read_access: AccessTimeout::new(()),
-            stream: Stream::new(raw, true),
+            access: AccessTimeout::duplex(0, 0),
+            stream: Stream::new(raw, true, home.counters()),
             data: Arc::new(TcpData {
                 home: home,
                 handle: raw,
+                drain: AtomicUint::new(0),
+                reset_on_timeout: AtomicBool::new(false),
             })
         })
     }
@@ -88,6 +122,65 @@ impl Tcp {
         Ok(tcp)
     }
 
+    /// Converts a stream of the standard library into a stream on the local
+    /// event loop.
+    ///
+    /// The file descriptor is duplicated and `stream` is closed. Fails with
+    /// EINVAL if the descriptor isn't a TCP socket.
+    #[cfg(unix)]
+    pub fn from_std(stream: TcpStream) -> UvResult<Tcp> {
+        Tcp::from_std_on(&mut *try!(EventLoop::borrow()), stream)
+    }
+
+    #[cfg(unix)]
+    pub fn from_std_on(eloop: &mut EventLoop,
+                       stream: TcpStream) -> UvResult<Tcp> {
+        let fd = try!(::dup_std(stream.as_raw_fd(), ::HandleType::Tcp));
+        drop(stream);
+        Tcp::open_on(eloop, fd).map_err(|e| {
+            unsafe { libc::close(fd); }
+            e
+        })
+    }
+
+    /// Releases the file descriptor backing this stream, for use outside of
+    /// libuv.
+    ///
+    /// The descriptor is duplicated, after which this handle is closed. Clones
+    /// of this stream which are still alive keep using the old handle.
+    #[cfg(unix)]
+    pub fn into_fd(self) -> UvResult<libc::c_int> {
+        let _m = self.data.fire_homing_missile();
+        ::dup_fileno(&self.data.handle)
+    }
+
+    /// Moves this stream onto the event loop of the calling task.
+    ///
+    /// The underlying file descriptor is duplicated and reopened on the local
+    /// event loop, after which this handle is closed on its original loop.
+    /// Clones of this stream which are still alive keep using the old handle.
+    #[cfg(unix)]
+    pub fn migrate(self) -> UvResult<Tcp> {
+        let fd = try!(self.into_fd());
+        let mut eloop = try!(EventLoop::borrow());
+        Tcp::reopen(&mut *eloop, fd)
+    }
+
+    /// Same as `migrate`, but specifies what event loop the stream is moved to.
+    #[cfg(unix)]
+    pub fn migrate_on(self, eloop: &mut EventLoop) -> UvResult<Tcp> {
+        let fd = try!(self.into_fd());
+        Tcp::reopen(eloop, fd)
+    }
+
+    #[cfg(unix)]
+    fn reopen(eloop: &mut EventLoop, fd: libc::c_int) -> UvResult<Tcp> {
+        Tcp::open_on(eloop, fd).map_err(|e| {
+            unsafe { libc::close(fd); }
+            e
+        })
+    }
+
     pub fn connect(addr: ip::SocketAddr) -> UvResult<Tcp> {
         Tcp::connect_on(&mut *try!(EventLoop::borrow()), addr, None)
     }
@@ -108,6 +201,31 @@ impl Tcp {
         })
     }
 
+    /// Connects to `remote` from the local address `local`.
+    ///
+    /// The stream is bound to `local` before connecting, which selects the
+    /// interface and source port used on machines with multiple addresses. A
+    /// port of 0 lets the system pick the source port.
+    pub fn connect_from(local: ip::SocketAddr, remote: ip::SocketAddr,
+                        timeout: Option<Duration>) -> UvResult<Tcp> {
+        Tcp::connect_from_on(&mut *try!(EventLoop::borrow()), local, remote,
+                             timeout)
+    }
+
+    pub fn connect_from_on(eloop: &mut EventLoop, local: ip::SocketAddr,
+                           remote: ip::SocketAddr,
+                           timeout: Option<Duration>) -> UvResult<Tcp> {
+        let tcp = unsafe {
+            try!(Tcp::new(&eloop.uv_loop(), eloop.make_handle()))
+        };
+        let mut handle = tcp.data.handle;
+        try!(handle.bind(local));
+        let cx = ConnectCtx::new();
+        cx.connect(tcp, timeout, eloop, |mut req, tcp, cb| {
+            req.tcp_connect(tcp.stream.handle, remote, cb)
+        })
+    }
+
     /// Gain access to the underlying raw tcp object.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -115,9 +233,21 @@ impl Tcp {
     /// assumptions of this object.
     pub unsafe fn raw(&self) -> raw::Tcp { self.data.handle }
 
+    /// Returns the homing statistics of this stream, if homing diagnostics were
+    /// enabled on its event loop when it was created.
+    pub fn homing_record(&self) -> Option<HomingRecord> {
+        self.data.home.record()
+    }
+
+    /// Returns the I/O counters of this stream, shared with its clones, if I/O
+    /// counters were enabled on its event loop when it was created.
+    pub fn io_counters(&self) -> Option<IoCounters> {
+        self.data.home.io_counters()
+    }
+
     pub fn uv_read(&mut self, buf: &mut [u8]) -> UvResult<uint> {
         let m = self.data.fire_homing_missile();
-        let guard = try!(self.read_access.grant(m));
+        let guard = try!(self.access.grant(m));
 
         // see comments in close_read about this check
         if guard.access.is_closed() {
@@ -127,33 +257,209 @@ impl Tcp {
         self.stream.read(buf)
     }
 
+    /// Reads all remaining data from this stream until EOF.
+    ///
+    /// This is much faster than reading in a loop for bulk transfers as
+    /// reading stays active the whole time, with libuv choosing how much to
+    /// read at once and the returned buffer growing to accommodate it.
+    pub fn read_to_end(&mut self) -> UvResult<Vec<u8>> {
+        let m = self.data.fire_homing_missile();
+        let guard = try!(self.access.grant(m));
+
+        // see comments in close_read about this check
+        if guard.access.is_closed() {
+            return Ok(Vec::new())
+        }
+
+        self.stream.read_to_end()
+    }
+
+    /// Starts reading up to `len` bytes from this stream without blocking,
+    /// returning a promise for the data read.
+    ///
+    /// The promise completes with an EOF error at the end of the stream. Other
+    /// reads from this stream, through any clone, fail with EBUSY until the
+    /// promise has completed. See the `promise` module for more information.
+    pub fn read_async(&mut self, len: uint) -> UvResult<Promise<Vec<u8>>> {
+        let m = self.data.fire_homing_missile();
+        let guard = try!(self.access.grant(m));
+
+        // see comments in close_read about this check
+        if guard.access.is_closed() {
+            return Err(UvError(uvll::EOF))
+        }
+
+        self.stream.read_async(len, &self.data.home)
+    }
+
     pub fn uv_write(&mut self, buf: &[u8]) -> UvResult<()> {
         let m = self.data.fire_homing_missile();
-        let _guard = self.write_access.grant(0, m);
-        self.stream.write(buf)
+        let mut guard = try!(self.access.grant_write(m));
+
+        // Advertise who's writing so `cancel_io` can find the writer from any
+        // clone of this stream.
+        *guard.access = self.stream.writer();
+        let ret = self.stream.write(buf);
+        *guard.access = 0;
+        ret
     }
 
     pub fn close_read(&mut self) -> UvResult<()> {
         // See comments in Pipe::close_read
         let task = {
             let m = self.data.fire_homing_missile();
-            self.read_access.access().close(&m);
+            self.access.access().close(&m);
             Stream::cancel_read(self.stream.handle, uvll::EOF as libc::ssize_t)
         };
         let _ = task.map(|t| t.reawaken());
         Ok(())
     }
 
+    /// Cancels any I/O which is currently pending on this stream.
+    ///
+    /// Any task currently blocked reading from or writing to this stream
+    /// (through any clone) is woken up with an ECANCELED error. Tasks which are
+    /// still waiting for their turn to read or write are not affected, and
+    /// future operations proceed as usual.
+    ///
+    /// Note that libuv cannot cancel a write which is in progress, so a
+    /// cancelled write may still be written in the background.
+    ///
+    /// A connect is not covered, as the stream doesn't exist until the connect
+    /// has completed. Use `connect_timeout` or `with_timeout` to bound it.
+    pub fn cancel_io(&mut self) -> UvResult<()> {
+        let (reader, writer) = {
+            let m = self.data.fire_homing_missile();
+            let reader = Stream::cancel_read(self.stream.handle,
+                                             uvll::ECANCELED as libc::ssize_t);
+            let writer = match *self.access.write_access().get_mut(&m) {
+                0 => None,
+                writer => stream::cancel_write(writer),
+            };
+            (reader, writer)
+        };
+        let _ = reader.map(|t| t.reawaken());
+        let _ = writer.map(|t| t.reawaken());
+        Ok(())
+    }
+
+    /// Returns the number of bytes which have been read from the socket into
+    /// the buffer of this stream (see `Buffer`) but not consumed yet.
+    pub fn buffered(&self) -> uint {
+        self.stream.buffered()
+    }
+
+    /// Returns whether this stream can still be read from, which is no longer
+    /// the case once the peer has closed its writing half.
+    pub fn is_readable(&self) -> bool {
+        use raw::Stream;
+
+        let _m = self.data.fire_homing_missile();
+        self.stream.handle.is_readable()
+    }
+
+    /// Returns whether this stream can still be written to, which is no longer
+    /// the case once it or its writing half has been closed.
+    pub fn is_writable(&self) -> bool {
+        use raw::Stream;
+
+        let _m = self.data.fire_homing_missile();
+        self.stream.handle.is_writable()
+    }
+
     pub fn close_write(&mut self) -> UvResult<()> {
         let _m = self.data.fire_homing_missile();
-        shutdown(self.stream.handle)
+        shutdown(self.stream.handle, None)
+    }
+
+    /// Same as `close_write`, but gives up once `dur` has elapsed.
+    ///
+    /// A shutdown only completes once every pending write has been flushed,
+    /// which never happens if the peer stops reading. If the timeout expires
+    /// first, the shutdown is cancelled by closing this stream and ECANCELED
+    /// is returned. The stream is then unusable through any of its clones.
+    pub fn close_write_timeout(&mut self, dur: Duration) -> UvResult<()> {
+        let m = self.data.fire_homing_missile();
+        match shutdown(self.stream.handle, Some(dur)) {
+            Err(e) if e.code() == uvll::ECANCELED => {
+                close_stream(self.stream.handle, &mut self.access, m);
+                Err(e)
+            }
+            other => other,
+        }
+    }
+
+    /// Blocks until every write queued on this stream has completed.
+    ///
+    /// Writes normally block until they complete, but writes which timed out
+    /// or were cancelled are still written in the background. This waits for
+    /// those as well, and can be bounded with `with_timeout`.
+    pub fn flush(&mut self) -> UvResult<()> {
+        let m = self.data.fire_homing_missile();
+        let mut guard = try!(self.access.grant_write(m));
+        *guard.access = self.stream.writer();
+        let ret = self.stream.flush();
+        *guard.access = 0;
+        ret
+    }
+
+    /// Closes this stream after all pending writes have been flushed, giving
+    /// up after `dur`.
+    ///
+    /// This is a shorthand for `close_write_timeout` followed by dropping
+    /// this handle.
+    pub fn graceful_close(mut self, dur: Duration) -> UvResult<()> {
+        self.close_write_timeout(dur)
+    }
+
+    /// Sets whether dropping the last clone of this stream waits for pending
+    /// writes to be flushed before closing it.
+    ///
+    /// By default the handle is closed immediately, discarding any writes
+    /// which are still queued. With a timeout set, the stream is instead shut
+    /// down first, blocking the dropping task for up to `dur`. This suits
+    /// servers which close each connection right after writing a response.
+    pub fn set_drain_on_drop(&mut self, dur: Option<Duration>) {
+        let ms = dur.map_or(0, |d| {
+            cmp::max(d.num_milliseconds(), 0) as uint + 1
+        });
+        self.data.drain.store(ms, SeqCst);
+    }
+
+    /// Selects how this stream is closed once its last clone is dropped.
+    ///
+    /// `Linger` bounds how long the dropping task waits for pending writes,
+    /// much like `SO_LINGER`, but without blocking the event loop. `Reset`
+    /// lets servers shed connections without accumulating TIME_WAIT state.
+    pub fn set_close_mode(&mut self, mode: CloseMode) -> UvResult<()> {
+        let _m = self.data.fire_homing_missile();
+        let (drain, reset) = match mode {
+            CloseMode::Normal => (None, false),
+            CloseMode::Linger(dur) => (Some(dur), false),
+            CloseMode::Reset => (None, true),
+        };
+        try!(set_reset(self.stream.handle, reset));
+        self.set_drain_on_drop(drain);
+        self.data.reset_on_timeout.store(drain.is_some(), SeqCst);
+        Ok(())
+    }
+
+    /// Resets the connection immediately, discarding all unsent data.
+    ///
+    /// Like a timed out `close_write_timeout`, this closes the stream for all
+    /// of its clones.
+    pub fn close_reset(mut self) -> UvResult<()> {
+        let m = self.data.fire_homing_missile();
+        try!(set_reset(self.stream.handle, true));
+        close_stream(self.stream.handle, &mut self.access, m);
+        Ok(())
     }
 
     pub fn set_read_timeout(&mut self, dur: Option<Duration>) {
         let _m = self.data.fire_homing_missile();
         let uv_loop = self.stream.handle.uv_loop();
-        self.read_access.set_timeout(dur, uv_loop, cancel_read,
-                                     self.stream.handle.raw() as uint);
+        self.access.set_timeout(dur, uv_loop, cancel_read,
+                                self.stream.handle.raw() as uint);
 
         fn cancel_read(stream: uint) -> Option<BlockedTask> {
             let stream = stream as *mut uvll::uv_tcp_t;
@@ -181,6 +487,27 @@ impl Tcp {
         let _m = self.data.fire_homing_missile();
         self.stream.handle.keepalive(ttl)
     }
+
+    /// Enables keepalive with full control over the probes sent.
+    ///
+    /// The first probe is sent once the connection has been idle for `idle`,
+    /// and the following ones every `interval` until one is answered. The
+    /// connection is dropped after `count` probes went unanswered. Durations
+    /// are rounded down to whole seconds, with a minimum of one second.
+    ///
+    /// `keepalive` only configures the idle time, leaving a dead peer to go
+    /// unnoticed for minutes with the system defaults. Fails with ENOSYS on
+    /// platforms where the interval and count can't be configured.
+    pub fn set_keepalive_params(&mut self, idle: Duration, interval: Duration,
+                                count: uint) -> UvResult<()> {
+        fn secs(dur: Duration) -> uint {
+            cmp::max(dur.num_seconds(), 1) as uint
+        }
+
+        let _m = self.data.fire_homing_missile();
+        try!(self.stream.handle.keepalive(Some(secs(idle))));
+        set_keepalive_probes(self.stream.handle, secs(interval), count)
+    }
 }
 
 impl HomingIO for TcpData {
@@ -190,30 +517,66 @@ impl HomingIO for TcpData {
 impl Clone for Tcp {
     fn clone(&self) -> Tcp {
         Tcp {
-            read_access: self.read_access.clone(),
-            write_access: self.write_access.clone(),
-            stream: Stream::new(self.data.handle, false),
+            access: self.access.clone(),
+            stream: Stream::new(self.data.handle, false,
+                                self.data.home.counters()),
             data: self.data.clone(),
         }
     }
 }
 
+#[cfg(unix)]
+impl Selectable for Tcp {
+    fn is_local(&self) -> bool { self.data.home.is_local() }
+
+    fn start_watch(&mut self, cb: Box<Callback + Send>) -> UvResult<()> {
+        Stream::watch(self.data.handle, cb)
+    }
+
+    fn stop_watch(&mut self) { Stream::unwatch(self.data.handle) }
+}
+
 impl Reader for Tcp {
     fn read(&mut self, into: &mut [u8]) -> io::IoResult<uint> {
-        self.uv_read(into).map_err(|e| e.to_io_error())
+        Ok(try!(self.uv_read(into)))
+    }
+
+    fn read_to_end(&mut self) -> io::IoResult<Vec<u8>> {
+        Ok(try!(self.read_to_end()))
+    }
+}
+
+impl Buffer for Tcp {
+    fn fill_buf<'a>(&'a mut self) -> io::IoResult<&'a [u8]> {
+        let m = self.data.fire_homing_missile();
+        let guard = try!(self.access.grant(m));
+        if guard.access.is_closed() {
+            return Err(UvError(uvll::EOF).to_io_error())
+        }
+        Ok(try!(self.stream.fill_buf()))
     }
+
+    fn consume(&mut self, amt: uint) { self.stream.consume(amt) }
 }
 
 impl Writer for Tcp {
     fn write(&mut self, buf: &[u8]) -> io::IoResult<()> {
-        self.uv_write(buf).map_err(|e| e.to_io_error())
+        Ok(try!(self.uv_write(buf)))
+    }
+
+    fn flush(&mut self) -> io::IoResult<()> {
+        Ok(try!(self.flush()))
     }
 }
 
 impl Drop for TcpData {
     fn drop(&mut self) {
         let _m = self.fire_homing_missile();
-        unsafe { self.handle.close_and_free(); }
+        if !drain(self.handle, &self.drain) &&
+           self.reset_on_timeout.load(SeqCst) {
+            let _ = set_reset(self.handle, true);
+        }
+        unsafe { close_handle(self.handle) }
     }
 }
 
@@ -236,6 +599,73 @@ impl TcpListener {
         }
     }
 
+    /// Binds a new listener to `addr` with `SO_REUSEPORT` set.
+    ///
+    /// Any number of listeners created this way may be bound to the same
+    /// address, and the kernel spreads incoming connections among them.
+    #[cfg(unix)]
+    pub fn bind_reuseport(addr: ip::SocketAddr) -> UvResult<TcpListener> {
+        TcpListener::bind_reuseport_on(&mut *try!(EventLoop::borrow()), addr)
+    }
+
+    #[cfg(unix)]
+    pub fn bind_reuseport_on(eloop: &mut EventLoop,
+                             addr: ip::SocketAddr) -> UvResult<TcpListener> {
+        let fd = try!(reuseport_socket(addr));
+        let mut ret = TcpListener {
+            handle: unsafe {
+                match raw::Tcp::new(&eloop.uv_loop()) {
+                    Ok(handle) => handle,
+                    Err(e) => { libc::close(fd); return Err(e) }
+                }
+            },
+            home: eloop.make_handle(),
+        };
+        match ret.handle.open(fd) {
+            Ok(()) => Ok(ret),
+            Err(e) => { unsafe { libc::close(fd); } Err(e) }
+        }
+    }
+
+    /// Binds one listener to `addr` on each of the schedulers behind `scheds`.
+    ///
+    /// Each listener is created with `bind_reuseport` from a task running on
+    /// its scheduler, so the nth listener returned is homed to the event loop
+    /// of the nth scheduler. Accepting from each listener in a task pinned to
+    /// its scheduler spreads accept load across all of the event loops instead
+    /// of funneling it through one.
+    ///
+    /// # Failure
+    ///
+    /// This function will fail if the current task is not a green task.
+    #[cfg(unix)]
+    pub fn bind_sharded(addr: ip::SocketAddr,
+                        scheds: &mut [SchedHandle])
+                        -> UvResult<Vec<TcpListener>> {
+        let rxs: Vec<_> = scheds.iter_mut().map(|sched| {
+            let (tx, rx) = channel();
+            green::task::spawn_on(sched, proc() {
+                tx.send(TcpListener::bind_reuseport(addr));
+            });
+            rx
+        }).collect();
+
+        // Wait for every scheduler before returning so none of the tasks try
+        // to send to a receiver which has gone away.
+        let mut ret = Vec::new();
+        let mut err = None;
+        for rx in rxs.into_iter() {
+            match rx.recv() {
+                Ok(listener) => ret.push(listener),
+                Err(e) => { if err.is_none() { err = Some(e) } }
+            }
+        }
+        match err {
+            Some(e) => Err(e),
+            None => Ok(ret),
+        }
+    }
+
     pub fn listen(self) -> UvResult<TcpAcceptor> {
         use raw::Stream;
 
@@ -258,6 +688,27 @@ impl TcpListener {
         Ok(acceptor)
     }
 
+    /// Sets whether listening for connections keeps the event loop alive,
+    /// which it does by default. This setting carries over to the acceptor
+    /// returned by `listen`. See `Timer::keep_alive`.
+    pub fn keep_alive(&mut self, keep: bool) {
+        let _m = self.fire_homing_missile();
+        if keep { self.handle.uv_ref() } else { self.handle.uv_unref() }
+    }
+
+    /// Sets whether the listener queues several accepts at once, which it
+    /// does by default.
+    ///
+    /// This only has an effect on windows, where disabling it makes processes
+    /// sharing a listening socket take turns accepting connections rather than
+    /// the first of them accepting most of them. It should be called before
+    /// `listen`, as libuv doesn't allow re-enabling simultaneous accepts once
+    /// the socket is listening.
+    pub fn simultaneous_accepts(&mut self, enabled: bool) -> UvResult<()> {
+        let _m = self.fire_homing_missile();
+        self.handle.simultaneous_accepts(enabled)
+    }
+
     /// Gain access to the underlying raw tcp object.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -271,9 +722,77 @@ impl TcpListener {
     }
 }
 
+// Makes closing the socket send an RST, by enabling SO_LINGER with a timeout
+// of zero, or restores the default close behavior.
+#[cfg(unix)]
+fn set_reset(mut handle: raw::Tcp, reset: bool) -> UvResult<()> {
+    #[repr(C)]
+    struct Linger {
+        l_onoff: libc::c_int,
+        l_linger: libc::c_int,
+    }
+    let linger = Linger { l_onoff: reset as libc::c_int, l_linger: 0 };
+    handle.set_sockopt(libc::SOL_SOCKET, uvll::sockopt::SO_LINGER, linger)
+}
+
+#[cfg(windows)]
+fn set_reset(_handle: raw::Tcp, reset: bool) -> UvResult<()> {
+    if reset {Err(UvError(uvll::ENOSYS))} else {Ok(())}
+}
+
+// Sets how often keepalive probes are sent and how many of them may go
+// unanswered, which libuv has no function for.
+#[cfg(unix)]
+fn set_keepalive_probes(mut handle: raw::Tcp, interval: uint,
+                        count: uint) -> UvResult<()> {
+    try!(handle.set_sockopt(libc::IPPROTO_TCP, uvll::sockopt::TCP_KEEPINTVL,
+                            interval as libc::c_int));
+    handle.set_sockopt(libc::IPPROTO_TCP, uvll::sockopt::TCP_KEEPCNT,
+                       count as libc::c_int)
+}
+
+#[cfg(windows)]
+fn set_keepalive_probes(_handle: raw::Tcp, _interval: uint,
+                        _count: uint) -> UvResult<()> {
+    Err(UvError(uvll::ENOSYS))
+}
+
+// Creates a socket bound to `addr` with SO_REUSEPORT set. libuv only creates
+// its sockets while binding, so the option can't be set through a handle.
+#[cfg(unix)]
+fn reuseport_socket(addr: ip::SocketAddr) -> UvResult<libc::c_int> {
+    let family = match addr.ip {
+        ip::Ipv4Addr(..) => libc::AF_INET,
+        ip::Ipv6Addr(..) => libc::AF_INET6,
+    };
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = raw::addr_to_sockaddr(addr, &mut storage);
+        let fd = libc::socket(family, libc::SOCK_STREAM, 0);
+        if fd == -1 {
+            return Err(UvError(-(os::errno() as libc::c_int)))
+        }
+        let one: libc::c_int = 1;
+        let size = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let optval = &one as *const _ as *const libc::c_void;
+        let ok = libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR,
+                                  optval, size) == 0 &&
+                 libc::setsockopt(fd, libc::SOL_SOCKET,
+                                  uvll::sockopt::SO_REUSEPORT,
+                                  optval, size) == 0 &&
+                 libc::bind(fd, &storage as *const _ as *const _, len) == 0;
+        if !ok {
+            let err = UvError(-(os::errno() as libc::c_int));
+            libc::close(fd);
+            return Err(err)
+        }
+        Ok(fd)
+    }
+}
+
 impl io::Listener<Tcp, TcpAcceptor> for TcpListener {
     fn listen(self) -> io::IoResult<TcpAcceptor> {
-        self.listen().map_err(|e| e.to_io_error())
+        Ok(try!(self.listen()))
     }
 }
 
@@ -316,10 +835,45 @@ impl Drop for TcpListener {
 // TcpAcceptor implementation and traits
 
 impl TcpAcceptor {
+    /// Waits for the next connection, or for the timeout of this acceptor to
+    /// expire.
+    ///
+    /// Once the timeout has expired, this returns connections which are
+    /// already pending and fails with `ECANCELED` if there are none.
     pub fn accept(&mut self) -> UvResult<Tcp> {
         let m = self.fire_homing_missile();
         let uv_loop = self.data.listener.handle.uv_loop();
-        self.access.accept(m, uv_loop)
+        let _b = block_on(&self.data.listener.handle, "uv_accept");
+        match self.access.accept(m, uv_loop) {
+            Err(ref e) if e.code() == uvll::ECANCELED => self.accept_pending(),
+            ret => ret,
+        }
+    }
+
+    // Picks up a connection which libuv hasn't accepted yet after a timeout,
+    // see `::accept_pending`.
+    #[cfg(unix)]
+    fn accept_pending(&mut self) -> UvResult<Tcp> {
+        let _m = self.fire_homing_missile();
+        let listener = &self.data.listener;
+        let fd = match try!(::accept_pending(&listener.handle)) {
+            Some(fd) => fd,
+            None => return Err(UvError(uvll::ECANCELED)),
+        };
+        let client = unsafe {
+            Tcp::new(&listener.handle.uv_loop(), listener.home.clone())
+        };
+        let ret = client.and_then(|client| {
+            let mut handle = client.data.handle;
+            handle.open(fd).map(|()| client)
+        });
+        if ret.is_err() { unsafe { libc::close(fd); } }
+        ret
+    }
+
+    #[cfg(windows)]
+    fn accept_pending(&mut self) -> UvResult<Tcp> {
+        Err(UvError(uvll::ECANCELED))
     }
 
     pub fn set_timeout(&mut self, dur: Option<Duration>) {
@@ -334,6 +888,15 @@ impl TcpAcceptor {
         Ok(())
     }
 
+    /// Sets whether accepting connections keeps the event loop alive, which
+    /// it does by default. This affects all clones of this acceptor. See
+    /// `Timer::keep_alive`.
+    pub fn keep_alive(&mut self, keep: bool) {
+        let _m = self.fire_homing_missile();
+        let handle = self.data.listener.handle;
+        if keep { handle.uv_ref() } else { handle.uv_unref() }
+    }
+
     /// Gain access to the underlying raw tcp object.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -344,7 +907,7 @@ impl TcpAcceptor {
 
 impl io::Acceptor<Tcp> for TcpAcceptor {
     fn accept(&mut self) -> io::IoResult<Tcp> {
-        self.accept().map_err(|e| e.to_io_error())
+        Ok(try!(self.accept()))
     }
 }
 
@@ -352,41 +915,63 @@ impl HomingIO for TcpAcceptor {
     fn home(&self) -> &HomeHandle { &self.data.listener.home }
 }
 
-////////////////////////////////////////////////////////////////////////////////
-// Shutdown helper
-////////////////////////////////////////////////////////////////////////////////
+// Closes a stream whose shutdown timed out, which is the only way to cancel
+// the shutdown request. Reads through any clone return EOF from now on,
+// blocked readers and writers are woken up, and the handle itself is freed
+// once the last clone is gone (see `free_closed`).
+pub fn close_stream<T, U>(mut handle: U, access: &mut AccessTimeout<uint>,
+                          m: HomingMissile)
+                          where T: raw::Allocated, U: raw::Stream<T> {
+    access.access().close(&m);
+    let reader = Stream::cancel_read(handle, uvll::ECANCELED as libc::ssize_t);
+    let writer = match *access.write_access().get_mut(&m) {
+        0 => None,
+        writer => stream::cancel_write(writer),
+    };
+    unsafe { handle.close(None) }
+    drop(m);
+    let _ = reader.map(|t| t.reawaken());
+    let _ = writer.map(|t| t.reawaken());
+}
 
-pub fn shutdown<T, U>(mut handle: U) -> UvResult<()>
-                      where T: raw::Allocated, U: raw::Stream<T> {
-    struct Ctx {
-        slot: Option<BlockedTask>,
-        status: libc::c_int,
-    }
-    unsafe {
-        let mut req: raw::Shutdown = raw::Request::alloc();
-        let mut cx = Ctx { slot: None, status: 0 };
-        req.set_data(&mut cx as *mut _ as *mut _);
-
-        let ret = match req.send(&mut handle, shutdown_cb) {
-            Ok(()) => {
-                ::block(handle.uv_loop(), |task| {
-                    cx.slot = Some(task);
-                });
-                if cx.status < 0 {Err(UvError(cx.status))} else {Ok(())}
-            }
-            Err(e) => Err(e),
-        };
-        req.free();
-        return ret;
+// Waits for the pending writes of a stream whose last clone has been dropped,
+// as configured by `set_drain_on_drop`. Returns false if they couldn't be
+// flushed in time.
+pub fn drain<T, U>(handle: U, drain: &AtomicUint) -> bool
+               where T: raw::Allocated, U: raw::Stream<T> {
+    if handle.is_closing() { return true }
+    match drain.load(SeqCst) {
+        n if n > 0 && handle.write_queue_size() > 0 => {
+            let dur = Duration::milliseconds(n as i64 - 1);
+            shutdown(handle, Some(dur)).is_ok()
+        }
+        _ => true,
     }
+}
 
-    extern fn shutdown_cb(req: *mut uvll::uv_shutdown_t, status: libc::c_int) {
-        unsafe {
-            assert!(status != uvll::ECANCELED);
-            let req: raw::Shutdown = raw::Request::from_raw(req);
-            let cx: &mut Ctx = mem::transmute(req.get_data());
-            cx.status = status;
-            ::wakeup(&mut cx.slot);
-        }
+// Closes the handle of a stream once its last clone has been dropped. A read
+// started by `read_async` which is still pending is cancelled first.
+pub unsafe fn close_handle<T, U>(mut handle: U)
+                                 where T: raw::Allocated, U: raw::Stream<T> {
+    Stream::destroy(handle);
+    if handle.is_closing() {
+        free_closed(handle)
+    } else {
+        handle.close_and_free()
     }
 }
+
+// Frees a handle which was closed by `close_stream`. libuv may still touch the
+// handle until its close has been processed at the end of the current turn of
+// the event loop, so it is freed on the next turn instead.
+pub unsafe fn free_closed<T, U>(handle: U)
+                                where T: raw::Allocated, U: raw::Handle<T> {
+    use green::EventLoop;
+
+    let raw = handle.raw() as uint;
+    let eloop = ::EventLoop::borrow_raw().unwrap();
+    (*eloop).callback(proc() {
+        let mut handle: U = Handle::from_raw(raw as *mut T);
+        handle.free();
+    });
+}
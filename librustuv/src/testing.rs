@@ -0,0 +1,39 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers for testing code which uses libuv from a green task.
+//!
+//! See `green::testing` for more information.
+
+use std::time::Duration;
+use green;
+
+use EventLoop;
+
+/// Runs `f` in a green task on a scheduler powered by a fresh libuv event
+/// loop, returning once the scheduler has exited.
+///
+/// # Failure
+///
+/// This function fails if `f` fails.
+pub fn run(f: proc(): Send) {
+    let event_loop = box EventLoop::new().unwrap();
+    green::testing::run_on(event_loop as Box<green::EventLoop + Send>, f)
+}
+
+/// Runs `f` as `run` does, but fails if `f` hasn't completed after `timeout`.
+///
+/// See `green::testing::run_on_timeout` for what happens to a test which times
+/// out.
+pub fn run_timeout(timeout: Duration, f: proc(): Send) {
+    let event_loop = box EventLoop::new().unwrap();
+    green::testing::run_on_timeout(event_loop as Box<green::EventLoop + Send>,
+                                   timeout, f)
+}
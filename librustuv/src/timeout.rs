@@ -8,23 +8,37 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp;
 use std::mem;
 use std::rt::task::BlockedTask;
 use std::time::Duration;
 use libc;
 
-use homing::HomingMissile;
-use {access, uvll, raw, UvError, UvResult, EventLoop};
+use homing::{HomingIO, HomingMissile, HomeHandle};
+use {access, event_loop, uvll, raw, wheel, UvError, UvResult, EventLoop};
+use wheel::TimerWheel;
 use raw::{Handle, Request};
 
-/// Management of a timeout when gaining access to a portion of a duplex stream.
+/// Management of timeouts when gaining access to the halves of a duplex stream.
+///
+/// Each half of the stream can have its own deadline, but both halves share a
+/// single entry in the timer wheel of the event loop, which is always set for
+/// whichever deadline expires first. Servers with many connections using
+/// timeouts therefore don't pay for any timer handles.
 pub struct AccessTimeout<T> {
     inner: Box<Inner<T>>, // stored in a box to get a stable address
 }
 
 struct Inner<T> {
+    // The wheel of the home event loop, and our entry in it
+    timeout: Option<(*mut TimerWheel, wheel::Timeout)>,
+    read: Half<T>,
+    write: Option<Half<T>>,
+}
+
+struct Half<T> {
     state: State,
-    timer: Option<raw::Timer>,
+    deadline: u64,
     user_unblock: Option<fn(uint) -> Option<BlockedTask>>,
     user_payload: uint,
     access: access::Access<T>,
@@ -51,40 +65,150 @@ enum Client {
 }
 
 impl<T: Send> AccessTimeout<T> {
+    /// Creates a new timeout manager for only the read half of a stream.
     pub fn new(data: T) -> AccessTimeout<T> {
         AccessTimeout {
             inner: box Inner {
-                state: State::NoTimeout,
-                timer: None,
-                user_unblock: None,
-                user_payload: 0,
-                access: access::Access::new(data),
+                timeout: None,
+                read: Half::new(access::Access::new(data)),
+                write: None,
             },
         }
     }
 
-    /// Grants access to half of a duplex stream, timing out if necessary.
+    /// Creates a new timeout manager for both halves of a duplex stream.
+    pub fn duplex(read: T, write: T) -> AccessTimeout<T> {
+        AccessTimeout {
+            inner: box Inner {
+                timeout: None,
+                read: Half::new(access::Access::new(read)),
+                write: Some(Half::new(access::Access::new(write))),
+            },
+        }
+    }
+
+    /// Grants access to the read half of a duplex stream, timing out if
+    /// necessary.
     ///
     /// On success, Ok(Guard) is returned and access has been granted to the
     /// stream. If a timeout occurs, then Err is returned with an appropriate
     /// error.
     pub fn grant<'a>(&'a mut self, m: HomingMissile) -> UvResult<Guard<'a, T>> {
+        self.inner.read.grant(m)
+    }
+
+    /// Same as `grant`, but for the write half of a duplex stream.
+    pub fn grant_write<'a>(&'a mut self,
+                           m: HomingMissile) -> UvResult<Guard<'a, T>> {
+        self.inner.write.as_mut().unwrap().grant(m)
+    }
+
+    pub fn timed_out(&self) -> bool {
+        match self.inner.read.state {
+            State::TimedOut => true,
+            _ => false,
+        }
+    }
+
+    pub fn access(&mut self) -> &mut access::Access<T> {
+        &mut self.inner.read.access
+    }
+
+    pub fn write_access(&mut self) -> &mut access::Access<T> {
+        &mut self.inner.write.as_mut().unwrap().access
+    }
+
+    /// Sets the pending timeout of the read half to the value specified.
+    ///
+    /// The loop is used to construct a timer if one has not been previously
+    /// constructed.
+    ///
+    /// The callback will be invoked with `data` if the timeout elapses while a
+    /// request is pending, and it is expected to cancel the request and return
+    /// the task which was blocked on it.
+    pub fn set_timeout(&mut self, dur: Option<Duration>,
+                       uv_loop: raw::Loop,
+                       cb: fn(uint) -> Option<BlockedTask>,
+                       data: uint) {
+        let inner = &mut *self.inner;
+        inner.read.set_timeout(dur, &uv_loop, cb, data);
+        inner.arm();
+    }
+}
+
+impl<T: Send> Inner<T> {
+    // Sets our entry in the timer wheel for the earliest pending deadline
+    // among both halves, removing it if there are no pending deadlines.
+    fn arm(&mut self) {
+        self.disarm();
+        let write = self.write.as_ref().and_then(|w| w.pending());
+        let deadline = match (self.read.pending(), write) {
+            (Some(a), Some(b)) => cmp::min(a, b),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => return,
+        };
+        let wheel = event_loop::local_wheel();
+        let timeout = wheel.insert(deadline, expired::<T>,
+                                   self as *mut _ as uint);
+        self.timeout = Some((wheel as *mut TimerWheel, timeout));
+
+        // When our deadline is reached we expire each half whose deadline has
+        // passed and then re-arm for any half which is still pending. Tasks are
+        // only woken once all of our own state has been updated.
+        fn expired<T: Send>(inner: uint) {
+            unsafe {
+                let inner: &mut Inner<T> = mem::transmute(inner);
+                inner.timeout = None;
+                let now = event_loop::local_wheel().now();
+                let read = inner.read.expire(now);
+                let write = match inner.write {
+                    Some(ref mut half) => half.expire(now),
+                    None => None,
+                };
+                inner.arm();
+                let _ = read.map(|t| t.reawaken());
+                let _ = write.map(|t| t.reawaken());
+            }
+        }
+    }
+}
+
+impl<T> Inner<T> {
+    fn disarm(&mut self) {
+        match self.timeout.take() {
+            Some((wheel, timeout)) => unsafe { (*wheel).cancel(timeout) },
+            None => {}
+        }
+    }
+}
+
+impl<T: Send> Half<T> {
+    fn new(access: access::Access<T>) -> Half<T> {
+        Half {
+            state: State::NoTimeout,
+            deadline: 0,
+            user_unblock: None,
+            user_payload: 0,
+            access: access,
+        }
+    }
+
+    fn grant<'a>(&'a mut self, m: HomingMissile) -> UvResult<Guard<'a, T>> {
         // First, flag that we're attempting to acquire access. This will allow
         // us to cancel the pending grant if we timeout out while waiting for a
         // grant.
-        let inner = &mut *self.inner;
-        match inner.state {
+        match self.state {
             State::NoTimeout => {},
             State::TimeoutPending(ref mut client) => {
                 *client = Client::AccessPending;
             }
             State::TimedOut => return Err(UvError(uvll::ECANCELED))
         }
-        let access = inner.access.grant(inner as *mut _ as uint, m);
+        let access = self.access.grant(self as *mut _ as uint, m);
 
         // After acquiring the grant, we need to flag ourselves as having a
         // pending request so the timeout knows to cancel the request.
-        let can_timeout = match inner.state {
+        let can_timeout = match self.state {
             State::NoTimeout => false,
             State::TimeoutPending(ref mut client) => {
                 *client = Client::RequestPending; true
@@ -94,81 +218,59 @@ impl<T: Send> AccessTimeout<T> {
 
         Ok(Guard {
             access: access,
-            state: &mut inner.state,
+            state: &mut self.state,
             can_timeout: can_timeout
         })
     }
 
-    pub fn timed_out(&self) -> bool {
-        match self.inner.state {
-            State::TimedOut => true,
-            _ => false,
-        }
-    }
-
-    pub fn access(&mut self) -> &mut access::Access<T> { &mut self.inner.access }
-
-    /// Sets the pending timeout to the value specified.
-    ///
-    /// The home/loop variables are used to construct a timer if one has not
-    /// been previously constructed.
-    ///
-    /// The callback will be invoked if the timeout elapses, and the data of
-    /// the time will be set to `data`.
-    pub fn set_timeout(&mut self, dur: Option<Duration>,
-                       uv_loop: raw::Loop,
-                       cb: fn(uint) -> Option<BlockedTask>,
-                       data: uint) {
-        self.inner.state = State::NoTimeout;
+    fn set_timeout(&mut self, dur: Option<Duration>, uv_loop: &raw::Loop,
+                   cb: fn(uint) -> Option<BlockedTask>, data: uint) {
+        self.state = State::NoTimeout;
         let ms = match dur {
             Some(dur) if dur.num_milliseconds() < 0 => 0,
             Some(dur) => dur.num_milliseconds() as u64,
-            None => return match self.inner.timer {
-                Some(ref mut t) => t.stop().unwrap(),
-                None => {}
-            }
+            None => return,
         };
 
-        // If we have a timeout, lazily initialize the timer which will be used
-        // to fire when the timeout runs out.
-        if self.inner.timer.is_none() {
-            let mut timer = unsafe { raw::Timer::new(&uv_loop).unwrap() };
-            timer.set_data(&*self.inner as *const _ as *mut _);
-            self.inner.timer = Some(timer);
+        // Update our local state with the appropriate information for the new
+        // timeout, the shared timer is armed by our caller.
+        self.deadline = uv_loop.now() + ms;
+        self.user_unblock = Some(cb);
+        self.user_payload = data;
+        self.state = State::TimeoutPending(Client::NoWaiter);
+    }
+
+    fn pending(&self) -> Option<u64> {
+        match self.state {
+            State::TimeoutPending(..) => Some(self.deadline),
+            State::NoTimeout | State::TimedOut => None,
         }
+    }
 
-        // Update our local state and timer with the appropriate information for
-        // the new timeout.
-        self.inner.user_unblock = Some(cb);
-        self.inner.user_payload = data;
-        self.inner.state = State::TimeoutPending(Client::NoWaiter);
-        let timer = self.inner.timer.as_mut().unwrap();
-        timer.stop().unwrap();
-        timer.start(ms, 0, timer_cb::<T>).unwrap();
+    // Expires this half if its deadline has passed, returning the task which
+    // needs to be woken up as a result (if any).
+    unsafe fn expire(&mut self, now: u64) -> Option<BlockedTask> {
+        match self.state {
+            State::TimeoutPending(..) if self.deadline <= now => {}
+            _ => return None,
+        }
 
         // When the timeout fires, we expect a TimeoutPending message and we
         // take an appropriate action depending on what state any waiter is in.
-        extern fn timer_cb<T: Send>(timer: *mut uvll::uv_timer_t) {
-            unsafe {
-                let timer: raw::Timer = Handle::from_raw(timer);
-                let inner: &mut Inner<T> = mem::transmute(timer.get_data());
-                match mem::replace(&mut inner.state, State::TimedOut) {
-                    State::TimedOut | State::NoTimeout => unreachable!(),
-                    State::TimeoutPending(Client::NoWaiter) => {}
-                    State::TimeoutPending(Client::AccessPending) => {
-                        match inner.access.dequeue(inner as *mut _ as uint) {
-                            Some(task) => task.reawaken(),
-                            None => unreachable!(),
-                        }
-                    }
-                    State::TimeoutPending(Client::RequestPending) => {
-                        match (inner.user_unblock.unwrap())(inner.user_payload) {
-                            Some(task) => task.reawaken(),
-                            None => unreachable!(),
-                        }
-                    }
+        match mem::replace(&mut self.state, State::TimedOut) {
+            State::TimedOut | State::NoTimeout => unreachable!(),
+            State::TimeoutPending(Client::NoWaiter) => None,
+            State::TimeoutPending(Client::AccessPending) => {
+                match self.access.dequeue(self as *mut _ as uint) {
+                    Some(task) => Some(task),
+                    None => unreachable!(),
                 }
             }
+            // Note that the request may have already completed, in which case
+            // its task has been woken up and there's nothing to cancel.
+            State::TimeoutPending(Client::RequestPending) => {
+                (self.user_unblock.unwrap())(self.user_payload)
+            }
         }
     }
 }
@@ -177,11 +279,11 @@ impl<T: Send> Clone for AccessTimeout<T> {
     fn clone(&self) -> AccessTimeout<T> {
         AccessTimeout {
             inner: box Inner {
-                access: self.inner.access.clone(),
-                state: State::NoTimeout,
-                timer: None,
-                user_unblock: None,
-                user_payload: 0,
+                timeout: None,
+                read: Half::new(self.inner.read.access.clone()),
+                write: self.inner.write.as_ref().map(|w| {
+                    Half::new(w.access.clone())
+                }),
             },
         }
     }
@@ -205,12 +307,7 @@ impl<'a, T> Drop for Guard<'a, T> {
 #[unsafe_destructor]
 impl<T> Drop for AccessTimeout<T> {
     fn drop(&mut self) {
-        match self.inner.timer {
-            Some(ref mut timer) => unsafe {
-                timer.close_and_free();
-            },
-            None => {}
-        }
+        self.inner.disarm();
     }
 }
 
@@ -221,38 +318,43 @@ impl<T> Drop for AccessTimeout<T> {
 pub struct ConnectCtx {
     status: libc::c_int,
     task: Option<BlockedTask>,
-    timer: Option<raw::Timer>,
+    // Our entry in the timer wheel of the event loop, if we have a timeout
+    timeout: Option<(*mut TimerWheel, wheel::Timeout)>,
 }
 
 impl ConnectCtx {
     pub fn new() -> ConnectCtx {
-        ConnectCtx { status: -1, task: None, timer: None }
+        ConnectCtx { status: -1, task: None, timeout: None }
     }
 
     pub fn connect<T>(mut self, obj: T, timeout: Option<Duration>,
                       io: &mut EventLoop,
                       f: |raw::Connect, &T, uvll::uv_connect_cb| -> UvResult<()>)
                       -> UvResult<T> {
+        let _p = try!(pending(cancel_connect, &self as *const _ as uint,
+                              io.uv_loop()));
+
         // Issue the connect request
-        let mut req = unsafe { Request::alloc() };
+        let mut req: raw::Connect = unsafe { event_loop::alloc_req() };
         match f(req, &obj, connect_cb) {
             Ok(()) => {}
-            Err(e) => unsafe { req.free(); return Err(e) },
+            Err(e) => unsafe { event_loop::free_req(req); return Err(e) },
         }
         req.set_data(&self as *const _ as *mut _);
 
-        // Apply any timeout by scheduling a timer to fire when the timeout
-        // expires which will wake up the task.
+        // Apply any timeout by inserting an entry into the timer wheel which
+        // will wake up the task when the timeout expires.
         match timeout {
-            Some(t) => unsafe {
+            Some(t) => {
                 let t = t.num_milliseconds();
                 if t <= 0 { return Err(UvError(uvll::ECANCELED)) }
 
-                let mut timer = raw::Timer::new(&io.uv_loop()).unwrap();
-                timer.start(t as u64, 0, timer_cb).unwrap();
-                timer.set_data(&self as *const _ as *mut _);
-                self.timer = Some(timer);
-            },
+                let wheel = event_loop::wheel(io);
+                let deadline = wheel.now() + t as u64;
+                let timeout = wheel.insert(deadline, expired,
+                                           &self as *const _ as uint);
+                self.timeout = Some((wheel as *mut TimerWheel, timeout));
+            }
             None => {}
         }
 
@@ -266,10 +368,7 @@ impl ConnectCtx {
         // Make sure an erroneously fired callback doesn't have access
         // to the context any more.
         req.set_data(0 as *mut _);
-        match self.timer {
-            Some(ref mut t) => unsafe { t.close_and_free() },
-            None => {}
-        }
+        self.disarm();
 
         // If we failed because of a timeout, drop the TcpWatcher as
         // soon as possible because it's data is now set to null and we
@@ -279,15 +378,19 @@ impl ConnectCtx {
             n => { drop(obj); Err(UvError(n)) }
         };
 
-        extern fn timer_cb(handle: *mut uvll::uv_timer_t) {
+        fn cancel_connect(cx: uint) -> Option<BlockedTask> {
+            let cx: &mut ConnectCtx = unsafe { mem::transmute(cx) };
+            cx.status = uvll::ECANCELED;
+            cx.task.take()
+        }
+
+        fn expired(cx: uint) {
             // Don't close the corresponding request, just wake up the task
             // and let RAII take care of the pending watcher.
-            unsafe {
-                let raw: raw::Timer = Handle::from_raw(handle);
-                let cx: &mut ConnectCtx = mem::transmute(raw.get_data());
-                cx.status = uvll::ECANCELED;
-                ::wakeup(&mut cx.task);
-            }
+            let cx: &mut ConnectCtx = unsafe { mem::transmute(cx) };
+            cx.timeout = None;
+            cx.status = uvll::ECANCELED;
+            ::wakeup(&mut cx.task);
         }
 
         extern fn connect_cb(req: *mut uvll::uv_connect_t, status: libc::c_int) {
@@ -295,24 +398,24 @@ impl ConnectCtx {
             // closed by the timeout callback. In that case we just want to free
             // the request and be along our merry way.
             unsafe {
-                let mut req: raw::Connect = Request::from_raw(req);
-                if status == uvll::ECANCELED { req.free(); return }
+                let req: raw::Connect = Request::from_raw(req);
+                if status == uvll::ECANCELED {
+                    event_loop::free_req(req);
+                    return
+                }
 
                 // Apparently on windows when the handle is closed this callback
                 // may not be invoked with ECANCELED but rather another error
                 // code.  Either ways, if the data is null, then our timeout has
                 // expired and there's nothing we can do.
                 let data = req.get_data();
-                if data.is_null() { req.free(); return }
+                if data.is_null() { event_loop::free_req(req); return }
 
                 let cx: &mut ConnectCtx = &mut *(data as *mut ConnectCtx);
                 cx.status = status;
-                match cx.timer {
-                    Some(ref mut t) => t.stop().unwrap(),
-                    None => {}
-                }
+                cx.disarm();
 
-                // Note that the timer callback doesn't cancel the connect
+                // Note that the expired timeout doesn't cancel the connect
                 // request (that's the job of uv_close()), so it's possible for
                 // this callback to get triggered after the timeout callback
                 // fires, but before the task wakes up. In that case, we did
@@ -322,10 +425,17 @@ impl ConnectCtx {
                 if cx.task.is_some() {
                     ::wakeup(&mut cx.task);
                 }
-                req.free();
+                event_loop::free_req(req);
             }
         }
     }
+
+    fn disarm(&mut self) {
+        match self.timeout.take() {
+            Some((wheel, timeout)) => unsafe { (*wheel).cancel(timeout) },
+            None => {}
+        }
+    }
 }
 
 pub struct AcceptTimeout<T> {
@@ -354,11 +464,13 @@ impl<T: Send> AcceptTimeout<T> {
     pub fn accept(&mut self,
                   missile: HomingMissile,
                   uv_loop: raw::Loop) -> UvResult<T> {
+        let data = self.access.inner.read.access.unsafe_get() as uint;
+
         // If we've timed out but we're not closed yet, poll the state of the
         // queue to see if we can peel off a connection.
         if self.access.timed_out() &&
-           !self.access.inner.access.is_closed(&missile) {
-            let tmp = self.access.inner.access.get_mut(&missile);
+           !self.access.inner.read.access.is_closed(&missile) {
+            let tmp = self.access.inner.read.access.get_mut(&missile);
             return match tmp.pending.remove(0) {
                 Some(msg) => msg,
                 None => Err(UvError(uvll::ECANCELED))
@@ -382,6 +494,7 @@ impl<T: Send> AcceptTimeout<T> {
             None => {}
         }
 
+        let _p = try!(pending(cancel_accept::<T>, data, uv_loop));
         ::block(uv_loop, |task| {
             guard.access.blocked_acceptor = Some(task);
         });
@@ -394,31 +507,32 @@ impl<T: Send> AcceptTimeout<T> {
     }
 
     pub fn pusher(&self) -> Pusher<T> {
-        Pusher { access: self.access.inner.access.clone() }
+        Pusher { access: self.access.inner.read.access.clone() }
     }
 
     pub fn set_timeout(&mut self,
                        dur: Option<Duration>,
                        uv_loop: raw::Loop) {
-        let data = self.access.inner.access.unsafe_get() as uint;
+        let data = self.access.inner.read.access.unsafe_get() as uint;
         self.access.set_timeout(dur, uv_loop, cancel_accept::<T>, data);
-
-        fn cancel_accept<T: Send>(me: uint) -> Option<BlockedTask> {
-            unsafe {
-                let me: &mut AcceptorState<T> = mem::transmute(me);
-                me.blocked_acceptor.take()
-            }
-        }
     }
 
     pub fn close(&mut self, m: HomingMissile) {
-        self.access.inner.access.close(&m);
-        let task = self.access.inner.access.get_mut(&m).blocked_acceptor.take();
+        let access = &mut self.access.inner.read.access;
+        access.close(&m);
+        let task = access.get_mut(&m).blocked_acceptor.take();
         drop(m);
         let _ = task.map(|t| t.reawaken());
     }
 }
 
+fn cancel_accept<T: Send>(me: uint) -> Option<BlockedTask> {
+    unsafe {
+        let me: &mut AcceptorState<T> = mem::transmute(me);
+        me.blocked_acceptor.take()
+    }
+}
+
 impl<T: Send> Pusher<T> {
     pub unsafe fn push(&self, t: UvResult<T>) {
         let state = self.access.unsafe_get();
@@ -432,3 +546,158 @@ impl<T: Send> Clone for AcceptTimeout<T> {
         AcceptTimeout { access: self.access.clone() }
     }
 }
+
+////////////////////////////////////////////////////////////////////////////////
+// Scoped timeouts
+////////////////////////////////////////////////////////////////////////////////
+
+// The innermost `with_timeout` scope of the current task, if any
+local_data_key!(SCOPE: uint)
+
+struct Scope {
+    expired: bool,
+    pending: Option<PendingOp>,
+    parent: *mut Scope,
+    uv_loop: raw::Loop,
+}
+
+// An operation the current task is blocked on, and how to cancel it
+struct PendingOp {
+    cancel: fn(uint) -> Option<BlockedTask>,
+    data: uint,
+    uv_loop: raw::Loop,
+}
+
+/// Restores the enclosing scope when a `with_timeout` call returns.
+struct ScopeGuard {
+    timer: raw::Timer,
+    parent: *mut Scope,
+    home: HomeHandle,
+}
+
+/// Unregisters a pending operation from the enclosing scopes once it's over.
+pub struct PendingGuard {
+    registered: bool,
+}
+
+/// Runs `f`, cancelling any I/O it is blocked on once `dur` has elapsed.
+///
+/// When the timeout expires, a read, write, connect or accept which `f` is
+/// blocked on fails with ECANCELED, as does any such operation `f` attempts
+/// afterwards. This puts a single deadline on a sequence of operations, rather
+/// than setting a timeout on each handle involved:
+///
+/// ```rust,ignore
+/// let response = rustuv::with_timeout(Duration::seconds(5), || {
+///     let mut conn = try!(Tcp::connect(addr));
+///     try!(conn.uv_write(request));
+///     conn.read_to_end()
+/// });
+/// ```
+///
+/// Only operations on handles of the calling task's event loop are cancelled
+/// while they are blocked. Operations on handles homed to other event loops
+/// fail with ECANCELED if they are started after the timeout expired, but
+/// aren't interrupted.
+///
+/// Scopes can be nested, in which case an operation is cancelled when any of
+/// the enclosing timeouts expires.
+pub fn with_timeout<T>(dur: Duration, f: || -> UvResult<T>) -> UvResult<T> {
+    let (uv_loop, home) = {
+        let mut eloop = try!(EventLoop::borrow());
+        (eloop.uv_loop(), eloop.make_handle())
+    };
+    let parent = current_scope();
+    let mut scope = box Scope {
+        expired: false,
+        pending: None,
+        parent: parent,
+        uv_loop: uv_loop,
+    };
+    let mut timer = unsafe { try!(raw::Timer::new(&uv_loop)) };
+    timer.set_data(&mut *scope as *mut Scope as *mut _);
+    let ms = cmp::max(dur.num_milliseconds(), 0) as u64;
+    match timer.start(ms, 0, scope_timer_cb) {
+        Ok(()) => {}
+        Err(e) => unsafe { timer.close_and_free(); return Err(e) }
+    }
+
+    SCOPE.replace(Some(&mut *scope as *mut Scope as uint));
+    let _guard = ScopeGuard { timer: timer, parent: parent, home: home };
+    f()
+}
+
+/// Registers an operation which the current task is about to block on, so
+/// that the enclosing `with_timeout` scopes can cancel it with `cancel(data)`.
+///
+/// Fails with ECANCELED if one of the scopes has already expired.
+pub fn pending(cancel: fn(uint) -> Option<BlockedTask>, data: uint,
+               uv_loop: raw::Loop) -> UvResult<PendingGuard> {
+    let mut scope = current_scope();
+    if scope.is_null() { return Ok(PendingGuard { registered: false }) }
+    unsafe {
+        while !scope.is_null() {
+            if (*scope).expired {
+                clear_pending();
+                return Err(UvError(uvll::ECANCELED))
+            }
+            (*scope).pending = Some(PendingOp {
+                cancel: cancel,
+                data: data,
+                uv_loop: uv_loop,
+            });
+            scope = (*scope).parent;
+        }
+    }
+    Ok(PendingGuard { registered: true })
+}
+
+fn current_scope() -> *mut Scope {
+    match SCOPE.get() {
+        Some(scope) => *scope as *mut Scope,
+        None => 0 as *mut Scope,
+    }
+}
+
+fn clear_pending() {
+    let mut scope = current_scope();
+    while !scope.is_null() {
+        unsafe {
+            (*scope).pending = None;
+            scope = (*scope).parent;
+        }
+    }
+}
+
+// The scope's timeout expired, so its pending operation is cancelled if it's
+// on this event loop.
+extern fn scope_timer_cb(handle: *mut uvll::uv_timer_t) {
+    unsafe {
+        let timer: raw::Timer = Handle::from_raw(handle);
+        let scope: &mut Scope = mem::transmute(timer.get_data());
+        scope.expired = true;
+        let op = match scope.pending {
+            Some(ref op) if op.uv_loop.raw() == scope.uv_loop.raw() => op,
+            _ => return,
+        };
+        let _ = (op.cancel)(op.data).map(|t| t.reawaken());
+    }
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        let _m = self.home.fire_homing_missile();
+        if self.parent.is_null() {
+            SCOPE.replace(None);
+        } else {
+            SCOPE.replace(Some(self.parent as uint));
+        }
+        unsafe { self.timer.close_and_free() }
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        if self.registered { clear_pending() }
+    }
+}
@@ -14,8 +14,9 @@ use std::rt::task::BlockedTask;
 
 use green::Callback;
 
-use {raw, uvll, EventLoop, UvResult};
-use homing::{HomeHandle, HomingIO, HomingMissile};
+use {raw, uvll, event_loop, EventLoop, UvResult};
+use homing::{HomeHandle, HomingIO, HomingMissile, HomingRecord};
+use promise::{mod, Promise, Completer};
 use raw::Handle;
 
 /// A libuv-based timer to schedule callbacks to run on an event loop.
@@ -27,6 +28,18 @@ pub struct Timer {
 struct Data {
     action: Option<Action>,
     id: uint, // see comments in timer_cb
+    // Loop time at which the pending action is next due, and how often it
+    // repeats afterwards, used to carry the action over in `migrate`
+    due: u64,
+    repeat: u64,
+}
+
+// An action taken off a timer being migrated, along with how long until it's
+// due and its repeat interval.
+struct Pending {
+    action: Action,
+    left: u64,
+    repeat: u64,
 }
 
 enum Action {
@@ -48,16 +61,70 @@ impl Timer {
             let data = box Data {
                 action: None,
                 id: 0,
+                due: 0,
+                repeat: 0,
             };
             let mut ret = Timer {
                 handle: try!(raw::Timer::new(&eloop.uv_loop())),
                 home: eloop.make_handle(),
             };
             ret.handle.set_data(mem::transmute(data));
+            uvtrace!(Debug, Timer, "created timer {:p}", ret.handle.raw());
             Ok(ret)
         }
     }
 
+    /// Moves this timer onto the event loop of the calling task.
+    ///
+    /// A fresh timer is created on the local event loop and this timer is then
+    /// closed on its original loop. A pending `oneshot` or `periodic` action is
+    /// carried over to the new timer, and fires when it would have on the old
+    /// one.
+    pub fn migrate(self) -> UvResult<Timer> {
+        let pending = self.take_pending();
+        let mut ret = try!(Timer::new());
+        ret.resume(pending);
+        Ok(ret)
+    }
+
+    /// Same as `migrate`, but specifies what event loop the timer is moved to.
+    pub fn migrate_on(self, eloop: &mut EventLoop) -> UvResult<Timer> {
+        let pending = self.take_pending();
+        let mut ret = try!(Timer::new_on(eloop));
+        ret.resume(pending);
+        Ok(ret)
+    }
+
+    // Stops this timer and closes it, returning its pending action.
+    fn take_pending(mut self) -> Option<Pending> {
+        let (_m, data, mut handle) = self.data();
+        handle.stop().unwrap();
+        data.id += 1;
+        let now = handle.uv_loop().now();
+        let left = if data.due > now {data.due - now} else {0};
+        let repeat = data.repeat;
+        data.action.take().map(|action| {
+            Pending { action: action, left: left, repeat: repeat }
+        })
+    }
+
+    // Arms this fresh timer with an action taken off another one.
+    fn resume(&mut self, pending: Option<Pending>) {
+        let Pending { action, left, repeat } = match pending {
+            Some(pending) => pending,
+            None => return,
+        };
+        let (_m, data, mut handle) = self.data();
+        data.id += 1;
+        data.due = handle.uv_loop().now() + left;
+        data.repeat = repeat;
+        data.action = Some(match action {
+            Action::CallMany(cb, _) => Action::CallMany(cb, data.id),
+            action => action,
+        });
+        handle.start(left, repeat, timer_cb).unwrap();
+    }
+
     /// Sleep for a specified duration of time.
     ///
     /// See [`std::io::Timer::sleep`][1] for semantic information.
@@ -85,6 +152,8 @@ impl Timer {
         let (_m, data, mut handle) = self.data();
         assert!(data.action.is_none());
         data.id += 1;
+        uvtrace!(Trace, Timer, "timer {:p} sleeping for {}ms", handle.raw(),
+                 ms);
         ::block(handle.uv_loop(), |task| {
             data.action = Some(Action::WakeTask(task));
             handle.stop().unwrap();
@@ -105,6 +174,8 @@ impl Timer {
         let _prev = {
             let (_m, data, mut handle) = self.data();
             data.id += 1;
+            data.due = handle.uv_loop().now() + ms as u64;
+            data.repeat = 0;
             handle.stop().unwrap();
             handle.start(ms as u64, 0, timer_cb).unwrap();
             mem::replace(&mut data.action, Some(Action::CallOnce(cb)))
@@ -124,17 +195,69 @@ impl Timer {
         let _prev = {
             let (_m, data, mut handle) = self.data();
             data.id += 1;
+            data.due = handle.uv_loop().now() + ms as u64;
+            data.repeat = ms as u64;
             handle.stop().unwrap();
             handle.start(ms as u64, ms as u64, timer_cb).unwrap();
             mem::replace(&mut data.action, Some(Action::CallMany(cb, data.id)))
         };
     }
 
+    /// Returns a promise which completes once `dur` has elapsed, without
+    /// blocking the current task.
+    ///
+    /// Combined with `Promise::wait_any`, this puts a deadline on waiting for
+    /// other promises. See the `promise` module for more information.
+    pub fn after(dur: Duration) -> UvResult<Promise<()>> {
+        let mut eloop = try!(EventLoop::borrow());
+        Timer::after_on(&mut *eloop, dur)
+    }
+
+    /// Same as `after`, but specifies what event loop the timer runs on.
+    pub fn after_on(eloop: &mut EventLoop,
+                    dur: Duration) -> UvResult<Promise<()>> {
+        let mut ms = dur.num_milliseconds();
+        if ms <= 0 { ms = 0; }
+
+        unsafe {
+            let uv_loop = eloop.uv_loop();
+            let mut handle = try!(raw::Timer::new(&uv_loop));
+            let (ret, completer) = promise::new(eloop.make_handle(), uv_loop);
+            handle.set_data(mem::transmute(box completer));
+            match handle.start(ms as u64, 0, after_cb) {
+                Ok(()) => Ok(ret),
+                Err(e) => {
+                    let _completer: Box<Completer<()>> =
+                        mem::transmute(handle.get_data());
+                    handle.close_and_free();
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Returns the homing statistics of this timer, if homing diagnostics were
+    /// enabled on its event loop when it was created.
+    pub fn homing_record(&self) -> Option<HomingRecord> {
+        self.home.record()
+    }
+
     fn data(&mut self) -> (HomingMissile, &mut Data, raw::Timer) {
         let m = self.fire_homing_missile();
         (m, unsafe { mem::transmute(self.handle.get_data()) }, self.handle)
     }
 
+    /// Sets whether this timer keeps its event loop alive while it's active.
+    ///
+    /// Timers keep the loop alive by default. A timer running background
+    /// housekeeping, such as a periodic cache cleanup, can opt out so that it
+    /// doesn't prevent the event loop, and with it the pool of schedulers,
+    /// from shutting down once all other work is done. See `uv_unref`.
+    pub fn keep_alive(&mut self, keep: bool) {
+        let _m = self.fire_homing_missile();
+        if keep { self.handle.uv_ref() } else { self.handle.uv_unref() }
+    }
+
     /// Gain access to the underlying raw timer handle.
     ///
     /// This function is unsafe as there is no guarantee that any safe
@@ -143,14 +266,54 @@ impl Timer {
     pub unsafe fn raw(&self) -> raw::Timer { self.handle }
 }
 
+/// Blocks the current task for `dur`.
+///
+/// Unlike `Timer::sleep`, this doesn't require creating a timer for each
+/// delay: a timer is kept around by the local event loop and reused by every
+/// call.
+pub fn sleep(dur: Duration) -> UvResult<()> {
+    let mut ms = dur.num_milliseconds();
+    if ms <= 0 { ms = 0; }
+
+    let (mut handle, home) = {
+        let mut eloop = try!(EventLoop::borrow());
+        (try!(event_loop::take_sleep_timer(&mut *eloop)), eloop.make_handle())
+    };
+    let _m = home.fire_homing_missile();
+    let mut slot = None;
+    handle.set_data(&mut slot as *mut Option<BlockedTask> as *mut _);
+    ::block(handle.uv_loop(), |task| {
+        slot = Some(task);
+        handle.start(ms as u64, 0, sleep_cb).unwrap();
+    });
+
+    // We're back on the event loop the timer came from
+    match EventLoop::borrow() {
+        Ok(mut eloop) => event_loop::return_sleep_timer(&mut *eloop, handle),
+        Err(..) => unsafe { handle.close_and_free() },
+    }
+    Ok(())
+}
+
+extern fn sleep_cb(timer: *mut uvll::uv_timer_t) {
+    unsafe {
+        let timer: raw::Timer = Handle::from_raw(timer);
+        let slot: &mut Option<BlockedTask> = mem::transmute(timer.get_data());
+        ::wakeup(slot);
+    }
+}
+
 extern fn timer_cb(timer: *mut uvll::uv_timer_t) {
     let timer: raw::Timer = unsafe { Handle::from_raw(timer) };
     let data: &mut Data = unsafe { mem::transmute(timer.get_data()) };
+    uvtrace!(Trace, Timer, "timer {:p} fired", timer.raw());
     match data.action.take().unwrap() {
         Action::WakeTask(task) => task.reawaken(),
-        Action::CallOnce(mut cb) => cb.call(),
+        Action::CallOnce(mut cb) => {
+            ::watchdog::time(timer.uv_loop(), "timer callback", || cb.call())
+        }
         Action::CallMany(mut cb, id) => {
-            cb.call();
+            ::watchdog::time(timer.uv_loop(), "timer callback", || cb.call());
 
             // Note that the above operation could have performed some form
             // of scheduling. This means that the timer may have decided to
@@ -162,12 +325,22 @@ extern fn timer_cb(timer: *mut uvll::uv_timer_t) {
             // running on the same thread, so there's no need for any
             // synchronization here.
             if data.id == id {
+                data.due = timer.uv_loop().now() + data.repeat;
                 data.action = Some(Action::CallMany(cb, id));
             }
         }
     }
 }
 
+extern fn after_cb(timer: *mut uvll::uv_timer_t) {
+    unsafe {
+        let mut timer: raw::Timer = Handle::from_raw(timer);
+        let completer: Box<Completer<()>> = mem::transmute(timer.get_data());
+        timer.close_and_free();
+        (*completer).complete(Ok(()));
+    }
+}
+
 impl HomingIO for Timer {
     fn home(&self) -> &HomeHandle { &self.home }
 }
@@ -190,6 +363,7 @@ impl Drop for Timer {
             self.handle.stop().unwrap();
             let data: &mut Data = mem::transmute(self.handle.get_data());
             data.id += 1;
+            uvtrace!(Debug, Timer, "closing timer {:p}", self.handle.raw());
             self.handle.close(Some(close_cb));
             data.action.take()
         };
@@ -0,0 +1,272 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runtime tracing of event loop activity.
+//!
+//! Each event loop can be given a `Logger` along with a `Filter` selecting
+//! which events it receives. Events are emitted for handles being created and
+//! closed, tasks blocking and being woken up, tasks migrating between event
+//! loops, and I/O operations starting and completing.
+//!
+//! Tracing is off by default. It can be enabled for every event loop by
+//! setting the `RUSTUV_TRACE` environment variable to a comma-separated list
+//! of directives, in which case events are printed to stderr:
+//!
+//! * a level (`error`, `warn`, `info`, `debug` or `trace`) enables all
+//!   categories up to that level,
+//! * a category (`loop`, `homing`, `stream`, `timer` or `fs`) enables all
+//!   events in that category,
+//! * `category=level` enables a category up to the given level.
+//!
+//! For example `RUSTUV_TRACE=info,fs=trace` traces every file system operation
+//! along with the more important events of the other categories.
+//!
+//! Tracing can also be configured programmatically with
+//! `EventLoop::set_tracing`.
+
+use std::fmt;
+use std::os;
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, Relaxed};
+
+use event_loop::{local_tracer, finish_logging};
+
+// Number of tracers alive in the process, so that events can be skipped
+// without looking up the local event loop when tracing is off everywhere.
+static TRACERS: AtomicUint = INIT_ATOMIC_UINT;
+
+/// The importance of a trace event. Levels are ordered from most to least
+/// important.
+#[deriving(Clone, PartialEq, Eq, PartialOrd, Ord, Show)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+/// The part of the library a trace event comes from.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub enum Category {
+    /// Blocking and waking tasks, closing handles
+    Loop,
+    /// Tasks migrating between event loops
+    Homing,
+    /// TCP, pipe and TTY streams
+    Stream,
+    /// Timers
+    Timer,
+    /// File system requests
+    Fs,
+}
+
+static NUM_CATEGORIES: uint = 5;
+
+/// A single trace event.
+pub struct Record<'a> {
+    pub level: Level,
+    pub category: Category,
+    pub args: &'a fmt::Arguments<'a>,
+}
+
+/// A receiver of trace events.
+pub trait Logger {
+    fn log(&mut self, record: &Record);
+}
+
+/// Selects the trace events which are delivered to a `Logger`, by giving the
+/// least important level enabled for each category.
+#[deriving(Clone, PartialEq, Eq, Show)]
+pub struct Filter {
+    levels: [Option<Level>, ..NUM_CATEGORIES],
+}
+
+impl Filter {
+    /// Creates a filter with every category disabled.
+    pub fn new() -> Filter {
+        Filter { levels: [None, ..NUM_CATEGORIES] }
+    }
+
+    /// Creates a filter enabling every category up to `level`.
+    pub fn all(level: Level) -> Filter {
+        Filter { levels: [Some(level), ..NUM_CATEGORIES] }
+    }
+
+    /// Enables `category` up to `level`, or disables it with `None`.
+    pub fn set(&mut self, category: Category,
+               level: Option<Level>) -> &mut Filter {
+        self.levels[category as uint] = level;
+        self
+    }
+
+    /// Returns whether events of `level` in `category` pass this filter.
+    pub fn enabled(&self, level: Level, category: Category) -> bool {
+        match self.levels[category as uint] {
+            Some(max) => level <= max,
+            None => false,
+        }
+    }
+
+    /// Parses a filter in the format of the `RUSTUV_TRACE` environment
+    /// variable, returning `None` if any directive is invalid.
+    pub fn parse(spec: &str) -> Option<Filter> {
+        let mut ret = Filter::new();
+        for directive in spec.split(',').map(|s| s.trim()) {
+            if directive.is_empty() { continue }
+            let mut parts = directive.splitn(1, '=');
+            let name = parts.next().unwrap();
+            match (parts.next(), parse_category(name), parse_level(name)) {
+                (None, None, Some(level)) => {
+                    for l in ret.levels.iter_mut() {
+                        *l = Some(level);
+                    }
+                }
+                (None, Some(category), None) => {
+                    ret.set(category, Some(Level::Trace));
+                }
+                (Some(level), Some(category), None) => {
+                    match parse_level(level) {
+                        Some(level) => { ret.set(category, Some(level)); }
+                        None => return None,
+                    }
+                }
+                _ => return None,
+            }
+        }
+        Some(ret)
+    }
+}
+
+fn parse_level(s: &str) -> Option<Level> {
+    match s {
+        "error" => Some(Level::Error),
+        "warn" => Some(Level::Warn),
+        "info" => Some(Level::Info),
+        "debug" => Some(Level::Debug),
+        "trace" => Some(Level::Trace),
+        _ => None,
+    }
+}
+
+fn parse_category(s: &str) -> Option<Category> {
+    match s {
+        "loop" => Some(Category::Loop),
+        "homing" => Some(Category::Homing),
+        "stream" => Some(Category::Stream),
+        "timer" => Some(Category::Timer),
+        "fs" => Some(Category::Fs),
+        _ => None,
+    }
+}
+
+/// The tracing configuration of an event loop.
+pub struct Tracer {
+    filter: Filter,
+    logger: Box<Logger + Send>,
+    // Set while the logger runs, so events emitted by the logger itself (for
+    // example by doing I/O) don't re-enter it.
+    logging: bool,
+}
+
+impl Tracer {
+    pub fn new(filter: Filter, logger: Box<Logger + Send>) -> Tracer {
+        TRACERS.fetch_add(1, Relaxed);
+        Tracer { filter: filter, logger: logger, logging: false }
+    }
+
+    /// Returns whether the logger of this tracer is currently running.
+    pub fn is_logging(&self) -> bool { self.logging }
+
+    /// Creates the tracer configured by the `RUSTUV_TRACE` environment
+    /// variable, if any.
+    pub fn from_env() -> Option<Tracer> {
+        let spec = match os::getenv("RUSTUV_TRACE") {
+            Some(spec) => spec,
+            None => return None,
+        };
+        match Filter::parse(spec.as_slice()) {
+            Some(filter) => {
+                let logger = box StderrLogger as Box<Logger + Send>;
+                Some(Tracer::new(filter, logger))
+            }
+            None => {
+                uverrln!("rustuv: invalid RUSTUV_TRACE value `{}`", spec);
+                None
+            }
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        TRACERS.fetch_sub(1, Relaxed);
+    }
+}
+
+/// A logger printing every event to stderr.
+pub struct StderrLogger;
+
+impl Logger for StderrLogger {
+    fn log(&mut self, record: &Record) {
+        uverrln!("rustuv {} {}: {}", record.level, record.category,
+                 record.args)
+    }
+}
+
+/// Returns whether an event of `level` in `category` would be delivered by the
+/// local event loop.
+pub fn enabled(level: Level, category: Category) -> bool {
+    if TRACERS.load(Relaxed) == 0 { return false }
+    match local_tracer() {
+        Some(t) => !t.logging && t.filter.enabled(level, category),
+        None => false,
+    }
+}
+
+/// Delivers an event to the logger of the local event loop. This should only
+/// be called through the `uvtrace!` macro.
+pub fn emit(level: Level, category: Category, args: &fmt::Arguments) {
+    let tracer = match local_tracer() {
+        Some(t) => t,
+        None => return,
+    };
+    if tracer.logging || !tracer.filter.enabled(level, category) { return }
+    tracer.logging = true;
+    tracer.logger.log(&Record {
+        level: level,
+        category: category,
+        args: args,
+    });
+    tracer.logging = false;
+
+    // The logger may have reconfigured tracing, which only takes effect now
+    // that it's done with the tracer.
+    finish_logging();
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, Level, Category};
+
+    #[test]
+    fn parse() {
+        assert_eq!(Filter::parse(""), Some(Filter::new()));
+        assert_eq!(Filter::parse("debug"), Some(Filter::all(Level::Debug)));
+
+        let f = Filter::parse("warn, fs=trace,homing").unwrap();
+        assert!(f.enabled(Level::Warn, Category::Stream));
+        assert!(!f.enabled(Level::Info, Category::Stream));
+        assert!(f.enabled(Level::Trace, Category::Fs));
+        assert!(f.enabled(Level::Trace, Category::Homing));
+
+        assert_eq!(Filter::parse("fs=loud"), None);
+        assert_eq!(Filter::parse("nope"), None);
+    }
+}
@@ -9,16 +9,26 @@
 // except according to those terms.
 
 use std::io;
+use std::rt::task::BlockedTask;
+use std::time::Duration;
 use libc;
 
-use {raw, uvll, EventLoop, UvResult, UvError};
+use green::Callback;
+
+use {raw, uvll, net, EventLoop, UvResult, UvError};
 use stream::Stream;
 use raw::Handle;
 use homing::{HomingIO, HomeHandle};
+use timeout::AccessTimeout;
 
 pub struct Tty {
     home: HomeHandle,
     stream: Stream<raw::Tty>,
+
+    // see comments in Tcp for why this exists
+    access: AccessTimeout<()>,
+
+    resize: Option<resize::Resize>,
 }
 
 impl Tty {
@@ -39,8 +49,7 @@ impl Tty {
         // Related:
         // - https://github.com/joyent/libuv/issues/982
         // - https://github.com/joyent/libuv/issues/988
-        let guess = raw::Tty::guess_handle(fd);
-        if guess != uvll::UV_TTY {
+        if !::isatty(fd) {
             return Err(UvError(uvll::EBADF));
         }
 
@@ -54,15 +63,25 @@ impl Tty {
 
         unsafe {
             let handle = try!(raw::Tty::new(&eloop.uv_loop(), fd, readable));
+            let home = eloop.make_handle();
             Ok(Tty {
-                stream: Stream::new(handle, false),
-                home: eloop.make_handle(),
+                stream: Stream::new(handle, true, home.counters()),
+                home: home,
+                access: AccessTimeout::new(()),
+                resize: None,
             })
         }
     }
 
     pub fn uv_read(&mut self, buf: &mut [u8]) -> UvResult<uint> {
-        let _m = self.fire_homing_missile();
+        let m = self.fire_homing_missile();
+        let guard = try!(self.access.grant(m));
+
+        // see comments in Pipe::close_read about this check
+        if guard.access.is_closed() {
+            return Err(UvError(uvll::EOF))
+        }
+
         self.stream.read(buf)
     }
 
@@ -71,6 +90,51 @@ impl Tty {
         self.stream.write(buf)
     }
 
+    pub fn close_read(&mut self) -> UvResult<()> {
+        // See comments in Pipe::close_read
+        let task = {
+            let m = self.fire_homing_missile();
+            self.access.access().close(&m);
+            Stream::cancel_read(self.stream.handle, uvll::EOF as libc::ssize_t)
+        };
+        let _ = task.map(|t| t.reawaken());
+        Ok(())
+    }
+
+    /// Returns whether this tty can still be read from.
+    pub fn is_readable(&self) -> bool {
+        use raw::Stream;
+
+        let _m = self.fire_homing_missile();
+        self.stream.handle.is_readable()
+    }
+
+    /// Returns whether this tty can still be written to.
+    pub fn is_writable(&self) -> bool {
+        use raw::Stream;
+
+        let _m = self.fire_homing_missile();
+        self.stream.handle.is_writable()
+    }
+
+    pub fn close_write(&mut self) -> UvResult<()> {
+        let _m = self.fire_homing_missile();
+        net::shutdown(self.stream.handle, None)
+    }
+
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        let _m = self.fire_homing_missile();
+        let uv_loop = self.stream.handle.uv_loop();
+        self.access.set_timeout(dur, uv_loop, cancel_read,
+                                self.stream.handle.raw() as uint);
+
+        fn cancel_read(stream: uint) -> Option<BlockedTask> {
+            let stream = stream as *mut uvll::uv_tty_t;
+            let raw: raw::Tty = unsafe { Handle::from_raw(stream) };
+            Stream::cancel_read(raw, uvll::ECANCELED as libc::ssize_t)
+        }
+    }
+
     pub fn set_raw(&mut self, raw: bool) -> UvResult<()> {
         let _m = self.fire_homing_missile();
         self.stream.handle.set_mode(raw)
@@ -81,6 +145,34 @@ impl Tty {
         self.stream.handle.winsize()
     }
 
+    /// Invokes `cb` on the event loop whenever the terminal is resized. This
+    /// replaces any callback previously registered.
+    ///
+    /// The new size can be queried with `winsize`.
+    ///
+    /// On unix this listens for `SIGWINCH`, which the kernel sends when the
+    /// controlling terminal of the process is resized, so `cb` runs for every
+    /// `SIGWINCH` the process receives regardless of which terminal this is.
+    ///
+    /// Windows has no such signal. Resizes are reported by the console API as
+    /// input events, which libuv only sees while reading the console in raw
+    /// mode, so the console is instead polled for changes to its size every
+    /// 100ms and `cb` may run up to that long after a resize. Key events are
+    /// not reported here on any platform, they are read from the `Tty` like
+    /// any other input.
+    pub fn watch_resize(&mut self, cb: Box<Callback + Send>) -> UvResult<()> {
+        let _m = self.fire_homing_missile();
+        let resize = try!(resize::Resize::start(self.stream.handle, cb));
+        self.resize = Some(resize);
+        Ok(())
+    }
+
+    /// Stops invoking the callback registered with `watch_resize`.
+    pub fn unwatch_resize(&mut self) {
+        let _m = self.fire_homing_missile();
+        self.resize = None;
+    }
+
     // One day we may support creating instances of a tty which don't
     // correspond to an actual underlying TTY, so this is a method.
     pub fn isatty(&self) -> bool { true }
@@ -99,13 +191,26 @@ impl HomingIO for Tty {
 
 impl Reader for Tty {
     fn read(&mut self, buf: &mut [u8]) -> io::IoResult<uint> {
-        self.uv_read(buf).map_err(|e| e.to_io_error())
+        Ok(try!(self.uv_read(buf)))
     }
 }
 
+impl Buffer for Tty {
+    fn fill_buf<'a>(&'a mut self) -> io::IoResult<&'a [u8]> {
+        let m = self.fire_homing_missile();
+        let guard = try!(self.access.grant(m));
+        if guard.access.is_closed() {
+            return Err(UvError(uvll::EOF).to_io_error())
+        }
+        Ok(try!(self.stream.fill_buf()))
+    }
+
+    fn consume(&mut self, amt: uint) { self.stream.consume(amt) }
+}
+
 impl Writer for Tty {
     fn write(&mut self, buf: &[u8]) -> io::IoResult<()> {
-        self.uv_write(buf).map_err(|e| e.to_io_error())
+        Ok(try!(self.uv_write(buf)))
     }
 }
 
@@ -113,7 +218,96 @@ impl Drop for Tty {
     fn drop(&mut self) {
         unsafe {
             let _m = self.fire_homing_missile();
+            // the resize watcher may refer to our handle, so it must go first
+            self.resize = None;
+            Stream::destroy(self.stream.handle);
             self.stream.handle.close_and_free();
         }
     }
 }
+
+#[cfg(unix)]
+mod resize {
+    use green::Callback;
+
+    use {raw, uvll, UvResult};
+    use signal::Signal;
+
+    pub struct Resize {
+        _signal: Signal,
+    }
+
+    impl Resize {
+        pub fn start(_tty: raw::Tty,
+                     cb: Box<Callback + Send>) -> UvResult<Resize> {
+            let mut signal = try!(Signal::new());
+            try!(signal.start(uvll::SIGWINCH, cb));
+            Ok(Resize { _signal: signal })
+        }
+    }
+}
+
+#[cfg(windows)]
+mod resize {
+    use std::mem;
+
+    use green::Callback;
+
+    use {raw, uvll, UvResult};
+    use raw::Handle;
+
+    // How often the console is checked for a new size, in milliseconds
+    static POLL_MS: u64 = 100;
+
+    pub struct Resize {
+        timer: raw::Timer,
+    }
+
+    struct Data {
+        tty: raw::Tty,
+        size: (int, int),
+        callback: Box<Callback + Send>,
+    }
+
+    impl Resize {
+        pub fn start(mut tty: raw::Tty,
+                     cb: Box<Callback + Send>) -> UvResult<Resize> {
+            let size = try!(tty.winsize());
+            unsafe {
+                let mut timer = try!(raw::Timer::new(&tty.uv_loop()));
+                let data = box Data { tty: tty, size: size, callback: cb };
+                timer.set_data(mem::transmute(data));
+                match timer.start(POLL_MS, POLL_MS, poll_cb) {
+                    Ok(()) => Ok(Resize { timer: timer }),
+                    Err(e) => { drop(Resize { timer: timer }); Err(e) }
+                }
+            }
+        }
+    }
+
+    extern fn poll_cb(timer: *mut uvll::uv_timer_t) {
+        unsafe {
+            let timer: raw::Timer = Handle::from_raw(timer);
+            let data: &mut Data = mem::transmute(timer.get_data());
+            let size = match data.tty.winsize() {
+                Ok(size) => size,
+                Err(..) => return,
+            };
+            if size != data.size {
+                data.size = size;
+                ::watchdog::time(timer.uv_loop(), "resize callback",
+                                 || data.callback.call());
+            }
+        }
+    }
+
+    impl Drop for Resize {
+        fn drop(&mut self) {
+            let _data: Box<Data> = unsafe {
+                let _ = self.timer.stop();
+                self.timer.close_and_free();
+                mem::transmute(self.timer.get_data())
+            };
+        }
+    }
+}
@@ -8,41 +8,88 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::cmp;
 use std::io::net::ip;
 use std::mem;
 use std::rt::task::BlockedTask;
+use std::slice;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, SeqCst};
 use std::time::Duration;
+use green::Callback;
 use libc;
+#[cfg(unix)] use std::io::net::udp::UdpSocket;
+#[cfg(unix)] use std::os::unix::AsRawFd;
 
-use homing::{HomingIO, HomeHandle};
+use counters::IoCounters;
+use homing::{HomingIO, HomeHandle, HomingRecord};
 use access::Access;
 use timeout::AccessTimeout;
+#[cfg(unix)] use selector::Selectable;
 
 use {raw, uvll, UvResult, UvError, EventLoop};
 use raw::{Request, Handle};
+use event_loop::{block_on, alloc_req, free_req};
 
 pub struct Udp {
     data: Arc<Data>,
 
-    // See tcp for what these fields are
-    read_access: AccessTimeout<()>,
+    // See tcp for what these fields are. The read half also holds a datagram
+    // which was received by `peek_from` but not yet consumed.
+    read_access: AccessTimeout<Peeked>,
     write_access: Access<()>,
 }
 
 struct Data {
     handle: raw::Udp,
     home: HomeHandle,
+    // The `Watch` which the data of the handle points to while a selector
+    // waits for the socket to become readable, or zero.
+    watch: AtomicUint,
+}
+
+// Notified once a watched socket becomes readable, see `Data::start_watch`.
+struct Watch {
+    cb: Box<Callback + Send>,
+}
+
+/// Largest datagram which can be buffered by `peek_from`.
+static MAX_DATAGRAM: uint = 64 * 1024;
+
+// A datagram received by `peek_from` but not yet consumed. The buffer is
+// allocated by the first peek on a socket and reused by all later ones.
+struct Peeked {
+    buf: Vec<u8>,
+    len: uint,
+    addr: Option<ip::SocketAddr>,
+}
+
+impl Peeked {
+    fn new() -> Peeked {
+        Peeked { buf: Vec::new(), len: 0, addr: None }
+    }
+
+    // Consumes the pending datagram, if any, leaving the buffer in place.
+    fn take<'a>(&'a mut self) -> Option<(&'a [u8], ip::SocketAddr)> {
+        match self.addr.take() {
+            Some(addr) => Some((self.buf.slice_to(self.len), addr)),
+            None => None,
+        }
+    }
 }
 
 struct UdpRecvCtx {
     task: Option<BlockedTask>,
     buf: Option<uvll::uv_buf_t>,
+    // Buffers a vectored receive scatters the datagram into, see
+    // `vectored_recv_cb`
+    scatter: Vec<uvll::uv_buf_t>,
     result: Option<(libc::ssize_t, Option<ip::SocketAddr>)>,
 }
 
 struct UdpSendCtx {
     result: libc::c_int,
+    pending: uint,
     task: Option<BlockedTask>,
 }
 
@@ -56,16 +103,107 @@ impl Udp {
                    -> UvResult<Udp> {
         let mut udp = Data {
             home: eloop.make_handle(),
-            handle: unsafe { try!(raw::Udp::new(&eloop.uv_loop())) }
+            handle: unsafe { try!(raw::Udp::new(&eloop.uv_loop())) },
+            watch: AtomicUint::new(0),
         };
         try!(udp.handle.bind(addr));
         Ok(Udp {
             data: Arc::new(udp),
-            read_access: AccessTimeout::new(()),
+            read_access: AccessTimeout::new(Peeked::new()),
             write_access: Access::new(()),
         })
     }
 
+    pub fn open(sock: uvll::uv_os_socket_t) -> UvResult<Udp> {
+        Udp::open_on(&mut *try!(EventLoop::borrow()), sock)
+    }
+
+    pub fn open_on(eloop: &mut EventLoop, sock: uvll::uv_os_socket_t)
+                   -> UvResult<Udp> {
+        let mut udp = Data {
+            home: eloop.make_handle(),
+            handle: unsafe { try!(raw::Udp::new(&eloop.uv_loop())) },
+            watch: AtomicUint::new(0),
+        };
+        try!(udp.handle.open(sock));
+        Ok(Udp {
+            data: Arc::new(udp),
+            read_access: AccessTimeout::new(Peeked::new()),
+            write_access: Access::new(()),
+        })
+    }
+
+    /// Converts a socket of the standard library into a socket on the local
+    /// event loop.
+    ///
+    /// The file descriptor is duplicated and `sock` is closed. Fails with
+    /// EINVAL if the descriptor isn't a UDP socket.
+    #[cfg(unix)]
+    pub fn from_std(sock: UdpSocket) -> UvResult<Udp> {
+        Udp::from_std_on(&mut *try!(EventLoop::borrow()), sock)
+    }
+
+    #[cfg(unix)]
+    pub fn from_std_on(eloop: &mut EventLoop,
+                       sock: UdpSocket) -> UvResult<Udp> {
+        let fd = try!(::dup_std(sock.as_raw_fd(), ::HandleType::Udp));
+        drop(sock);
+        Udp::open_on(eloop, fd).map_err(|e| {
+            unsafe { libc::close(fd); }
+            e
+        })
+    }
+
+    /// Releases the file descriptor backing this socket, for use outside of
+    /// libuv.
+    ///
+    /// The descriptor is duplicated, after which this handle is closed. Clones
+    /// of this socket which are still alive keep using the old handle.
+    #[cfg(unix)]
+    pub fn into_fd(self) -> UvResult<libc::c_int> {
+        let _m = self.data.fire_homing_missile();
+        ::dup_fileno(&self.data.handle)
+    }
+
+    /// Moves this socket onto the event loop of the calling task.
+    ///
+    /// The underlying file descriptor is duplicated and reopened on the local
+    /// event loop, after which this handle is closed on its original loop.
+    /// Clones of this socket which are still alive keep using the old handle.
+    #[cfg(unix)]
+    pub fn migrate(self) -> UvResult<Udp> {
+        let fd = try!(self.into_fd());
+        let mut eloop = try!(EventLoop::borrow());
+        Udp::reopen(&mut *eloop, fd)
+    }
+
+    /// Same as `migrate`, but specifies what event loop the socket is moved to.
+    #[cfg(unix)]
+    pub fn migrate_on(self, eloop: &mut EventLoop) -> UvResult<Udp> {
+        let fd = try!(self.into_fd());
+        Udp::reopen(eloop, fd)
+    }
+
+    #[cfg(unix)]
+    fn reopen(eloop: &mut EventLoop, fd: libc::c_int) -> UvResult<Udp> {
+        Udp::open_on(eloop, fd).map_err(|e| {
+            unsafe { libc::close(fd); }
+            e
+        })
+    }
+
+    /// Returns the homing statistics of this socket, if homing diagnostics were
+    /// enabled on its event loop when it was created.
+    pub fn homing_record(&self) -> Option<HomingRecord> {
+        self.data.home.record()
+    }
+
+    /// Returns the I/O counters of this socket, shared with its clones, if I/O
+    /// counters were enabled on its event loop when it was created.
+    pub fn io_counters(&self) -> Option<IoCounters> {
+        self.data.home.io_counters()
+    }
+
     pub fn socket_name(&mut self) -> UvResult<ip::SocketAddr> {
         let _m = self.data.fire_homing_missile();
         let mut handle = self.data.handle;
@@ -75,83 +213,93 @@ impl Udp {
     pub fn recv_from(&mut self, buf: &mut [u8])
                      -> UvResult<(uint, ip::SocketAddr)> {
         let m = self.data.fire_homing_missile();
-        let _guard = try!(self.read_access.grant(m));
-        let mut handle = self.data.handle;
-        let mut cx = UdpRecvCtx {
-            task: None,
-            buf: Some(raw::slice_to_uv_buf(buf)),
-            result: None,
-        };
-
-        try!(handle.recv_start(alloc_cb, recv_cb));
-        handle.set_data(&mut cx as *mut _ as *mut _);
-        ::block(handle.uv_loop(), |task| {
-            cx.task = Some(task);
-        });
-        handle.set_data(0 as *mut _);
-
-        return match cx.result.take().unwrap() {
-            (n, _) if n < 0 => Err(UvError(n as libc::c_int)),
-            (n, addr) => Ok((n as uint, addr.unwrap()))
-        };
-
-        extern fn alloc_cb(handle: *mut uvll::uv_handle_t,
-                           _suggested_size: libc::size_t,
-                           buf: *mut uvll::uv_buf_t) {
-            unsafe {
-                let handle = handle as *mut uvll::uv_udp_t;
-                let raw: raw::Udp = Handle::from_raw(handle);
-                let cx: &mut UdpRecvCtx = mem::transmute(raw.get_data());
-                *buf = cx.buf.take().expect("recv alloc_cb called more than once")
-            }
+        let mut guard = try!(self.read_access.grant(m));
+        match guard.access.take() {
+            Some((data, addr)) => return Ok((copy(buf, data), addr)),
+            None => {}
         }
+        self.data.recv_from(buf)
+    }
 
-        extern fn recv_cb(handle: *mut uvll::uv_udp_t, nread: libc::ssize_t,
-                          buf: *const uvll::uv_buf_t,
-                          addr: *const libc::sockaddr, _flags: libc::c_uint) {
-            assert!(nread != uvll::ECANCELED as libc::ssize_t);
-
-            unsafe {
-                let mut raw: raw::Udp = Handle::from_raw(handle);
-                let cx: &mut UdpRecvCtx = mem::transmute(raw.get_data());
-
-                // When there's no data to read the recv callback can be a
-                // no-op.  This can happen if read returns EAGAIN/EWOULDBLOCK.
-                // By ignoring this we just drop back to kqueue and wait for the
-                // next callback.
-                if nread == 0 {
-                    cx.buf = Some(*buf);
-                    return
-                }
+    /// Receives a datagram, scattering it across `bufs` in order.
+    ///
+    /// Each buffer is filled up before moving on to the next one, so a
+    /// datagram can be split into, for example, a fixed size header and its
+    /// payload. The total number of bytes received is returned, and as with
+    /// `recv_from` whatever doesn't fit into the buffers is discarded.
+    ///
+    /// On unix the datagram is read straight into the buffers, while on
+    /// windows it is received into a temporary buffer and copied.
+    pub fn recv_from_vectored(&mut self, bufs: &mut [&mut [u8]])
+                              -> UvResult<(uint, ip::SocketAddr)> {
+        let m = self.data.fire_homing_missile();
+        let mut guard = try!(self.read_access.grant(m));
+        match guard.access.take() {
+            Some((data, addr)) => return Ok((scatter(bufs, data), addr)),
+            None => {}
+        }
+        self.data.recv_from_vectored(bufs)
+    }
 
-                raw.recv_stop().unwrap();
-                let addr = if addr.is_null() {
-                    None
-                } else {
-                    let len = mem::size_of::<libc::sockaddr_storage>();
-                    Some(raw::sockaddr_to_addr(mem::transmute(addr), len))
-                };
-                cx.result = Some((nread, addr));
-                ::wakeup(&mut cx.task);
+    /// Receives the next datagram into `buf` without consuming it.
+    ///
+    /// The datagram is buffered inside of this socket, so the next call to
+    /// `recv_from` or `peek_from` (on this socket or any of its clones) returns
+    /// the same datagram again. As with `recv_from`, a datagram larger than
+    /// `buf` is truncated.
+    pub fn peek_from(&mut self, buf: &mut [u8])
+                     -> UvResult<(uint, ip::SocketAddr)> {
+        let m = self.data.fire_homing_missile();
+        let mut guard = try!(self.read_access.grant(m));
+        let peeked = &mut *guard.access;
+        if peeked.addr.is_none() {
+            if peeked.buf.len() == 0 {
+                peeked.buf = Vec::from_elem(MAX_DATAGRAM, 0u8);
             }
+            let (n, addr) = try!(self.data.recv_from(peeked.buf.as_mut_slice()));
+            peeked.len = n;
+            peeked.addr = Some(addr);
         }
+        let data = peeked.buf.slice_to(peeked.len);
+        Ok((copy(buf, data), peeked.addr.unwrap()))
     }
 
     pub fn send_to(&mut self, buf: &[u8], dst: ip::SocketAddr) -> UvResult<()> {
+        self.send_many(&[(buf, dst)])
+    }
+
+    /// Sends each buffer in `msgs` to its paired address.
+    ///
+    /// All of the sends are submitted to libuv up front and the calling task
+    /// is only descheduled once, until every send has completed. If a send
+    /// fails to be submitted the remaining ones are skipped. The first error
+    /// encountered is returned.
+    pub fn send_many(&mut self,
+                     msgs: &[(&[u8], ip::SocketAddr)]) -> UvResult<()> {
         let m = self.data.fire_homing_missile();
         let _guard = self.write_access.grant(0, m);
         let mut cx = UdpSendCtx {
-            result: uvll::ECANCELED,
+            result: 0,
+            pending: 0,
             task: None,
         };
+        let counters = self.data.home.counters();
 
-        unsafe {
-            let mut req: raw::UdpSend = Request::alloc();
-            req.set_data(&mut cx as *mut _ as *mut _);
-            match req.send(self.data.handle, buf, dst, send_cb) {
-                Ok(()) => {}
-                Err(e) => { req.free(); return Err(e) }
+        for &(buf, dst) in msgs.iter() {
+            unsafe {
+                let mut req: raw::UdpSend = alloc_req();
+                req.set_data(&mut cx as *mut _ as *mut _);
+                match req.send(self.data.handle, buf, dst, send_cb) {
+                    Ok(()) => {
+                        cx.pending += 1;
+                        counters.as_ref().map(|c| c.wrote(buf.len()));
+                    }
+                    Err(e) => { free_req(req); cx.result = e.code(); break }
+                }
             }
+        }
+        if cx.pending > 0 {
+            let _b = block_on(&self.data.handle, "uv_udp_send");
             ::block(self.data.handle.uv_loop(), |task| {
                 cx.task = Some(task);
             });
@@ -160,11 +308,52 @@ impl Udp {
 
         extern fn send_cb(req: *mut uvll::uv_udp_send_t, status: libc::c_int) {
             unsafe {
-                let mut req: raw::UdpSend = Request::from_raw(req);
+                let req: raw::UdpSend = Request::from_raw(req);
                 let cx: &mut UdpSendCtx = mem::transmute(req.get_data());
-                cx.result = status;
-                ::wakeup(&mut cx.task);
-                req.free();
+                cx.pending -= 1;
+                if status < 0 && cx.result == 0 {
+                    cx.result = status;
+                }
+                if cx.pending == 0 {
+                    ::wakeup(&mut cx.task);
+                }
+                free_req(req);
+            }
+        }
+    }
+
+    /// Same as `send_many`, but returns as soon as the sends have been
+    /// submitted without waiting for them to complete.
+    ///
+    /// The buffers are copied so they may be reused immediately. Only errors
+    /// which occur while submitting the sends are reported, later failures
+    /// are silently dropped just as if the datagram had been lost.
+    pub fn send_many_nowait(&mut self,
+                            msgs: &[(&[u8], ip::SocketAddr)]) -> UvResult<()> {
+        let m = self.data.fire_homing_missile();
+        let _guard = self.write_access.grant(0, m);
+        let counters = self.data.home.counters();
+
+        for &(buf, dst) in msgs.iter() {
+            unsafe {
+                let buf = box buf.to_vec();
+                let mut req: raw::UdpSend = alloc_req();
+                match req.send(self.data.handle, buf.as_slice(), dst, send_cb) {
+                    Ok(()) => {
+                        counters.as_ref().map(|c| c.wrote(buf.len()));
+                        req.set_data(mem::transmute(buf));
+                    }
+                    Err(e) => { free_req(req); return Err(e) }
+                }
+            }
+        }
+        return Ok(());
+
+        extern fn send_cb(req: *mut uvll::uv_udp_send_t, _status: libc::c_int) {
+            unsafe {
+                let req: raw::UdpSend = Request::from_raw(req);
+                let _buf: Box<Vec<u8>> = mem::transmute(req.get_data());
+                free_req(req);
             }
         }
     }
@@ -205,26 +394,97 @@ impl Udp {
         handle.set_broadcast(enable)
     }
 
+    /// Sets the type-of-service byte (the traffic class for IPv6 sockets)
+    /// of outgoing datagrams, for example to apply a DSCP marking.
+    #[cfg(unix)]
+    pub fn set_traffic_class(&mut self, tclass: u8) -> UvResult<()> {
+        let _m = self.data.fire_homing_missile();
+        let mut handle = self.data.handle;
+        let tclass = tclass as libc::c_int;
+        match try!(handle.getsockname()).ip {
+            ip::Ipv4Addr(..) => {
+                handle.set_sockopt(libc::IPPROTO_IP, uvll::sockopt::IP_TOS,
+                                   tclass)
+            }
+            ip::Ipv6Addr(..) => {
+                handle.set_sockopt(libc::IPPROTO_IPV6,
+                                   uvll::sockopt::IPV6_TCLASS, tclass)
+            }
+        }
+    }
+
+    /// Sets the size of the kernel's receive buffer for this socket.
+    ///
+    /// The operating system may round or clamp the size, `recv_buffer_size`
+    /// returns the size actually in use.
+    #[cfg(unix)]
+    pub fn set_recv_buffer_size(&mut self, size: uint) -> UvResult<()> {
+        let _m = self.data.fire_homing_missile();
+        let mut handle = self.data.handle;
+        handle.set_sockopt(libc::SOL_SOCKET, uvll::sockopt::SO_RCVBUF,
+                           size as libc::c_int)
+    }
+
+    /// Sets the size of the kernel's send buffer for this socket.
+    #[cfg(unix)]
+    pub fn set_send_buffer_size(&mut self, size: uint) -> UvResult<()> {
+        let _m = self.data.fire_homing_missile();
+        let mut handle = self.data.handle;
+        handle.set_sockopt(libc::SOL_SOCKET, uvll::sockopt::SO_SNDBUF,
+                           size as libc::c_int)
+    }
+
+    #[cfg(unix)]
+    pub fn recv_buffer_size(&mut self) -> UvResult<uint> {
+        let _m = self.data.fire_homing_missile();
+        self.data.handle.get_sockopt::<libc::c_int>(libc::SOL_SOCKET,
+                                                    uvll::sockopt::SO_RCVBUF)
+            .map(|n| n as uint)
+    }
+
+    #[cfg(unix)]
+    pub fn send_buffer_size(&mut self) -> UvResult<uint> {
+        let _m = self.data.fire_homing_missile();
+        self.data.handle.get_sockopt::<libc::c_int>(libc::SOL_SOCKET,
+                                                    uvll::sockopt::SO_SNDBUF)
+            .map(|n| n as uint)
+    }
+
     pub fn set_read_timeout(&mut self, dur: Option<Duration>) {
         let _m = self.data.fire_homing_missile();
         self.read_access.set_timeout(dur, self.data.handle.uv_loop(),
                                      cancel_read,
                                      self.data.handle.raw() as uint);
+    }
 
-        fn cancel_read(stream: uint) -> Option<BlockedTask> {
-            // This method is quite similar to StreamWatcher::cancel_read, see
-            // there for more information
-            unsafe {
-                let handle = stream as *mut uvll::uv_udp_t;
-                let mut raw: raw::Udp = Handle::from_raw(handle);
-                raw.recv_stop().unwrap();
-                if raw.get_data().is_null() { return None }
+    /// Cancels a receive which is currently pending on this socket.
+    ///
+    /// Any task currently blocked in `recv_from` on this socket (through any
+    /// clone) is woken up with an ECANCELED error. Sends never wait on a peer,
+    /// so there is nothing to cancel for them.
+    pub fn cancel_io(&mut self) -> UvResult<()> {
+        let task = {
+            let _m = self.data.fire_homing_missile();
+            cancel_read(self.data.handle.raw() as uint)
+        };
+        let _ = task.map(|t| t.reawaken());
+        Ok(())
+    }
+}
 
-                let cx: &mut UdpRecvCtx = mem::transmute(raw.get_data());
-                cx.result = Some((uvll::ECANCELED as libc::ssize_t, None));
-                cx.task.take()
-            }
-        }
+fn cancel_read(stream: uint) -> Option<BlockedTask> {
+    // This method is quite similar to StreamWatcher::cancel_read, see there for
+    // more information
+    unsafe {
+        let handle = stream as *mut uvll::uv_udp_t;
+        let mut raw: raw::Udp = Handle::from_raw(handle);
+        raw.recv_stop().unwrap();
+        if raw.get_data().is_null() { return None }
+
+        let cx: &mut UdpRecvCtx = mem::transmute(raw.get_data());
+        raw.set_data(0 as *mut _);
+        cx.result = Some((uvll::ECANCELED as libc::ssize_t, None));
+        cx.task.take()
     }
 }
 
@@ -238,6 +498,304 @@ impl Clone for Udp {
     }
 }
 
+#[cfg(unix)]
+impl Selectable for Udp {
+    fn is_local(&self) -> bool { self.data.home.is_local() }
+
+    fn start_watch(&mut self, cb: Box<Callback + Send>) -> UvResult<()> {
+        self.data.start_watch(cb)
+    }
+
+    fn stop_watch(&mut self) { self.data.stop_watch(false) }
+}
+
+impl Data {
+    fn recv_from(&self, buf: &mut [u8]) -> UvResult<(uint, ip::SocketAddr)> {
+        let mut cx = UdpRecvCtx {
+            task: None,
+            buf: Some(raw::slice_to_uv_buf(buf)),
+            scatter: Vec::new(),
+            result: None,
+        };
+        return self.recv(&mut cx, recv_cb);
+
+        extern fn recv_cb(handle: *mut uvll::uv_udp_t, nread: libc::ssize_t,
+                          buf: *const uvll::uv_buf_t,
+                          addr: *const libc::sockaddr, _flags: libc::c_uint) {
+            assert!(nread != uvll::ECANCELED as libc::ssize_t);
+
+            unsafe {
+                let mut raw: raw::Udp = Handle::from_raw(handle);
+                let cx: &mut UdpRecvCtx = mem::transmute(raw.get_data());
+
+                // When there's no data to read the recv callback can be a
+                // no-op.  This can happen if read returns EAGAIN/EWOULDBLOCK.
+                // By ignoring this we just drop back to kqueue and wait for the
+                // next callback.
+                if nread == 0 {
+                    cx.buf = Some(*buf);
+                    return
+                }
+
+                raw.recv_stop().unwrap();
+                let addr = if addr.is_null() {
+                    None
+                } else {
+                    let len = mem::size_of::<libc::sockaddr_storage>();
+                    Some(raw::sockaddr_to_addr(mem::transmute(addr), len))
+                };
+                cx.result = Some((nread, addr));
+                ::wakeup(&mut cx.task);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn recv_from_vectored(&self, bufs: &mut [&mut [u8]])
+                          -> UvResult<(uint, ip::SocketAddr)> {
+        // libuv is handed an empty buffer, so it reports ENOBUFS whenever a
+        // datagram arrives without reading it, at which point it is read
+        // with `recvmsg` instead.
+        let mut cx = UdpRecvCtx {
+            task: None,
+            buf: Some(raw::slice_to_uv_buf(&[])),
+            scatter: bufs.iter().map(|b| raw::slice_to_uv_buf(&**b)).collect(),
+            result: None,
+        };
+        self.recv(&mut cx, vectored_recv_cb)
+    }
+
+    #[cfg(windows)]
+    fn recv_from_vectored(&self, bufs: &mut [&mut [u8]])
+                          -> UvResult<(uint, ip::SocketAddr)> {
+        let len = bufs.iter().fold(0, |n, b| n + b.len());
+        let mut data = Vec::from_elem(len, 0u8);
+        let (n, addr) = try!(self.recv_from(data.as_mut_slice()));
+        Ok((scatter(bufs, data.slice_to(n)), addr))
+    }
+
+    fn recv(&self, cx: &mut UdpRecvCtx,
+            recv_cb: uvll::uv_udp_recv_cb) -> UvResult<(uint, ip::SocketAddr)> {
+        // A selector watching the socket is notified, as this is about to
+        // consume the datagram it was waiting for.
+        self.stop_watch(true);
+        let mut handle = self.handle;
+        try!(handle.recv_start(alloc_cb, recv_cb));
+        handle.set_data(&mut *cx as *mut UdpRecvCtx as *mut _);
+        {
+            let _b = block_on(&handle, "uv_udp_recv");
+            ::block(handle.uv_loop(), |task| {
+                cx.task = Some(task);
+            });
+        }
+        handle.set_data(0 as *mut _);
+
+        match cx.result.take().unwrap() {
+            (n, _) if n < 0 => Err(UvError(n as libc::c_int)),
+            (n, addr) => {
+                match self.home.counters() {
+                    Some(c) => c.read(n as uint),
+                    None => {}
+                }
+                Ok((n as uint, addr.unwrap()))
+            }
+        }
+    }
+}
+
+extern fn alloc_cb(handle: *mut uvll::uv_handle_t,
+                   _suggested_size: libc::size_t,
+                   buf: *mut uvll::uv_buf_t) {
+    unsafe {
+        let handle = handle as *mut uvll::uv_udp_t;
+        let raw: raw::Udp = Handle::from_raw(handle);
+        let cx: &mut UdpRecvCtx = mem::transmute(raw.get_data());
+        *buf = cx.buf.take().expect("recv alloc_cb called more than once")
+    }
+}
+
+#[cfg(unix)]
+extern fn vectored_recv_cb(handle: *mut uvll::uv_udp_t, nread: libc::ssize_t,
+                           buf: *const uvll::uv_buf_t,
+                           _addr: *const libc::sockaddr,
+                           _flags: libc::c_uint) {
+    assert!(nread != uvll::ECANCELED as libc::ssize_t);
+
+    unsafe {
+        let mut raw: raw::Udp = Handle::from_raw(handle);
+        let cx: &mut UdpRecvCtx = mem::transmute(raw.get_data());
+        cx.buf = Some(*buf);
+        let result = if nread == uvll::ENOBUFS as libc::ssize_t {
+            match msg::recv(raw, cx.scatter.as_mut_slice()) {
+                (n, _) if n == uvll::EAGAIN as libc::ssize_t => return,
+                result => result,
+            }
+        } else if nread == 0 {
+            return
+        } else {
+            (nread, None)
+        };
+
+        raw.recv_stop().unwrap();
+        cx.result = Some(result);
+        ::wakeup(&mut cx.task);
+    }
+}
+
+// libuv only ever receives into a single buffer, so vectored receives call
+// `recvmsg` on the socket directly.
+#[cfg(unix)]
+mod msg {
+    use std::io::net::ip;
+    use std::mem;
+    use std::os;
+    use libc;
+
+    use {raw, uvll};
+    use raw::Handle;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    type iovlen_t = libc::size_t;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    type iovlen_t = libc::c_int;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[repr(C)]
+    struct msghdr {
+        msg_name: *mut libc::c_void,
+        msg_namelen: libc::socklen_t,
+        msg_iov: *mut uvll::uv_buf_t,
+        msg_iovlen: iovlen_t,
+        msg_control: *mut libc::c_void,
+        msg_controllen: libc::size_t,
+        msg_flags: libc::c_int,
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    #[repr(C)]
+    struct msghdr {
+        msg_name: *mut libc::c_void,
+        msg_namelen: libc::socklen_t,
+        msg_iov: *mut uvll::uv_buf_t,
+        msg_iovlen: iovlen_t,
+        msg_control: *mut libc::c_void,
+        msg_controllen: libc::socklen_t,
+        msg_flags: libc::c_int,
+    }
+
+    extern {
+        fn recvmsg(fd: libc::c_int, msg: *mut msghdr,
+                   flags: libc::c_int) -> libc::ssize_t;
+    }
+
+    // On unix a uv_buf_t has the same layout as a struct iovec, so the buffers
+    // are handed to the kernel as they are.
+    pub unsafe fn recv(handle: raw::Udp, bufs: &mut [uvll::uv_buf_t])
+                       -> (libc::ssize_t, Option<ip::SocketAddr>) {
+        let fd = match handle.fileno() {
+            Ok(fd) => fd,
+            Err(e) => return (e.code() as libc::ssize_t, None),
+        };
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut msg: msghdr = mem::zeroed();
+        msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>()
+                              as libc::socklen_t;
+        msg.msg_iov = bufs.as_mut_ptr();
+        msg.msg_iovlen = bufs.len() as iovlen_t;
+        loop {
+            let n = recvmsg(fd, &mut msg, uvll::MSG_DONTWAIT);
+            if n >= 0 {
+                let addr = raw::sockaddr_to_addr(&storage,
+                                                 msg.msg_namelen as uint);
+                return (n, Some(addr))
+            }
+            let errno = os::errno() as libc::c_int;
+            if errno != libc::EINTR {
+                return (-errno as libc::ssize_t, None)
+            }
+        }
+    }
+}
+
+// Copies as much of a buffered datagram into `dst` as fits.
+fn copy(dst: &mut [u8], src: &[u8]) -> uint {
+    let n = cmp::min(dst.len(), src.len());
+    slice::bytes::copy_memory(dst, src.slice_to(n));
+    n
+}
+
+// Copies as much of a buffered datagram into `bufs` as fits, in order.
+fn scatter(bufs: &mut [&mut [u8]], mut src: &[u8]) -> uint {
+    let mut n = 0;
+    for buf in bufs.iter_mut() {
+        let amt = copy(*buf, src);
+        src = src.slice_from(amt);
+        n += amt;
+    }
+    n
+}
+
+impl Data {
+    // Starts waiting for the socket to become readable on behalf of a
+    // selector, invoking `cb` once it is. Fails with EBUSY if a receive is
+    // already pending.
+    //
+    // Like `recv_from_vectored`, libuv is handed an empty buffer so that it
+    // reports ENOBUFS when a datagram arrives without reading it.
+    #[cfg(unix)]
+    fn start_watch(&self, cb: Box<Callback + Send>) -> UvResult<()> {
+        let mut handle = self.handle;
+        if !handle.get_data().is_null() { return Err(UvError(uvll::EBUSY)) }
+        try!(handle.recv_start(watch_alloc_cb, watch_recv_cb));
+        let watch: *mut Watch = unsafe { mem::transmute(box Watch { cb: cb }) };
+        handle.set_data(watch as *mut _);
+        self.watch.store(watch as uint, SeqCst);
+        Ok(())
+    }
+
+    // Stops waiting for the socket to become readable, if a selector still
+    // is, notifying the selector if `notify` is true.
+    fn stop_watch(&self, notify: bool) {
+        let watch = self.watch.swap(0, SeqCst);
+        let mut handle = self.handle;
+        // The watch has already fired (and been freed) if the data of the
+        // handle has been reset since.
+        if watch == 0 || handle.get_data() as uint != watch { return }
+        handle.recv_stop().unwrap();
+        handle.set_data(0 as *mut _);
+        let watch: Box<Watch> = unsafe { mem::transmute(watch) };
+        let Watch { mut cb } = *watch;
+        if notify { cb.call() }
+    }
+}
+
+#[cfg(unix)]
+extern fn watch_alloc_cb(_handle: *mut uvll::uv_handle_t,
+                         _suggested_size: libc::size_t,
+                         buf: *mut uvll::uv_buf_t) {
+    unsafe { *buf = raw::slice_to_uv_buf(&[]) }
+}
+
+// An error is reported as the socket being readable, so that the receive
+// performed in response surfaces it.
+#[cfg(unix)]
+extern fn watch_recv_cb(handle: *mut uvll::uv_udp_t, nread: libc::ssize_t,
+                        _buf: *const uvll::uv_buf_t,
+                        _addr: *const libc::sockaddr, _flags: libc::c_uint) {
+    if nread == 0 { return }
+    unsafe {
+        let mut raw: raw::Udp = Handle::from_raw(handle);
+        raw.recv_stop().unwrap();
+        let watch: Box<Watch> = mem::transmute(raw.get_data());
+        raw.set_data(0 as *mut _);
+        let Watch { mut cb } = *watch;
+        cb.call();
+    }
+}
+
 impl HomingIO for Data {
     fn home(&self) -> &HomeHandle { &self.home }
 }
@@ -245,6 +803,7 @@ impl HomingIO for Data {
 impl Drop for Data {
     fn drop(&mut self) {
         let _m = self.fire_homing_missile();
+        self.stop_watch(false);
         unsafe { self.handle.close_and_free(); }
     }
 }
@@ -0,0 +1,250 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::io::net::ip::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+#[cfg(unix)] use std::os;
+use green;
+use green::sync::Mutex;
+#[cfg(unix)] use libc;
+
+use {raw, uvll, get_host_addresses, EventLoop, Tcp, Timer, UvResult, UvError};
+use homing::{HomingIO, HomeHandle};
+#[cfg(unix)] use raw::Handle;
+
+/// A cache of idle TCP connections, keyed by the host and port they are
+/// connected to.
+///
+/// Connections are taken out of the pool with `checkout`, which reuses an idle
+/// connection if there is one and connects a new one otherwise, and given back
+/// with `checkin` once the request made on them is complete.
+///
+/// Before an idle connection is handed out it is health checked, and it is
+/// closed instead if it fails the check. By default a connection fails the
+/// check if its peer closed it or sent data which was never asked for.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut pool = try!(ConnectionPool::new(4));
+/// try!(pool.set_idle_timeout(Some(Duration::seconds(30))));
+/// let mut conn = try!(pool.checkout("example.com", 80));
+/// try!(conn.uv_write(request));
+/// let response = try!(read_response(&mut conn));
+/// pool.checkin("example.com", 80, conn);
+/// ```
+pub struct ConnectionPool {
+    inner: Arc<Mutex<Inner>>,
+}
+
+// The state of a pool, shared with the task reaping its idle connections
+struct Inner {
+    idle: HashMap<String, Vec<Idle>>,
+    max_idle: uint,
+    idle_timeout: Option<Duration>,
+    check: fn(&mut Tcp) -> bool,
+    // Whether a task is running `reaper` for this pool
+    reaping: bool,
+    uv_loop: raw::Loop,
+    home: HomeHandle,
+}
+
+struct Idle {
+    conn: Tcp,
+    // Loop time at which the connection was checked in
+    since: u64,
+}
+
+impl ConnectionPool {
+    /// Creates a pool on the local event loop which keeps at most `max_idle`
+    /// idle connections to each host.
+    pub fn new(max_idle: uint) -> UvResult<ConnectionPool> {
+        let mut eloop = try!(EventLoop::borrow());
+        Ok(ConnectionPool::new_on(&mut *eloop, max_idle))
+    }
+
+    /// Same as `new`, but specifies what event loop the idle timeouts are
+    /// measured on.
+    pub fn new_on(eloop: &mut EventLoop, max_idle: uint) -> ConnectionPool {
+        ConnectionPool {
+            inner: Arc::new(Mutex::new(Inner {
+                idle: HashMap::new(),
+                max_idle: max_idle,
+                idle_timeout: None,
+                check: healthy,
+                reaping: false,
+                uv_loop: eloop.uv_loop(),
+                home: eloop.make_handle(),
+            })),
+        }
+    }
+
+    /// Sets how long a connection may stay idle in the pool before it is
+    /// closed, or `None` to keep idle connections around indefinitely.
+    ///
+    /// Expired connections are closed by a task spawned on the current
+    /// scheduler, which sleeps on a timer until the next idle connection
+    /// expires. The task exits once the pool has no idle timeout or has been
+    /// dropped, which it notices the next time it wakes up.
+    pub fn set_idle_timeout(&mut self, dur: Option<Duration>) -> UvResult<()> {
+        let mut inner = self.inner.lock();
+        inner.idle_timeout = dur;
+        if dur.is_none() || inner.reaping { return Ok(()) }
+        let timer = try!(Timer::new());
+        let pool = self.inner.downgrade();
+        inner.reaping = true;
+        green::task::spawn(proc() reaper(pool, timer));
+        Ok(())
+    }
+
+    /// Sets the check an idle connection must pass to be handed out by
+    /// `checkout`.
+    pub fn set_health_check(&mut self, check: fn(&mut Tcp) -> bool) {
+        self.inner.lock().check = check;
+    }
+
+    /// Returns an idle connection to `host` on `port`, or connects a new one
+    /// if there are no healthy idle connections.
+    pub fn checkout(&mut self, host: &str, port: u16) -> UvResult<Tcp> {
+        let key = format!("{}:{}", host, port);
+        {
+            let mut inner = self.inner.lock();
+            inner.reap();
+            let check = inner.check;
+            loop {
+                let idle = match inner.idle.get_mut(&key) {
+                    Some(conns) => conns.pop(),
+                    None => None,
+                };
+                match idle {
+                    Some(mut idle) => {
+                        if check(&mut idle.conn) { return Ok(idle.conn) }
+                    }
+                    None => break,
+                }
+            }
+        }
+        connect(host, port)
+    }
+
+    /// Returns `conn`, connected to `host` on `port`, to the pool.
+    ///
+    /// The connection is closed instead if the pool already holds as many idle
+    /// connections to that host as it may.
+    pub fn checkin(&mut self, host: &str, port: u16, conn: Tcp) {
+        let mut inner = self.inner.lock();
+        inner.reap();
+        let now = inner.now();
+        let key = format!("{}:{}", host, port);
+        if !inner.idle.contains_key(&key) {
+            inner.idle.insert(key.clone(), Vec::new());
+        }
+        let max_idle = inner.max_idle;
+        let conns = inner.idle.get_mut(&key).unwrap();
+        if conns.len() < max_idle {
+            conns.push(Idle { conn: conn, since: now });
+        }
+    }
+
+    /// Closes all connections which have been idle for longer than the idle
+    /// timeout, without waiting for the reaper task to do so.
+    pub fn reap(&mut self) {
+        self.inner.lock().reap();
+    }
+
+    /// Returns the number of idle connections in the pool.
+    pub fn idle_count(&self) -> uint {
+        self.inner.lock().idle.values().fold(0, |n, conns| n + conns.len())
+    }
+}
+
+impl Inner {
+    fn now(&self) -> u64 {
+        let _m = self.fire_homing_missile();
+        self.uv_loop.now()
+    }
+
+    // Closes the expired connections, returning how long it is until the
+    // next idle connection expires, or `None` if there is no idle timeout.
+    fn reap(&mut self) -> Option<Duration> {
+        let timeout = match self.idle_timeout {
+            Some(dur) => dur.num_milliseconds() as u64,
+            None => return None,
+        };
+        let now = self.now();
+        let mut next = timeout;
+        for conns in self.idle.values_mut() {
+            conns.retain(|idle| now - idle.since < timeout);
+            for idle in conns.iter() {
+                next = cmp::min(next, idle.since + timeout - now);
+            }
+        }
+        Some(Duration::milliseconds(next as i64))
+    }
+}
+
+impl HomingIO for Inner {
+    fn home(&self) -> &HomeHandle { &self.home }
+}
+
+// Closes the expired connections of `pool` for as long as it has an idle
+// timeout, sleeping on `timer` in between.
+fn reaper(pool: Weak<Mutex<Inner>>, mut timer: Timer) {
+    loop {
+        let next = match pool.upgrade() {
+            Some(inner) => {
+                let mut inner = inner.lock();
+                match inner.reap() {
+                    Some(next) => next,
+                    None => { inner.reaping = false; return }
+                }
+            }
+            None => return,
+        };
+        timer.sleep(next);
+    }
+}
+
+fn connect(host: &str, port: u16) -> UvResult<Tcp> {
+    let mut err = UvError(uvll::EADDRNOTAVAIL);
+    for ip in try!(get_host_addresses(host)).into_iter() {
+        match Tcp::connect(SocketAddr { ip: ip, port: port }) {
+            Ok(conn) => return Ok(conn),
+            Err(e) => err = e,
+        }
+    }
+    Err(err)
+}
+
+// An idle connection is healthy if there is nothing to read from it: data
+// can't be a response to anything, and EOF or an error means it is unusable.
+// This includes data which was already read into the buffer of the stream.
+#[cfg(unix)]
+fn healthy(conn: &mut Tcp) -> bool {
+    if conn.buffered() > 0 { return false }
+    let fd = match unsafe { conn.raw() }.fileno() {
+        Ok(fd) => fd,
+        Err(..) => return false,
+    };
+    let mut buf = [0u8];
+    let n = unsafe {
+        libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void, 1,
+                   uvll::MSG_PEEK | uvll::MSG_DONTWAIT)
+    };
+    n < 0 && os::errno() as libc::c_int == libc::EAGAIN
+}
+
+// There is no way to peek at a socket without blocking here, so idle
+// connections are assumed to be healthy unless data was already buffered.
+#[cfg(windows)]
+fn healthy(conn: &mut Tcp) -> bool { conn.buffered() == 0 }
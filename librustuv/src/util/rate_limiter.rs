@@ -0,0 +1,112 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::Duration;
+use green::sync::Mutex;
+
+use {uvll, Timer, UvResult, UvError};
+
+/// A token bucket limiting the rate at which tasks perform an operation.
+///
+/// The bucket holds up to `burst` tokens and is refilled with `rate` tokens
+/// per second. Tasks take tokens out of the bucket with `acquire`, blocking
+/// until enough of them are available.
+///
+/// A rate limiter can be shared among tasks on any event loop, for example by
+/// placing it in an `Arc`. Tokens are handed out in the order in which tasks
+/// asked for them: a task which has to wait reserves its tokens up front, so
+/// that tasks asking later wait for it too.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// // At most 10 requests per second, in bursts of at most 5
+/// let limiter = RateLimiter::new(10, 5);
+/// loop {
+///     try!(limiter.acquire(1));
+///     send_request();
+/// }
+/// ```
+pub struct RateLimiter {
+    // Tokens added per millisecond
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    // Negative while tasks are waiting for the tokens they reserved
+    tokens: f64,
+    // Time in milliseconds at which `tokens` was last refilled
+    last: u64,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter, starting with a full bucket.
+    ///
+    /// # Failure
+    ///
+    /// This function fails if `rate` or `burst` is 0.
+    pub fn new(rate: uint, burst: uint) -> RateLimiter {
+        assert!(rate > 0 && burst > 0, "rate limiter can never be acquired");
+        RateLimiter {
+            rate: rate as f64 / 1000.0,
+            burst: burst as f64,
+            bucket: Mutex::new(Bucket { tokens: burst as f64, last: now() }),
+        }
+    }
+
+    /// Takes `n` tokens out of the bucket, blocking the current task until
+    /// they are available. The task sleeps on its local event loop.
+    ///
+    /// Fails with EINVAL if `n` is greater than the size of the bucket, as
+    /// that many tokens are never available at once.
+    pub fn acquire(&self, n: uint) -> UvResult<()> {
+        let n = n as f64;
+        if n > self.burst { return Err(UvError(uvll::EINVAL)) }
+        let missing = {
+            let mut bucket = self.bucket.lock();
+            self.refill(&mut *bucket);
+            bucket.tokens -= n;
+            -bucket.tokens
+        };
+        if missing > 0.0 {
+            let ms = (missing / self.rate).ceil() as i64;
+            try!(Timer::new()).sleep(Duration::milliseconds(ms));
+        }
+        Ok(())
+    }
+
+    /// Takes `n` tokens out of the bucket if they are available, without
+    /// blocking.
+    pub fn try_acquire(&self, n: uint) -> bool {
+        let mut bucket = self.bucket.lock();
+        self.refill(&mut *bucket);
+        if bucket.tokens >= n as f64 {
+            bucket.tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket) {
+        let now = now();
+        let elapsed = (now - bucket.last) as f64;
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+    }
+}
+
+// The bucket may be used from several event loops, so it follows the
+// monotonic clock rather than the cached time of any one loop.
+fn now() -> u64 {
+    unsafe { uvll::uv_hrtime() / 1000000 }
+}
@@ -38,7 +38,8 @@ use libc::uintptr_t;
 
 pub use self::errors::{EACCES, ECONNREFUSED, ECONNRESET, EPIPE, ECONNABORTED,
                        ECANCELED, EBADF, ENOTCONN, ENOENT, EADDRNOTAVAIL,
-                       EADDRINUSE, EPERM, EINVAL};
+                       EADDRINUSE, EEXIST, EPERM, EINVAL, EAGAIN, ENOSYS, EBUSY,
+                       ETIMEDOUT, ENOBUFS, EMFILE};
 
 pub use self::uv_membership::{UV_JOIN_GROUP, UV_LEAVE_GROUP};
 pub use self::uv_handle_type::{UV_UNKNOWN_HANDLE, UV_ASYNC, UV_CHECK};
@@ -76,6 +77,13 @@ pub mod errors {
     pub static EADDRINUSE: c_int = -4091;
     pub static EPERM: c_int = -4048;
     pub static EINVAL: c_int = -4071;
+    pub static EAGAIN: c_int = -4088;
+    pub static ENOSYS: c_int = -4054;
+    pub static EEXIST: c_int = -4075;
+    pub static EBUSY: c_int = -4082;
+    pub static ETIMEDOUT: c_int = -4039;
+    pub static ENOBUFS: c_int = -4060;
+    pub static EMFILE: c_int = -4066;
 }
 
 #[cfg(not(windows))]
@@ -96,6 +104,13 @@ pub mod errors {
     pub static EADDRINUSE : c_int = -libc::EADDRINUSE;
     pub static EPERM: c_int = -libc::EPERM;
     pub static EINVAL: c_int = -libc::EINVAL;
+    pub static EAGAIN: c_int = -libc::EAGAIN;
+    pub static ENOSYS: c_int = -libc::ENOSYS;
+    pub static EEXIST: c_int = -libc::EEXIST;
+    pub static EBUSY: c_int = -libc::EBUSY;
+    pub static ETIMEDOUT: c_int = -libc::ETIMEDOUT;
+    pub static ENOBUFS: c_int = -libc::ENOBUFS;
+    pub static EMFILE: c_int = -libc::EMFILE;
 }
 
 pub static PROCESS_SETUID: c_int = 1 << 0;
@@ -104,6 +119,13 @@ pub static PROCESS_WINDOWS_VERBATIM_ARGUMENTS: c_int = 1 << 2;
 pub static PROCESS_DETACHED: c_int = 1 << 3;
 pub static PROCESS_WINDOWS_HIDE: c_int = 1 << 4;
 
+pub static UV_RENAME: c_int = 1;
+pub static UV_CHANGE: c_int = 2;
+
+pub static FS_EVENT_WATCH_ENTRY: c_uint = 1 << 0;
+pub static FS_EVENT_STAT: c_uint = 1 << 1;
+pub static FS_EVENT_RECURSIVE: c_uint = 1 << 2;
+
 pub static STDIO_IGNORE: c_int = 0x00;
 pub static STDIO_CREATE_PIPE: c_int = 0x01;
 pub static STDIO_INHERIT_FD: c_int = 0x02;
@@ -111,6 +133,140 @@ pub static STDIO_INHERIT_STREAM: c_int = 0x04;
 pub static STDIO_READABLE_PIPE: c_int = 0x10;
 pub static STDIO_WRITABLE_PIPE: c_int = 0x20;
 
+#[cfg(all(any(target_os = "linux", target_os = "android"),
+          not(target_arch = "mips"), not(target_arch = "mipsel")))]
+pub static SIGWINCH: c_int = 28;
+#[cfg(all(any(target_os = "linux", target_os = "android"),
+          any(target_arch = "mips", target_arch = "mipsel")))]
+pub static SIGWINCH: c_int = 20;
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly"))]
+pub static SIGWINCH: c_int = 28;
+// libuv emulates the signal on windows, see uv-win.h
+#[cfg(windows)]
+pub static SIGWINCH: c_int = 28;
+
+#[cfg(all(any(target_os = "linux", target_os = "android"),
+          not(target_arch = "mips"), not(target_arch = "mipsel")))]
+pub static SIGUSR2: c_int = 12;
+#[cfg(all(any(target_os = "linux", target_os = "android"),
+          any(target_arch = "mips", target_arch = "mipsel")))]
+pub static SIGUSR2: c_int = 17;
+#[cfg(any(target_os = "macos",
+          target_os = "ios",
+          target_os = "freebsd",
+          target_os = "dragonfly"))]
+pub static SIGUSR2: c_int = 31;
+
+// Flags of recv and recvmsg, which libuv doesn't wrap
+#[cfg(unix)]
+pub static MSG_PEEK: c_int = 0x2;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub static MSG_DONTWAIT: c_int = 0x40;
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "dragonfly"))]
+pub static MSG_DONTWAIT: c_int = 0x80;
+
+// Socket options which libuv doesn't provide a function for
+#[cfg(unix)]
+pub mod sockopt {
+    use libc::c_int;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static SO_SNDBUF: c_int = 7;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static SO_RCVBUF: c_int = 8;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static SO_LINGER: c_int = 13;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static SO_REUSEPORT: c_int = 15;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static SO_PEERCRED: c_int = 17;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    pub static SO_SNDBUF: c_int = 0x1001;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    pub static SO_RCVBUF: c_int = 0x1002;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    pub static SO_LINGER: c_int = 0x80;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    pub static SO_REUSEPORT: c_int = 0x200;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static IP_TOS: c_int = 1;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    pub static IP_TOS: c_int = 3;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static IPV6_TCLASS: c_int = 67;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub static IPV6_TCLASS: c_int = 36;
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub static IPV6_TCLASS: c_int = 61;
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static TCP_KEEPINTVL: c_int = 5;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub static TCP_KEEPCNT: c_int = 6;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub static TCP_KEEPINTVL: c_int = 0x101;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub static TCP_KEEPCNT: c_int = 0x102;
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub static TCP_KEEPINTVL: c_int = 512;
+    #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+    pub static TCP_KEEPCNT: c_int = 1024;
+
+    // Options of unix domain sockets
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub static SOL_LOCAL: c_int = 0;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub static LOCAL_PEERCRED: c_int = 1;
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    pub static LOCAL_PEERPID: c_int = 2;
+}
+
+// fcntl and its flags, which libuv doesn't wrap
+#[cfg(unix)]
+pub mod fcntl {
+    use libc::c_int;
+
+    pub use self::os::{F_SETFD, F_SETFL, FD_CLOEXEC, O_NONBLOCK};
+
+    extern {
+        pub fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    mod os {
+        use libc::c_int;
+
+        pub static F_SETFD: c_int = 2;
+        pub static F_SETFL: c_int = 4;
+        pub static FD_CLOEXEC: c_int = 1;
+        pub static O_NONBLOCK: c_int = 0o4000;
+    }
+
+    #[cfg(any(target_os = "macos",
+              target_os = "ios",
+              target_os = "freebsd",
+              target_os = "dragonfly"))]
+    mod os {
+        use libc::c_int;
+
+        pub static F_SETFD: c_int = 2;
+        pub static F_SETFL: c_int = 4;
+        pub static FD_CLOEXEC: c_int = 1;
+        pub static O_NONBLOCK: c_int = 0x4;
+    }
+}
+
 #[cfg(unix)]
 pub type uv_buf_len_t = libc::size_t;
 #[cfg(windows)]
@@ -126,6 +282,8 @@ pub struct uv_buf_t {
 
 #[cfg(unix)]
 pub type uv_os_socket_t = c_int;
+#[cfg(unix)]
+pub type uv_os_fd_t = c_int;
 
 // see libuv/include/uv-win.h
 #[cfg(windows)]
@@ -137,6 +295,8 @@ pub struct uv_buf_t {
 
 #[cfg(windows)]
 pub type uv_os_socket_t = libc::SOCKET;
+#[cfg(windows)]
+pub type uv_os_fd_t = libc::HANDLE;
 
 #[repr(C)]
 pub enum uv_run_mode {
@@ -175,10 +335,13 @@ pub struct uv_stdio_container_t {
 
 // handles
 pub enum uv_async_t {}
+pub enum uv_check_t {}
+pub enum uv_fs_event_t {}
 pub enum uv_handle_t {}
 pub enum uv_idle_t {}
 pub enum uv_pipe_t {}
 pub enum uv_poll_t {}
+pub enum uv_prepare_t {}
 pub enum uv_process_t {}
 pub enum uv_signal_t {}
 pub enum uv_stream_t {}
@@ -256,6 +419,8 @@ impl uv_stat_t {
 }
 
 pub type uv_idle_cb = extern "C" fn(handle: *mut uv_idle_t);
+pub type uv_prepare_cb = extern "C" fn(handle: *mut uv_prepare_t);
+pub type uv_check_cb = extern "C" fn(handle: *mut uv_check_t);
 pub type uv_alloc_cb = extern "C" fn(stream: *mut uv_handle_t,
                                      suggested_size: size_t,
                                      buf: *mut uv_buf_t);
@@ -291,6 +456,10 @@ pub type uv_exit_cb = extern "C" fn(handle: *mut uv_process_t,
                                     term_signal: c_int);
 pub type uv_signal_cb = extern "C" fn(handle: *mut uv_signal_t,
                                       signum: c_int);
+pub type uv_fs_event_cb = extern "C" fn(handle: *mut uv_fs_event_t,
+                                        filename: *const c_char,
+                                        events: c_int,
+                                        status: c_int);
 pub type uv_fs_cb = extern "C" fn(req: *mut uv_fs_t);
 pub type uv_shutdown_cb = extern "C" fn(req: *mut uv_shutdown_t, status: c_int);
 
@@ -400,8 +569,11 @@ fn request_sanity_check() {
 
 // uv_support is the result of compiling rust_uv.c
 #[link(name = "uv_support", kind = "static")]
-#[link(name = "uv", kind = "static")]
+#[cfg_attr(not(feature = "system-libuv"), link(name = "uv", kind = "static"))]
+#[cfg_attr(feature = "system-libuv", link(name = "uv"))]
 extern {
+    pub fn uv_version() -> c_uint;
+    pub fn rust_uv_bundled_version() -> c_uint;
     pub fn uv_loop_size() -> size_t;
     pub fn uv_loop_init(l: *mut uv_loop_t) -> c_int;
     pub fn uv_loop_close(l: *mut uv_loop_t) -> c_int;
@@ -425,10 +597,15 @@ extern {
                                                       -> *mut uv_stream_t;
     pub fn rust_uv_get_stream_handle_from_write_req(req: *mut uv_write_t)
                                                     -> *mut uv_stream_t;
+    #[cfg(unix)]
+    pub fn rust_uv_get_buf_from_write_req(req: *mut uv_write_t)
+                                          -> *mut uv_buf_t;
+    pub fn rust_uv_get_write_queue_size(stream: *mut uv_stream_t) -> size_t;
     pub fn rust_uv_get_data_for_uv_loop(l: *mut uv_loop_t) -> *mut c_void;
     pub fn rust_uv_set_data_for_uv_loop(l: *mut uv_loop_t, data: *mut c_void);
     pub fn rust_uv_get_loop_for_uv_handle(handle: *mut uv_handle_t)
                                           -> *mut uv_loop_t;
+    pub fn rust_uv_handle_type(handle: *mut uv_handle_t) -> uv_handle_type;
     pub fn rust_uv_get_data_for_uv_handle(handle: *mut uv_handle_t)
                                           -> *mut c_void;
     pub fn rust_uv_set_data_for_uv_handle(handle: *mut uv_handle_t,
@@ -441,6 +618,7 @@ extern {
                                            stream: *mut uv_stream_t);
     pub fn rust_uv_process_pid(p: *mut uv_process_t) -> c_int;
     pub fn rust_uv_guess_handle(fd: c_int) -> c_int;
+    pub fn rust_uv_gettimeofday(sec: *mut i64, usec: *mut i32) -> c_int;
 
     // generic uv functions
     pub fn uv_loop_delete(l: *mut uv_loop_t);
@@ -449,8 +627,15 @@ extern {
     pub fn uv_handle_size(ty: uv_handle_type) -> size_t;
     pub fn uv_req_size(ty: uv_req_type) -> size_t;
     pub fn uv_run(l: *mut uv_loop_t, mode: uv_run_mode) -> c_int;
+    pub fn uv_stop(l: *mut uv_loop_t);
+    pub fn uv_now(l: *const uv_loop_t) -> u64;
+    pub fn uv_hrtime() -> u64;
     pub fn uv_close(h: *mut uv_handle_t, cb: Option<uv_close_cb>);
+    pub fn uv_is_closing(h: *const uv_handle_t) -> c_int;
+    pub fn uv_is_active(h: *const uv_handle_t) -> c_int;
+    pub fn uv_has_ref(h: *const uv_handle_t) -> c_int;
     pub fn uv_walk(l: *mut uv_loop_t, cb: uv_walk_cb, arg: *mut c_void);
+    pub fn uv_fileno(h: *const uv_handle_t, fd: *mut uv_os_fd_t) -> c_int;
     pub fn uv_buf_init(base: *mut c_char, len: c_uint) -> uv_buf_t;
     pub fn uv_strerror(err: c_int) -> *const c_char;
     pub fn uv_err_name(err: c_int) -> *const c_char;
@@ -463,10 +648,14 @@ extern {
     pub fn uv_read_stop(stream: *mut uv_stream_t) -> c_int;
     pub fn uv_shutdown(req: *mut uv_shutdown_t, handle: *mut uv_stream_t,
                        cb: uv_shutdown_cb) -> c_int;
+    pub fn uv_is_readable(handle: *const uv_stream_t) -> c_int;
+    pub fn uv_is_writable(handle: *const uv_stream_t) -> c_int;
 
     pub fn uv_write(req: *mut uv_write_t, stream: *mut uv_stream_t,
                     buf_in: *const uv_buf_t, buf_cnt: c_int,
                     cb: uv_write_cb) -> c_int;
+    pub fn uv_try_write(stream: *mut uv_stream_t, buf_in: *const uv_buf_t,
+                        buf_cnt: c_uint) -> c_int;
     pub fn uv_cancel(req: *mut uv_req_t) -> c_int;
 
     // idle bindings
@@ -474,6 +663,16 @@ extern {
     pub fn uv_idle_start(i: *mut uv_idle_t, cb: uv_idle_cb) -> c_int;
     pub fn uv_idle_stop(i: *mut uv_idle_t) -> c_int;
 
+    // prepare bindings
+    pub fn uv_prepare_init(l: *mut uv_loop_t, p: *mut uv_prepare_t) -> c_int;
+    pub fn uv_prepare_start(p: *mut uv_prepare_t, cb: uv_prepare_cb) -> c_int;
+    pub fn uv_prepare_stop(p: *mut uv_prepare_t) -> c_int;
+
+    // check bindings
+    pub fn uv_check_init(l: *mut uv_loop_t, c: *mut uv_check_t) -> c_int;
+    pub fn uv_check_start(c: *mut uv_check_t, cb: uv_check_cb) -> c_int;
+    pub fn uv_check_stop(c: *mut uv_check_t) -> c_int;
+
     // async bindings
     pub fn uv_async_init(l: *mut uv_loop_t, a: *mut uv_async_t,
                          cb: uv_async_cb) -> c_int;
@@ -628,6 +827,13 @@ extern {
     pub fn uv_signal_start(h: *mut uv_signal_t, cb: uv_signal_cb,
                            signum: c_int) -> c_int;
     pub fn uv_signal_stop(handle: *mut uv_signal_t) -> c_int;
+
+    // fs events
+    pub fn uv_fs_event_init(loop_: *mut uv_loop_t,
+                            handle: *mut uv_fs_event_t) -> c_int;
+    pub fn uv_fs_event_start(handle: *mut uv_fs_event_t, cb: uv_fs_event_cb,
+                             filename: *const c_char, flags: c_uint) -> c_int;
+    pub fn uv_fs_event_stop(handle: *mut uv_fs_event_t) -> c_int;
 }
 
 // libuv requires other native libraries on various platforms. These are all
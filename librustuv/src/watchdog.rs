@@ -0,0 +1,149 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Detection of code blocking the event loop.
+//!
+//! Nothing else happens on an event loop while one of its callbacks runs, and
+//! the green tasks of a scheduler are themselves run from a callback of its
+//! event loop. A task which accidentally blocks, for example by using native
+//! I/O or by spinning, therefore stalls every other task and handle on its
+//! event loop.
+//!
+//! A watchdog, installed with `EventLoop::set_watchdog`, measures every turn
+//! of the event loop and every callback run by rustuv, and reports those which
+//! take longer than a threshold. A turn of the loop is measured from the
+//! moment the loop has finished polling for I/O until it starts polling again,
+//! so time spent waiting for events isn't counted. Note that a slow callback
+//! usually also makes its turn of the loop slow, so both are reported.
+
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+use {raw, uvll, UvResult};
+use raw::{Loop, Handle};
+use metrics::PollHooks;
+use event_loop::{local_watchdog, finish_reporting, loop_data};
+
+/// A callback or turn of the event loop which exceeded the threshold of a
+/// watchdog.
+pub struct Stall {
+    /// What was running, such as "loop iteration" or "timer callback".
+    pub what: &'static str,
+    /// How long it ran for.
+    pub elapsed: Duration,
+}
+
+/// A receiver of the stalls detected by a watchdog.
+pub trait Hook {
+    fn stalled(&mut self, stall: &Stall);
+}
+
+/// The watchdog of an event loop.
+pub struct Watchdog {
+    _hooks: PollHooks,
+    state: Box<State>,
+}
+
+struct State {
+    threshold: u64,
+    hook: Option<Box<Hook + Send>>,
+    // When the loop last finished polling for I/O, in nanoseconds
+    busy_since: u64,
+    // Set while the hook runs, so it can't report itself
+    reporting: bool,
+}
+
+impl Watchdog {
+    /// Creates a watchdog reporting stalls longer than `threshold` to `hook`,
+    /// or to stderr if there is no hook.
+    pub fn new(uv_loop: &Loop, threshold: Duration,
+               hook: Option<Box<Hook + Send>>) -> UvResult<Watchdog> {
+        let mut state = box State {
+            threshold: threshold.num_microseconds().unwrap_or(0) as u64 * 1000,
+            hook: hook,
+            busy_since: hrtime(),
+            reporting: false,
+        };
+        let data = &mut *state as *mut State as *mut _;
+        let hooks = try!(unsafe {
+            PollHooks::new(uv_loop, data, prepare_cb, check_cb)
+        });
+        Ok(Watchdog { _hooks: hooks, state: state })
+    }
+}
+
+impl Watchdog {
+    /// Returns whether the hook of this watchdog is currently running.
+    pub fn is_reporting(&self) -> bool { self.state.reporting }
+}
+
+impl State {
+    fn report(&mut self, what: &'static str, nanos: u64) {
+        if nanos < self.threshold || self.reporting { return }
+        let stall = Stall {
+            what: what,
+            elapsed: Duration::microseconds((nanos / 1000) as i64),
+        };
+        self.reporting = true;
+        match self.hook {
+            Some(ref mut hook) => hook.stalled(&stall),
+            None => uverrln!("rustuv: {}", stall),
+        }
+        self.reporting = false;
+    }
+}
+
+/// Runs `f`, a callback of `uv_loop`, reporting it to the watchdog of the
+/// loop (if any) as `what` if it runs for too long.
+pub fn time<T>(uv_loop: Loop, what: &'static str, f: || -> T) -> T {
+    if !unsafe { loop_data(uv_loop).watched } { return f() }
+    let start = hrtime();
+    let ret = f();
+    // The watchdog may have been removed by `f`, so it is looked up again.
+    match local_watchdog() {
+        Some(w) => {
+            w.state.report(what, hrtime() - start);
+            // `w` may be freed from here on
+            finish_reporting();
+        }
+        None => {}
+    }
+    ret
+}
+
+fn hrtime() -> u64 { unsafe { uvll::uv_hrtime() } }
+
+// The loop is about to poll for I/O, ending a turn
+extern fn prepare_cb(handle: *mut uvll::uv_prepare_t) {
+    unsafe {
+        let prepare: raw::Prepare = Handle::from_raw(handle);
+        let state: &mut State = mem::transmute(prepare.get_data());
+        let busy_since = state.busy_since;
+        state.report("loop iteration", hrtime() - busy_since);
+        // `state` may be freed from here on
+        finish_reporting();
+    }
+}
+
+// The loop has polled for I/O, starting a turn
+extern fn check_cb(handle: *mut uvll::uv_check_t) {
+    unsafe {
+        let check: raw::Check = Handle::from_raw(handle);
+        let state: &mut State = mem::transmute(check.get_data());
+        state.busy_since = hrtime();
+    }
+}
+
+impl fmt::Show for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} took {}ms", self.what, self.elapsed.num_milliseconds())
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A hierarchical timer wheel multiplexing timeouts onto one libuv timer.
+//!
+//! A server with many connections each having a read timeout would otherwise
+//! need a `uv_timer_t` per connection. Instead, each event loop has a wheel
+//! into which timeouts are inserted, and a single timer which is armed for the
+//! earliest point in time at which the wheel has work to do.
+//!
+//! The wheel has `LEVELS` levels of `SLOTS` slots each. A slot of level `l`
+//! spans `SLOTS^l` milliseconds of loop time, and a timeout is stored in the
+//! lowest level in which its deadline is within one turn of the wheel. Whenever
+//! time moves past a slot of a higher level, the timeouts in it are moved down
+//! to a lower level, until they end up in level 0 and expire. Inserting and
+//! cancelling a timeout are therefore constant time operations.
+
+use std::cmp;
+use std::mem;
+
+use {raw, uvll, UvResult};
+use raw::{Loop, Handle};
+
+static BITS: uint = 6;
+static SLOTS: uint = 1 << BITS;
+static LEVELS: uint = 4;
+
+pub struct TimerWheel {
+    timer: raw::Timer,
+    // Loop time up to which the wheel has been advanced
+    current: u64,
+    // Indices into `entries`, `SLOTS` per level
+    slots: Vec<Vec<uint>>,
+    entries: Vec<Entry>,
+    free: Vec<uint>,
+    len: uint,
+}
+
+/// A timeout inserted into a wheel, used to cancel it.
+#[deriving(PartialEq, Clone, Show)]
+pub struct Timeout {
+    index: uint,
+    generation: uint,
+}
+
+struct Entry {
+    generation: uint,
+    deadline: u64,
+    // Where the entry is stored: `slots[slot][pos]`
+    slot: uint,
+    pos: uint,
+    cb: Option<fn(uint)>,
+    data: uint,
+}
+
+impl TimerWheel {
+    pub fn new(uv_loop: &Loop) -> UvResult<Box<TimerWheel>> {
+        let mut ret = box TimerWheel {
+            timer: unsafe { try!(raw::Timer::new(uv_loop)) },
+            current: uv_loop.now(),
+            slots: Vec::from_fn(LEVELS * SLOTS, |_| Vec::new()),
+            entries: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        };
+        let data = &mut *ret as *mut TimerWheel as *mut _;
+        ret.timer.set_data(data);
+        Ok(ret)
+    }
+
+    /// Calls `cb(data)` once the loop time reaches `deadline`.
+    pub fn insert(&mut self, deadline: u64, cb: fn(uint),
+                  data: uint) -> Timeout {
+        if self.len == 0 {
+            self.current = self.now();
+        }
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.entries.push(Entry {
+                    generation: 0,
+                    deadline: 0,
+                    slot: 0,
+                    pos: 0,
+                    cb: None,
+                    data: 0,
+                });
+                self.entries.len() - 1
+            }
+        };
+        {
+            let entry = &mut self.entries[index];
+            entry.deadline = deadline;
+            entry.cb = Some(cb);
+            entry.data = data;
+        }
+        self.len += 1;
+
+        // A deadline which has already passed expires on the next tick
+        let deadline = cmp::max(deadline, self.current + 1);
+        self.place(index, deadline);
+        self.arm();
+        Timeout { index: index, generation: self.entries[index].generation }
+    }
+
+    /// Cancels `timeout`, which does nothing if it has already expired.
+    pub fn cancel(&mut self, timeout: Timeout) {
+        if self.entries[timeout.index].generation != timeout.generation {
+            return
+        }
+        // The last entry of the slot takes the place of the cancelled one
+        let (slot, pos) = {
+            let entry = &self.entries[timeout.index];
+            (entry.slot, entry.pos)
+        };
+        self.slots[slot].swap_remove(pos);
+        if pos < self.slots[slot].len() {
+            let moved = self.slots[slot][pos];
+            self.entries[moved].pos = pos;
+        }
+        self.release(timeout.index);
+        self.arm();
+    }
+
+    /// Returns the current loop time, which deadlines are compared against.
+    pub fn now(&self) -> u64 {
+        self.timer.uv_loop().now()
+    }
+
+    pub unsafe fn close(&mut self) {
+        self.timer.close_and_free();
+    }
+
+    // Stores the entry at `index` in the slot in which it's found when time
+    // reaches `deadline`, which must be in the future.
+    fn place(&mut self, index: uint, deadline: u64) {
+        let mut level = 0;
+        while level < LEVELS - 1 &&
+              deadline >> (BITS * (level + 1)) !=
+                  self.current >> (BITS * (level + 1)) {
+            level += 1;
+        }
+
+        // Deadlines beyond the range of the wheel are stored as far out as
+        // possible, and placed again once that slot is reached.
+        let shift = BITS * level;
+        let last = ((self.current >> shift) + SLOTS as u64 - 1) << shift;
+        let deadline = cmp::min(deadline, last);
+        let slot = level * SLOTS + ((deadline >> shift) as uint & (SLOTS - 1));
+        self.entries[index].slot = slot;
+        self.entries[index].pos = self.slots[slot].len();
+        self.slots[slot].push(index);
+    }
+
+    fn release(&mut self, index: uint) {
+        let entry = &mut self.entries[index];
+        entry.generation += 1;
+        entry.cb = None;
+        self.free.push(index);
+        self.len -= 1;
+    }
+
+    // Returns the next point in time at which a slot with timeouts in it is
+    // reached.
+    fn next_tick(&self) -> Option<u64> {
+        let mut ret = None;
+        for level in range(0, LEVELS) {
+            let shift = BITS * level;
+            let base = self.current >> shift;
+            for i in range(1, SLOTS as u64 + 1) {
+                let slot = level * SLOTS + ((base + i) as uint & (SLOTS - 1));
+                if self.slots[slot].len() > 0 {
+                    let at = (base + i) << shift;
+                    ret = Some(ret.map_or(at, |t| cmp::min(t, at)));
+                    break
+                }
+            }
+        }
+        ret
+    }
+
+    // Moves time forward to `now`, returning the callbacks of the timeouts
+    // which expired.
+    fn advance(&mut self, now: u64) -> Vec<(fn(uint), uint)> {
+        let mut expired = Vec::new();
+        loop {
+            let t = match self.next_tick() {
+                Some(t) if t <= now => t,
+                _ => break,
+            };
+            self.current = t;
+
+            // Move down the timeouts of every higher level slot reached at
+            // this point, then expire those which ended up in level 0.
+            for level in range(1, LEVELS).rev() {
+                let shift = BITS * level;
+                if t & ((1 << shift) - 1) != 0 { continue }
+                let slot = level * SLOTS + ((t >> shift) as uint & (SLOTS - 1));
+                let indices = mem::replace(&mut self.slots[slot], Vec::new());
+                for &index in indices.iter() {
+                    let deadline = self.entries[index].deadline;
+                    if deadline <= t {
+                        self.expire(index, &mut expired);
+                    } else {
+                        self.place(index, deadline);
+                    }
+                }
+            }
+            let slot = t as uint & (SLOTS - 1);
+            let indices = mem::replace(&mut self.slots[slot], Vec::new());
+            for &index in indices.iter() {
+                self.expire(index, &mut expired);
+            }
+        }
+        self.current = cmp::max(self.current, now);
+        expired
+    }
+
+    fn expire(&mut self, index: uint, expired: &mut Vec<(fn(uint), uint)>) {
+        let cb = self.entries[index].cb.unwrap();
+        expired.push((cb, self.entries[index].data));
+        self.release(index);
+    }
+
+    // Arms the timer for the next tick, or stops it if the wheel is empty.
+    fn arm(&mut self) {
+        match self.next_tick() {
+            Some(t) => {
+                let now = self.now();
+                let ms = if t > now {t - now} else {0};
+                self.timer.stop().unwrap();
+                self.timer.start(ms, 0, timer_cb).unwrap();
+            }
+            None => self.timer.stop().unwrap(),
+        }
+    }
+}
+
+// Callbacks may insert and cancel timeouts themselves, so they're only run
+// once the wheel is done updating its own state.
+extern fn timer_cb(handle: *mut uvll::uv_timer_t) {
+    let expired = unsafe {
+        let timer: raw::Timer = Handle::from_raw(handle);
+        let wheel: &mut TimerWheel = mem::transmute(timer.get_data());
+        let expired = wheel.advance(timer.uv_loop().now());
+        wheel.arm();
+        expired
+    };
+    for &(cb, data) in expired.iter() {
+        cb(data);
+    }
+}
@@ -1,4 +1,5 @@
-use rustuv::get_host_addresses;
+use rustuv::{get_host_addresses, get_host_addresses_async, lookup};
+use std::io::net::addrinfo::{Hint, SocketType};
 use std::io::net::ip::Ipv4Addr;
 
 test!(fn dns_smoke_test() {
@@ -11,8 +12,32 @@ test!(fn dns_smoke_test() {
     assert!(found_local);
 })
 
+test!(fn dns_async() {
+    let rx1 = get_host_addresses_async("localhost");
+    let rx2 = get_host_addresses_async("localhost");
+    for rx in [rx1, rx2].iter() {
+        let ipaddrs = rx.recv().unwrap();
+        assert!(ipaddrs.contains(&Ipv4Addr(127, 0, 0, 1)));
+    }
+})
+
 test!(fn issue_10663() {
     // Something should happen here, but this certainly shouldn't cause
     // everything to die. The actual outcome we don't care too much about.
     get_host_addresses("example.com").unwrap();
 })
+
+test!(fn lookup_hints() {
+    let hint = Hint {
+        family: 0,
+        socktype: Some(SocketType::Datagram),
+        protocol: None,
+        flags: 0,
+    };
+    let entries = lookup(Some("localhost"), Some("53"), Some(hint)).unwrap();
+    assert!(entries.len() > 0);
+    for entry in entries.iter() {
+        assert_eq!(entry.socktype, Some(SocketType::Datagram));
+        assert_eq!(entry.address.port, 53);
+    }
+})
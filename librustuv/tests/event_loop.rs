@@ -12,3 +12,340 @@ test!(fn callback_run_once() {
     assert_eq!(count, 1);
 })
 
+test!(fn stop_from_callback() {
+    let mut event_loop = rustuv::EventLoop::new().unwrap();
+    // The handle keeps the loop alive, so only stopping it makes it return
+    let handle = event_loop.handle();
+    let eloop: *mut rustuv::EventLoop = &mut event_loop;
+    event_loop.callback(proc() {
+        unsafe { (*eloop).stop().unwrap() }
+    });
+    event_loop.run();
+    drop(handle);
+})
+
+test!(fn stop_from_other_thread() {
+    use std::rt::thread::Thread;
+
+    let mut event_loop = rustuv::EventLoop::new().unwrap();
+    let handle = event_loop.handle();
+    let remote = handle.clone();
+    let thread = Thread::start(proc() remote.stop().unwrap());
+    event_loop.run();
+    thread.join();
+    drop(handle);
+})
+
+test!(fn stop_scheduler_loop() {
+    use rustuv::uvll;
+
+    // Tests run in a green task, so the local loop is owned by a scheduler
+    let mut eloop = rustuv::EventLoop::borrow().unwrap();
+    assert_eq!(eloop.stop().err().unwrap().code(), uvll::EINVAL);
+    let handle = eloop.handle();
+    assert_eq!(handle.stop().err().unwrap().code(), uvll::EINVAL);
+})
+
+test!(fn graceful_shutdown() {
+    use std::io::Acceptor;
+    use std::io::test::next_test_ip4;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use rustuv::shutdown::{Shutdown, Stage, Progress};
+
+    struct Record(Arc<Mutex<Vec<Stage>>>);
+    impl Progress for Record {
+        fn progress(&mut self, stage: Stage) {
+            let Record(ref stages) = *self;
+            stages.lock().push(stage);
+        }
+    }
+
+    let listener = rustuv::TcpListener::bind(next_test_ip4()).unwrap();
+    let acceptor = listener.listen().unwrap();
+    let udp = rustuv::Udp::bind(next_test_ip4()).unwrap();
+    let mut shutdown = Shutdown::new();
+    shutdown.listener(box acceptor.clone());
+    shutdown.connection(box udp.clone());
+    let stages = Arc::new(Mutex::new(Vec::new()));
+    shutdown.on_progress(box Record(stages.clone()));
+
+    let (tx1, rx1) = channel();
+    let (tx2, rx2) = channel();
+    spawn(proc() {
+        let mut acceptor = acceptor;
+        tx1.send(acceptor.accept().is_err());
+    });
+    spawn(proc() {
+        let mut udp = udp;
+        let err = udp.recv_from(&mut [0]).err().unwrap();
+        tx2.send(err.code());
+    });
+    rustuv::sleep(Duration::milliseconds(10)).unwrap();
+
+    shutdown.run(Duration::milliseconds(50)).unwrap();
+    assert!(rx1.recv());
+    assert_eq!(rx2.recv(), rustuv::uvll::ECANCELED);
+    let stages = stages.lock();
+    let n = stages.len();
+    assert_eq!(stages.slice_from(n - 2),
+               [Stage::Cancelling(1), Stage::Done].as_slice());
+})
+
+test!(fn dump_handles() {
+    use std::io::MemWriter;
+
+    let _timer = rustuv::Timer::new().unwrap();
+    let eloop = rustuv::EventLoop::borrow().unwrap();
+    let mut all = MemWriter::new();
+    eloop.dump_handles(&mut all).unwrap();
+    let all = String::from_utf8(all.unwrap()).unwrap();
+    assert!(all.as_slice().lines().all(|line| line.starts_with("[")));
+    assert!(all.as_slice().contains("] timer 0x"));
+
+    let mut active = MemWriter::new();
+    eloop.dump_active_handles(&mut active).unwrap();
+    assert!(active.get_ref().len() < all.len());
+})
+
+test!(fn homing_diagnostics() {
+    let mut timer = {
+        let mut eloop = rustuv::EventLoop::borrow().unwrap();
+        assert_eq!(eloop.homing_report().len(), 0);
+        eloop.set_homing_diagnostics(true);
+        rustuv::Timer::new_on(&mut *eloop).unwrap()
+    };
+    timer.sleep(::std::time::Duration::milliseconds(1));
+
+    let record = timer.homing_record().unwrap();
+    assert_eq!(record.migrations, 0);
+
+    let mut eloop = rustuv::EventLoop::borrow().unwrap();
+    let report = eloop.homing_report();
+    assert_eq!(report.len(), 1);
+    assert_eq!(report[0].id, record.id);
+    eloop.set_homing_diagnostics(false);
+    assert_eq!(eloop.homing_report().len(), 0);
+})
+
+test!(fn queue_stats() {
+    fn stats() -> rustuv::QueueStats {
+        rustuv::EventLoop::borrow().unwrap().queue_stats()
+    }
+
+    let before = stats();
+    assert_eq!(before.depth, 0);
+    let timer = rustuv::Timer::new().unwrap();
+    assert_eq!(stats().handles, before.handles + 1);
+    drop(timer);
+    assert_eq!(stats().handles, before.handles);
+})
+
+test!(fn home_handles_dropped_elsewhere() {
+    fn handles() -> uint {
+        rustuv::EventLoop::borrow().unwrap().queue_stats().handles
+    }
+
+    let before = handles();
+    let home = rustuv::EventLoop::borrow().unwrap().make_handle();
+    let (tx, rx) = channel();
+    for _ in range(0u, 10) {
+        let (home, tx) = (home.clone(), tx.clone());
+        spawn(proc() {
+            let more = home.clone();
+            drop(home);
+            drop(more);
+            tx.send(());
+        });
+    }
+    for _ in range(0u, 10) { rx.recv() }
+    assert_eq!(handles(), before + 1);
+    drop(home);
+    assert_eq!(handles(), before);
+})
+
+test!(fn tracing() {
+    use std::sync::{Arc, Mutex};
+    use rustuv::trace::{Filter, Logger, Record, Level, Category};
+
+    struct Collect(Arc<Mutex<Vec<(Category, String)>>>);
+
+    impl Logger for Collect {
+        fn log(&mut self, record: &Record) {
+            let Collect(ref events) = *self;
+            events.lock().push((record.category, format!("{}", record.args)));
+        }
+    }
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut filter = Filter::new();
+    filter.set(Category::Timer, Some(Level::Trace));
+    {
+        let mut eloop = rustuv::EventLoop::borrow().unwrap();
+        let logger = box Collect(events.clone()) as Box<Logger + Send>;
+        eloop.set_tracing(filter, logger);
+    }
+
+    let mut timer = rustuv::Timer::new().unwrap();
+    timer.sleep(::std::time::Duration::milliseconds(1));
+    drop(timer);
+    rustuv::EventLoop::borrow().unwrap().disable_tracing();
+    let _ = rustuv::Timer::new().unwrap();
+
+    let events = events.lock();
+    let msgs: Vec<&str> = events.iter().map(|&(c, ref msg)| {
+        assert_eq!(c, Category::Timer);
+        msg.as_slice()
+    }).collect();
+    assert_eq!(msgs.len(), 4);
+    assert!(msgs[0].starts_with("created timer"));
+    assert!(msgs[1].contains("sleeping for 1ms"));
+    assert!(msgs[2].ends_with("fired"));
+    assert!(msgs[3].starts_with("closing timer"));
+})
+
+test!(fn disable_tracing_from_logger() {
+    use std::sync::{Arc, Mutex};
+    use rustuv::trace::{Filter, Logger, Record, Level};
+
+    // Stops tracing from within the logger, which mustn't free the logger
+    // while it's still running.
+    struct Once(Arc<Mutex<uint>>);
+
+    impl Logger for Once {
+        fn log(&mut self, _record: &Record) {
+            unsafe {
+                (*rustuv::EventLoop::borrow_raw().unwrap()).disable_tracing();
+            }
+            let Once(ref count) = *self;
+            *count.lock() += 1;
+        }
+    }
+
+    let count = Arc::new(Mutex::new(0u));
+    {
+        let mut eloop = rustuv::EventLoop::borrow().unwrap();
+        let logger = box Once(count.clone()) as Box<Logger + Send>;
+        eloop.set_tracing(Filter::all(Level::Trace), logger);
+    }
+
+    let mut timer = rustuv::Timer::new().unwrap();
+    timer.sleep(::std::time::Duration::milliseconds(1));
+    assert_eq!(*count.lock(), 1);
+})
+
+test!(fn borrow_twice() {
+    use rustuv::uvll;
+
+    let _eloop = rustuv::EventLoop::borrow().unwrap();
+    match rustuv::EventLoop::borrow() {
+        Ok(..) => panic!(),
+        Err(e) => assert_eq!(e.code(), uvll::EBUSY),
+    }
+    assert!(rustuv::EventLoop::try_borrow().unwrap().is_none());
+    assert!(rustuv::Timer::new().is_err());
+})
+
+test!(#[should_fail] fn unwrap_borrow_twice() {
+    let _eloop = rustuv::EventLoop::borrow().unwrap();
+    rustuv::EventLoop::borrow().unwrap();
+})
+
+test!(fn with_loop() {
+    let timer = rustuv::EventLoop::with_loop(|eloop| {
+        assert!(rustuv::EventLoop::try_borrow().unwrap().is_none());
+        rustuv::Timer::new_on(eloop)
+    });
+    assert!(timer.unwrap().is_ok());
+    assert!(rustuv::EventLoop::try_borrow().unwrap().is_some());
+})
+
+test!(fn watchdog() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use rustuv::uvll;
+    use rustuv::watchdog::{Hook, Stall};
+
+    struct Collect(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Hook for Collect {
+        fn stalled(&mut self, stall: &Stall) {
+            let Collect(ref stalls) = *self;
+            assert!(stall.elapsed >= Duration::milliseconds(20));
+            stalls.lock().push(stall.what);
+        }
+    }
+
+    let stalls = Arc::new(Mutex::new(Vec::new()));
+    {
+        let mut eloop = rustuv::EventLoop::borrow().unwrap();
+        let hook = box Collect(stalls.clone()) as Box<Hook + Send>;
+        eloop.set_watchdog(Duration::milliseconds(20), Some(hook)).unwrap();
+    }
+
+    // Spinning in a task holds up the event loop
+    let start = unsafe { uvll::uv_hrtime() };
+    while unsafe { uvll::uv_hrtime() } - start < 30 * 1000000 {}
+    let mut timer = rustuv::Timer::new().unwrap();
+    timer.sleep(Duration::milliseconds(1));
+    rustuv::EventLoop::borrow().unwrap().disable_watchdog();
+
+    let stalls = stalls.lock();
+    assert!(stalls.contains(&"loop iteration"));
+})
+
+test!(fn disable_watchdog_from_hook() {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use rustuv::uvll;
+    use rustuv::watchdog::{Hook, Stall};
+
+    // Removes the watchdog from within its hook, which mustn't free the hook
+    // while it's still running.
+    struct Once(Arc<Mutex<uint>>);
+
+    impl Hook for Once {
+        fn stalled(&mut self, _stall: &Stall) {
+            unsafe {
+                (*rustuv::EventLoop::borrow_raw().unwrap()).disable_watchdog();
+            }
+            let Once(ref count) = *self;
+            *count.lock() += 1;
+        }
+    }
+
+    let count = Arc::new(Mutex::new(0u));
+    {
+        let mut eloop = rustuv::EventLoop::borrow().unwrap();
+        let hook = box Once(count.clone()) as Box<Hook + Send>;
+        eloop.set_watchdog(Duration::milliseconds(5), Some(hook)).unwrap();
+    }
+
+    for _ in range(0u, 2) {
+        let start = unsafe { uvll::uv_hrtime() };
+        while unsafe { uvll::uv_hrtime() } - start < 10 * 1000000 {}
+        let mut timer = rustuv::Timer::new().unwrap();
+        timer.sleep(Duration::milliseconds(1));
+    }
+    assert_eq!(*count.lock(), 1);
+})
+
+test!(fn idle_time() {
+    use std::time::Duration;
+
+    fn idle_time() -> Option<Duration> {
+        rustuv::EventLoop::borrow().unwrap().stats().idle_time
+    }
+
+    assert_eq!(idle_time(), None);
+    rustuv::EventLoop::borrow().unwrap().set_idle_time_metrics(true).unwrap();
+    assert_eq!(idle_time(), Some(Duration::zero()));
+
+    // Sleeping leaves the event loop waiting on the timer
+    let mut timer = rustuv::Timer::new().unwrap();
+    timer.sleep(Duration::milliseconds(50));
+    assert!(idle_time().unwrap() >= Duration::milliseconds(40));
+
+    rustuv::EventLoop::borrow().unwrap().set_idle_time_metrics(false).unwrap();
+    assert_eq!(idle_time(), None);
+})
@@ -1,12 +1,14 @@
 use std::str;
-use std::os;
-use std::rand::{mod, StdRng, Rng};
-use std::io::{mod, Open, Read, SeekSet, SeekCur, SeekEnd, ReadWrite};
+use std::rand::{StdRng, Rng};
+use std::io::{mod, SeekSet, SeekCur, SeekEnd};
 use std::io::fs::PathExtensions;
 
+use rustuv::uvll;
 use rustuv::fs::{File, rmdir, mkdir, readdir, mkdir_recursive, rmdir_recursive,
-                 unlink, stat, symlink, link, copy,
-                 readlink, chmod, lstat, change_file_times};
+                 unlink, stat, symlink, link, copy, rename,
+                 readlink, chmod, lstat, change_file_times,
+                 change_file_times_precise, stat_times, FileTime,
+                 TempDir, NamedTempFile, OpenOptions};
 
 macro_rules! check( ($e:expr) => (
     match $e {
@@ -23,33 +25,19 @@ macro_rules! error( ($e:expr, $s:expr) => (
     }
 ) )
 
-pub struct TempDir(Path);
-
-impl TempDir {
-    fn join(&self, path: &str) -> Path {
-        let TempDir(ref p) = *self;
-        p.join(path)
-    }
-
-    fn path<'a>(&'a self) -> &'a Path {
-        let TempDir(ref p) = *self;
-        p
-    }
+// Shorthand for the paths of the entries the tests create in their directory
+trait Join {
+    fn join(&self, path: &str) -> Path;
 }
 
-impl Drop for TempDir {
-    fn drop(&mut self) {
-        // Gee, seeing how we're testing the fs module I sure hope that we
-        // at least implement this correctly!
-        let TempDir(ref p) = *self;
-        check!(rmdir_recursive(p));
+impl Join for TempDir {
+    fn join(&self, path: &str) -> Path {
+        self.path().join(path)
     }
 }
 
 pub fn tmpdir() -> TempDir {
-    let ret = os::tmpdir().join(format!("rust-{}", rand::random::<u32>()));
-    check!(mkdir(&ret, io::USER_RWX));
-    TempDir(ret)
+    check!(TempDir::new("rust-"))
 }
 
 test!(fn file_test_io_smoke_test() {
@@ -57,11 +45,13 @@ test!(fn file_test_io_smoke_test() {
     let tmpdir = tmpdir();
     let filename = &tmpdir.path().join("file_rt_io_file_test.txt");
     {
-        let mut write_stream = check!(File::open_mode(filename, Open, ReadWrite));
+        let mut write_stream = check!(OpenOptions::new().read(true).write(true)
+                                                 .create(true).open(filename));
         check!(write_stream.write(message.as_bytes()));
     }
     {
-        let mut read_stream = check!(File::open_mode(filename, Open, Read));
+        let mut read_stream = check!(OpenOptions::new().read(true)
+                                                .open(filename));
         let mut read_buf = [0, .. 1028];
         let read_str = match check!(read_stream.read(&mut read_buf)) {
             -1|0 => panic!("shouldn't happen"),
@@ -75,7 +65,7 @@ test!(fn file_test_io_smoke_test() {
 test!(fn invalid_path_raises() {
     let tmpdir = tmpdir();
     let filename = &tmpdir.join("file_that_does_not_exist.txt");
-    let result = File::open_mode(filename, Open, Read);
+    let result = OpenOptions::new().read(true).open(filename);
     assert!(result.is_err());
 
     if cfg!(unix) {
@@ -101,11 +91,13 @@ test!(fn file_test_io_non_positional_read() {
     let tmpdir = tmpdir();
     let filename = &tmpdir.join("file_rt_io_file_test_positional.txt");
     {
-        let mut rw_stream = check!(File::open_mode(filename, Open, ReadWrite));
+        let mut rw_stream = check!(OpenOptions::new().read(true).write(true)
+                                              .create(true).open(filename));
         check!(rw_stream.write(message.as_bytes()));
     }
     {
-        let mut read_stream = check!(File::open_mode(filename, Open, Read));
+        let mut read_stream = check!(OpenOptions::new().read(true)
+                                                .open(filename));
         {
             let read_buf = read_mem.slice_mut(0, 4);
             check!(read_stream.read(read_buf));
@@ -129,11 +121,13 @@ test!(fn file_test_io_seek_and_tell_smoke_test() {
     let tmpdir = tmpdir();
     let filename = &tmpdir.join("file_rt_io_file_test_seeking.txt");
     {
-        let mut rw_stream = check!(File::open_mode(filename, Open, ReadWrite));
+        let mut rw_stream = check!(OpenOptions::new().read(true).write(true)
+                                              .create(true).open(filename));
         check!(rw_stream.write(message.as_bytes()));
     }
     {
-        let mut read_stream = check!(File::open_mode(filename, Open, Read));
+        let mut read_stream = check!(OpenOptions::new().read(true)
+                                                .open(filename));
         check!(read_stream.seek(set_cursor as i64, SeekSet));
         tell_pos_pre_read = check!(read_stream.tell());
         check!(read_stream.read(&mut read_mem));
@@ -155,13 +149,15 @@ test!(fn file_test_io_seek_and_write() {
     let tmpdir = tmpdir();
     let filename = &tmpdir.join("file_rt_io_file_test_seek_and_write.txt");
     {
-        let mut rw_stream = check!(File::open_mode(filename, Open, ReadWrite));
+        let mut rw_stream = check!(OpenOptions::new().read(true).write(true)
+                                              .create(true).open(filename));
         check!(rw_stream.write(initial_msg.as_bytes()));
         check!(rw_stream.seek(seek_idx as i64, SeekSet));
         check!(rw_stream.write(overwrite_msg.as_bytes()));
     }
     {
-        let mut read_stream = check!(File::open_mode(filename, Open, Read));
+        let mut read_stream = check!(OpenOptions::new().read(true)
+                                                .open(filename));
         check!(read_stream.read(&mut read_mem));
     }
     check!(unlink(filename));
@@ -178,11 +174,13 @@ test!(fn file_test_io_seek_shakedown() {
     let tmpdir = tmpdir();
     let filename = &tmpdir.join("file_rt_io_file_test_seek_shakedown.txt");
     {
-        let mut rw_stream = check!(File::open_mode(filename, Open, ReadWrite));
+        let mut rw_stream = check!(OpenOptions::new().read(true).write(true)
+                                              .create(true).open(filename));
         check!(rw_stream.write(initial_msg.as_bytes()));
     }
     {
-        let mut read_stream = check!(File::open_mode(filename, Open, Read));
+        let mut read_stream = check!(OpenOptions::new().read(true)
+                                                .open(filename));
 
         check!(read_stream.seek(-4, SeekEnd));
         check!(read_stream.read(&mut read_mem));
@@ -203,7 +201,8 @@ test!(fn file_test_stat_is_correct_on_is_file() {
     let tmpdir = tmpdir();
     let filename = &tmpdir.join("file_stat_correct_on_is_file.txt");
     {
-        let mut fs = check!(File::open_mode(filename, Open, ReadWrite));
+        let mut fs = check!(OpenOptions::new().read(true).write(true)
+                                       .create(true).open(filename));
         let msg = "hw";
         fs.write(msg.as_bytes()).unwrap();
 
@@ -336,12 +335,14 @@ test!(fn unicode_path_is_dir() {
     dirpath.push(format!("test-가一ー你好"));
     check!(mkdir(&dirpath, io::USER_RWX));
     assert!(dirpath.is_dir());
+    assert_eq!(check!(stat(&dirpath)).kind, io::TypeDirectory);
 
     let mut filepath = dirpath;
     filepath.push("unicode-file-\uac00\u4e00\u30fc\u4f60\u597d.rs");
     check!(File::create(&filepath)); // ignore return; touch only
     assert!(!filepath.is_dir());
     assert!(filepath.exists());
+    assert_eq!(check!(stat(&filepath)).kind, io::TypeFile);
 })
 
 test!(fn unicode_path_exists() {
@@ -354,6 +355,42 @@ test!(fn unicode_path_exists() {
     check!(mkdir(&unicode, io::USER_RWX));
     assert!(unicode.exists());
     assert!(!Path::new("test/unicode-bogus-path-각丁ー再见").exists());
+    assert!(stat(&Path::new("test/unicode-bogus-path-각丁ー再见")).is_err());
+})
+
+test!(fn unicode_path_operations() {
+    let tmpdir = tmpdir();
+    let dir = tmpdir.join("каталог-目录");
+    check!(mkdir(&dir, io::USER_RWX));
+
+    let names = ["файл.txt", "ファイル.txt", "αρχείο", "مل"];
+    for name in names.iter() {
+        let mut file = check!(File::create(&dir.join(*name)));
+        check!(file.write(name.as_bytes()));
+    }
+
+    let mut found: Vec<String> = check!(readdir(&dir)).iter().map(|p| {
+        assert_eq!(p.dir_path(), dir);
+        p.filename_str().unwrap().to_string()
+    }).collect();
+    found.sort();
+    let mut expected: Vec<String> = names.iter().map(|n| n.to_string())
+                                         .collect();
+    expected.sort();
+    assert_eq!(found, expected);
+
+    let from = dir.join("файл.txt");
+    let to = dir.join("文件.txt");
+    check!(rename(&from, &to));
+    assert!(stat(&from).is_err());
+    let mut file = check!(File::open(&to));
+    assert_eq!(check!(file.read_to_end()).as_slice(), "файл.txt".as_bytes());
+    drop(file);
+
+    check!(unlink(&to));
+    assert!(!to.exists());
+    check!(rmdir_recursive(&dir));
+    assert!(!dir.exists());
 })
 
 test!(fn copy_file_does_not_exist() {
@@ -513,7 +550,8 @@ test!(fn sync_doesnt_kill_anything() {
     let tmpdir = tmpdir();
     let path = tmpdir.join("in.txt");
 
-    let mut file = check!(File::open_mode(&path, io::Open, io::ReadWrite));
+    let mut file = check!(OpenOptions::new().read(true).write(true).create(true)
+                                     .open(&path));
     check!(file.fsync());
     check!(file.datasync());
     check!(file.write(b"foo"));
@@ -526,7 +564,8 @@ test!(fn truncate_works() {
     let tmpdir = tmpdir();
     let path = tmpdir.join("in.txt");
 
-    let mut file = check!(File::open_mode(&path, io::Open, io::ReadWrite));
+    let mut file = check!(OpenOptions::new().read(true).write(true).create(true)
+                                     .open(&path));
     check!(file.write(b"foo"));
     check!(file.fsync());
 
@@ -556,41 +595,53 @@ test!(fn truncate_works() {
 test!(fn open_flavors() {
     let tmpdir = tmpdir();
 
-    match File::open_mode(&tmpdir.join("a"), io::Open, io::Read) {
+    match OpenOptions::new().read(true).open(&tmpdir.join("a")) {
         Ok(..) => panic!(), Err(..) => {}
     }
 
     // Perform each one twice to make sure that it succeeds the second time
     // (where the file exists)
-    check!(File::open_mode(&tmpdir.join("b"), io::Open, io::Write));
+    check!(OpenOptions::new().write(true).create(true)
+                      .open(&tmpdir.join("b")));
     assert!(tmpdir.join("b").exists());
-    check!(File::open_mode(&tmpdir.join("b"), io::Open, io::Write));
+    check!(OpenOptions::new().write(true).create(true)
+                      .open(&tmpdir.join("b")));
 
-    check!(File::open_mode(&tmpdir.join("c"), io::Open, io::ReadWrite));
+    check!(OpenOptions::new().read(true).write(true).create(true)
+                      .open(&tmpdir.join("c")));
     assert!(tmpdir.join("c").exists());
-    check!(File::open_mode(&tmpdir.join("c"), io::Open, io::ReadWrite));
+    check!(OpenOptions::new().read(true).write(true).create(true)
+                      .open(&tmpdir.join("c")));
 
-    check!(File::open_mode(&tmpdir.join("d"), io::Append, io::Write));
+    check!(OpenOptions::new().append(true).create(true)
+                      .open(&tmpdir.join("d")));
     assert!(tmpdir.join("d").exists());
-    check!(File::open_mode(&tmpdir.join("d"), io::Append, io::Write));
+    check!(OpenOptions::new().append(true).create(true)
+                      .open(&tmpdir.join("d")));
 
-    check!(File::open_mode(&tmpdir.join("e"), io::Append, io::ReadWrite));
+    check!(OpenOptions::new().read(true).append(true).create(true)
+                      .open(&tmpdir.join("e")));
     assert!(tmpdir.join("e").exists());
-    check!(File::open_mode(&tmpdir.join("e"), io::Append, io::ReadWrite));
+    check!(OpenOptions::new().read(true).append(true).create(true)
+                      .open(&tmpdir.join("e")));
 
-    check!(File::open_mode(&tmpdir.join("f"), io::Truncate, io::Write));
+    check!(OpenOptions::new().write(true).truncate(true).create(true)
+                      .open(&tmpdir.join("f")));
     assert!(tmpdir.join("f").exists());
-    check!(File::open_mode(&tmpdir.join("f"), io::Truncate, io::Write));
+    check!(OpenOptions::new().write(true).truncate(true).create(true)
+                      .open(&tmpdir.join("f")));
 
-    check!(File::open_mode(&tmpdir.join("g"), io::Truncate, io::ReadWrite));
+    check!(OpenOptions::new().read(true).write(true).truncate(true).create(true)
+                      .open(&tmpdir.join("g")));
     assert!(tmpdir.join("g").exists());
-    check!(File::open_mode(&tmpdir.join("g"), io::Truncate, io::ReadWrite));
+    check!(OpenOptions::new().read(true).write(true).truncate(true).create(true)
+                      .open(&tmpdir.join("g")));
 
     check!(check!(File::create(&tmpdir.join("h"))).write("foo".as_bytes()));
-    check!(File::open_mode(&tmpdir.join("h"), io::Open, io::Read));
+    check!(OpenOptions::new().read(true).open(&tmpdir.join("h")));
     {
-        let mut f = check!(File::open_mode(&tmpdir.join("h"), io::Open,
-                                           io::Read));
+        let mut f = check!(OpenOptions::new().read(true)
+                                      .open(&tmpdir.join("h")));
         match f.write("wut".as_bytes()) {
             Ok(..) => panic!(), Err(..) => {}
         }
@@ -598,15 +649,16 @@ test!(fn open_flavors() {
     assert!(check!(stat(&tmpdir.join("h"))).size == 3,
             "write/stat failed");
     {
-        let mut f = check!(File::open_mode(&tmpdir.join("h"), io::Append,
-                                           io::Write));
+        let mut f = check!(OpenOptions::new().append(true).create(true)
+                                      .open(&tmpdir.join("h")));
         check!(f.write("bar".as_bytes()));
     }
     assert!(check!(stat(&tmpdir.join("h"))).size == 6,
             "append didn't append");
     {
-        let mut f = check!(File::open_mode(&tmpdir.join("h"), io::Truncate,
-                                           io::Write));
+        let mut f = check!(OpenOptions::new().write(true).truncate(true)
+                                      .create(true)
+                                      .open(&tmpdir.join("h")));
         check!(f.write("bar".as_bytes()));
     }
     assert!(check!(stat(&tmpdir.join("h"))).size == 3,
@@ -624,6 +676,23 @@ test!(fn utime() {
     assert_eq!(check!(path.stat()).modified, 200000);
 })
 
+test!(fn utime_precise() {
+    let tmpdir = tmpdir();
+    let path = tmpdir.join("a");
+    check!(File::create(&path));
+
+    let atime = FileTime { sec: 100000, nsec: 123456789 };
+    let mtime = FileTime { sec: 200000, nsec: 987654321 };
+    check!(change_file_times_precise(&path, atime, mtime));
+    let (stat, times) = check!(stat_times(&path));
+    assert_eq!(stat.accessed, atime.to_msec());
+    assert_eq!(stat.modified, mtime.to_msec());
+    if cfg!(target_os = "linux") {
+        assert_eq!(times.accessed, atime);
+        assert_eq!(times.modified, mtime);
+    }
+})
+
 test!(fn utime_noexist() {
     let tmpdir = tmpdir();
 
@@ -652,3 +721,142 @@ test!(fn unlink_readonly() {
     check!(unlink(&path));
 })
 
+test!(fn tempdir_removed_on_drop() {
+    let path = {
+        let tmpdir = check!(TempDir::new("rust-"));
+        check!(mkdir(&tmpdir.path().join("a"), io::USER_RWX));
+        check!(File::create(&tmpdir.path().join("a/b")));
+        tmpdir.path().clone()
+    };
+    assert!(!path.exists());
+
+    let tmpdir = check!(TempDir::new("rust-"));
+    let path = tmpdir.into_inner();
+    assert!(path.is_dir());
+    check!(rmdir(&path));
+})
+
+test!(fn named_temp_file() {
+    let tmpdir = tmpdir();
+    let path = {
+        let mut file = check!(NamedTempFile::new_in(tmpdir.path(), "foo"));
+        assert!(file.path().dir_path() == *tmpdir.path());
+        check!(file.write(b"hello"));
+        check!(file.seek(0, SeekSet));
+        assert_eq!(check!(file.read_to_end()).as_slice(), b"hello");
+        file.path().clone()
+    };
+    assert!(!path.exists());
+
+    let a = check!(NamedTempFile::new_in(tmpdir.path(), "foo"));
+    let b = check!(NamedTempFile::new_in(tmpdir.path(), "foo"));
+    assert!(a.path() != b.path());
+    check!(a.close());
+})
+
+test!(fn open_options() {
+    let tmpdir = tmpdir();
+    let path = tmpdir.join("a");
+
+    // no access mode at all
+    assert!(OpenOptions::new().create(true).open(&path).is_err());
+
+    check!(OpenOptions::new().write(true).create_new(true)
+                      .mode(io::USER_RWX).open(&path));
+    if cfg!(unix) {
+        assert_eq!(check!(stat(&path)).perm & io::USER_RWX, io::USER_RWX);
+    }
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(..) => panic!(),
+        Err(e) => assert_eq!(e.code(), uvll::EEXIST),
+    }
+})
+
+#[cfg(unix)]
+test!(fn from_std() {
+    let tmpdir = tmpdir();
+    let path = tmpdir.join("from_std");
+    {
+        let mut std_file = check!(io::File::create(&path));
+        check!(std_file.write(b"hello"));
+    }
+
+    let std_file = check!(io::File::open(&path));
+    let mut file = check!(File::from_std(std_file));
+    assert_eq!(file.path(), &path);
+    assert_eq!(check!(file.read_to_end()).as_slice(), b"hello");
+
+    let fd = file.into_fd();
+    let mut file = unsafe { File::wrap(fd, &path) };
+    check!(file.seek(0, SeekSet));
+    assert_eq!(check!(file.read_to_end()).as_slice(), b"hello");
+
+    let std_dir = check!(io::File::open(tmpdir.path()));
+    assert_eq!(File::from_std(std_dir).err().unwrap().code(), uvll::EINVAL);
+})
+
+test!(fn vectored_read_write() {
+    let tmpdir = tmpdir();
+    let path = tmpdir.join("vectored");
+
+    let mut file = check!(OpenOptions::new().read(true).write(true).create(true)
+                                     .open(&path));
+    check!(file.write_at_vectored(&[b"foo", b"", b"bar"], 0));
+    check!(file.write_at_vectored(&[b"!"], 6));
+    check!(file.write_at_vectored(&[], 7));
+
+    let mut a = [0u8, ..2];
+    let mut b = [0u8, ..10];
+    {
+        let mut bufs = [a.as_mut_slice(), b.as_mut_slice()];
+        assert_eq!(check!(file.read_at_vectored(&mut bufs, 0)), 7);
+    }
+    assert_eq!(a.as_slice(), b"fo");
+    assert_eq!(b.slice_to(5), b"obar!");
+
+    let mut bufs = [a.as_mut_slice()];
+    match file.read_at_vectored(&mut bufs, 7) {
+        Ok(..) => panic!(),
+        Err(e) => assert_eq!(e.code(), uvll::EOF),
+    }
+})
+
+test!(fn try_clone() {
+    let tmpdir = tmpdir();
+    let path = tmpdir.join("clone");
+
+    let mut file = check!(OpenOptions::new().read(true).write(true).create(true)
+                                     .open(&path));
+    check!(file.write(b"hello world"));
+    let mut clone = check!(file.try_clone());
+    assert_eq!(clone.path(), &path);
+    drop(file);
+
+    let (tx, rx) = channel();
+    let mut other = check!(clone.try_clone());
+    spawn(proc() {
+        let mut buf = [0u8, ..5];
+        assert_eq!(check!(other.read_at(&mut buf, 6)), 5);
+        tx.send(buf.to_vec());
+    });
+    let mut buf = [0u8, ..5];
+    assert_eq!(check!(clone.read_at(&mut buf, 0)), 5);
+    assert_eq!(buf.as_slice(), b"hello");
+    assert_eq!(rx.recv().as_slice(), b"world");
+})
+
+test!(fn into_fd_keeps_descriptor_open() {
+    let tmpdir = tmpdir();
+    let path = tmpdir.join("into_fd");
+
+    let mut file = check!(OpenOptions::new().read(true).write(true).create(true)
+                                     .open(&path));
+    check!(file.write(b"still open"));
+    let fd = file.into_fd();
+
+    let mut file = unsafe { File::wrap(fd, &path) };
+    assert_eq!(check!(file.stat()).size, 10);
+    let mut buf = [0u8, ..5];
+    assert_eq!(check!(file.read_at(&mut buf, 6)), 4);
+    assert_eq!(buf.slice_to(4), b"open");
+})
@@ -0,0 +1,71 @@
+use std::io;
+use std::time::Duration;
+
+use rustuv::{FsWatcher, FsEvent};
+use rustuv::fs::{File, TempDir, mkdir};
+
+// Receives events until one for `path` shows up.
+fn wait_for(watcher: &mut FsWatcher, path: &Path) -> FsEvent {
+    loop {
+        let events = watcher.recv().unwrap();
+        match events.into_iter().find(|e| e.path == *path) {
+            Some(e) => return e,
+            None => {}
+        }
+    }
+}
+
+test!(fn smoke() {
+    let tmpdir = TempDir::new("rust-").unwrap();
+    let mut watcher = FsWatcher::new(tmpdir.path(), false).unwrap();
+    let path = tmpdir.path().join("a");
+    File::create(&path).unwrap();
+    let event = wait_for(&mut watcher, &path);
+    assert!(event.rename);
+})
+
+test!(fn watch_file() {
+    let tmpdir = TempDir::new("rust-").unwrap();
+    let path = tmpdir.path().join("a");
+    let mut file = File::create(&path).unwrap();
+    let mut watcher = FsWatcher::new(&path, false).unwrap();
+    file.write(b"foo").unwrap();
+    let event = wait_for(&mut watcher, &path);
+    assert!(event.change);
+})
+
+test!(fn recursive() {
+    let tmpdir = TempDir::new("rust-").unwrap();
+    let sub = tmpdir.path().join("sub");
+    mkdir(&sub, io::USER_RWX).unwrap();
+    let mut watcher = FsWatcher::new(tmpdir.path(), true).unwrap();
+
+    let path = sub.join("a");
+    File::create(&path).unwrap();
+    wait_for(&mut watcher, &path);
+
+    // directories created after the watcher started are watched as well
+    let new = tmpdir.path().join("new");
+    mkdir(&new, io::USER_RWX).unwrap();
+    wait_for(&mut watcher, &new);
+    let path = new.join("b");
+    File::create(&path).unwrap();
+    wait_for(&mut watcher, &path);
+})
+
+test!(fn debounce() {
+    let tmpdir = TempDir::new("rust-").unwrap();
+    let mut watcher = FsWatcher::new(tmpdir.path(), false).unwrap();
+    watcher.set_debounce(Some(Duration::milliseconds(100)));
+
+    let path = tmpdir.path().join("a");
+    let mut file = File::create(&path).unwrap();
+    for _ in range(0u, 10) {
+        file.write(b"foo").unwrap();
+    }
+    let events = watcher.recv().unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].path, path);
+    assert!(events[0].rename);
+    assert!(events[0].change);
+})
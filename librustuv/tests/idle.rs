@@ -91,3 +91,19 @@ test!(fn pause_pauses() {
     idle1.resume();
     assert_eq!(sleep(&chan1), 1);
 })
+
+test!(fn keep_alive() {
+    use green::EventLoop;
+    use rustuv;
+
+    struct Nop;
+    impl Callback for Nop { fn call(&mut self) {} }
+
+    // An unreferenced idle callback doesn't stop the loop from exiting.
+    let mut eloop = rustuv::EventLoop::new().unwrap();
+    let cb = box Nop as Box<Callback + Send>;
+    let mut idle = Idle::new_on(&mut eloop, cb).unwrap();
+    idle.keep_alive(false);
+    idle.resume();
+    eloop.run();
+})
@@ -3,7 +3,7 @@ use std::io::fs::PathExtensions;
 use std::time::Duration;
 use green::task::spawn;
 
-use rustuv::{Pipe, PipeListener};
+use rustuv::{Pipe, PipeListener, UvStream};
 use rustuv::uvll;
 
 pub fn smalltest(server: proc(Pipe):Send, client: proc(Pipe):Send) {
@@ -66,6 +66,24 @@ test!(fn smoke() {
     })
 })
 
+test!(fn generic_stream() {
+    fn bump<S: UvStream>(s: &mut S) {
+        let mut buf = [0];
+        assert_eq!(s.uv_read(&mut buf).unwrap(), 1);
+        s.uv_write(&[buf[0] + 1]).unwrap();
+        s.close_write().unwrap();
+        s.close_read().unwrap();
+        assert_eq!(s.uv_read(&mut buf).err().unwrap().code(), uvll::EOF);
+    }
+
+    smalltest(proc(mut server) {
+        bump(&mut server);
+    }, proc(mut client) {
+        client.write(&[1]).unwrap();
+        assert_eq!(client.read_to_end().unwrap(), vec![2]);
+    })
+})
+
 test!(fn read_eof() {
     smalltest(proc(mut server) {
         let mut buf = [0];
@@ -238,7 +256,7 @@ test!(fn drop_removes_acceptor_path() {
     assert!(!path.exists());
 })
 
-test!(fn accept_timeout() {
+test!(#[timeout = 10000] fn accept_timeout() {
     let addr = next_test_unix();
     let mut a = PipeListener::bind(&addr).unwrap().listen().unwrap();
 
@@ -484,3 +502,20 @@ test!(fn close_accept_concurrent() {
 
     assert_eq!(rx.recv().err().unwrap().code(), uvll::EOF);
 })
+
+#[cfg(any(target_os = "linux", target_os = "android",
+          target_os = "macos", target_os = "ios"))]
+test!(fn peer_credentials() {
+    use libc;
+
+    fn me() -> (libc::uid_t, libc::gid_t, libc::pid_t) {
+        use libc::funcs::posix88::unistd::{getuid, getgid, getpid};
+        unsafe { (getuid(), getgid(), getpid()) }
+    }
+
+    smalltest(proc(server) {
+        assert_eq!(server.peer_credentials().unwrap(), me());
+    }, proc(client) {
+        assert_eq!(client.peer_credentials().unwrap(), me());
+    });
+})
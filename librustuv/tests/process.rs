@@ -0,0 +1,264 @@
+#[cfg(unix)]
+mod test_unix {
+    use std::io::process::{ExitStatus, ExitSignal};
+    use std::time::Duration;
+    use libc;
+    use green::Callback;
+    use rustuv::{uvll, Command, Process, Stdio};
+
+    fn sender(tx: Sender<libc::pid_t>, pid: libc::pid_t)
+              -> Box<Callback + Send> {
+        struct MySender { tx: Sender<libc::pid_t>, pid: libc::pid_t }
+        impl Callback for MySender {
+            fn call(&mut self) { self.tx.send(self.pid); }
+        }
+        box MySender { tx: tx, pid: pid } as Box<Callback + Send>
+    }
+
+    test!(fn smoke() {
+        let mut p = Command::new("true").spawn().unwrap();
+        assert_eq!(p.wait().unwrap(), ExitStatus(0));
+    })
+
+    test!(fn exit_status() {
+        let mut p = Command::new("false").spawn().unwrap();
+        assert_eq!(p.wait().unwrap(), ExitStatus(1));
+        assert_eq!(p.try_wait(), Some(ExitStatus(1)));
+    })
+
+    test!(fn spawn_error() {
+        match Command::new("/does/not/exist").spawn() {
+            Ok(..) => panic!(),
+            Err(e) => assert_eq!(e.code(), uvll::ENOENT),
+        }
+    })
+
+    test!(fn stdout_pipe() {
+        let mut p = Command::new("echo").arg("hello").spawn().unwrap();
+        let out = p.stdout.take().unwrap().read_to_end().unwrap();
+        assert_eq!(out.as_slice(), b"hello\n");
+        assert_eq!(p.wait().unwrap(), ExitStatus(0));
+    })
+
+    test!(fn output() {
+        let out = Command::new("sh").arg("-c").arg("echo out; echo err >&2")
+                                    .output().unwrap();
+        assert_eq!(out.status, ExitStatus(0));
+        assert_eq!(out.stdout.as_slice(), b"out\n");
+        assert_eq!(out.stderr.as_slice(), b"err\n");
+    })
+
+    test!(fn output_large_stderr() {
+        // Enough to fill up the stderr pipe before anything is written to
+        // stdout.
+        let script = "head -c 1000000 /dev/zero >&2; echo done";
+        let out = Command::new("sh").arg("-c").arg(script).output().unwrap();
+        assert_eq!(out.stderr.len(), 1000000);
+        assert_eq!(out.stdout.as_slice(), b"done\n");
+    })
+
+    test!(fn stdin_pipe() {
+        let mut p = Command::new("cat").spawn().unwrap();
+        {
+            let mut stdin = p.stdin.take().unwrap();
+            stdin.write(b"foobar").unwrap();
+        }
+        let out = p.stdout.take().unwrap().read_to_end().unwrap();
+        assert_eq!(out.as_slice(), b"foobar");
+        assert_eq!(p.wait().unwrap(), ExitStatus(0));
+    })
+
+    test!(fn env_and_cwd() {
+        let mut p = Command::new("sh").arg("-c").arg("echo $FOO; pwd")
+                                      .env("FOO", "bar").cwd("/")
+                                      .spawn().unwrap();
+        let out = p.stdout.take().unwrap().read_to_end().unwrap();
+        assert_eq!(out.as_slice(), b"bar\n/\n");
+    })
+
+    test!(fn signal() {
+        let mut p = Command::new("sleep").arg("1000").spawn().unwrap();
+        p.signal(libc::SIGKILL as int).unwrap();
+        assert_eq!(p.wait().unwrap(), ExitSignal(libc::SIGKILL as int));
+        assert!(Process::kill(p.id(), 0).is_err());
+    })
+
+    test!(fn inherit_fd() {
+        let mut p = Command::new("true").stdout(Stdio::Fd(1)).spawn().unwrap();
+        assert!(p.stdout.is_none());
+        assert_eq!(p.wait().unwrap(), ExitStatus(0));
+    })
+
+    test!(fn redirect_each_stream() {
+        use rustuv::fs::TempDir;
+        use rustuv::File;
+
+        let dir = TempDir::new("process").unwrap();
+        let path = dir.path().join("out");
+        let file = File::create(&path).unwrap();
+        let script = "cat; echo out; echo err >&2";
+        let mut p = Command::new("sh").arg("-c").arg(script)
+                                      .stdin(Stdio::Null)
+                                      .stdout(Stdio::file(&file))
+                                      .stderr(Stdio::Piped)
+                                      .spawn().unwrap();
+        drop(file);
+        assert!(p.stdin.is_none() && p.stdout.is_none());
+        let err = p.stderr.take().unwrap().read_to_end().unwrap();
+        assert_eq!(err.as_slice(), b"err\n");
+        assert_eq!(p.wait().unwrap(), ExitStatus(0));
+
+        let out = File::open(&path).unwrap().read_to_end().unwrap();
+        assert_eq!(out.as_slice(), b"out\n");
+    })
+
+    test!(fn detached_process_group() {
+        let mut p = Command::new("sleep").arg("1000").detached(true)
+                                         .spawn().unwrap();
+
+        // The child leads its own process group, so it can be signalled
+        // through the group.
+        assert_eq!(unsafe {
+            libc::funcs::posix88::signal::kill(-p.id(), libc::SIGKILL)
+        }, 0);
+        assert_eq!(p.wait().unwrap(), ExitSignal(libc::SIGKILL as int));
+    })
+
+    test!(fn same_uid_gid() {
+        use libc::funcs::posix88::unistd::{getuid, getgid};
+        let (uid, gid) = unsafe { (getuid(), getgid()) };
+        let mut p = Command::new("id").arg("-u")
+                                      .uid(uid as uint).gid(gid as uint)
+                                      .spawn().unwrap();
+        let out = p.stdout.take().unwrap().read_to_end().unwrap();
+        assert_eq!(out, format!("{}\n", uid).into_bytes());
+        assert_eq!(p.wait().unwrap(), ExitStatus(0));
+    })
+
+    test!(fn wait_timeout() {
+        let mut p = Command::new("sleep").arg("1000").spawn().unwrap();
+        let err = p.wait_timeout(Duration::milliseconds(10)).err().unwrap();
+        assert_eq!(err.code(), uvll::ECANCELED);
+        let err = p.wait_timeout(Duration::milliseconds(10)).err().unwrap();
+        assert_eq!(err.code(), uvll::ECANCELED);
+
+        p.signal(libc::SIGKILL as int).unwrap();
+        let status = p.wait_timeout(Duration::seconds(10)).unwrap();
+        assert_eq!(status, ExitSignal(libc::SIGKILL as int));
+    })
+
+    fn wait_gone(pid: libc::pid_t) {
+        for _ in range(0u, 500) {
+            if Process::kill(pid, 0).is_err() { return }
+            ::rustuv::sleep(Duration::milliseconds(10)).unwrap();
+        }
+        panic!("child {} is still running", pid);
+    }
+
+    test!(fn kill_on_drop() {
+        let p = Command::new("sleep").arg("1000")
+                                     .kill_on_drop(Some(Duration::seconds(10)))
+                                     .spawn().unwrap();
+        let pid = p.id();
+        drop(p);
+        wait_gone(pid);
+    })
+
+    test!(fn kill_on_drop_ignoring_sigterm() {
+        let script = "trap '' TERM; exec sleep 1000";
+        let grace = Duration::milliseconds(50);
+        let p = Command::new("sh").arg("-c").arg(script)
+                                  .kill_on_drop(Some(grace))
+                                  .spawn().unwrap();
+        let pid = p.id();
+        ::rustuv::sleep(Duration::milliseconds(50)).unwrap();
+        drop(p);
+        wait_gone(pid);
+    })
+
+    test!(fn on_exit_many() {
+        let (tx, rx) = channel();
+        let mut children = Vec::new();
+        for _ in range(0u, 4) {
+            let mut p = Command::new("true").spawn().unwrap();
+            p.on_exit(sender(tx.clone(), p.id()));
+            children.push(p);
+        }
+
+        for _ in range(0u, 4) {
+            let pid = rx.recv();
+            let p = children.iter_mut().find(|p| p.id() == pid).unwrap();
+            assert_eq!(p.try_wait(), Some(ExitStatus(0)));
+        }
+
+        // Registering after the child exited runs the callback right away
+        children.as_mut_slice()[0].on_exit(sender(tx, 0));
+        assert_eq!(rx.recv(), 0);
+    })
+
+    test!(fn pty() {
+        let (mut p, mut tty) = Command::new("sh")
+                                       .arg("-c").arg("test -t 0 && echo yes")
+                                       .spawn_pty().unwrap();
+        assert!(p.stdin.is_none());
+        assert_eq!(p.wait().unwrap(), ExitStatus(0));
+
+        // Reading the master fails once the child has gone away
+        let mut out = Vec::new();
+        let mut buf = [0, ..128];
+        loop {
+            match tty.uv_read(&mut buf) {
+                Ok(n) => out.push_all(buf.slice_to(n)),
+                Err(..) => break,
+            }
+        }
+        assert_eq!(out.as_slice(), b"yes\r\n");
+    })
+
+    test!(fn pty_watch_resize() {
+        let (mut p, mut tty) = Command::new("sh").arg("-c").arg("sleep 10")
+                                                 .spawn_pty().unwrap();
+        let (tx, rx) = channel();
+        tty.watch_resize(sender(tx, p.id())).unwrap();
+        unsafe {
+            libc::funcs::posix88::signal::kill(libc::getpid(), uvll::SIGWINCH);
+        }
+        assert_eq!(rx.recv(), p.id());
+
+        tty.unwatch_resize();
+        p.signal(libc::SIGKILL as int).unwrap();
+        p.wait().unwrap();
+    })
+
+    test!(fn raw_spawn() {
+        use std::c_str::ToCStr;
+        use std::mem;
+        use rustuv::{raw, EventLoop};
+        use rustuv::raw::Handle;
+
+        extern fn exit_cb(handle: *mut uvll::uv_process_t, status: i64,
+                          _signal: libc::c_int) {
+            unsafe {
+                let mut p: raw::Process = Handle::from_raw(handle);
+                let tx: Box<Sender<i64>> = mem::transmute(p.get_data());
+                tx.send(status);
+                p.close_and_free();
+            }
+        }
+
+        let file = "sh".to_c_str();
+        let args = ["sh".to_c_str(), "-c".to_c_str(), "exit 3".to_c_str()];
+        let stdio = [raw::Stdio::Ignore, raw::Stdio::InheritFd(1)];
+        let (tx, rx) = channel();
+        unsafe {
+            let mut options = raw::ProcessOptions::new(&file, exit_cb);
+            options.args = &args;
+            options.stdio = &stdio;
+            let eloop = EventLoop::borrow().unwrap();
+            let mut p = raw::Process::spawn_with(&eloop.uv_loop(),
+                                                 &options).unwrap();
+            p.set_data(mem::transmute(box tx));
+        }
+        assert_eq!(rx.recv(), 3);
+    })
+}
@@ -0,0 +1,80 @@
+use std::io;
+use std::io::test::next_test_ip4;
+use std::time::Duration;
+
+use rustuv::{uvll, fs, Promise, Tcp, TcpListener, Timer};
+
+test!(fn overlapped() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut c = Tcp::connect(addr).unwrap();
+    let mut s = a.accept().unwrap();
+
+    let read = s.read_async(16).unwrap();
+    let stat = fs::stat_async(&Path::new(".")).unwrap();
+    assert_eq!(stat.wait().unwrap().kind, io::TypeDirectory);
+    assert!(!read.is_ready());
+
+    // Other reads have to wait for the promise
+    let err = s.uv_read(&mut [0]).err().unwrap();
+    assert_eq!(err.code(), uvll::EBUSY);
+
+    c.write(&[1, 2, 3]).unwrap();
+    assert_eq!(read.wait().unwrap(), vec![1, 2, 3]);
+    drop(c);
+    assert_eq!(s.read_async(16).unwrap().wait().err().unwrap().code(),
+               uvll::EOF);
+})
+
+test!(fn wait_any() {
+    let promises = vec![
+        Timer::after(Duration::milliseconds(200)).unwrap(),
+        Timer::after(Duration::milliseconds(1)).unwrap(),
+    ];
+    assert_eq!(Promise::wait_any(promises.as_slice()), 1);
+    assert!(!promises[0].is_ready());
+    let results = Promise::wait_all(promises);
+    assert!(results.iter().all(|r| r.is_ok()));
+})
+
+test!(fn cancelled_by_close() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let _c = Tcp::connect(addr).unwrap();
+    let mut s = a.accept().unwrap();
+
+    let read = s.read_async(16).unwrap();
+    drop(s);
+    assert_eq!(read.wait().err().unwrap().code(), uvll::ECANCELED);
+})
+
+test!(fn select_with_channels() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut c = Tcp::connect(addr).unwrap();
+    let mut s = a.accept().unwrap();
+
+    let (tx, rx) = channel::<int>();
+    let data = s.read_async(16).unwrap().into_receiver();
+    let timeout = Timer::after(Duration::milliseconds(10)).unwrap()
+                        .into_receiver();
+    select! {
+        _ = rx.recv() => panic!(),
+        _ = data.recv() => panic!(),
+        r = timeout.recv() => assert!(r.is_ok())
+    }
+
+    c.write(&[1]).unwrap();
+    select! {
+        _ = rx.recv() => panic!(),
+        r = data.recv() => assert_eq!(r.unwrap(), vec![1])
+    }
+
+    tx.send(2);
+    let timeout = Timer::after(Duration::milliseconds(10)).unwrap()
+                        .into_receiver();
+    select! {
+        n = rx.recv() => assert_eq!(n, 2),
+        _ = timeout.recv() => panic!()
+    }
+})
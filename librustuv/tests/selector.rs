@@ -0,0 +1,65 @@
+use std::io::test::next_test_ip4;
+use std::time::Duration;
+
+use rustuv::{uvll, Selector, Tcp, TcpListener, Udp};
+use rustuv::selector::{Interest, Event};
+
+test!(fn smoke() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut c1 = Tcp::connect(addr).unwrap();
+    let mut s1 = a.accept().unwrap();
+    let _c2 = Tcp::connect(addr).unwrap();
+    let s2 = a.accept().unwrap();
+
+    let mut selector = Selector::new().unwrap();
+    selector.register(&s1, 1, Interest::Readable).unwrap();
+    selector.register(&s2, 2, Interest::Readable).unwrap();
+    assert_eq!(selector.register(&s2, 1, Interest::Readable).err().unwrap()
+                       .code(), uvll::EEXIST);
+
+    c1.write(&[1]).unwrap();
+    let events = selector.select(None).unwrap();
+    assert_eq!(events, vec![Event { token: 1, readable: true, writable: false }]);
+
+    // Watching a handle doesn't read anything from it
+    let mut buf = [0u8, 0];
+    assert_eq!(s1.read(&mut buf).unwrap(), 1);
+    assert_eq!(buf[0], 1);
+
+    // Handles stay ready until they're read from
+    selector.deregister(1).unwrap();
+    selector.reregister(2, Interest::Both).unwrap();
+    let events = selector.select(None).unwrap();
+    assert_eq!(events, vec![Event { token: 2, readable: false, writable: true }]);
+    assert_eq!(selector.deregister(1).err().unwrap().code(), uvll::ENOENT);
+})
+
+test!(fn timeout() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let _c = Tcp::connect(addr).unwrap();
+    let s = a.accept().unwrap();
+
+    let mut selector = Selector::new().unwrap();
+    selector.register(&s, 1, Interest::Readable).unwrap();
+    let events = selector.select(Some(Duration::milliseconds(10))).unwrap();
+    assert!(events.is_empty());
+})
+
+test!(fn udp() {
+    let (addr1, addr2) = (next_test_ip4(), next_test_ip4());
+    let mut a = Udp::bind(addr1).unwrap();
+    let mut b = Udp::bind(addr2).unwrap();
+
+    let mut selector = Selector::new().unwrap();
+    selector.register(&b, 1, Interest::Readable).unwrap();
+    a.send_to(&[1, 2], addr2).unwrap();
+    let events = selector.select(None).unwrap();
+    assert_eq!(events, vec![Event { token: 1, readable: true, writable: false }]);
+
+    let mut buf = [0u8, ..4];
+    let (n, from) = b.recv_from(&mut buf).unwrap();
+    assert_eq!(buf.slice_to(n), [1u8, 2].as_slice());
+    assert_eq!(from, addr1);
+})
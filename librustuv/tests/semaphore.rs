@@ -0,0 +1,63 @@
+use std::sync::Arc;
+use std::time::Duration;
+use green::task::spawn;
+
+use rustuv::{uvll, Semaphore, Timer};
+
+test!(fn smoke() {
+    let sem = Semaphore::new(1);
+    let g = sem.acquire().unwrap();
+    assert_eq!(sem.available(), 0);
+    assert!(sem.try_acquire().is_none());
+    drop(g);
+    assert_eq!(sem.available(), 1);
+    assert!(sem.try_acquire().is_some());
+})
+
+test!(fn blocks_tasks() {
+    let sem = Arc::new(Semaphore::new(1));
+    let g = sem.acquire().unwrap();
+
+    let (tx, rx) = channel();
+    let sem2 = sem.clone();
+    spawn(proc() {
+        let _g = sem2.acquire().unwrap();
+        tx.send(());
+    });
+
+    // The other task is waiting for us, without blocking the scheduler
+    Timer::new().unwrap().sleep(Duration::milliseconds(10));
+    assert!(rx.try_recv().is_err());
+    drop(g);
+    rx.recv();
+    assert_eq!(sem.available(), 1);
+})
+
+test!(fn acquire_timeout() {
+    let sem = Semaphore::new(1);
+    let g = sem.acquire().unwrap();
+    let err = sem.acquire_timeout(Duration::milliseconds(10)).err().unwrap();
+    assert_eq!(err.code(), uvll::ETIMEDOUT);
+    drop(g);
+    assert!(sem.acquire_timeout(Duration::milliseconds(10)).is_ok());
+    assert_eq!(sem.available(), 1);
+})
+
+test!(fn timeout_while_others_wait() {
+    let sem = Arc::new(Semaphore::new(1));
+    let g = sem.acquire().unwrap();
+
+    let (tx, rx) = channel();
+    let sem2 = sem.clone();
+    spawn(proc() {
+        let _g = sem2.acquire().unwrap();
+        tx.send(());
+    });
+
+    // Timing out wakes up the other waiter as well, which keeps waiting
+    let err = sem.acquire_timeout(Duration::milliseconds(10)).err().unwrap();
+    assert_eq!(err.code(), uvll::ETIMEDOUT);
+    assert!(rx.try_recv().is_err());
+    drop(g);
+    rx.recv();
+})
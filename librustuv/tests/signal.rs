@@ -3,7 +3,7 @@
 mod test_unix {
     use libc;
     use green::Callback;
-    use rustuv::Signal;
+    use rustuv::{uvll, Signal};
 
     fn sender(tx: Sender<()>) -> Box<Callback + Send> {
         struct MySender { tx: Sender<()> }
@@ -51,4 +51,47 @@ mod test_unix {
         rx1.recv();
         assert!(rx2.recv_opt().is_err());
     })
+
+    test!(fn test_io_signal_invalid() {
+        let mut s = Signal::new().unwrap();
+        let (tx, _rx) = channel();
+        assert!(s.start(0, sender(tx.clone())).is_err());
+        assert!(s.start(1000, sender(tx)).is_err());
+    })
+
+    test!(fn test_io_signal_realtime() {
+        let (min, max) = match Signal::realtime_range() {
+            Some(range) => range,
+            None => { assert!(Signal::realtime(0).is_err()); return }
+        };
+        assert_eq!(Signal::realtime(0).unwrap(), min);
+        assert!(Signal::realtime((max - min) as uint + 1).is_err());
+
+        let signum = Signal::realtime(1).unwrap();
+        let mut s = Signal::new().unwrap();
+        let (tx, rx) = channel();
+        s.start(signum, sender(tx)).unwrap();
+        unsafe {
+            libc::funcs::posix88::signal::kill(libc::getpid(), signum);
+        }
+        rx.recv();
+    })
+
+    test!(fn test_io_signal_wait() {
+        // The new task runs right away, so it yields back to us to give us a
+        // chance to start waiting first.
+        spawn(proc() {
+            ::std::task::deschedule();
+            sigint();
+        });
+        Signal::wait(libc::SIGINT, None).unwrap();
+    })
+
+    test!(fn test_io_signal_wait_timeout() {
+        use std::time::Duration;
+
+        // Nothing sends SIGUSR2, unlike SIGINT which other tests raise
+        let err = Signal::wait(uvll::SIGUSR2, Some(Duration::milliseconds(10)));
+        assert_eq!(err.unwrap_err().code(), uvll::ETIMEDOUT);
+    })
 }
@@ -4,7 +4,8 @@ use std::io::test::{next_test_ip4, next_test_ip6};
 use std::time::Duration;
 use green::task::spawn;
 
-use rustuv::{uvll, TcpListener, UvResult, Tcp};
+use rustuv::{uvll, TcpListener, UvResult, Tcp, Timer, EventLoop};
+use rustuv::io::proxy;
 
 fn to_sockaddr(s: &str, port: u16) -> SocketAddr {
     if s.contains(":") {
@@ -504,6 +505,31 @@ test!(fn socket_and_peer_name_ip6() {
     socket_name(next_test_ip6());
 })
 
+test!(fn connect_from() {
+    let addr = next_test_ip4();
+    let local = next_test_ip4();
+    let a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    spawn(proc() {
+        let mut a = a;
+        let mut c = a.accept().unwrap();
+        assert_eq!(c.peer_name(), Ok(local));
+    });
+
+    let mut s = Tcp::connect_from(local, addr, None).unwrap();
+    assert_eq!(s.socket_name(), Ok(local));
+    assert_eq!(s.peer_name(), Ok(addr));
+})
+
+test!(fn connect_from_bind_error() {
+    // An address from TEST-NET-1 (RFC 5737), which no host is configured with
+    let addr = next_test_ip4();
+    let local = to_sockaddr("192.0.2.1", 0);
+    match Tcp::connect_from(local, addr, None) {
+        Ok(..) => panic!(),
+        Err(e) => assert_eq!(e.code(), uvll::EADDRNOTAVAIL),
+    }
+})
+
 test!(fn partial_read() {
     let addr = next_test_ip4();
     let port = addr.port;
@@ -679,7 +705,29 @@ test!(fn shutdown_smoke() {
     assert_eq!(s.read_to_end(), Ok(vec!(1)));
 })
 
-test!(fn accept_timeout() {
+test!(fn readable_writable() {
+    let addr = next_test_ip4();
+    let ip_str = addr.ip.to_string();
+    let port = addr.port;
+    let a = bind(ip_str.as_slice(), port).unwrap().listen().unwrap();
+    spawn(proc() {
+        let mut a = a;
+        let mut c = a.accept().unwrap();
+        assert!(c.is_readable());
+        assert_eq!(c.read_to_end(), Ok(vec!()));
+        assert!(!c.is_readable());
+        assert!(c.is_writable());
+    });
+
+    let mut s = connect(ip_str.as_slice(), port).unwrap();
+    assert!(s.is_readable());
+    assert!(s.is_writable());
+    s.close_write().unwrap();
+    assert!(!s.is_writable());
+    assert_eq!(s.read_to_end(), Ok(vec!()));
+})
+
+test!(#[timeout = 10000] fn accept_timeout() {
     let addr = next_test_ip4();
     let ip_str = addr.ip.to_string();
     let port = addr.port;
@@ -695,27 +743,14 @@ test!(fn accept_timeout() {
 
     // Also make sure that even though the timeout is expired that we will
     // continue to receive any pending connections.
-    //
-    // FIXME: freebsd apparently never sees the pending connection, but
-    //        testing manually always works. Need to investigate this
-    //        flakiness.
-    if !cfg!(target_os = "freebsd") {
-        let (tx, rx) = channel();
-        spawn(proc() {
-            tx.send(connect(addr.ip.to_string().as_slice(),
-                            port).unwrap());
-        });
-        let _l = rx.recv();
-        for i in range(0i, 1001) {
-            match a.accept() {
-                Ok(..) => break,
-                Err(ref e) if e.code() == uvll::ECANCELED => {}
-                Err(e) => panic!("error: {}", e),
-            }
-            ::std::task::deschedule();
-            if i == 1000 { panic!("should have a pending connection") }
-        }
-    }
+    let (tx, rx) = channel();
+    spawn(proc() {
+        tx.send(connect(addr.ip.to_string().as_slice(), port).unwrap());
+    });
+    let _l = rx.recv();
+    a.accept().unwrap();
+    let err = a.accept().err().unwrap();
+    assert_eq!(err.code(), uvll::ECANCELED);
 
     // Unset the timeout and make sure that this always blocks.
     a.set_timeout(None);
@@ -953,3 +988,486 @@ test!(fn close_accept_concurrent() {
 
     assert_eq!(rx.recv().err().unwrap().code(), uvll::EOF);
 })
+
+#[cfg(unix)]
+test!(fn migrate_smoke() {
+    let addr = next_test_ip4();
+    let l = bind(addr.ip.to_string().as_slice(), addr.port).unwrap();
+    let mut a = l.listen().unwrap();
+
+    spawn(proc() {
+        let s = connect(addr.ip.to_string().as_slice(), addr.port).unwrap();
+        let mut s = s.migrate().unwrap();
+        s.write(&[1]).unwrap();
+    });
+
+    let s = a.accept().unwrap();
+    let mut s = {
+        let mut eloop = EventLoop::borrow().unwrap();
+        s.migrate_on(&mut *eloop).unwrap()
+    };
+    let mut buf = [0];
+    assert_eq!(s.read(&mut buf).unwrap(), 1);
+    assert_eq!(buf[0], 1);
+})
+
+test!(fn proxy_smoke() {
+    let upstream = next_test_ip4();
+    let front = next_test_ip4();
+    let mut ua = bind("127.0.0.1", upstream.port).unwrap().listen().unwrap();
+    let mut fa = bind("127.0.0.1", front.port).unwrap().listen().unwrap();
+
+    spawn(proc() {
+        let mut s = ua.accept().unwrap();
+        assert_eq!(s.read_to_end().unwrap(), b"hello".to_vec());
+        s.write(b"world").unwrap();
+    });
+
+    let (tx, rx) = channel();
+    spawn(proc() {
+        let client = fa.accept().unwrap();
+        let server = connect("127.0.0.1", upstream.port).unwrap();
+        tx.send(proxy(client, server));
+    });
+
+    let mut c = connect("127.0.0.1", front.port).unwrap();
+    c.write(b"hello").unwrap();
+    c.close_write().unwrap();
+    assert_eq!(c.read_to_end().unwrap(), b"world".to_vec());
+    assert!(rx.recv().is_ok());
+})
+
+test!(fn buffered_lines() {
+    let addr = next_test_ip4();
+    let mut a = bind("127.0.0.1", addr.port).unwrap().listen().unwrap();
+
+    spawn(proc() {
+        let mut s = connect("127.0.0.1", addr.port).unwrap();
+        s.write(b"hello\nwor").unwrap();
+        s.write(b"ld\nrest").unwrap();
+    });
+
+    let mut s = a.accept().unwrap();
+    assert_eq!(s.read_line().unwrap().as_slice(), "hello\n");
+    assert_eq!(s.read_line().unwrap().as_slice(), "world\n");
+
+    // buffered data is handed out by plain reads as well
+    let mut buf = [0, ..2];
+    assert_eq!(s.read(&mut buf).unwrap(), 2);
+    assert_eq!(buf.as_slice(), b"re");
+    assert_eq!(s.read_to_end().unwrap(), b"st".to_vec());
+})
+
+test!(fn read_to_end_bulk() {
+    let addr = next_test_ip4();
+    let mut a = bind("127.0.0.1", addr.port).unwrap().listen().unwrap();
+
+    spawn(proc() {
+        let mut s = connect("127.0.0.1", addr.port).unwrap();
+        for i in range(0u, 1024) {
+            s.write(&[i as u8, ..1024]).unwrap();
+        }
+    });
+
+    let mut s = a.accept().unwrap();
+    let data = s.read_to_end().unwrap();
+    assert_eq!(data.len(), 1024 * 1024);
+    for (i, chunk) in data.as_slice().chunks(1024).enumerate() {
+        assert!(chunk.iter().all(|&b| b == i as u8));
+    }
+})
+
+#[cfg(unix)]
+test!(fn bind_reuseport() {
+    let addr = next_test_ip4();
+    let a = TcpListener::bind_reuseport(addr).unwrap();
+    let b = TcpListener::bind_reuseport(addr).unwrap();
+    let mut a = a.listen().unwrap();
+
+    // Once the other listener is closed all connections go to `a`
+    drop(b.listen().unwrap());
+
+    spawn(proc() {
+        let mut c = Tcp::connect(addr).unwrap();
+        c.write(&[1]).unwrap();
+    });
+    let mut s = a.accept().unwrap();
+    assert_eq!(s.read_byte().unwrap(), 1);
+})
+
+#[cfg(unix)]
+test!(fn bind_sharded() {
+    let mut pool = ::rustuv::builder().threads(1).pool();
+    let mut scheds = vec![pool.spawn_sched(), pool.spawn_sched()];
+
+    let addr = next_test_ip4();
+    let listeners = TcpListener::bind_sharded(addr,
+                                              scheds.as_mut_slice()).unwrap();
+    assert_eq!(listeners.len(), 2);
+    for mut listener in listeners.into_iter() {
+        assert_eq!(listener.socket_name().unwrap(), addr);
+    }
+
+    drop(scheds);
+    pool.shutdown();
+})
+
+test!(fn blocked_ops() {
+    use std::rt::task::TaskOpts;
+    use green::task::spawn_opts;
+
+    EventLoop::borrow().unwrap().set_blocked_diagnostics(true);
+    let addr = next_test_ip4();
+    let mut acceptor = TcpListener::bind(addr).unwrap().listen().unwrap();
+
+    let (tx, rx) = channel();
+    let mut opts = TaskOpts::new();
+    opts.name = Some("reader".into_maybe_owned());
+    spawn_opts(opts, proc() {
+        let mut stream = Tcp::connect(addr).unwrap();
+        tx.send(());
+        let mut buf = [0];
+        stream.read(&mut buf).unwrap();
+        tx.send(());
+    });
+
+    let mut stream = acceptor.accept().unwrap();
+    rx.recv();
+    Timer::new().unwrap().sleep(Duration::milliseconds(10));
+
+    let ops = EventLoop::borrow().unwrap().blocked_ops();
+    let reads: Vec<_> = ops.iter().filter(|op| op.op == "uv_read").collect();
+    assert_eq!(reads.len(), 1);
+    assert_eq!(reads[0].kind, "tcp");
+    assert_eq!(reads[0].task, Some("reader".to_string()));
+    assert!(reads[0].elapsed >= Duration::milliseconds(5));
+
+    stream.write(&[1]).unwrap();
+    rx.recv();
+    let ops = EventLoop::borrow().unwrap().blocked_ops();
+    assert!(ops.iter().all(|op| op.op != "uv_read"));
+})
+
+test!(fn close_write_timeout() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel::<()>();
+    spawn(proc() {
+        let _s = Tcp::connect(addr).unwrap();
+        rx.recv();
+    });
+
+    // Fill up the socket buffers so the shutdown can never complete.
+    let mut s = a.accept().unwrap();
+    fill(&mut s);
+
+    let mut s2 = s.clone();
+    let err = s.close_write_timeout(Duration::milliseconds(20)).err().unwrap();
+    assert_eq!(err.code(), uvll::ECANCELED);
+    assert_eq!(s2.uv_read(&mut [0]).err().unwrap().code(), uvll::EOF);
+    assert!(s2.uv_write(&[0]).is_err());
+    drop(s);
+    drop(s2);
+
+    tx.send(());
+})
+
+test!(fn close_write_timeout_ok() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    spawn(proc() {
+        let mut s = Tcp::connect(addr).unwrap();
+        s.write(&[1, 2, 3]).unwrap();
+        s.close_write_timeout(Duration::seconds(10)).unwrap();
+    });
+
+    let mut s = a.accept().unwrap();
+    assert_eq!(s.read_to_end().unwrap(), vec![1, 2, 3]);
+})
+
+// Writes to `s` until a write times out, returning how many bytes were
+// written including the timed out write, which completes in the background.
+fn fill(s: &mut Tcp) -> uint {
+    let mut total = 0;
+    let err = rustuv::with_timeout(Duration::milliseconds(20), || {
+        loop {
+            total += 128 * 1024;
+            try!(s.uv_write(&[0, ..128 * 1024]));
+        }
+    }).err().unwrap();
+    assert_eq!(err.code(), uvll::ECANCELED);
+    total
+}
+
+test!(fn flush() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel();
+    spawn(proc() {
+        let mut s = Tcp::connect(addr).unwrap();
+        let n: uint = rx.recv();
+        let mut buf = Vec::from_elem(n, 0u8);
+        let mut read = 0;
+        while read < n {
+            read += s.uv_read(buf.slice_from_mut(read)).unwrap();
+        }
+    });
+
+    let mut s = a.accept().unwrap();
+    let total = fill(&mut s);
+    tx.send(total);
+    s.flush().unwrap();
+})
+
+test!(fn drain_on_drop() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx1, rx1) = channel();
+    let (tx2, rx2) = channel();
+    spawn(proc() {
+        let mut s = Tcp::connect(addr).unwrap();
+        rx1.recv();
+        tx2.send(s.read_to_end().unwrap().len());
+    });
+
+    let mut s = a.accept().unwrap();
+    let total = fill(&mut s);
+    s.set_drain_on_drop(Some(Duration::seconds(10)));
+    tx1.send(());
+    drop(s);
+    assert_eq!(rx2.recv(), total);
+})
+
+test!(fn close_reset() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel();
+    spawn(proc() {
+        let mut s = Tcp::connect(addr).unwrap();
+        tx.send(s.uv_read(&mut [0]).err().unwrap().code());
+    });
+
+    let s = a.accept().unwrap();
+    let mut s2 = s.clone();
+    s.close_reset().unwrap();
+    assert_eq!(s2.uv_read(&mut [0]).err().unwrap().code(), uvll::EOF);
+    assert_eq!(rx.recv(), uvll::ECONNRESET);
+})
+
+#[cfg(target_os = "linux")]
+test!(fn keepalive_params() {
+    use libc;
+    use rustuv::raw::Handle;
+
+    let addr = next_test_ip4();
+    let _a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut s = Tcp::connect(addr).unwrap();
+    s.set_keepalive_params(Duration::seconds(10), Duration::seconds(3),
+                           4).unwrap();
+
+    let raw = unsafe { s.raw() };
+    let get = |name: libc::c_int| -> libc::c_int {
+        raw.get_sockopt(libc::IPPROTO_TCP, name).unwrap()
+    };
+    assert_eq!(get(4), 10); // TCP_KEEPIDLE
+    assert_eq!(get(5), 3);  // TCP_KEEPINTVL
+    assert_eq!(get(6), 4);  // TCP_KEEPCNT
+})
+
+test!(fn close_mode() {
+    use rustuv::CloseMode;
+
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel();
+    spawn(proc() {
+        let mut s = Tcp::connect(addr).unwrap();
+        s.set_close_mode(CloseMode::Linger(Duration::seconds(1))).unwrap();
+        s.set_close_mode(CloseMode::Reset).unwrap();
+        s.set_close_mode(CloseMode::Normal).unwrap();
+        s.write(&[1]).unwrap();
+        drop(s);
+        tx.send(());
+    });
+
+    let mut s = a.accept().unwrap();
+    rx.recv();
+    assert_eq!(s.read_to_end().unwrap(), vec![1]);
+})
+
+test!(fn close_mode_linger() {
+    use rustuv::CloseMode;
+
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel();
+    spawn(proc() {
+        let mut s = Tcp::connect(addr).unwrap();
+        s.set_close_mode(CloseMode::Linger(Duration::seconds(1))).unwrap();
+        rx.recv();
+        s.write(&[1, 2, 3]).unwrap();
+    });
+
+    // The peer sees all of the data followed by a FIN
+    let mut s = a.accept().unwrap();
+    tx.send(());
+    assert_eq!(s.read_to_end().unwrap(), vec![1, 2, 3]);
+})
+
+test!(fn close_mode_reset() {
+    use rustuv::CloseMode;
+
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel();
+    spawn(proc() {
+        let mut s = Tcp::connect(addr).unwrap();
+        s.set_close_mode(CloseMode::Reset).unwrap();
+        rx.recv();
+    });
+
+    // The peer sees an RST instead of a FIN
+    let mut s = a.accept().unwrap();
+    tx.send(());
+    let err = s.read(&mut [0]).err().unwrap();
+    assert_eq!(err.code(), uvll::ECONNRESET);
+})
+
+#[cfg(unix)]
+test!(fn from_std() {
+    use std::io::net::tcp::TcpStream;
+
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    spawn(proc() {
+        let std_stream = TcpStream::connect(addr).unwrap();
+        let mut s = Tcp::from_std(std_stream).unwrap();
+        s.write(&[1]).unwrap();
+        let fd = s.into_fd().unwrap();
+        let mut s = Tcp::open(fd).unwrap();
+        s.write(&[2]).unwrap();
+    });
+
+    let mut s = a.accept().unwrap();
+    assert_eq!(s.read_to_end().unwrap(), vec![1, 2]);
+})
+
+test!(fn io_counters() {
+    use rustuv::{EventLoop, IoCounters};
+
+    EventLoop::borrow().unwrap().set_io_counters(true);
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut c = Tcp::connect(addr).unwrap();
+    let mut s = a.accept().unwrap();
+    c.write(&[1, 2, 3]).unwrap();
+    c.write(&[4]).unwrap();
+    drop(c);
+    assert_eq!(s.read_to_end().unwrap(), vec![1, 2, 3, 4]);
+
+    let counters = s.io_counters().unwrap();
+    assert_eq!(counters.bytes_read, 4);
+    assert_eq!(counters.writes, 0);
+    let total = EventLoop::borrow().unwrap().io_counters().unwrap();
+    assert_eq!(total, IoCounters {
+        bytes_read: 4,
+        bytes_written: 4,
+        reads: 1,
+        writes: 2,
+    });
+})
+
+test!(fn simultaneous_accepts() {
+    let addr = next_test_ip4();
+    let mut l = TcpListener::bind(addr).unwrap();
+    l.simultaneous_accepts(false).unwrap();
+    let mut a = l.listen().unwrap();
+    let mut c = Tcp::connect(addr).unwrap();
+    let mut s = a.accept().unwrap();
+    c.write(&[1]).unwrap();
+    assert_eq!(s.read_byte().unwrap(), 1);
+})
+
+test!(fn with_timeout_cancels_read() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let _c = Tcp::connect(addr).unwrap();
+    let mut s = a.accept().unwrap();
+
+    let err = rustuv::with_timeout(Duration::milliseconds(10), || {
+        s.uv_read(&mut [0])
+    }).err().unwrap();
+    assert_eq!(err.code(), uvll::ECANCELED);
+
+    // Once expired, operations in the scope fail right away
+    let err = rustuv::with_timeout(Duration::milliseconds(10), || {
+        let _ = s.uv_read(&mut [0]);
+        s.uv_write(&[1])
+    }).err().unwrap();
+    assert_eq!(err.code(), uvll::ECANCELED);
+
+    // The stream is still usable afterwards
+    s.uv_write(&[1]).unwrap();
+})
+
+test!(fn with_timeout_nested() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let err = rustuv::with_timeout(Duration::milliseconds(10), || {
+        rustuv::with_timeout(Duration::seconds(10), || a.accept())
+    }).err().unwrap();
+    assert_eq!(err.code(), uvll::ECANCELED);
+
+    let mut c = Tcp::connect(addr).unwrap();
+    let mut s = rustuv::with_timeout(Duration::seconds(10), || {
+        a.accept()
+    }).unwrap();
+    c.uv_write(&[1]).unwrap();
+    assert_eq!(s.uv_read(&mut [0]).unwrap(), 1);
+})
+
+test!(fn many_read_timeouts() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel();
+
+    // Deadlines across several levels of the timer wheel expire in order
+    for &ms in [300i64, 5, 70].iter() {
+        let _c = Tcp::connect(addr).unwrap();
+        let mut s = a.accept().unwrap();
+        let tx = tx.clone();
+        spawn(proc() {
+            let _c = _c;
+            s.set_read_timeout(Some(Duration::milliseconds(ms)));
+            let err = s.uv_read(&mut [0]).err().unwrap();
+            assert_eq!(err.code(), uvll::ECANCELED);
+            tx.send(ms);
+        });
+    }
+    assert_eq!(rx.recv(), 5);
+    assert_eq!(rx.recv(), 70);
+    assert_eq!(rx.recv(), 300);
+})
+
+test!(fn read_timeouts_sharing_a_slot() {
+    let addr = next_test_ip4();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let (tx, rx) = channel();
+
+    // Cancelling one timeout of the slot leaves the others in place
+    let mut clients = Vec::new();
+    for i in range(0u, 3) {
+        clients.push(Tcp::connect(addr).unwrap());
+        let mut s = a.accept().unwrap();
+        let tx = tx.clone();
+        spawn(proc() {
+            s.set_read_timeout(Some(Duration::milliseconds(50)));
+            tx.send((i, s.uv_read(&mut [0]).map_err(|e| e.code())));
+        });
+    }
+    clients[mut][0].uv_write(&[1]).unwrap();
+    let mut results = range(0u, 3).map(|_| rx.recv()).collect::<Vec<_>>();
+    results.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+    assert_eq!(results, vec![(0, Ok(1)), (1, Err(uvll::ECANCELED)),
+                             (2, Err(uvll::ECANCELED))]);
+})
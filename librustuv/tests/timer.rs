@@ -1,7 +1,7 @@
 use std::time::Duration;
 use green::Callback;
 use green::task::spawn;
-use rustuv::Timer;
+use rustuv::{Timer, EventLoop};
 
 fn sender(tx: Sender<()>) -> Box<Callback + Send> {
     struct MySender { tx: Sender<()> }
@@ -253,3 +253,45 @@ test!(fn periodic_negative() {
     rx.recv();
     rx.recv();
 })
+
+test!(fn migrate_sleep() {
+    let timer = Timer::new().unwrap();
+    let mut timer = timer.migrate().unwrap();
+    timer.sleep(ms(1));
+})
+
+test!(fn migrate_pending_actions() {
+    let mut timer = Timer::new().unwrap();
+    let (tx, rx) = channel();
+    timer.oneshot(ms(10), sender(tx));
+    let _timer = {
+        let mut eloop = EventLoop::borrow().unwrap();
+        timer.migrate_on(&mut *eloop).unwrap()
+    };
+    rx.recv();
+
+    let mut timer = Timer::new().unwrap();
+    let (tx, rx) = channel();
+    timer.periodic(ms(1), sender(tx));
+    rx.recv();
+    let _timer = timer.migrate().unwrap();
+    rx.recv();
+    rx.recv();
+})
+
+test!(fn free_sleep() {
+    rustuv::sleep(ms(1)).unwrap();
+    rustuv::sleep(ms(1)).unwrap();
+
+    // Tasks sleeping at the same time don't share a timer
+    let (tx, rx) = channel();
+    for _ in range(0u, 3) {
+        let tx = tx.clone();
+        spawn(proc() {
+            rustuv::sleep(ms(5)).unwrap();
+            tx.send(());
+        });
+    }
+    rustuv::sleep(ms(1)).unwrap();
+    for _ in range(0u, 3) { rx.recv() }
+})
@@ -276,3 +276,101 @@ test!(fn recv_from_timeout() {
     // Make sure the child didn't die
     rx2.recv();
 })
+
+test!(fn cancel_io_wakes_receiver() {
+    let mut a = Udp::bind(next_test_ip4()).unwrap();
+    let a2 = a.clone();
+
+    let (tx, rx) = channel();
+    let (tx2, rx2) = channel();
+    spawn(proc() {
+        let mut a2 = a2;
+        tx.send(());
+        let err = a2.recv_from(&mut [0]).err().unwrap();
+        tx2.send(err.code());
+    });
+
+    rx.recv();
+    a.cancel_io().unwrap();
+    assert_eq!(rx2.recv(), uvll::ECANCELED);
+})
+
+test!(fn peek_from() {
+    let addr1 = next_test_ip4();
+    let addr2 = next_test_ip4();
+    let mut a = Udp::bind(addr1).unwrap();
+    let mut b = Udp::bind(addr2).unwrap();
+    b.send_to(&[1, 2, 3], addr1).unwrap();
+    b.send_to(&[4], addr1).unwrap();
+
+    let mut buf = [0, ..2];
+    assert_eq!(a.peek_from(&mut buf).unwrap(), (2, addr2));
+    assert_eq!(buf.as_slice(), [1, 2].as_slice());
+
+    let mut buf = [0, ..10];
+    assert_eq!(a.clone().peek_from(&mut buf).unwrap(), (3, addr2));
+    assert_eq!(a.recv_from(&mut buf).unwrap(), (3, addr2));
+    assert_eq!(buf.slice_to(3), [1, 2, 3].as_slice());
+    assert_eq!(a.recv_from(&mut buf).unwrap(), (1, addr2));
+    assert_eq!(buf[0], 4);
+})
+
+test!(fn recv_from_vectored() {
+    let addr1 = next_test_ip4();
+    let addr2 = next_test_ip4();
+    let mut a = Udp::bind(addr1).unwrap();
+    let mut b = Udp::bind(addr2).unwrap();
+    b.send_to(&[1, 2, 3, 4, 5], addr1).unwrap();
+    b.send_to(&[6, 7, 8], addr1).unwrap();
+
+    let mut header = [0, ..2];
+    let mut payload = [0, ..10];
+    {
+        let mut bufs = [header.as_mut_slice(), payload.as_mut_slice()];
+        assert_eq!(a.recv_from_vectored(&mut bufs).unwrap(), (5, addr2));
+    }
+    assert_eq!(header.as_slice(), [1, 2].as_slice());
+    assert_eq!(payload.slice_to(3), [3, 4, 5].as_slice());
+
+    // A peeked datagram is scattered as well
+    assert_eq!(a.peek_from(&mut [0]).unwrap(), (1, addr2));
+    {
+        let mut bufs = [header.as_mut_slice(), payload.as_mut_slice()];
+        assert_eq!(a.recv_from_vectored(&mut bufs).unwrap(), (3, addr2));
+    }
+    assert_eq!(header.as_slice(), [6, 7].as_slice());
+    assert_eq!(payload[0], 8);
+})
+
+#[cfg(unix)]
+test!(fn socket_options() {
+    let mut a = Udp::bind(next_test_ip4()).unwrap();
+    a.set_traffic_class(0xb8).unwrap();
+    a.set_recv_buffer_size(256 * 1024).unwrap();
+    a.set_send_buffer_size(128 * 1024).unwrap();
+    assert!(a.recv_buffer_size().unwrap() >= 128 * 1024);
+    assert!(a.send_buffer_size().unwrap() >= 64 * 1024);
+
+    let mut b = Udp::bind(next_test_ip6()).unwrap();
+    b.set_traffic_class(0xb8).unwrap();
+})
+
+test!(fn send_many() {
+    let addr1 = next_test_ip4();
+    let addr2 = next_test_ip4();
+    let mut a = Udp::bind(addr1).unwrap();
+    let mut b = Udp::bind(addr2).unwrap();
+
+    let bufs = [[1u8], [2], [3]];
+    let msgs: Vec<(&[u8], SocketAddr)> = bufs.iter().map(|b| {
+        (b.as_slice(), addr1)
+    }).collect();
+    b.send_many(msgs.as_slice()).unwrap();
+    b.send_many_nowait(msgs.as_slice()).unwrap();
+
+    let mut buf = [0];
+    for i in range(0u, 6) {
+        assert_eq!(a.recv_from(&mut buf).unwrap(), (1, addr2));
+        assert_eq!(buf[0], (i % 3 + 1) as u8);
+    }
+})
@@ -0,0 +1,103 @@
+use std::io::test::next_test_ip4;
+use std::sync::Arc;
+use std::time::Duration;
+use green::task::spawn;
+
+use rustuv::{uvll, TcpListener, Timer};
+use rustuv::util::{ConnectionPool, RateLimiter};
+
+test!(fn rate_limiter() {
+    let limiter = RateLimiter::new(100, 5);
+    assert!(limiter.try_acquire(5));
+    assert!(!limiter.try_acquire(1));
+    assert_eq!(limiter.acquire(6).err().unwrap().code(), uvll::EINVAL);
+
+    // 5 tokens take 50ms to come back
+    let start = unsafe { uvll::uv_hrtime() };
+    limiter.acquire(5).unwrap();
+    let elapsed = (unsafe { uvll::uv_hrtime() } - start) / 1000000;
+    assert!(elapsed >= 40, "only waited {}ms", elapsed);
+})
+
+test!(fn rate_limiter_shared() {
+    let limiter = Arc::new(RateLimiter::new(100, 5));
+    let start = unsafe { uvll::uv_hrtime() };
+
+    // 20 tokens are taken, 15 of which take 150ms to come back
+    let (tx, rx) = channel();
+    for _ in range(0u, 4) {
+        let (limiter, tx) = (limiter.clone(), tx.clone());
+        spawn(proc() {
+            limiter.acquire(5).unwrap();
+            tx.send(());
+        });
+    }
+    for _ in range(0u, 4) { rx.recv() }
+    let elapsed = (unsafe { uvll::uv_hrtime() } - start) / 1000000;
+    assert!(elapsed >= 140, "only waited {}ms", elapsed);
+})
+
+test!(fn connection_pool() {
+    let addr = next_test_ip4();
+    let ip = addr.ip.to_string();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut pool = ConnectionPool::new(1).unwrap();
+
+    let mut c = pool.checkout(ip.as_slice(), addr.port).unwrap();
+    let s1 = a.accept().unwrap();
+    let name = c.socket_name().unwrap();
+    pool.checkin(ip.as_slice(), addr.port, c);
+    assert_eq!(pool.idle_count(), 1);
+
+    // The idle connection is reused
+    let mut c = pool.checkout(ip.as_slice(), addr.port).unwrap();
+    assert_eq!(c.socket_name().unwrap(), name);
+    assert_eq!(pool.idle_count(), 0);
+    pool.checkin(ip.as_slice(), addr.port, c);
+
+    // Until its peer closes it, which can only be detected on unix
+    if cfg!(windows) { return }
+    drop(s1);
+    Timer::new().unwrap().sleep(Duration::milliseconds(10));
+    let mut c = pool.checkout(ip.as_slice(), addr.port).unwrap();
+    let _s2 = a.accept().unwrap();
+    assert!(c.socket_name().unwrap() != name);
+})
+
+test!(fn connection_pool_idle_timeout() {
+    let addr = next_test_ip4();
+    let ip = addr.ip.to_string();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut pool = ConnectionPool::new(2).unwrap();
+    pool.set_idle_timeout(Some(Duration::milliseconds(10))).unwrap();
+
+    let c = pool.checkout(ip.as_slice(), addr.port).unwrap();
+    let _s = a.accept().unwrap();
+    pool.checkin(ip.as_slice(), addr.port, c);
+    assert_eq!(pool.idle_count(), 1);
+
+    // The connection is closed in the background
+    Timer::new().unwrap().sleep(Duration::milliseconds(50));
+    assert_eq!(pool.idle_count(), 0);
+})
+
+test!(fn connection_pool_buffered() {
+    let addr = next_test_ip4();
+    let ip = addr.ip.to_string();
+    let mut a = TcpListener::bind(addr).unwrap().listen().unwrap();
+    let mut pool = ConnectionPool::new(1).unwrap();
+
+    let mut c = pool.checkout(ip.as_slice(), addr.port).unwrap();
+    let mut s = a.accept().unwrap();
+    let name = c.socket_name().unwrap();
+    s.write(&[1, 2]).unwrap();
+    assert_eq!(c.fill_buf().unwrap(), [1u8, 2].as_slice());
+    c.consume(1);
+    assert_eq!(c.buffered(), 1);
+
+    // Data left in the buffer of the stream was never asked for either
+    pool.checkin(ip.as_slice(), addr.port, c);
+    let mut c = pool.checkout(ip.as_slice(), addr.port).unwrap();
+    let _s2 = a.accept().unwrap();
+    assert!(c.socket_name().unwrap() != name);
+})